@@ -0,0 +1,142 @@
+use colored::*;
+use hf2::SerialOutput;
+use hidapi::HidDevice;
+use std::io::Write;
+
+/// What the console is waiting for before it exits.
+pub struct Patterns {
+    pub pass: Option<String>,
+    pub fail: Option<String>,
+}
+
+impl Patterns {
+    fn exit_code(&self, line: &str) -> Option<i32> {
+        if let Some(fail) = &self.fail {
+            if line.contains(fail.as_str()) {
+                return Some(1);
+            }
+        }
+        if let Some(pass) = &self.pass {
+            if line.contains(pass.as_str()) {
+                return Some(0);
+            }
+        }
+        None
+    }
+
+    fn any(&self) -> bool {
+        self.pass.is_some() || self.fail.is_some()
+    }
+}
+
+/// Print the device's serial output until a pattern matches or the device goes away, returning the exit code.
+pub fn console(d: &HidDevice, elf: &[u8], patterns: &Patterns) -> i32 {
+    println!("    {} console", "Attaching".green().bold());
+
+    let mut decoder = Decoder::new(elf);
+    let mut stderr = String::new();
+
+    loop {
+        let lines = match hf2::read_serial(d) {
+            Ok(Some(SerialOutput::StdOut(data))) => decoder.received(&data),
+            Ok(Some(SerialOutput::StdErr(data))) => {
+                stderr.push_str(&String::from_utf8_lossy(&data));
+                split_lines(&mut stderr)
+            }
+            Ok(None) => continue,
+            Err(e) => {
+                log::debug!("console read failed {:?}", e);
+                eprintln!("device disconnected");
+                return if patterns.any() { 1 } else { 0 };
+            }
+        };
+
+        for line in lines {
+            println!("{}", line);
+            if let Some(code) = patterns.exit_code(&line) {
+                std::io::stdout().flush().ok();
+                return code;
+            }
+        }
+    }
+}
+
+// drain complete lines, leaving any partial line in the buffer
+fn split_lines(buffer: &mut String) -> Vec<String> {
+    let mut lines = vec![];
+    while let Some(index) = buffer.find('\n') {
+        let line: String = buffer.drain(..=index).collect();
+        lines.push(line.trim_end().to_string());
+    }
+    lines
+}
+
+#[cfg(feature = "defmt")]
+struct Decoder {
+    stream: Option<Box<dyn defmt_decoder::StreamDecoder + Send + Sync>>,
+    text: String,
+}
+
+#[cfg(feature = "defmt")]
+impl Decoder {
+    fn new(elf: &[u8]) -> Self {
+        let table = defmt_decoder::Table::parse(elf).expect("Couldn't parse defmt table");
+        // the stream decoder borrows the table for as long as the console runs, which is the rest of the process
+        let stream = table.map(|table| {
+            println!("    {} defmt frames", "Decoding".green().bold());
+            let table: &'static defmt_decoder::Table = Box::leak(Box::new(table));
+            table.new_stream_decoder()
+        });
+        Decoder {
+            stream,
+            text: String::new(),
+        }
+    }
+
+    fn received(&mut self, data: &[u8]) -> Vec<String> {
+        let stream = match &mut self.stream {
+            Some(stream) => stream,
+            None => {
+                self.text.push_str(&String::from_utf8_lossy(data));
+                return split_lines(&mut self.text);
+            }
+        };
+
+        stream.received(data);
+        let mut lines = vec![];
+        loop {
+            match stream.decode() {
+                Ok(frame) => lines.push(frame.display(false).to_string()),
+                Err(defmt_decoder::DecodeError::UnexpectedEof) => break,
+                Err(defmt_decoder::DecodeError::Malformed) => {
+                    eprintln!("malformed defmt frame");
+                    break;
+                }
+            }
+        }
+        lines
+    }
+}
+
+#[cfg(not(feature = "defmt"))]
+struct Decoder {
+    text: String,
+}
+
+#[cfg(not(feature = "defmt"))]
+impl Decoder {
+    fn new(elf: &[u8]) -> Self {
+        // crude, but avoids parsing the elf just to print a hint
+        if elf.windows(6).any(|w| w == b".defmt") {
+            eprintln!("binary uses defmt, rebuild cargo-hf2 with --features defmt to decode it");
+        }
+        Decoder {
+            text: String::new(),
+        }
+    }
+
+    fn received(&mut self, data: &[u8]) -> Vec<String> {
+        self.text.push_str(&String::from_utf8_lossy(data));
+        split_lines(&mut self.text)
+    }
+}
@@ -1,101 +1,97 @@
 use colored::*;
-use hf2::utils::{elf_to_bin, flash_bin, vendor_map};
+use hf2::utils::{
+    bin_to_uf2, check_softdevice, check_vector_table, elf_firmware_info, elf_to_bin, flash_bin,
+    flash_uf2_volume, open_queued, open_unlocked, uf2_volumes, DeviceLock, FirmwareInfo, PadPolicy,
+    Uf2Volume, UtilError,
+};
 use hidapi::{HidApi, HidDevice};
+use std::io::{BufRead, BufReader};
 use std::path::PathBuf;
 use std::process::{Command, Stdio};
-use std::time::Instant;
+use std::time::{Duration, Instant};
 use structopt::StructOpt;
 
+mod console;
+
 fn main() {
     // Initialize the logging backend.
     pretty_env_logger::init();
 
+    // Remove first two args which is the calling application name and the `hf2` command from cargo.
+    // Anything after `--` goes to `cargo build` untouched.
+    let mut args: Vec<_> = std::env::args().skip(2).collect();
+    let passthrough = match args.iter().position(|x| x == "--") {
+        Some(index) => args.split_off(index).split_off(1),
+        None => vec![],
+    };
+
     // Get commandline options.
     // Skip the first arg which is the calling application name.
-    let opt = Opt::from_iter(std::env::args().skip(1));
+    let opt = Opt::from_iter(std::env::args().take(2).skip(1).chain(args.clone()));
 
     // Try and get the cargo project information.
     let project = cargo_project::Project::query(".").expect("Couldn't parse the Cargo.toml");
 
     // Decide what artifact to use.
     let artifact = if let Some(bin) = &opt.bin {
-        cargo_project::Artifact::Bin(bin)
+        Artifact::Bin(bin)
     } else if let Some(example) = &opt.example {
-        cargo_project::Artifact::Example(example)
+        Artifact::Example(example)
     } else {
-        cargo_project::Artifact::Bin(project.name())
+        Artifact::Bin(project.name())
     };
 
-    // Decide what profile to use.
-    let profile = if opt.release {
-        cargo_project::Profile::Release
-    } else {
-        cargo_project::Profile::Dev
-    };
-
-    // Try and get the artifact path.
-    let path = project
-        .path(
-            artifact,
-            profile,
-            opt.target.as_deref(),
-            "x86_64-unknown-linux-gnu",
-        )
-        .expect("Couldn't find the build result");
-
-    // Remove first two args which is the calling application name and the `hf2` command from cargo.
-    let mut args: Vec<_> = std::env::args().skip(2).collect();
-
     // todo, keep as iter. difficult because we want to filter map remove two items at once.
     // Remove our args as cargo build does not understand them.
-    let flags = ["--pid", "--vid"].iter();
+    let flags = ["--pid", "--vid", "--pad", "--pass", "--fail", "--wait"].iter();
     for flag in flags {
         if let Some(index) = args.iter().position(|x| x == flag) {
             args.remove(index);
             args.remove(index);
         }
     }
+    // and the ones without a value
+    args.retain(|x| x != "--steal" && x != "--console");
 
-    let status = Command::new("cargo")
+    // cargo says where it put the elf on stdout, still rendering its diagnostics on stderr
+    let mut build = Command::new("cargo")
         .arg("build")
+        .arg("--message-format=json-render-diagnostics")
         .args(args)
-        .stdout(Stdio::inherit())
+        .args(passthrough)
+        .stdout(Stdio::piped())
         .stderr(Stdio::inherit())
         .spawn()
-        .unwrap()
-        .wait()
         .unwrap();
+    let stdout = BufReader::new(build.stdout.take().unwrap());
+    let path = built_elf(stdout, &artifact);
+    let status = build.wait().unwrap();
 
     if !status.success() {
         exit_with_process_status(status)
     }
+    let path = path.unwrap_or_else(|| panic!("cargo didn't report building {:?}", artifact));
 
-    let api = HidApi::new().expect("Couldn't find system usb");
+    let mut api = HidApi::new().expect("Couldn't find system usb");
 
-    let d = if let (Some(v), Some(p)) = (opt.vid, opt.pid) {
-        api.open(v, p)
-            .expect("Are you sure device is plugged in and in bootloader mode?")
+    let candidates: Vec<hf2::DeviceInfo> = if let (Some(v), Some(p)) = (opt.vid, opt.pid) {
+        api.device_list()
+            .filter(|info| info.vendor_id() == v && info.product_id() == p)
+            .map(hf2::DeviceInfo::from)
+            .collect()
     } else {
         println!(
             "    {} for a connected device with known vid/pid pair.",
             "Searching".green().bold(),
         );
 
-        let mut device: Option<HidDevice> = None;
-
-        let vendor = vendor_map();
+        hf2::enumerate(&api)
+    };
 
-        for device_info in api.device_list() {
-            if let Some(products) = vendor.get(&device_info.vendor_id()) {
-                if products.contains(&device_info.product_id()) {
-                    if let Ok(d) = device_info.open_device(&api) {
-                        device = Some(d);
-                        break;
-                    }
-                }
-            }
-        }
-        device.expect("Are you sure device is plugged in and in bootloader mode?")
+    let (d, lock) = match open(&api, &candidates, &opt) {
+        Ok(opened) => opened,
+        Err(_) if uf2_fallback(&path, &opt) => return attach(&mut api, &opt, &path),
+        Err(_) => panic!("Are you sure device is plugged in and in bootloader mode?"),
     };
 
     println!(
@@ -107,7 +103,16 @@ fn main() {
 
     println!("    {} {:?}", "Flashing".green().bold(), path);
 
-    let (binary, address) = elf_to_bin(path).unwrap();
+    let (binary, address) = elf_to_bin(path.clone()).unwrap();
+    // only used in the report, flashing goes on without it
+    let firmware = elf_firmware_info(path.clone()).unwrap_or_else(|e| {
+        println!(
+            "    {} couldn't read the build id and version, {:?}",
+            "Warning".yellow().bold(),
+            e
+        );
+        FirmwareInfo::default()
+    });
 
     // Start timer.
     let instant = Instant::now();
@@ -115,15 +120,172 @@ fn main() {
     let bininfo = hf2::bin_info(&d).expect("bin_info failed");
     log::debug!("{:?}", bininfo);
 
-    flash_bin(&binary, address, &bininfo, &d).unwrap();
+    check_vector_table(&binary, address, &bininfo).unwrap();
+    if let Some(warning) = check_softdevice(&binary, address, &bininfo, &d).unwrap() {
+        println!("    {} {}", "Warning".yellow().bold(), warning);
+    }
+    flash_bin(&binary, address, opt.pad, &bininfo, &d).unwrap();
 
     // Stop timer.
     let elapsed = instant.elapsed();
     println!(
-        "    {} in {}s",
+        "    {} {} in {}s",
         "Finished".green().bold(),
+        firmware,
         elapsed.as_millis() as f32 / 1000.0
     );
+
+    // the app comes back as a new usb device, so the bootloader handle is useless now
+    drop(d);
+    drop(lock);
+    attach(&mut api, &opt, &path)
+}
+
+// follow the app's output once it's flashed, when asked to
+fn attach(api: &mut HidApi, opt: &Opt, path: &std::path::Path) {
+    if !(opt.console || opt.pass.is_some() || opt.fail.is_some()) {
+        return;
+    }
+    let (d, _lock) = reopen(api, opt);
+
+    let elf = std::fs::read(path).expect("Couldn't read the build result");
+    let patterns = console::Patterns {
+        pass: opt.pass.clone(),
+        fail: opt.fail.clone(),
+    };
+    std::process::exit(console::console(&d, &elf, &patterns));
+}
+
+#[derive(Debug)]
+enum Artifact<'a> {
+    Bin(&'a str),
+    Example(&'a str),
+}
+
+// the executable cargo build reported for artifact among its json messages
+fn built_elf(messages: impl BufRead, artifact: &Artifact) -> Option<PathBuf> {
+    let (name, kind) = match artifact {
+        Artifact::Bin(name) => (name, "bin"),
+        Artifact::Example(name) => (name, "example"),
+    };
+    messages
+        .lines()
+        .map_while(Result::ok)
+        .filter_map(|line| serde_json::from_str::<serde_json::Value>(&line).ok())
+        .filter(|message| {
+            message["reason"] == "compiler-artifact"
+                && message["target"]["name"] == *name
+                && message["target"]["kind"]
+                    .as_array()
+                    .is_some_and(|kinds| kinds.iter().any(|k| k == kind))
+        })
+        .filter_map(|message| message["executable"].as_str().map(PathBuf::from))
+        .last()
+}
+
+// without an hf2 interface, copy a UF2 file onto the mounted UF2 drive of the board --board-id
+// names instead. False if there's no such drive, a drive of some other board is left alone.
+fn uf2_fallback(path: &std::path::Path, opt: &Opt) -> bool {
+    let volumes = uf2_volumes();
+    let board_id = match &opt.board_id {
+        Some(board_id) => board_id,
+        None => {
+            for volume in &volumes {
+                eprintln!(
+                    "no hf2 device, pass --board-id {} to copy onto the UF2 drive {:?}",
+                    volume.board_id.as_deref().unwrap_or("<Board-ID>"),
+                    volume.path
+                );
+            }
+            return false;
+        }
+    };
+    let volume = match board_volumes(&volumes, board_id).as_slice() {
+        [volume] => *volume,
+        [] => return false,
+        _ => {
+            eprintln!(
+                "no hf2 device and several UF2 drives of {} mounted, not guessing which to use",
+                board_id
+            );
+            return false;
+        }
+    };
+
+    println!(
+        "    {} to UF2 drive {:?} {:?}",
+        "Copying".green().bold(),
+        volume.path,
+        volume.board_id
+    );
+
+    let instant = Instant::now();
+    let copied = elf_to_bin(path.to_path_buf()).and_then(|(binary, address)| {
+        flash_uf2_volume(
+            &bin_to_uf2(&binary, address, None),
+            volume,
+            Duration::from_secs(10),
+        )
+    });
+    if let Err(e) = copied {
+        eprintln!("couldn't copy {:?} onto the UF2 drive, {:?}", path, e);
+        std::process::exit(1);
+    }
+
+    println!(
+        "    {} in {}s",
+        "Finished".green().bold(),
+        instant.elapsed().as_millis() as f32 / 1000.0
+    );
+    true
+}
+
+// the drives whose INFO_UF2.TXT names board_id
+fn board_volumes<'a>(volumes: &'a [Uf2Volume], board_id: &str) -> Vec<&'a Uf2Volume> {
+    volumes
+        .iter()
+        .filter(|volume| volume.board_id.as_deref() == Some(board_id))
+        .collect()
+}
+
+fn open(
+    api: &HidApi,
+    candidates: &[hf2::DeviceInfo],
+    opt: &Opt,
+) -> Result<(HidDevice, Option<DeviceLock>), UtilError> {
+    let opened = match opt.wait {
+        Some(secs) => open_queued(api, candidates, Duration::from_secs(secs)),
+        None => open_unlocked(api, candidates, opt.steal),
+    };
+
+    match opened {
+        Err(UtilError::Busy(pid)) => {
+            let pid = pid.map_or("unknown".into(), |pid| pid.to_string());
+            eprintln!(
+                "device busy (pid {}), pass --wait to queue behind it or --steal to use it anyway",
+                pid
+            );
+            std::process::exit(1);
+        }
+        opened => opened,
+    }
+}
+
+// wait for the freshly flashed app to enumerate, it may not keep the bootloader's vid/pid but has to speak hf2
+fn reopen(api: &mut HidApi, opt: &Opt) -> (HidDevice, Option<DeviceLock>) {
+    let deadline = Instant::now() + Duration::from_secs(5);
+    loop {
+        std::thread::sleep(Duration::from_millis(250));
+        api.refresh_devices().expect("Couldn't find system usb");
+        match open(api, &hf2::enumerate(api), opt) {
+            Ok(opened) => return opened,
+            Err(_) if Instant::now() < deadline => continue,
+            Err(_) => {
+                eprintln!("app didn't come back as an hf2 device, can't attach the console or tell a pass from a fail");
+                std::process::exit(1);
+            }
+        }
+    }
 }
 
 #[cfg(unix)]
@@ -147,6 +309,8 @@ fn parse_hex_16(input: &str) -> Result<u16, std::num::ParseIntError> {
     }
 }
 
+// most `cargo build` arguments are only parsed so they're accepted, they get forwarded untouched
+#[allow(dead_code)]
 #[derive(Debug, StructOpt)]
 struct Opt {
     // `cargo build` arguments
@@ -173,4 +337,70 @@ struct Opt {
     pid: Option<u16>,
     #[structopt(name = "vid", long = "vid",  parse(try_from_str = parse_hex_16))]
     vid: Option<u16>,
+    /// use the device even if another hf2 process holds it
+    #[structopt(long = "steal")]
+    steal: bool,
+    /// when another hf2 process holds the device, wait up to this many seconds for it to finish
+    #[structopt(name = "wait", long = "wait")]
+    wait: Option<u64>,
+    /// how to fill the end of the last page, zero, erased or preserve
+    #[structopt(name = "pad", long = "pad", default_value = "zero")]
+    pad: PadPolicy,
+    /// after flashing, print the app's serial output until interrupted
+    #[structopt(long = "console")]
+    console: bool,
+    /// attach the console and exit 0 once a line contains this text
+    #[structopt(name = "pass", long = "pass")]
+    pass: Option<String>,
+    /// attach the console and exit 1 once a line contains this text
+    #[structopt(name = "fail", long = "fail")]
+    fail: Option<String>,
+    /// with no hf2 device, copy the build onto the mounted UF2 drive whose INFO_UF2.TXT has this Board-ID
+    #[structopt(name = "board-id", long = "board-id")]
+    board_id: Option<String>,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn elf_from_messages() {
+        let messages = r#"{"reason":"compiler-artifact","target":{"kind":["lib"],"name":"app"},"executable":null}
+{"reason":"compiler-artifact","target":{"kind":["example"],"name":"blinky"},"executable":"/w/target/thumbv7em-none-eabihf/release/examples/blinky"}
+{"reason":"compiler-artifact","target":{"kind":["bin"],"name":"app"},"executable":"/w/target/thumbv7em-none-eabihf/release/app"}
+{"reason":"build-finished","success":true}
+"#;
+        assert_eq!(
+            built_elf(messages.as_bytes(), &Artifact::Bin("app")),
+            Some(PathBuf::from("/w/target/thumbv7em-none-eabihf/release/app"))
+        );
+        assert_eq!(
+            built_elf(messages.as_bytes(), &Artifact::Example("blinky")),
+            Some(PathBuf::from(
+                "/w/target/thumbv7em-none-eabihf/release/examples/blinky"
+            ))
+        );
+        assert_eq!(
+            built_elf(messages.as_bytes(), &Artifact::Example("app")),
+            None
+        );
+    }
+    #[test]
+    fn drives_of_the_board() {
+        let volume = |path: &str, board_id: Option<&str>| Uf2Volume {
+            path: path.into(),
+            board_id: board_id.map(String::from),
+        };
+        let volumes = [
+            volume("/media/FEATHERBOOT", Some("SAMD21G18A-Feather-v0")),
+            volume("/media/PYGAMERBOOT", Some("SAMD51J19A-PyGamer-M4")),
+            volume("/media/RPI-RP2", None),
+        ];
+        assert_eq!(
+            board_volumes(&volumes, "SAMD51J19A-PyGamer-M4"),
+            [&volumes[1]]
+        );
+        assert!(board_volumes(&volumes, "nRF52840-Feather-revD").is_empty());
+    }
 }
@@ -0,0 +1,129 @@
+use crate::exit::Exit;
+use hf2::utils::{lock_device, UtilError};
+use hidapi::HidApi;
+use std::time::Instant;
+
+/// Runs through the usual reasons a board isn't found or can't be talked to, printing what passed
+/// and how to fix what didn't. Exits nonzero if anything failed.
+pub fn doctor() -> Result<(), Exit> {
+    let mut failed = false;
+    let mut check = |ok: bool, what: String, fix: &str| {
+        if ok {
+            outln!("[PASS] {}", what);
+        } else {
+            outln!("[FAIL] {}", what);
+            outln!("       fix: {}", fix);
+            failed = true;
+        }
+    };
+
+    let api = match HidApi::new() {
+        Ok(api) => {
+            check(true, "hid backend available".into(), "");
+            api
+        }
+        Err(e) => {
+            check(
+                false,
+                format!("hid backend available ({:?})", e),
+                "install libusb, ie sudo apt-get install libudev-dev libusb-1.0-0-dev",
+            );
+            return Err(Exit { code: 1 });
+        }
+    };
+
+    let devices = hf2::enumerate(&api);
+    check(
+        !devices.is_empty(),
+        format!("{} hf2 device(s) connected", devices.len()),
+        "plug the board in and put it in bootloader mode, usually by double tapping reset. Unknown boards need --vid and --pid",
+    );
+
+    if cfg!(target_os = "macos") {
+        outln!("[INFO] on macOS the terminal needs \"Input Monitoring\" permission to talk to hid devices");
+    }
+
+    for info in &devices {
+        let name = format!(
+            "{:04x}:{:04x} {}",
+            info.vendor_id,
+            info.product_id,
+            info.product.as_deref().unwrap_or("")
+        );
+
+        if cfg!(target_os = "linux") {
+            check(
+                udev_rule_exists(info.vendor_id),
+                format!("{} has a udev rule", name),
+                &format!(
+                    "add SUBSYSTEM==\"usb\", ATTRS{{idVendor}}==\"{:04x}\", MODE=\"0666\" to /etc/udev/rules.d/99-hf2-boards.rules then sudo udevadm control --reload-rules && sudo udevadm trigger",
+                    info.vendor_id
+                ),
+            );
+        }
+
+        match lock_device(&info.path) {
+            Err(UtilError::Busy(pid)) => check(
+                false,
+                format!("{} not in use by another hf2 (pid {:?})", name, pid),
+                "wait for the other process, or pass --steal",
+            ),
+            _ => check(true, format!("{} not in use by another hf2", name), ""),
+        }
+
+        let d = match info.open(&api) {
+            Ok(d) => {
+                check(true, format!("{} opens", name), "");
+                d
+            }
+            Err(e) => {
+                check(
+                    false,
+                    format!("{} opens ({:?})", name, e),
+                    "usually permissions, see the udev rules above or try sudo",
+                );
+                continue;
+            }
+        };
+
+        let start = Instant::now();
+        match hf2::bin_info(&d) {
+            Ok(bininfo) => check(
+                true,
+                format!(
+                    "{} answers bininfo in {}ms, {:?} mode",
+                    name,
+                    start.elapsed().as_millis(),
+                    bininfo.mode
+                ),
+                "",
+            ),
+            Err(e) => check(
+                false,
+                format!("{} answers bininfo ({})", name, e),
+                "this may not be the hf2 interface, or the bootloader is wedged. Reset the board and retry",
+            ),
+        }
+    }
+
+    if failed {
+        return Err(Exit { code: 1 });
+    }
+    Ok(())
+}
+
+/// Whether any installed udev rule mentions the vendor id.
+fn udev_rule_exists(vendor_id: u16) -> bool {
+    let vid = format!("{:04x}", vendor_id);
+    [
+        "/etc/udev/rules.d",
+        "/lib/udev/rules.d",
+        "/usr/lib/udev/rules.d",
+    ]
+    .iter()
+    .filter_map(|dir| std::fs::read_dir(dir).ok())
+    .flatten()
+    .filter_map(|entry| entry.ok())
+    .filter_map(|entry| std::fs::read_to_string(entry.path()).ok())
+    .any(|rules| rules.to_lowercase().contains(&vid))
+}
@@ -0,0 +1,205 @@
+use hf2::utils::UtilError;
+use hf2::{Error, ErrorCategory, ErrorKind};
+
+/// A failed command, already explained to the user, returned up to main which notifies and exits
+/// with code.
+#[derive(Debug, PartialEq)]
+pub struct Exit {
+    pub code: i32,
+}
+
+/// Errors with an exit code. Codes fall in ranges by who has to act, 10s for bad input, 20s for
+/// the device and 30s for this machine, see ErrorCategory.
+pub trait Failure: std::fmt::Debug {
+    fn category(&self) -> ErrorCategory;
+    fn code(&self) -> i32;
+    /// What to tell the user.
+    fn message(&self) -> String {
+        format!("{:?}", self)
+    }
+}
+
+impl Failure for Error {
+    fn category(&self) -> ErrorCategory {
+        Error::category(self)
+    }
+    fn code(&self) -> i32 {
+        match self.kind() {
+            // refused before anything was sent either way
+            ErrorKind::Arguments | ErrorKind::ReadOnly => 10,
+            // slow or garbled, either way no usable answer
+            ErrorKind::Parse | ErrorKind::Timeout => 23,
+            ErrorKind::CommandNotRecognized => 24,
+            ErrorKind::Execution => 25,
+            ErrorKind::Sequence => 26,
+            ErrorKind::Transmission => 27,
+            ErrorKind::Deadline => DEADLINE,
+            // only jobs of the async feature, which the cli doesn't run
+            ErrorKind::Panicked => 36,
+        }
+    }
+    // with what caused it, ie the usb error
+    fn message(&self) -> String {
+        let mut message = self.to_string();
+        let mut source = std::error::Error::source(self);
+        while let Some(cause) = source {
+            message.push_str(&format!(": {}", cause));
+            source = cause.source();
+        }
+        message
+    }
+}
+
+impl Failure for UtilError {
+    fn category(&self) -> ErrorCategory {
+        UtilError::category(self)
+    }
+    fn code(&self) -> i32 {
+        match self {
+            UtilError::ReadOnly => 10,
+            UtilError::File => 11,
+            UtilError::InvalidBinary => 12,
+            UtilError::Elf => 13,
+            UtilError::SymbolNotFound => 14,
+            UtilError::InvalidVectorTable(_) => 15,
+            UtilError::InvalidMarker => 16,
+            UtilError::InvalidRegion(_) => 17,
+            UtilError::Communication => 20,
+            UtilError::ContentsDifferent => 21,
+            UtilError::Internal => 22,
+            UtilError::PagesRejected(_) => 29,
+            // the execution error status it came from
+            UtilError::ReadProtected(_) => 25,
+            UtilError::Busy(_) => 30,
+            UtilError::NotFound => 31,
+            UtilError::Deadline => DEADLINE,
+            UtilError::Unidentified(_) => AMBIGUOUS_DEVICE,
+            // refused before anything was sent, like a usage error
+            UtilError::AppNotRunning(_) => USAGE,
+        }
+    }
+}
+
+// files and sockets named on the command line, like UtilError::File
+impl Failure for std::io::Error {
+    fn category(&self) -> ErrorCategory {
+        ErrorCategory::User
+    }
+    fn code(&self) -> i32 {
+        Failure::code(&UtilError::File)
+    }
+    fn message(&self) -> String {
+        self.to_string()
+    }
+}
+
+/// Options that don't go together or a required one missing, refused before anything was sent.
+pub const USAGE: i32 = 10;
+/// .data and .bss don't fit the RAM of the part.
+pub const RAM_OVERFLOW: i32 = 18;
+/// --vid/--pid, --serial or --device matched more than one board, or the bootloader a board handed
+/// over to couldn't be told apart from the others.
+pub const AMBIGUOUS_DEVICE: i32 = 19;
+/// Supply too low to flash.
+pub const LOW_SUPPLY: i32 = 28;
+/// The usb backend couldn't be loaded.
+pub const NO_BACKEND: i32 = 32;
+/// The agent for --remote couldn't be reached.
+pub const NO_AGENT: i32 = 33;
+/// hf2 self-update couldn't fetch, check or install the release.
+pub const UPDATE_FAILED: i32 = 34;
+/// --deadline passed before the command finished.
+pub const DEADLINE: i32 = 35;
+
+/// hf2 test saw a failing line, a test runner's failure rather than one of hf2's codes.
+pub const TEST_FAILED: i32 = 1;
+
+/// Print what went wrong, for main to exit with code.
+pub fn fail(category: ErrorCategory, code: i32, message: &str) -> Exit {
+    eprintln!("error ({:?}): {}", category, message);
+    Exit { code }
+}
+
+/// Options that don't go together or a required one missing, see USAGE.
+pub fn usage(message: &str) -> Exit {
+    fail(ErrorCategory::User, USAGE, message)
+}
+
+pub trait OrExit<T> {
+    /// Like expect, but says what went wrong and hands main the errors code rather than panicking.
+    fn or_exit(self, what: &str) -> Result<T, Exit>;
+}
+
+impl<T, E: Failure> OrExit<T> for Result<T, E> {
+    fn or_exit(self, what: &str) -> Result<T, Exit> {
+        self.map_err(|e| {
+            fail(
+                e.category(),
+                e.code(),
+                &format!("{}: {}", what, e.message()),
+            )
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn or_exit_hands_back_code() {
+        let failed: Result<(), UtilError> = Err(UtilError::NotFound);
+        assert_eq!(failed.or_exit("open failed"), Err(Exit { code: 31 }));
+        let unreadable: Result<(), std::io::Error> = Err(std::io::ErrorKind::NotFound.into());
+        assert_eq!(unreadable.or_exit("read failed"), Err(Exit { code: 11 }));
+        assert_eq!(Ok::<_, UtilError>(5).or_exit("unused"), Ok(5));
+    }
+
+    #[test]
+    fn codes_in_category_ranges() {
+        let range = |category| match category {
+            ErrorCategory::User => 10..20,
+            ErrorCategory::Device => 20..30,
+            ErrorCategory::Host => 30..40,
+        };
+
+        let errors = [
+            ErrorKind::Arguments,
+            ErrorKind::Parse,
+            ErrorKind::Timeout,
+            ErrorKind::CommandNotRecognized,
+            ErrorKind::Execution,
+            ErrorKind::Sequence,
+            ErrorKind::Transmission,
+            ErrorKind::Deadline,
+            ErrorKind::ReadOnly,
+        ]
+        .map(Error::from);
+        for e in &errors {
+            assert!(range(Failure::category(e)).contains(&e.code()), "{:?}", e);
+        }
+
+        let errors = [
+            UtilError::File,
+            UtilError::InvalidBinary,
+            UtilError::Elf,
+            UtilError::Internal,
+            UtilError::Communication,
+            UtilError::ContentsDifferent,
+            UtilError::SymbolNotFound,
+            UtilError::InvalidVectorTable(String::new()),
+            UtilError::InvalidMarker,
+            UtilError::InvalidRegion(String::new()),
+            UtilError::Busy(None),
+            UtilError::NotFound,
+            UtilError::PagesRejected(String::new()),
+            UtilError::ReadProtected(String::new()),
+            UtilError::Deadline,
+            UtilError::ReadOnly,
+            UtilError::AppNotRunning(String::new()),
+        ];
+        for e in &errors {
+            assert!(range(Failure::category(e)).contains(&e.code()), "{:?}", e);
+        }
+    }
+}
@@ -1,108 +1,1411 @@
-use hf2::utils::{elf_to_bin, flash_bin, vendor_map, verify_bin};
+use exit::OrExit;
+use hf2::utils::{
+    bin_to_uf2, changed_pages, check_softdevice, check_supply, check_vector_table,
+    elf_firmware_info, elf_sections, elf_to_bin, elf_variable, ensure_bootloader,
+    flash_bin_with_progress, flash_regions, flash_uf2_volume, installed_firmware, list_devices,
+    mismatched_pages, open_queued, open_unlocked, read_bytes, run_batch, transaction_interrupted,
+    uf2_regions, uf2_volumes, verify_bin_with_progress, write_bytes_preserving, Access, Crc16,
+    DryRun, PadPolicy, Probed, ReadCache, Region, UtilError, Variable, VariableType, VerifyMode,
+};
+use hf2::{ErrorCategory, ReadWrite};
 use hidapi::{HidApi, HidDevice};
 use std::fs::File;
-use std::io::Read;
-use std::path::PathBuf;
+use std::io::{Read, Write};
+use std::net::TcpListener;
+use std::path::{Path, PathBuf};
+use std::sync::OnceLock;
+use std::time::{Duration, Instant};
 use structopt::StructOpt;
 
+#[macro_use]
+mod output;
+mod doctor;
+mod exit;
+mod manifest;
+mod manpage;
+mod monitor;
+mod nicknames;
+mod notify;
+mod progress;
+mod provision;
+mod script;
+mod selfupdate;
+mod session;
+mod sinks;
+mod soak;
+mod station;
+mod testrun;
+mod timeline;
+use manifest::Manifest;
+use progress::ProgressBars;
+use timeline::Timeline;
+
 fn main() {
     pretty_env_logger::init();
 
     let args = Opt::from_args();
 
-    let api = HidApi::new().expect("Couldn't find system usb");
+    let notify = match (&args.notify, args.cmd.notified()) {
+        (Some(notify), Some(what)) => Some((notify.clone(), what)),
+        _ => None,
+    };
+    let stats = args.stats;
+    // failures come back as an Exit, panics are bugs but still worth notifying about
+    let result = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| start(args)));
+    progress::report_retries();
+    if stats {
+        eprintln!("{}", hf2::stability());
+    }
+    if let Some((notify, what)) = notify {
+        notify.send(matches!(result, Ok(Ok(()))), what);
+    }
+    match result {
+        Ok(Ok(())) => {}
+        Ok(Err(exit)) => std::process::exit(exit.code),
+        Err(panic) => std::panic::resume_unwind(panic),
+    }
+}
+
+// how every device this invocation opens is spoken to, from the flags
+static OPTIONS: OnceLock<hf2::SessionOptions> = OnceLock::new();
 
-    let d = if let (Some(v), Some(p)) = (args.vid, args.pid) {
-        api.open(v, p)
-            .expect("Are you sure device is plugged in and in bootloader mode?")
-    } else {
-        println!("no vid/pid provided..");
+/// A device wrapped in a session with this invocation's options.
+pub fn connect<D: ReadWrite>(d: D) -> hf2::Session<D> {
+    hf2::Session::with_options(d, OPTIONS.get().cloned().unwrap_or_default())
+}
+
+fn start(args: Opt) -> Result<(), exit::Exit> {
+    let deadline = args.deadline.map(enforce_deadline);
+    if session::active() && args.cmd != Cmd::release {
+        hf2::utils::set_hold_bootloader(true);
+        eprintln!("holding the bootloader, hf2 release resets into the app");
+    }
+    hf2::set_max_in_flight(args.max_in_flight);
+    hf2::set_low_latency(args.low_latency && !args.compat);
+    if args.read_only && args.cmd.mutating() {
+        return Err(exit::fail(
+            ErrorCategory::User,
+            exit::Failure::code(&UtilError::ReadOnly),
+            "refused, --read-only doesn't allow changing the board",
+        ));
+    }
+    let _ = OPTIONS.set(hf2::SessionOptions {
+        resync: args.resync,
+        read_only: args.read_only,
+        timeouts: args
+            .timeout
+            .iter()
+            .map(|(command_id, ms)| (*command_id, Duration::from_millis(*ms)))
+            .collect(),
+        // page writes and checksums get --retries, --retry sets any command's, compat sends
+        // everything once
+        retry_policies: [0x0006, 0x0007]
+            .iter()
+            .map(|command_id| (*command_id, args.retries))
+            .chain(args.retry.iter().copied())
+            .filter(|_| !args.compat)
+            .map(|(command_id, retries)| {
+                let backoff = Duration::from_millis(args.backoff);
+                (command_id, hf2::RetryPolicy { retries, backoff })
+            })
+            .collect(),
+        write_pacing: Duration::from_micros(args.pace_writes.unwrap_or(0)),
+        deadline,
+        compat: args.compat,
+        delta: args.delta,
+        continue_on_error: args.continue_on_error,
+        checksum: args.checksum,
+        ..Default::default()
+    });
+    for blocked in &args.block {
+        hf2::block_device(*blocked);
+    }
+    if let Some(path) = &args.event_log {
+        let mut file = std::fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)
+            .or_exit("Couldn't open event log")?;
+        // one append per line, several invocations may share the log
+        hf2::set_event_sink(move |event| {
+            let _ = file.write_all(format!("{}\n", event.to_json()).as_bytes());
+        });
+    }
 
-        let mut device: Option<HidDevice> = None;
+    // commands that don't need a device opened first
+    match &args.cmd {
+        Cmd::doctor => return doctor::doctor(),
+        Cmd::imagediff {
+            old,
+            new,
+            page_size,
+        } => return image_diff(old, new, *page_size),
+        Cmd::inspect { elf, family, ram } => {
+            return inspect(elf, ram.or_else(|| family.and_then(|f| f.ram_size())))
+        }
+        Cmd::completion { shell } => {
+            let shell = shell
+                .parse()
+                .map_err(|e: String| exit::fail(ErrorCategory::User, exit::USAGE, &e))?;
+            let mut completions = vec![];
+            Opt::clap().gen_completions_to("hf2", shell, &mut completions);
+            output::write(&completions);
+            return Ok(());
+        }
+        Cmd::man => {
+            out!("{}", manpage::render(Opt::clap()));
+            return Ok(());
+        }
+        Cmd::selfupdate { check } => return selfupdate::self_update(*check),
+        Cmd::nickname { serial, name } => return nickname(serial.as_deref(), name.as_deref()),
+        _ => {}
+    }
 
-        let vendor = vendor_map();
+    if args.dry_run
+        && (provisioning(&args.cmd)?.is_some()
+            || matches!(
+                args.cmd,
+                Cmd::station { .. }
+                    | Cmd::agent { .. }
+                    | Cmd::list
+                    | Cmd::run { .. }
+                    | Cmd::test { .. }
+                    | Cmd::soak { .. }
+            ))
+    {
+        return Err(exit::usage(
+            "dry runs cover commands that finish, not provisioning, station, agent, list, run, test or soak",
+        ));
+    }
 
-        for device_info in api.device_list() {
-            if let Some(products) = vendor.get(&device_info.vendor_id()) {
-                if products.contains(&device_info.product_id()) {
-                    if let Ok(d) = device_info.open_device(&api) {
-                        device = Some(d);
-                        break;
-                    }
-                }
-            }
+    if let Some(remote) = &args.remote {
+        if provisioning(&args.cmd)?.is_some() || matches!(args.cmd, Cmd::station { .. }) {
+            return Err(exit::usage(
+                "images are picked by usb serial so need a local device",
+            ));
+        }
+        if args.cmd == Cmd::list {
+            return Err(exit::usage(
+                "list shows local devices, run it where the agent is",
+            ));
+        }
+        if let Cmd::run { .. } | Cmd::test { .. } = args.cmd {
+            return Err(exit::usage(
+                "run and test wait for the app to enumerate so need a local device",
+            ));
+        }
+        if let Cmd::agent { .. } = args.cmd {
+            return Err(exit::usage(
+                "the agent shares a local device, run it where the board is plugged in",
+            ));
+        }
+        let token = args.remote_token.as_deref().ok_or_else(|| {
+            exit::usage(
+                "--remote needs the agent's token, pass --remote-token or set HF2_REMOTE_TOKEN",
+            )
+        })?;
+        let remote_device = hf2::RemoteDevice::connect(remote.as_str(), token).map_err(|e| {
+            exit::fail(
+                ErrorCategory::Host,
+                exit::NO_AGENT,
+                &format!("Couldn't connect to agent: {}", exit::Failure::message(&e)),
+            )
+        })?;
+        // a read only token gets the connection dropped on the first mutating command, refuse
+        // them here instead
+        let read_only = remote_device.access() == hf2::Access::ReadOnly;
+        if read_only && args.cmd.mutating() {
+            return Err(exit::fail(
+                ErrorCategory::User,
+                exit::Failure::code(&UtilError::ReadOnly),
+                "refused, the agent's token only allows reading the board",
+            ));
+        }
+        let d = hf2::Session::with_options(
+            remote_device,
+            hf2::SessionOptions {
+                read_only: read_only || args.read_only,
+                ..OPTIONS.get().cloned().unwrap_or_default()
+            },
+        );
+        outln!("connected to {}", remote);
+
+        if args.dry_run {
+            return dry_run(args.cmd, &d);
+        }
+        let mut timeline = Timeline::open(args.log).or_exit("Couldn't open log file")?;
+        return run_recorded(args.cmd, &d, args.record.as_deref(), &mut timeline);
+    }
+
+    if let Some(port) = &args.port {
+        if provisioning(&args.cmd)?.is_some()
+            || matches!(
+                args.cmd,
+                Cmd::station { .. } | Cmd::list | Cmd::run { .. } | Cmd::test { .. }
+            )
+        {
+            return Err(exit::usage(
+                "--port reaches the one board on that port, provisioning, station, list, run and test find boards over usb",
+            ));
+        }
+        if let Cmd::agent { .. } = args.cmd {
+            return Err(exit::usage(
+                "the agent shares a usb device, run it without --port",
+            ));
+        }
+        let d = connect(open_port(port)?);
+        if args.dry_run {
+            return dry_run(args.cmd, &d);
+        }
+        let mut timeline = Timeline::open(args.log).or_exit("Couldn't open log file")?;
+        return run_recorded(args.cmd, &d, args.record.as_deref(), &mut timeline);
+    }
+
+    let mut api = HidApi::new().map_err(|e| {
+        exit::fail(
+            ErrorCategory::Host,
+            exit::NO_BACKEND,
+            &format!("Couldn't find system usb: {}", e),
+        )
+    })?;
+
+    let find = |api: &HidApi| -> Vec<hf2::DeviceInfo> {
+        let found = if let (Some(v), Some(p)) = (args.vid, args.pid) {
+            api.device_list()
+                .filter(|info| info.vendor_id() == v && info.product_id() == p)
+                .map(hf2::DeviceInfo::from)
+                .collect()
+        } else {
+            hf2::enumerate(api)
+        };
+        if args.nickname {
+            nicknames::assign_new(&found);
+        }
+        found
+    };
+
+    if args.cmd == Cmd::list {
+        list(&api, &find(&api));
+        return Ok(());
+    }
+
+    if let Cmd::station {
+        manifest,
+        patches,
+        verify,
+    } = &args.cmd
+    {
+        let patches = patches
+            .as_deref()
+            .map(provision::Patches::load)
+            .transpose()?;
+        let mut timeline = Timeline::open(args.log.clone()).or_exit("Couldn't open log file")?;
+        station::station(
+            &mut api,
+            find,
+            manifest,
+            patches.as_ref(),
+            *verify,
+            &mut timeline,
+        );
+        return Ok(());
+    }
+
+    if args.vid.is_none() || args.pid.is_none() {
+        outln!("no vid/pid provided..");
+    }
+    let candidates = find(&api);
+
+    if let Some((images, true, address, pad)) = provisioning(&args.cmd)? {
+        return provision::flash_all(&api, &candidates, &images, address, pad, args.steal);
+    }
+
+    let candidates = select(candidates, &args)?;
+    let opened = match args.wait {
+        Some(secs) => open_queued(&api, &candidates, Duration::from_secs(secs)),
+        None => open_unlocked(&api, &candidates, args.steal),
+    };
+    let (d, _lock) = match opened {
+        Ok(opened) => opened,
+        Err(e @ UtilError::Busy(pid)) => {
+            let pid = pid.map_or("unknown".into(), |pid| pid.to_string());
+            return Err(exit::fail(
+                ErrorCategory::Host,
+                exit::Failure::code(&e),
+                &format!(
+                    "device busy (pid {}), pass --wait to queue behind it or --steal to use it anyway",
+                    pid
+                ),
+            ));
+        }
+        Err(_) if uf2_fallback(&args.cmd)? => return Ok(()),
+        Err(_) => {
+            return Err(exit::fail(
+                ErrorCategory::Host,
+                exit::Failure::code(&UtilError::NotFound),
+                "Are you sure device is plugged in and in bootloader mode?",
+            ))
         }
-        device.expect("Are you sure device is plugged in and in bootloader mode?")
     };
 
-    println!(
+    outln!(
         "found {:?} {:?}",
         d.get_manufacturer_string(),
         d.get_product_string()
     );
 
-    match args.cmd {
-        Cmd::resetIntoApp => hf2::reset_into_app(&d).unwrap(),
-        Cmd::resetIntoBootloader => hf2::reset_into_bootloader(&d).unwrap(),
-        Cmd::info => info(&d),
-        Cmd::bininfo => bininfo(&d),
-        Cmd::dmesg => dmesg(&d),
-        Cmd::flash { file, address } => {
-            let binary = get_binary(file);
-            let bininfo = hf2::bin_info(&d).expect("bin_info failed");
+    if args.dry_run {
+        return dry_run(args.cmd, &connect(&d));
+    }
+
+    // the supply reading lives in the app's RAM, gone once the bootloader runs
+    if let Cmd::manifest {
+        path, check: false, ..
+    } = &args.cmd
+    {
+        if let Some(supply) = &Manifest::load(path).map_err(|e| exit::usage(&e))?.supply {
+            check_manifest_supply(supply, &connect(&d))?;
+        }
+    }
+
+    // flashing from the app needs the bootloader, which usually comes back as a new device
+    let handover_timeout = args.handover_timeout.map(Duration::from_millis);
+    let (d, _lock) = if args.cmd.flashing().is_some() {
+        let (d, lock, _) = ensure_bootloader(&mut api, (d, _lock), find, handover_timeout)
+            .or_exit("couldn't get the device into its bootloader")?;
+        (d, lock)
+    } else {
+        (d, _lock)
+    };
+
+    if let Cmd::agent {
+        listen,
+        token,
+        read_only_token,
+    } = &args.cmd
+    {
+        // --read-only lets every token read only
+        let flash = if args.read_only {
+            hf2::Access::ReadOnly
+        } else {
+            hf2::Access::Flash
+        };
+        let tokens: Vec<_> = token
+            .iter()
+            .map(|token| (token.clone(), flash))
+            .chain(
+                read_only_token
+                    .iter()
+                    .map(|token| (token.clone(), hf2::Access::ReadOnly)),
+            )
+            .filter(|(token, _)| !token.is_empty())
+            .collect();
+        if tokens.is_empty() {
+            return Err(exit::usage(
+                "the agent needs a token clients connect with, pass --token or --read-only-token",
+            ));
+        }
+        return agent(&d, listen, &tokens);
+    }
+    let d = connect(d);
+
+    if let Some((images, _, address, pad)) = provisioning(&args.cmd)? {
+        return provision::flash_one(&d, &images, address, pad);
+    }
+
+    let mut timeline = Timeline::open(args.log.clone()).or_exit("Couldn't open log file")?;
+    let attached = match &args.cmd {
+        Cmd::run {
+            path,
+            pad,
+            verify,
+            expect_exit,
+            exit_event,
+        } => Some((path, *pad, *verify, *exit_event, *expect_exit, None)),
+        // a device that goes away before a line matches fails the test too
+        Cmd::test {
+            path,
+            until,
+            pad,
+            verify,
+            exit_event,
+        } => Some((path, *pad, *verify, *exit_event, true, Some(until))),
+        _ => None,
+    };
+    if let Some((path, pad, verify, exit_event, expect_exit, until)) = attached {
+        let elf = Cmd::elf {
+            path: path.clone(),
+            pad,
+            verify,
+            metadata: None,
+        };
+        run(elf, &d, &mut timeline)?;
+        // the app comes back as a new device
+        drop((d, _lock));
+        return testrun::attach(
+            &mut api,
+            find,
+            args.steal,
+            exit_event,
+            expect_exit,
+            until,
+            &mut timeline,
+        );
+    }
+    run_recorded(args.cmd, &d, args.record.as_deref(), &mut timeline)
+}
+
+// a board speaking hf2 over serial rather than hid, on the port given or the first that answers
+fn open_port(port: &str) -> Result<hf2::SerialDevice, exit::Exit> {
+    let opened = match port {
+        "auto" => hf2::SerialDevice::find(Duration::from_millis(500)).map(|(path, d)| {
+            outln!("found hf2 on {}", path);
+            d
+        }),
+        path => hf2::SerialDevice::open(path).ok(),
+    };
+    opened.ok_or_else(|| {
+        exit::fail(
+            ErrorCategory::Host,
+            exit::Failure::code(&UtilError::NotFound),
+            &format!("no board answering hf2 on serial port {}", port),
+        )
+    })
+}
+
+// with --record every report exchanged with the device is kept, for replaying with
+// hf2::testing::MockTransport
+fn run_recorded(
+    cmd: Cmd,
+    d: &impl ReadWrite,
+    record: Option<&Path>,
+    timeline: &mut Timeline,
+) -> Result<(), exit::Exit> {
+    match record {
+        Some(path) => {
+            let recorder = hf2::Recorder::create(d, path).or_exit("Couldn't create recording")?;
+            run(cmd, &recorder, timeline)
+        }
+        None => run(cmd, d, timeline),
+    }
+}
+
+fn run(cmd: Cmd, d: &impl ReadWrite, timeline: &mut Timeline) -> Result<(), exit::Exit> {
+    match cmd {
+        Cmd::resetIntoApp => {
+            hf2::reset_into_app(d).or_exit("reset failed")?;
+            timeline.mark("reset into app sent");
+        }
+        Cmd::resetIntoBootloader => {
+            hf2::reset_into_bootloader(d).or_exit("reset failed")?;
+            timeline.mark("reset into bootloader sent");
+        }
+        Cmd::reset { bootloader: false } => {
+            hf2::reset_into_app(d).or_exit("reset failed")?;
+            timeline.mark("reset into app sent");
+        }
+        Cmd::reset { bootloader: true } => {
+            hf2::reset_into_bootloader(d).or_exit("reset failed")?;
+            timeline.mark("reset into bootloader sent");
+        }
+        Cmd::hold => {
+            let bininfo = hf2::bin_info(d).or_exit("bin_info failed")?;
+            if bininfo.mode != hf2::BinInfoMode::Bootloader {
+                hf2::start_flash(d).or_exit("start flash failed")?;
+            }
+            session::begin().or_exit("Couldn't record session")?;
+            timeline.mark("holding bootloader");
+            outln!("holding the bootloader until hf2 release")
+        }
+        Cmd::release => {
+            session::end();
+            hf2::reset_into_app(d).or_exit("reset failed")?;
+            timeline.mark("released bootloader, reset into app sent");
+        }
+        Cmd::info => info(d)?,
+        Cmd::bininfo => bininfo(d)?,
+        Cmd::dmesg => dmesg(d)?,
+        Cmd::identify => identify(d)?,
+        Cmd::capabilities { output } => capabilities(d, output == "json")?,
+        Cmd::flash {
+            file,
+            address,
+            pad,
+            verify,
+        } => {
+            let bininfo = hf2::bin_info(d).or_exit("bin_info failed")?;
             log::debug!("{:?}", bininfo);
 
-            flash_bin(&binary, address, &bininfo, &d).unwrap();
-            println!("Success")
+            if is_uf2(&file) {
+                // each run of blocks at its own address, leaving the flash between them alone
+                let uf2 = std::fs::read(&file).or_exit("Couldn't read uf2")?;
+                let family = bininfo.family_id.map(u32::from);
+                let regions = uf2_regions(&uf2, family, bininfo.geometry.page_size)
+                    .or_exit("couldn't read uf2")?;
+                flash_regions(&regions, None, pad, verify, &bininfo, d).or_exit("flash failed")?;
+            } else {
+                let (binary, address) =
+                    provision::load_image(&file, address).or_exit("couldn't read the image")?;
+                warn_softdevice(&binary, address, &bininfo, d)?;
+                let flashed = flash_bin_with_progress(
+                    &binary,
+                    address,
+                    pad,
+                    verify,
+                    &bininfo,
+                    d,
+                    &mut ProgressBars::default(),
+                );
+                report_mismatches(flashed, &binary, address, pad, &bininfo, d)
+                    .or_exit("flash failed")?;
+            }
+            timeline.mark("flash complete, reset into app sent");
+            outln!("Success")
         }
-        Cmd::verify { file, address } => {
-            let binary = get_binary(file);
-            let bininfo = hf2::bin_info(&d).expect("bin_info failed");
+        Cmd::verify {
+            file,
+            address,
+            pad,
+            verify,
+        } => {
+            let binary = get_binary(file)?;
+            let bininfo = hf2::bin_info(d).or_exit("bin_info failed")?;
             log::debug!("{:?}", bininfo);
 
-            verify_bin(&binary, address, &bininfo, &d).unwrap();
-            println!("Success")
+            let verified = verify_bin_with_progress(
+                &binary,
+                address,
+                pad,
+                verify,
+                &bininfo,
+                d,
+                &mut ProgressBars::default(),
+            );
+            report_mismatches(verified, &binary, address, pad, &bininfo, d)
+                .or_exit("verify failed")?;
+            outln!("Success")
         }
-        Cmd::elf { path } => {
-            let (binary, address) = elf_to_bin(path).unwrap();
+        Cmd::elf {
+            path,
+            pad,
+            verify,
+            metadata,
+        } => {
+            let firmware = elf_firmware_info(path.clone()).or_exit("couldn't read elf")?;
+            let (binary, address) = elf_to_bin(path).or_exit("couldn't read elf")?;
 
-            let bininfo = hf2::bin_info(&d).expect("bin_info failed");
+            let bininfo = hf2::bin_info(d).or_exit("bin_info failed")?;
             log::debug!("{:?}", bininfo);
 
-            flash_bin(&binary, address, &bininfo, &d).unwrap();
+            check_vector_table(&binary, address, &bininfo).or_exit("wrong address")?;
+            warn_softdevice(&binary, address, &bininfo, d)?;
+            match metadata {
+                // one transaction with the image, so the page only records a build that verified
+                Some(metadata) => {
+                    let page = firmware
+                        .to_page(bininfo.geometry.page_size)
+                        .or_exit("metadata doesn't fit a page")?;
+                    let regions = [
+                        Region {
+                            address,
+                            binary,
+                            page_size: None,
+                        },
+                        Region {
+                            address: metadata,
+                            binary: page,
+                            page_size: None,
+                        },
+                    ];
+                    flash_regions(&regions, None, pad, verify, &bininfo, d)
+                        .or_exit("flash failed")?;
+                }
+                None => {
+                    let flashed = flash_bin_with_progress(
+                        &binary,
+                        address,
+                        pad,
+                        verify,
+                        &bininfo,
+                        d,
+                        &mut ProgressBars::default(),
+                    );
+                    report_mismatches(flashed, &binary, address, pad, &bininfo, d)
+                        .or_exit("flash failed")?;
+                }
+            }
+            outln!("flashed {}", firmware);
+            timeline.mark(&format!("flashed {}, reset into app sent", firmware));
         }
+        Cmd::installed { address } => {
+            let bininfo = hf2::bin_info(d).or_exit("bin_info failed")?;
+            log::debug!("{:?}", bininfo);
+
+            match installed_firmware(address, &bininfo, d).or_exit("read failed")? {
+                Some(firmware) => outln!("installed {}", firmware),
+                None => outln!("no installed metadata at {:#010x}", address),
+            }
+        }
+        Cmd::batch { script } => {
+            let bininfo = hf2::bin_info(d).or_exit("bin_info failed")?;
+            log::debug!("{:?}", bininfo);
+
+            let contents = std::fs::read_to_string(&script).or_exit("Couldn't read script")?;
+            let accesses = script::parse(&contents).map_err(|e| exit::usage(&e))?;
+            let results = run_batch(&accesses, &bininfo, d).or_exit("batch failed")?;
+            for (access, bytes) in accesses.iter().zip(results) {
+                if let Access::Read { address, .. } = access {
+                    outln!("{:#010x}: {:02X?}", address, bytes);
+                }
+            }
+            timeline.mark(&format!("ran batch of {} accesses", accesses.len()));
+        }
+        Cmd::read {
+            address,
+            len,
+            output,
+        } => {
+            let bininfo = hf2::bin_info(d).or_exit("bin_info failed")?;
+            log::debug!("{:?}", bininfo);
+
+            let bytes = read_bytes(address, len, &bininfo, d).or_exit("read failed")?;
+            match output {
+                Some(path) => std::fs::write(path, &bytes).or_exit("Couldn't write output")?,
+                None => hexdump(address, &bytes, bininfo.family_id),
+            }
+        }
+        Cmd::write { address, data } => {
+            let bininfo = hf2::bin_info(d).or_exit("bin_info failed")?;
+            log::debug!("{:?}", bininfo);
+
+            let data = parse_hex_bytes(&data).map_err(|e| exit::usage(&e))?;
+            let len = data.len();
+            run_batch(&[Access::Write { address, data }], &bininfo, d).or_exit("write failed")?;
+            timeline.mark(&format!("wrote {} bytes at {:#010x}", len, address));
+            outln!("Success")
+        }
+        Cmd::patch { address, data } => {
+            let bininfo = hf2::bin_info(d).or_exit("bin_info failed")?;
+            log::debug!("{:?}", bininfo);
+
+            let data = parse_hex_bytes(&data).map_err(|e| exit::usage(&e))?;
+            write_bytes_preserving(address, &data, &bininfo, d).or_exit("patch failed")?;
+            timeline.mark(&format!(
+                "patched {} bytes at {:#010x}",
+                data.len(),
+                address
+            ));
+            outln!("Success")
+        }
+        Cmd::manifest {
+            path,
+            check,
+            pad,
+            verify,
+        } => {
+            let manifest = Manifest::load(&path).map_err(|e| exit::usage(&e))?;
+            let bininfo = hf2::bin_info(d).or_exit("bin_info failed")?;
+            log::debug!("{:?}", bininfo);
+
+            if check {
+                let marker = manifest
+                    .marker
+                    .ok_or_else(|| exit::usage("manifest has no marker to check"))?;
+                if transaction_interrupted(marker, &bininfo, d).or_exit("check failed")? {
+                    eprintln!("previous flash was interrupted, regions may be half written");
+                    return Err(exit::Exit { code: 1 });
+                }
+                outln!("no interrupted flash");
+                return Ok(());
+            }
+
+            let regions = manifest
+                .regions(&path, &bininfo)
+                .map_err(|e| exit::usage(&e))?;
+            for region in &regions {
+                warn_softdevice(&region.binary, region.address, &bininfo, d)?;
+            }
+            flash_regions(&regions, manifest.marker, pad, verify, &bininfo, d)
+                .or_exit("flash failed")?;
+            timeline.mark("manifest flash complete, reset into app sent");
+            outln!("Success")
+        }
+        Cmd::watch {
+            targets,
+            elf,
+            len,
+            interval,
+            cache,
+        } => {
+            let variables = targets
+                .into_iter()
+                .map(|target| lookup_variable(target, elf.clone(), len))
+                .collect::<Result<_, _>>()?;
+            watch(d, variables, interval, cache)?
+        }
+        Cmd::assert {
+            target,
+            comparison,
+            expected,
+            elf,
+            len,
+        } => assert(d, lookup_variable(target, elf, len)?, comparison, expected)?,
+        Cmd::readfs { path, out } => {
+            let manifest = Manifest::load(&path).map_err(|e| exit::usage(&e))?;
+            let fs = manifest
+                .filesystem()
+                .ok_or_else(|| exit::usage("manifest has no filesystem region"))?;
+            let address = fs
+                .address
+                .ok_or_else(|| exit::usage("filesystem region needs an address"))?;
+            let size = fs
+                .size
+                .ok_or_else(|| exit::usage("filesystem region needs a size"))?;
+
+            let bininfo = hf2::bin_info(d).or_exit("bin_info failed")?;
+            log::debug!("{:?}", bininfo);
+
+            let image = read_bytes(address, size as usize, &bininfo, d).or_exit("read failed")?;
+            std::fs::write(&out, image).or_exit("Couldn't write filesystem image")?;
+            outln!("Success")
+        }
+        Cmd::doctor
+        | Cmd::imagediff { .. }
+        | Cmd::inspect { .. }
+        | Cmd::completion { .. }
+        | Cmd::man
+        | Cmd::selfupdate { .. }
+        | Cmd::nickname { .. }
+        | Cmd::list
+        | Cmd::run { .. }
+        | Cmd::test { .. }
+        | Cmd::agent { .. }
+        | Cmd::flashdir { .. }
+        | Cmd::flashtemplate { .. }
+        | Cmd::station { .. } => {
+            unreachable!("runs before a device is opened")
+        }
+        Cmd::soak { interval, misses } => {
+            timeline.mark("soak started");
+            soak::soak(d, interval, misses, timeline)?
+        }
+        Cmd::monitor {
+            stdin_command,
+            mut sinks,
+            sinks_from,
+        } => {
+            if let Some(path) = sinks_from {
+                sinks.extend(sinks::load(&path)?);
+            }
+            if sinks.is_empty() {
+                sinks.push(sinks::Sink::Terminal);
+            }
+            timeline.mark("monitor attached");
+            monitor::monitor(d, stdin_command, &sinks, timeline)?
+        }
+    }
+    Ok(())
+}
+
+// run against a stand in seeded with the device's bininfo, printing what would have been sent
+fn dry_run(cmd: Cmd, d: &impl ReadWrite) -> Result<(), exit::Exit> {
+    let bininfo = hf2::bin_info(d).or_exit("bin_info failed")?;
+    let dry = DryRun::new(bininfo);
+    run(
+        cmd,
+        &dry,
+        &mut Timeline::open(None).or_exit("Couldn't open log file")?,
+    )?;
+    for step in dry.transcript() {
+        outln!("{}", step);
     }
+    Ok(())
 }
 
-fn info(d: &HidDevice) {
-    let info = hf2::info(d).expect("info failed");
-    println!("{:?}", info);
+// relay reports for one client at a time until killed
+fn agent(d: &HidDevice, listen: &str, tokens: &[(String, hf2::Access)]) -> Result<(), exit::Exit> {
+    let listener = TcpListener::bind(listen).or_exit("Couldn't listen")?;
+    outln!("agent listening on {}", listen);
+
+    for stream in listener.incoming() {
+        let stream = match stream {
+            Ok(stream) => stream,
+            Err(_) => continue,
+        };
+        outln!("client {:?} connected", stream.peer_addr());
+        match hf2::serve_agent(d, &stream, tokens) {
+            Ok(()) => outln!("client disconnected"),
+            Err(e) => eprintln!("client dropped, {:?}", e),
+        }
+    }
+    Ok(())
 }
 
-fn bininfo(d: &HidDevice) {
-    let bininfo = hf2::bin_info(d).expect("bin_info failed");
-    println!(
-        "{:?} {:?}kb",
-        bininfo,
-        bininfo.flash_num_pages * bininfo.flash_page_size / 1024
+fn list(api: &HidApi, candidates: &[hf2::DeviceInfo]) {
+    let listed = list_devices(api, candidates);
+    if listed.is_empty() {
+        outln!("no hf2 devices found");
+    }
+    let boards = hf2::group_boards(candidates);
+    for device in listed {
+        let index = boards
+            .iter()
+            .position(|board| board.contains(&device.info))
+            .unwrap_or_default();
+        let state = match &device.probed {
+            Probed::Answered(bininfo) => match bininfo.family_id {
+                Some(family) => format!("{:?} {:?}", bininfo.mode, family),
+                None => format!("{:?}", bininfo.mode),
+            },
+            Probed::Busy(Some(pid)) => format!("busy, held by pid {}", pid),
+            Probed::Busy(None) => "busy".into(),
+            Probed::Silent => "no answer".into(),
+        };
+        outln!(
+            "{}  {}  {}  {}",
+            index,
+            nicknames::label(&device.info),
+            device.info.product.as_deref().unwrap_or("unknown"),
+            state
+        );
+    }
+}
+
+// lists nicknames, or names the board with serial
+fn nickname(serial: Option<&str>, name: Option<&str>) -> Result<(), exit::Exit> {
+    let mut nicknames = nicknames::Nicknames::load();
+    match (serial, name) {
+        (Some(serial), Some(name)) => {
+            nicknames.set(serial, name).map_err(|e| exit::usage(&e))?;
+            nicknames.save().or_exit("Couldn't save nicknames")?;
+            outln!("{} is {}", serial, name);
+        }
+        (Some(serial), None) => outln!("{}", nicknames.get(serial).unwrap_or("no nickname")),
+        _ => {
+            for (serial, name) in nicknames.iter() {
+                outln!("{}  {}", name, serial);
+            }
+        }
+    }
+    Ok(())
+}
+
+// narrows candidates to the board --serial or --device picks, and refuses to guess when a
+// selection still matches several boards. Without any selection the first that answers is used.
+fn select(
+    candidates: Vec<hf2::DeviceInfo>,
+    args: &Opt,
+) -> Result<Vec<hf2::DeviceInfo>, exit::Exit> {
+    let mut boards = hf2::group_boards(&candidates);
+    if let Some(index) = args.device {
+        boards = boards.into_iter().nth(index).into_iter().collect();
+    }
+    if let Some(serial) = &args.serial {
+        let nicknames = nicknames::Nicknames::load();
+        let serial = nicknames.serial(serial).unwrap_or(serial);
+        boards.retain(|board| board[0].serial_number.as_deref() == Some(serial));
+    }
+
+    let selecting = args.vid.is_some() || args.serial.is_some() || args.device.is_some();
+    if selecting && boards.len() > 1 {
+        let matched: Vec<String> = boards
+            .iter()
+            .map(|board| {
+                format!(
+                    "  {}  {}",
+                    nicknames::label(&board[0]),
+                    board[0].product.as_deref().unwrap_or("unknown")
+                )
+            })
+            .collect();
+        return Err(exit::fail(
+            ErrorCategory::User,
+            exit::AMBIGUOUS_DEVICE,
+            &format!(
+                "{} boards match, pick one with --serial or --device\n{}",
+                boards.len(),
+                matched.join("\n")
+            ),
+        ));
+    }
+    Ok(boards.concat())
+}
+
+fn info(d: &impl ReadWrite) -> Result<(), exit::Exit> {
+    let info = hf2::info(d).or_exit("info failed")?;
+    outln!("{:?}", info);
+    Ok(())
+}
+
+fn bininfo(d: &impl ReadWrite) -> Result<(), exit::Exit> {
+    let bininfo = hf2::bin_info(d).or_exit("bin_info failed")?;
+    outln!("{:?} {:?}kb", bininfo, bininfo.geometry.total_size() / 1024);
+    Ok(())
+}
+
+fn capabilities(d: &impl ReadWrite, json: bool) -> Result<(), exit::Exit> {
+    let bininfo = hf2::bin_info(d).or_exit("bin_info failed")?;
+    let capabilities = hf2::utils::capabilities(&bininfo, d);
+
+    if json {
+        let commands: Vec<_> = capabilities
+            .commands
+            .iter()
+            .map(|command| {
+                serde_json::json!({
+                    "id": command.id,
+                    "name": command.name,
+                    "support": format!("{:?}", command.support),
+                })
+            })
+            .collect();
+        let report = serde_json::json!({
+            "mode": format!("{:?}", bininfo.mode),
+            "family_id": bininfo.family_id.map(u32::from),
+            "info": capabilities.info,
+            "max_message_size": bininfo.max_message_size,
+            "page_size": bininfo.geometry.page_size,
+            "num_pages": bininfo.geometry.num_pages,
+            "flash_size": bininfo.geometry.total_size(),
+            "commands": commands,
+            "protocol": capabilities.dialect.protocol,
+            "quirks": capabilities.quirks,
+        });
+        outln!("{}", report);
+        return Ok(());
+    }
+
+    if let Some(info) = &capabilities.info {
+        if let Some(first) = info.lines().next() {
+            outln!("{}", first.trim());
+        }
+    }
+    outln!(
+        "Mode: {:?}\nFamily: {:?}\nMax message: {} bytes\nFlash: {}kb in {} byte pages",
+        bininfo.mode,
+        bininfo.family_id,
+        bininfo.max_message_size,
+        bininfo.geometry.total_size() / 1024,
+        bininfo.geometry.page_size
     );
+    outln!("Protocol: {}", capabilities.dialect.protocol);
+    outln!("Commands:");
+    for command in &capabilities.commands {
+        outln!(
+            "  {:#06x} {:<22} {:?}",
+            command.id,
+            command.name,
+            command.support
+        );
+    }
+    if capabilities.quirks.is_empty() {
+        outln!("Quirks: none");
+    } else {
+        outln!("Quirks:");
+        for quirk in &capabilities.quirks {
+            outln!("  {}", quirk);
+        }
+    }
+    Ok(())
+}
+
+fn identify(d: &impl ReadWrite) -> Result<(), exit::Exit> {
+    let info = hf2::info(d).or_exit("info failed")?;
+    for line in info.info.lines() {
+        outln!("{}", line.trim());
+    }
+
+    let bininfo = hf2::bin_info(d).or_exit("bin_info failed")?;
+    outln!(
+        "Mode: {:?}\nFamily: {:?}\nFlash: {}kb in {} byte pages",
+        bininfo.mode,
+        bininfo.family_id,
+        bininfo.geometry.total_size() / 1024,
+        bininfo.geometry.page_size
+    );
+
+    // not every bootloader keeps a log
+    if let Ok(dmesg) = hf2::dmesg(d) {
+        let build_info = dmesg.build_info();
+        if !build_info.is_empty() {
+            outln!("Build info from dmesg:");
+            for line in build_info {
+                outln!("  {}", line);
+            }
+        }
+    }
+    Ok(())
+}
+
+// 16 bytes a line, address first
+// each line ends with the region of the family's memory map it starts in, so a pasted dump says
+// what it's of
+fn hexdump(address: u32, bytes: &[u8], family: Option<hf2::FamilyId>) {
+    for (i, line) in bytes.chunks(16).enumerate() {
+        let at = address.wrapping_add(i as u32 * 16);
+        let hex: Vec<String> = line.iter().map(|byte| format!("{:02x}", byte)).collect();
+        match family.and_then(|family| family.region(at)) {
+            Some(region) => outln!("{:#010x}: {:<47}  {}", at, hex.join(" "), region.name),
+            None => outln!("{:#010x}: {}", at, hex.join(" ")),
+        }
+    }
 }
 
-fn dmesg(d: &HidDevice) {
-    // todo, test. not supported on my board
-    let dmesg = hf2::dmesg(d).expect("dmesg failed");
-    println!("{:?}", dmesg);
+fn dmesg(d: &impl ReadWrite) -> Result<(), exit::Exit> {
+    let dmesg = hf2::dmesg(d).or_exit("dmesg failed")?;
+    if dmesg.logs.is_empty() {
+        outln!("device log is empty");
+    } else {
+        outln!("{}", dmesg.logs.trim_end());
+    }
+    Ok(())
 }
 
-fn get_binary(file: PathBuf) -> Vec<u8> {
+fn watch(
+    d: &impl ReadWrite,
+    variables: Vec<Variable>,
+    interval: u64,
+    cache: u64,
+) -> Result<(), exit::Exit> {
+    let bininfo = hf2::bin_info(d).or_exit("bin_info failed")?;
+    log::debug!("{:?}", bininfo);
+
+    let cache = ReadCache::new(Duration::from_millis(cache));
+    let mut last = vec![None; variables.len()];
+    loop {
+        for (variable, last) in variables.iter().zip(last.iter_mut()) {
+            let bytes = cache
+                .read(variable.address, variable.ty.size() as usize, &bininfo, d)
+                .or_exit("read failed")?;
+
+            if last.as_ref() != Some(&bytes) {
+                outln!(
+                    "{} @ {:#010x} = {}",
+                    variable.name,
+                    variable.address,
+                    variable.ty.decode(&bytes)
+                );
+                *last = Some(bytes);
+            }
+        }
+
+        std::thread::sleep(Duration::from_millis(interval));
+    }
+}
+
+fn assert(
+    d: &impl ReadWrite,
+    variable: Variable,
+    comparison: Comparison,
+    expected: String,
+) -> Result<(), exit::Exit> {
+    let bininfo = hf2::bin_info(d).or_exit("bin_info failed")?;
+    log::debug!("{:?}", bininfo);
+
+    let bytes = read_bytes(variable.address, variable.ty.size() as usize, &bininfo, d)
+        .or_exit("read failed")?;
+    let actual = variable.ty.decode(&bytes);
+
+    // numbers compare by value so hex and decimal both work, anything else against the decoded text
+    let equal = match parse_hex_64(&expected) {
+        Ok(expected) if bytes.len() <= 8 => {
+            bytes
+                .iter()
+                .rev()
+                .fold(0_u64, |acc, b| acc << 8 | *b as u64)
+                == expected
+        }
+        _ => actual == expected,
+    };
+
+    if equal != (comparison == Comparison::Eq) {
+        eprintln!(
+            "assertion failed: {} @ {:#010x} = {}, expected {} {}",
+            variable.name, variable.address, actual, comparison, expected
+        );
+        return Err(exit::Exit { code: 1 });
+    }
+
+    outln!(
+        "{} @ {:#010x} = {}",
+        variable.name,
+        variable.address,
+        actual
+    );
+    Ok(())
+}
+
+/// Resolves an address, or a symbol name when an elf is given, to something readable.
+fn lookup_variable(
+    target: String,
+    elf: Option<PathBuf>,
+    len: usize,
+) -> Result<Variable, exit::Exit> {
+    if let Ok(address) = parse_hex_32(&target) {
+        return Ok(Variable {
+            name: target,
+            address,
+            ty: VariableType::Opaque(len as u64),
+        });
+    }
+
+    let elf = elf.ok_or_else(|| exit::usage("symbol names need an --elf to look them up in"))?;
+    let mut variable = elf_variable(elf, &target).or_exit("symbol not found in elf")?;
+
+    // symbols without a size or debug info, just read a word
+    if variable.ty.size() == 0 {
+        variable.ty = VariableType::Opaque(4);
+    }
+    Ok(variable)
+}
+
+fn image_diff(
+    old: &std::path::Path,
+    new: &std::path::Path,
+    page_size: usize,
+) -> Result<(), exit::Exit> {
+    let old = get_binary(old.to_path_buf())?;
+    let new = get_binary(new.to_path_buf())?;
+    let changed = changed_pages(&old, &new, page_size);
+    let pages = old.len().max(new.len()).div_ceil(page_size);
+
+    // collapse runs of changed pages into ranges
+    let mut ranges: Vec<(usize, usize)> = vec![];
+    for &page in &changed {
+        match ranges.last_mut() {
+            Some((_, end)) if *end == page => *end = page + 1,
+            _ => ranges.push((page, page + 1)),
+        }
+    }
+
+    for (start, end) in &ranges {
+        outln!(
+            "offset {:#08x}..{:#08x} pages {}..{} differ",
+            start * page_size,
+            end * page_size,
+            start,
+            end
+        );
+    }
+    outln!(
+        "{} of {} pages differ ({} bytes)",
+        changed.len(),
+        pages,
+        changed.len() * page_size
+    );
+    Ok(())
+}
+
+fn inspect(path: &std::path::Path, ram: Option<u32>) -> Result<(), exit::Exit> {
+    let sections = elf_sections(path.to_path_buf()).or_exit("couldn't read elf")?;
+
+    let mut flash = 0;
+    let mut used_ram = 0;
+    for section in &sections {
+        let place = match (section.stored, section.ram) {
+            (true, true) => "flash+ram",
+            (true, false) => "flash",
+            (false, _) => "ram",
+        };
+        outln!(
+            "{:<20} {:#010x} {:>8} {}",
+            section.name,
+            section.address,
+            section.size,
+            place
+        );
+        if section.stored {
+            flash += section.size;
+        }
+        if section.ram {
+            used_ram += section.size;
+        }
+    }
+    outln!("flash {} bytes, ram {} bytes", flash, used_ram);
+
+    if let Some(ram) = ram {
+        outln!(
+            "ram {:.1}% of {} bytes",
+            used_ram as f64 * 100.0 / ram as f64,
+            ram
+        );
+        if used_ram > ram {
+            return Err(exit::fail(
+                ErrorCategory::User,
+                exit::RAM_OVERFLOW,
+                &format!(
+                    ".data and .bss need {} bytes, {} more than the {} the part has, check RAM LENGTH in memory.x",
+                    used_ram,
+                    used_ram - ram,
+                    ram
+                ),
+            ));
+        }
+    }
+    Ok(())
+}
+
+// commands flashing each board with its own image, and whether to do every attached board
+#[allow(clippy::type_complexity)]
+fn provisioning(
+    cmd: &Cmd,
+) -> Result<Option<(provision::Images, bool, Option<u32>, PadPolicy)>, exit::Exit> {
+    Ok(match cmd {
+        Cmd::flashdir {
+            dir,
+            all,
+            address,
+            pad,
+        } => Some((provision::Images::Dir(dir.clone()), *all, *address, *pad)),
+        Cmd::flashtemplate {
+            image,
+            patches,
+            all,
+            address,
+            pad,
+        } => {
+            let images = provision::Images::Template {
+                image: image.clone(),
+                patches: provision::Patches::load(patches)?,
+            };
+            Some((images, *all, *address, *pad))
+        }
+        _ => None,
+    })
+}
+
+// without an hf2 interface, flash and elf can still copy a UF2 file onto a mounted UF2 drive
+fn uf2_fallback(cmd: &Cmd) -> Result<bool, exit::Exit> {
+    if !matches!(cmd, Cmd::flash { .. } | Cmd::elf { .. }) {
+        return Ok(false);
+    }
+    let volumes = uf2_volumes();
+    let volume = match volumes.as_slice() {
+        [volume] => volume,
+        [] => return Ok(false),
+        _ => {
+            eprintln!("no hf2 device and several UF2 drives mounted, not guessing which to use");
+            return Ok(false);
+        }
+    };
+
+    // only read once there's somewhere to copy it
+    let uf2 = match cmd {
+        Cmd::flash { file, .. } if is_uf2(file) => std::fs::read(file).map_err(|_| UtilError::File),
+        Cmd::flash { file, address, .. } => provision::load_image(file, *address)
+            .map(|(binary, address)| bin_to_uf2(&binary, address, None)),
+        Cmd::elf { path, metadata, .. } => {
+            if metadata.is_some() {
+                eprintln!("warning: --metadata isn't written when copying to a UF2 drive");
+            }
+            elf_to_bin(path.clone()).map(|(binary, address)| bin_to_uf2(&binary, address, None))
+        }
+        _ => return Ok(false),
+    }
+    .or_exit("couldn't read the image")?;
+
+    outln!(
+        "no hf2 device, copying to UF2 drive {:?} {:?}",
+        volume.path,
+        volume.board_id
+    );
+    flash_uf2_volume(&uf2, volume, Duration::from_secs(10)).or_exit("uf2 copy failed")?;
+    outln!("Success");
+    Ok(true)
+}
+
+// longer than any command waits for its response by default, so one already sent can finish
+const DEADLINE_GRACE: Duration = Duration::from_secs(10);
+
+// hf2 stops sending commands once the deadline passes. Waits outside of them, ie for a board to
+// enumerate or another process to let go of it, are cut short by exiting after a grace period.
+fn enforce_deadline(deadline: Duration) -> Instant {
+    std::thread::spawn(move || {
+        std::thread::sleep(deadline + DEADLINE_GRACE);
+        eprintln!(
+            "error ({:?}): deadline of {}s passed",
+            ErrorCategory::Host,
+            deadline.as_secs()
+        );
+        std::process::exit(exit::DEADLINE);
+    });
+    Instant::now() + deadline
+}
+
+// on a failed verify, say which pages differ before giving up
+fn report_mismatches(
+    result: Result<(), UtilError>,
+    binary: &[u8],
+    address: u32,
+    pad: PadPolicy,
+    bininfo: &hf2::BinInfoResponse,
+    d: &impl ReadWrite,
+) -> Result<(), UtilError> {
+    if let Err(UtilError::ContentsDifferent) = result {
+        if let Ok(pages) = mismatched_pages(binary, address, pad, bininfo, d) {
+            eprintln!();
+            for page in pages {
+                eprintln!("page at {:#010x} differs", page);
+            }
+        }
+    }
+    result
+}
+
+fn warn_softdevice(
+    binary: &[u8],
+    address: u32,
+    bininfo: &hf2::BinInfoResponse,
+    d: &impl ReadWrite,
+) -> Result<(), exit::Exit> {
+    if let Some(warning) =
+        check_softdevice(binary, address, bininfo, d).or_exit("softdevice check failed")?
+    {
+        eprintln!("warning: {}", warning);
+    }
+    Ok(())
+}
+
+// refuses to go on below the minimum, or without a reading, unless the manifest only warns
+fn check_manifest_supply(
+    supply: &manifest::ManifestSupply,
+    d: &impl ReadWrite,
+) -> Result<(), exit::Exit> {
+    let bininfo = hf2::bin_info(d).or_exit("bin_info failed")?;
+    let low = match check_supply(&supply.check(), &bininfo, d) {
+        Err(e) if supply.warn_only => {
+            eprintln!("warning: couldn't check the supply, {:?}", e);
+            return Ok(());
+        }
+        low => low.or_exit("supply check failed")?,
+    };
+    if let Some(low) = low {
+        if !supply.warn_only {
+            return Err(exit::fail(
+                ErrorCategory::Device,
+                exit::LOW_SUPPLY,
+                &low.to_string(),
+            ));
+        }
+        eprintln!("warning: {}", low);
+    }
+    Ok(())
+}
+
+fn is_uf2(file: &std::path::Path) -> bool {
+    file.extension().is_some_and(|ext| ext == "uf2")
+}
+
+fn get_binary(file: PathBuf) -> Result<Vec<u8>, exit::Exit> {
     //shouldnt there be a chunking interator for this?
-    let mut f = File::open(file).unwrap();
+    let mut f = File::open(file).or_exit("Couldn't open binary")?;
     let mut binary = Vec::new();
-    f.read_to_end(&mut binary).unwrap();
-    binary
+    f.read_to_end(&mut binary).or_exit("Couldn't read binary")?;
+    Ok(binary)
 }
 
 fn parse_hex_32(input: &str) -> Result<u32, std::num::ParseIntError> {
+    let input = input.replace('_', "");
     if let Some(stripped) = input.strip_prefix("0x") {
         u32::from_str_radix(stripped, 16)
     } else {
@@ -110,6 +1413,144 @@ fn parse_hex_32(input: &str) -> Result<u32, std::num::ParseIntError> {
     }
 }
 
+fn parse_timeout(input: &str) -> Result<(u32, u64), String> {
+    let mut parts = input.splitn(2, '=');
+    let command_id = parts.next().map(parse_hex_32);
+    let ms = parts.next().map(|ms| ms.parse::<u64>());
+    match (command_id, ms) {
+        (Some(Ok(command_id)), Some(Ok(ms))) => Ok((command_id, ms)),
+        _ => Err(format!(
+            "expected command_id=ms, ie 0x0007=10000, got {}",
+            input
+        )),
+    }
+}
+
+fn parse_retry(input: &str) -> Result<(u32, u32), String> {
+    let mut parts = input.splitn(2, '=');
+    let command_id = parts.next().map(parse_hex_32);
+    let retries = parts.next().map(|retries| retries.parse::<u32>());
+    match (command_id, retries) {
+        (Some(Ok(command_id)), Some(Ok(retries))) => Ok((command_id, retries)),
+        _ => Err(format!(
+            "expected command_id=retries, ie 0x0001=3, got {}",
+            input
+        )),
+    }
+}
+
+// a zero page size would divide by zero working out page counts
+fn parse_page_size(input: &str) -> Result<usize, String> {
+    match input.parse::<usize>() {
+        Ok(0) => Err("--page-size must be at least 1".into()),
+        Ok(page_size) => Ok(page_size),
+        Err(e) => Err(e.to_string()),
+    }
+}
+
+// two hex digits to a byte, ie 0xDEAD_BEEF, refusing a dangling digit rather than guessing which
+// byte it belongs to
+fn parse_hex_bytes(input: &str) -> Result<Vec<u8>, String> {
+    let digits = input.trim_start_matches("0x").replace('_', "");
+    if !digits.bytes().all(|digit| digit.is_ascii_hexdigit()) {
+        return Err(format!("{} isn't hex bytes", input));
+    }
+    if !digits.len().is_multiple_of(2) {
+        return Err(format!("{} has an odd number of hex digits", input));
+    }
+    digits
+        .as_bytes()
+        .chunks(2)
+        .map(|pair| {
+            let pair = std::str::from_utf8(pair).map_err(|e| e.to_string())?;
+            u8::from_str_radix(pair, 16).map_err(|e| e.to_string())
+        })
+        .collect()
+}
+
+fn parse_hex_64(input: &str) -> Result<u64, std::num::ParseIntError> {
+    let input = input.replace('_', "");
+    if let Some(stripped) = input.strip_prefix("0x") {
+        u64::from_str_radix(stripped, 16)
+    } else {
+        input.parse::<u64>()
+    }
+}
+
+#[derive(Debug, PartialEq)]
+pub enum Comparison {
+    Eq,
+    Ne,
+}
+
+impl std::str::FromStr for Comparison {
+    type Err = String;
+
+    fn from_str(input: &str) -> Result<Self, Self::Err> {
+        match input {
+            "==" => Ok(Comparison::Eq),
+            "!=" => Ok(Comparison::Ne),
+            _ => Err(format!("unknown comparison {}, use == or !=", input)),
+        }
+    }
+}
+
+impl std::fmt::Display for Comparison {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Comparison::Eq => write!(f, "=="),
+            Comparison::Ne => write!(f, "!="),
+        }
+    }
+}
+
+impl Cmd {
+    /// Name of commands that flash, which take long enough to be worth a notification.
+    fn flashing(&self) -> Option<&'static str> {
+        match self {
+            Cmd::flash { .. } => Some("flash"),
+            Cmd::elf { .. } => Some("elf"),
+            Cmd::run { .. } => Some("run"),
+            Cmd::test { .. } => Some("test"),
+            Cmd::manifest { check: false, .. } => Some("manifest"),
+            Cmd::flashdir { .. } => Some("flash-dir"),
+            Cmd::flashtemplate { .. } => Some("flash-template"),
+            _ => None,
+        }
+    }
+
+    /// Commands that change the board, refused up front with --read-only. hf2 refuses the commands
+    /// that would change it anyway, this says so before a device is even opened. Monitor forwarding
+    /// stdin to a vendor command is refused too, while the agent only lets clients read.
+    fn mutating(&self) -> bool {
+        self.flashing().is_some()
+            || matches!(
+                self,
+                Cmd::resetIntoApp
+                    | Cmd::resetIntoBootloader
+                    | Cmd::reset { .. }
+                    | Cmd::hold
+                    | Cmd::release
+                    | Cmd::test { .. }
+                    | Cmd::station { .. }
+                    | Cmd::write { .. }
+                    | Cmd::patch { .. }
+                    | Cmd::monitor {
+                        stdin_command: Some(_),
+                        ..
+                    }
+            )
+    }
+
+    /// Long running commands --notify reports the end of, and what to call them.
+    fn notified(&self) -> Option<&'static str> {
+        match self {
+            Cmd::soak { .. } => Some("soak"),
+            _ => self.flashing(),
+        }
+    }
+}
+
 fn parse_hex_16(input: &str) -> Result<u16, std::num::ParseIntError> {
     if let Some(stripped) = input.strip_prefix("0x") {
         u16::from_str_radix(stripped, 16)
@@ -121,11 +1562,25 @@ fn parse_hex_16(input: &str) -> Result<u16, std::num::ParseIntError> {
 #[allow(non_camel_case_types)]
 #[derive(StructOpt, Debug, PartialEq)]
 pub enum Cmd {
-    ///Reset the device into user-space app.
+    ///Reset the device into user-space app. Resets don't answer so this returns once it's sent.
+    #[structopt(alias = "reset-app")]
     resetIntoApp,
     ///Reset the device into bootloader, usually for flashing
+    #[structopt(alias = "reset-bootloader")]
     resetIntoBootloader,
 
+    /// reset into the app, or with --bootloader into the bootloader
+    reset {
+        #[structopt(long = "bootloader")]
+        bootloader: bool,
+    },
+
+    /// keep the device in the bootloader for the following invocations, flashes skip resetting into the app until release
+    hold,
+
+    /// end a hold and reset the device into its app
+    release,
+
     /// Various device information. The result is a character array. See INFO_UF2.TXT in UF2 format for details.
     info,
 
@@ -135,12 +1590,29 @@ pub enum Cmd {
     ///Return internal log buffer if any. The result is a character array.
     dmesg,
 
+    /// summarize info, bininfo and any bootloader build info found in dmesg
+    identify,
+
+    /// probe which hf2 commands the bootloader supports and list its limits and the quirks applied, for auditing bootloader versions
+    capabilities {
+        /// text or json
+        #[structopt(long = "output", default_value = "text", possible_values = &["text", "json"])]
+        output: String,
+    },
+
     /// flash binary, note includes a verify and reset into app
     flash {
         #[structopt(short = "f", name = "file", long = "file")]
         file: PathBuf,
+        /// where a .bin image goes, hex, uf2 and elf images carry their own
         #[structopt(short = "a", name = "address", long = "address", parse(try_from_str = parse_hex_32))]
-        address: u32,
+        address: Option<u32>,
+        /// how to fill the end of the last page, zero, erased or preserve
+        #[structopt(long = "pad", default_value = "zero")]
+        pad: PadPolicy,
+        /// checksum compares page crcs, full reads everything back and compares a sha-256
+        #[structopt(long = "verify", default_value = "checksum")]
+        verify: VerifyMode,
     },
 
     /// verify binary
@@ -149,12 +1621,310 @@ pub enum Cmd {
         file: PathBuf,
         #[structopt(short = "a", name = "address", long = "address", parse(try_from_str = parse_hex_32))]
         address: u32,
+        /// how to fill the end of the last page, zero, erased or preserve
+        #[structopt(long = "pad", default_value = "zero")]
+        pad: PadPolicy,
+        /// checksum compares page crcs, full reads everything back and compares a sha-256
+        #[structopt(long = "verify", default_value = "checksum")]
+        verify: VerifyMode,
     },
 
     /// flash elf, note includes a verify and reset into app
     elf {
         #[structopt(parse(from_os_str))]
         path: PathBuf,
+        /// how to fill the end of the last page, zero, erased or preserve
+        #[structopt(long = "pad", default_value = "zero")]
+        pad: PadPolicy,
+        /// checksum compares page crcs, full reads everything back and compares a sha-256
+        #[structopt(long = "verify", default_value = "checksum")]
+        verify: VerifyMode,
+        /// also write the elf's build id and version to the page at this address, read back with hf2 installed
+        #[structopt(long = "metadata", parse(try_from_str = parse_hex_32))]
+        metadata: Option<u32>,
+    },
+
+    /// print the build id and version elf --metadata recorded in the page at address
+    installed {
+        #[structopt(short = "a", name = "address", long = "address", parse(try_from_str = parse_hex_32))]
+        address: u32,
+    },
+
+    /// flash an elf, then print the app's serial output. With --expect-exit hf2 exits with the code the firmware reports, for on-target tests
+    run {
+        #[structopt(parse(from_os_str))]
+        path: PathBuf,
+        /// how to fill the end of the last page, zero, erased or preserve
+        #[structopt(long = "pad", default_value = "zero")]
+        pad: PadPolicy,
+        /// checksum compares page crcs, full reads everything back and compares a sha-256
+        #[structopt(long = "verify", default_value = "checksum")]
+        verify: VerifyMode,
+        /// wait for the firmware's exit status event and exit with its code
+        #[structopt(long = "expect-exit")]
+        expect_exit: bool,
+        /// id of the exit status event the firmware sends, in hex
+        #[structopt(long = "exit-event", default_value = "0x800001", parse(try_from_str = parse_hex_32))]
+        exit_event: u32,
+    },
+
+    /// flash an elf, then print the app's serial output until a line matches --until, exiting 0 if it's the first alternative and 1 for any other, for hardware ci
+    test {
+        #[structopt(parse(from_os_str))]
+        path: PathBuf,
+        /// output that ends the run, alternatives split on |, the first passing, ie PASS|FAIL
+        #[structopt(long = "until")]
+        until: testrun::Until,
+        /// how to fill the end of the last page, zero, erased or preserve
+        #[structopt(long = "pad", default_value = "zero")]
+        pad: PadPolicy,
+        /// checksum compares page crcs, full reads everything back and compares a sha-256
+        #[structopt(long = "verify", default_value = "checksum")]
+        verify: VerifyMode,
+        /// id of the exit status event the firmware sends, in hex. Exiting ends the run with its code before any line matches
+        #[structopt(long = "exit-event", default_value = "0x800001", parse(try_from_str = parse_hex_32))]
+        exit_event: u32,
+    },
+
+    /// flash every region of a toml manifest as one transaction, note includes a verify and reset into app
+    manifest {
+        #[structopt(parse(from_os_str))]
+        path: PathBuf,
+        /// only check whether a previous flash of this manifest was interrupted
+        #[structopt(long = "check")]
+        check: bool,
+        /// how to fill the end of the last page, zero, erased or preserve
+        #[structopt(long = "pad", default_value = "zero")]
+        pad: PadPolicy,
+        /// checksum compares page crcs, full reads everything back and compares a sha-256
+        #[structopt(long = "verify", default_value = "checksum")]
+        verify: VerifyMode,
+    },
+
+    /// flash the image named after the boards usb serial from a directory, ie ABC123.bin, or with --all every attached board that has one
+    #[structopt(name = "flash-dir")]
+    flashdir {
+        #[structopt(parse(from_os_str))]
+        dir: PathBuf,
+        /// flash every attached board with an image, not just the first found
+        #[structopt(long = "all")]
+        all: bool,
+        /// where .bin images go, hex, uf2 and elf images carry their own
+        #[structopt(short = "a", name = "address", long = "address", parse(try_from_str = parse_hex_32))]
+        address: Option<u32>,
+        /// how to fill the end of the last page, zero, erased or preserve
+        #[structopt(long = "pad", default_value = "zero")]
+        pad: PadPolicy,
+    },
+
+    /// flash one image to each board, patched with the values in the boards row of a csv keyed by usb serial
+    #[structopt(name = "flash-template")]
+    flashtemplate {
+        #[structopt(parse(from_os_str))]
+        image: PathBuf,
+        /// csv with a serial column then one column per address to patch
+        #[structopt(parse(from_os_str))]
+        patches: PathBuf,
+        /// flash every attached board with a row, not just the first found
+        #[structopt(long = "all")]
+        all: bool,
+        /// where a .bin image goes, hex, uf2 and elf images carry their own
+        #[structopt(short = "a", name = "address", long = "address", parse(try_from_str = parse_hex_32))]
+        address: Option<u32>,
+        /// how to fill the end of the last page, zero, erased or preserve
+        #[structopt(long = "pad", default_value = "zero")]
+        pad: PadPolicy,
+    },
+
+    /// programming station, flash each board plugged in with a manifest and show a big PASS or FAIL, until stopped
+    station {
+        /// manifest to flash every board with
+        #[structopt(parse(from_os_str))]
+        manifest: PathBuf,
+        /// csv of per board values keyed by usb serial, as for flash-template
+        #[structopt(long = "patches", parse(from_os_str))]
+        patches: Option<PathBuf>,
+        /// checksum compares page crcs, full reads everything back and compares a sha-256
+        #[structopt(long = "verify", default_value = "checksum")]
+        verify: VerifyMode,
+    },
+
+    /// compare two binaries page by page, showing what a flash of new over old would rewrite
+    #[structopt(name = "image-diff")]
+    imagediff {
+        #[structopt(parse(from_os_str))]
+        old: PathBuf,
+        #[structopt(parse(from_os_str))]
+        new: PathBuf,
+        /// flash page size of the target, see bininfo
+        #[structopt(long = "page-size", default_value = "256", parse(try_from_str = parse_page_size))]
+        page_size: usize,
+    },
+
+    /// show the size of each section of an elf, with --family or --ram also checking .data and .bss fit in RAM
+    inspect {
+        #[structopt(parse(from_os_str))]
+        elf: PathBuf,
+        /// chip family to take the RAM size from, ie samd21, samd51, nrf52840, stm32f103, stm32f401
+        #[structopt(long = "family")]
+        family: Option<hf2::FamilyId>,
+        /// RAM size in hex, overrides --family for parts with more or less than the family's smallest
+        #[structopt(long = "ram", parse(try_from_str = parse_hex_32))]
+        ram: Option<u32>,
+    },
+
+    /// list connected hf2 devices with their serial, product and whether they're in the bootloader
+    list,
+
+    /// print shell completions for hf2, ie hf2 completion bash > /usr/share/bash-completion/completions/hf2
+    completion {
+        #[structopt(possible_values = &structopt::clap::Shell::variants())]
+        shell: String,
+    },
+
+    /// print the hf2 man page in roff, ie hf2 man > /usr/share/man/man1/hf2.1
+    man,
+
+    /// list board nicknames, or name the board with this usb serial, see --nickname
+    nickname {
+        serial: Option<String>,
+        name: Option<String>,
+    },
+
+    /// replace this executable with the latest release for this platform, once its signature checks out against the release key
+    #[structopt(name = "self-update")]
+    selfupdate {
+        /// only say whether there's a newer release
+        #[structopt(long = "check")]
+        check: bool,
+    },
+
+    /// dump the filesystem region of a toml manifest back to a file
+    #[structopt(name = "read-fs")]
+    readfs {
+        /// manifest with a filesystem region
+        #[structopt(parse(from_os_str))]
+        path: PathBuf,
+        /// file to write the image to
+        #[structopt(parse(from_os_str))]
+        out: PathBuf,
+    },
+
+    /// read memory, printed as hex or with -o saved to a file
+    read {
+        #[structopt(short = "a", name = "address", long = "address", parse(try_from_str = parse_hex_32))]
+        address: u32,
+        /// bytes to read
+        #[structopt(short = "l", name = "len", long = "len")]
+        len: usize,
+        /// write the raw bytes here instead
+        #[structopt(short = "o", long = "output", parse(from_os_str))]
+        output: Option<PathBuf>,
+    },
+
+    /// write whole words to RAM or registers with WRITE WORDS, use patch for flash
+    write {
+        #[structopt(short = "a", name = "address", long = "address", parse(try_from_str = parse_hex_32))]
+        address: u32,
+        /// hex bytes in memory order, a multiple of 4, ie DEADBEEF
+        data: String,
+    },
+
+    /// write bytes to flash, rewriting only the pages they touch and keeping the rest of those pages
+    patch {
+        #[structopt(short = "a", name = "address", long = "address", parse(try_from_str = parse_hex_32))]
+        address: u32,
+        /// hex bytes in memory order, ie DEADBEEF
+        data: String,
+    },
+
+    /// run a script of memory reads and word writes, ie registers, merging neighbouring accesses into as few commands as possible
+    batch {
+        /// lines of read <address> <len> or write <address> <hex bytes>
+        #[structopt(parse(from_os_str))]
+        script: PathBuf,
+    },
+
+    /// share the device with hf2 --remote clients on other machines, relaying raw reports over tcp to clients holding one of its tokens
+    agent {
+        /// address to listen on, ie 0.0.0.0:7878 to accept clients from other machines. Tokens and reports travel as plain tcp, tunnel it over ssh off a trusted network
+        #[structopt(long = "listen", default_value = "127.0.0.1:7878")]
+        listen: String,
+        /// token clients that may flash the device connect with
+        #[structopt(long = "token", env = "HF2_AGENT_TOKEN", hide_env_values = true)]
+        token: Option<String>,
+        /// token clients that may only run commands that don't change the device connect with
+        #[structopt(
+            long = "read-only-token",
+            env = "HF2_AGENT_READ_ONLY_TOKEN",
+            hide_env_values = true
+        )]
+        read_only_token: Option<String>,
+    },
+
+    /// check for the usual reasons a board isn't found, printing fixes for anything that fails
+    doctor,
+
+    /// print serial output from the device, add --log to also save it alongside host side markers
+    monitor {
+        /// vendor command id the firmware accepts console input on, makes the console bidirectional
+        #[structopt(long = "stdin-command", parse(try_from_str = parse_hex_32))]
+        stdin_command: Option<u32>,
+        /// send output here rather than just the terminal, repeat for several: terminal,
+        /// file:<path>, tcp:<host:port> or an http url to post batches of lines to
+        #[structopt(long = "sink", number_of_values = 1)]
+        sinks: Vec<sinks::Sink>,
+        /// toml file listing more sinks, `sinks = ["terminal", "file:console.log"]`
+        #[structopt(long = "sinks-from", parse(from_os_str))]
+        sinks_from: Option<PathBuf>,
+    },
+
+    /// poll memory and print it when it changes, decoded by type when watching an elf symbol
+    watch {
+        /// addresses, or variable names when an elf is given
+        #[structopt(required = true)]
+        targets: Vec<String>,
+        #[structopt(short = "e", name = "elf", long = "elf", parse(from_os_str))]
+        elf: Option<PathBuf>,
+        /// bytes to read when watching a raw address
+        #[structopt(short = "l", name = "len", long = "len", default_value = "4")]
+        len: usize,
+        /// poll interval in milliseconds
+        #[structopt(
+            short = "i",
+            name = "interval",
+            long = "interval",
+            default_value = "500"
+        )]
+        interval: u64,
+        /// reuse reads for this many milliseconds, so targets inside one another share a round trip
+        #[structopt(long = "cache", default_value = "0")]
+        cache: u64,
+    },
+
+    /// check the board answers bininfo every interval, printing new dmesg output, until it stops answering
+    soak {
+        /// time between checks, ie 500ms, 60s, 5m or 1h
+        #[structopt(long = "interval", default_value = "60s", parse(try_from_str = soak::parse_duration))]
+        interval: Duration,
+        /// checks in a row that can fail before giving up
+        #[structopt(long = "misses", default_value = "3")]
+        misses: u32,
+    },
+
+    /// compare memory against a value, exiting nonzero on mismatch. ie assert 0x2000_0100 == 0xCAFEBABE
+    assert {
+        /// address, or variable name when an elf is given
+        target: String,
+        /// == or !=
+        comparison: Comparison,
+        /// number, or decoded value such as an enum variant when asserting an elf symbol
+        expected: String,
+        #[structopt(short = "e", name = "elf", long = "elf", parse(from_os_str))]
+        elf: Option<PathBuf>,
+        /// bytes to read when asserting a raw address
+        #[structopt(short = "l", name = "len", long = "len", default_value = "4")]
+        len: usize,
     },
 }
 
@@ -168,4 +1938,138 @@ struct Opt {
     pid: Option<u16>,
     #[structopt(short = "v", name = "vid", long = "vid", parse(try_from_str = parse_hex_16))]
     vid: Option<u16>,
+    /// use the board with this usb serial number, see hf2 list
+    #[structopt(long = "serial")]
+    serial: Option<String>,
+    /// use the board at this position in hf2 list
+    #[structopt(long = "device")]
+    device: Option<usize>,
+    /// use the device even if another hf2 process holds it
+    #[structopt(long = "steal")]
+    steal: bool,
+    /// use the device attached to another machine running hf2 agent, ie raspberrypi.local:7878
+    #[structopt(long = "remote")]
+    remote: Option<String>,
+    /// token the agent given with --remote was started with
+    #[structopt(
+        long = "remote-token",
+        env = "HF2_REMOTE_TOKEN",
+        hide_env_values = true
+    )]
+    remote_token: Option<String>,
+    /// use the board speaking hf2 over this serial port, ie /dev/ttyACM0 or COM3, or auto for the first usb serial port that answers
+    #[structopt(long = "port")]
+    port: Option<String>,
+    /// when another hf2 process holds the device, wait up to this many seconds for it to finish
+    #[structopt(long = "wait")]
+    wait: Option<u64>,
+    /// append timestamped host actions and monitored output to this file
+    #[structopt(long = "log", parse(from_os_str))]
+    log: Option<PathBuf>,
+    /// never open or probe devices matching vid, vid:pid or vid:pid:usage_page in hex, * for any. Repeatable
+    #[structopt(long = "block", number_of_values = 1)]
+    block: Vec<hf2::Blocked>,
+    /// poll for responses rather than blocking on them, faster for many small commands at the cost of a busy core
+    #[structopt(long = "low-latency")]
+    low_latency: bool,
+    /// drain reports left over from a command that timed out before sending each command, for bootloaders that answer late
+    #[structopt(long = "resync")]
+    resync: bool,
+    /// wait this many microseconds between the 64 byte reports of one command, for bootloaders that drop reports sent back to back
+    #[structopt(long = "pace-writes")]
+    pace_writes: Option<u64>,
+    /// when the bootloader refuses a page, likely protected, keep writing the rest and list every refused page at the end
+    #[structopt(long = "continue-on-error")]
+    continue_on_error: bool,
+    /// send a page write or checksum that timed out or came back garbled again, up to this many more times, like --retry 0x0006=2 --retry 0x0007=2. Every retry is reported
+    #[structopt(long = "retries", default_value = "2")]
+    retries: u32,
+    /// append a json line per command sent to the device, its id, tag, sizes, duration and result
+    #[structopt(long = "event-log", parse(from_os_str))]
+    event_log: Option<PathBuf>,
+    /// spec minimal lock step, one page checksums, single report reads and writes, no merging or low latency polling. A baseline for whether an optimization breaks a bootloader
+    #[structopt(long = "compat")]
+    compat: bool,
+    /// commands in flight at once across every board, taking turns, so flash-dir and flash-template don't overrun a shared hub. 0 doesn't limit
+    #[structopt(long = "max-in-flight", default_value = "4")]
+    max_in_flight: usize,
+    /// ask for the checksums of the pages first and only write the ones that changed, much faster when reflashing a build that mostly didn't
+    #[structopt(long = "delta")]
+    delta: bool,
+    /// milliseconds to wait for the bootloader to enumerate after handing over from the app, defaults by chip family
+    #[structopt(long = "handover-timeout")]
+    handover_timeout: Option<u64>,
+    /// only ask the device for its bininfo, then print the commands the subcommand would send with a digest of each payload
+    #[structopt(long = "dry-run")]
+    dry_run: bool,
+    /// print how reliably the device answered when done, to tell a bad cable or hub from a firmware problem
+    #[structopt(long = "stats")]
+    stats: bool,
+    /// override how long to wait for a commands response, command_id=ms ie 0x0007=10000. Repeatable
+    #[structopt(long = "timeout", number_of_values = 1, parse(try_from_str = parse_timeout))]
+    timeout: Vec<(u32, u64)>,
+    /// send a command again when the device was too slow to answer or its answer was garbled, command_id=retries ie 0x0001=3. Repeatable
+    #[structopt(long = "retry", number_of_values = 1, parse(try_from_str = parse_retry))]
+    retry: Vec<(u32, u32)>,
+    /// milliseconds to wait before the first retry, doubling before each one after
+    #[structopt(long = "backoff", default_value = "50")]
+    backoff: u64,
+    /// when a flash finishes or fails, ring the terminal bell, show a desktop notification or run a shell command, bell, desktop or the command
+    #[structopt(long = "notify")]
+    notify: Option<notify::Notify>,
+    /// page checksum the bootloader verifies with, ccitt, ccitt-false, kermit or arc for vendor forks
+    #[structopt(long = "checksum", default_value = "ccitt")]
+    checksum: Crc16,
+    /// refuse anything that would write, erase or reset the board, for inspecting production boards
+    #[structopt(long = "read-only")]
+    read_only: bool,
+    /// write every report sent to and received from the device to this file, for replaying in tests with hf2::testing::MockTransport
+    #[structopt(long = "record", parse(from_os_str))]
+    record: Option<PathBuf>,
+    /// give boards seen for the first time a short name from their serial, ie board-a3f2, kept between runs and shown in listings and logs
+    #[structopt(long = "nickname")]
+    nickname: bool,
+    /// give up on the whole run after this long, ie 120s, stopping between commands and exiting with code 35
+    #[structopt(long = "deadline", parse(try_from_str = soak::parse_duration))]
+    deadline: Option<Duration>,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn hex_bytes() {
+        assert_eq!(
+            parse_hex_bytes("0xDEAD_beef"),
+            Ok(vec![0xDE, 0xAD, 0xBE, 0xEF])
+        );
+        assert_eq!(parse_hex_bytes(""), Ok(vec![]));
+        assert_eq!(
+            parse_hex_bytes("0xABC"),
+            Err("0xABC has an odd number of hex digits".into())
+        );
+        assert!(parse_hex_bytes("é0").is_err());
+        assert!(parse_hex_bytes("0a€").is_err());
+        assert!(parse_hex_bytes("+f").is_err());
+    }
+
+    #[test]
+    fn page_size() {
+        assert_eq!(parse_page_size("256"), Ok(256));
+        assert_eq!(
+            parse_page_size("0"),
+            Err("--page-size must be at least 1".into())
+        );
+        assert!(parse_page_size("-1").is_err());
+        assert!(Opt::from_iter_safe(&[
+            "hf2",
+            "image-diff",
+            "old.bin",
+            "new.bin",
+            "--page-size",
+            "0"
+        ])
+        .is_err());
+    }
 }
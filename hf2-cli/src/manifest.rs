@@ -0,0 +1,255 @@
+use hf2::utils::{
+    check_filesystem, elf_to_bin, hex_to_bin, parse_uf2, Region, SupplyCheck, UtilError,
+};
+use hf2::BinInfoResponse;
+use serde::Deserialize;
+use std::path::{Path, PathBuf};
+
+/// Several images to flash together, read from a toml file like
+///
+/// ```toml
+/// marker = 0x3F000
+///
+/// [supply]
+/// address = 0x20000100
+/// width = 2
+/// min = 3300
+///
+/// [[region]]
+/// file = "settings.bin"
+/// address = 0x3E000
+///
+/// [[region]]
+/// file = "s140_nrf52_6.1.1_softdevice.hex"
+///
+/// [[region]]
+/// file = "target/thumbv7em-none-eabihf/release/app"
+///
+/// [[region]]
+/// kind = "filesystem"
+/// file = "littlefs.bin"
+/// address = 0x70000
+/// size = 0x10000
+///
+/// [[region]]
+/// kind = "external"
+/// file = "circuitpy.bin"
+/// address = 0x04000000
+/// page_size = 256
+/// ```
+#[derive(Debug, Deserialize)]
+pub struct Manifest {
+    /// Page marking a flash in progress, written first and erased last.
+    pub marker: Option<u32>,
+    /// Supply reading to check before flashing, for battery powered boards.
+    pub supply: Option<ManifestSupply>,
+    #[serde(rename = "region", default)]
+    pub regions: Vec<ManifestRegion>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct ManifestRegion {
+    /// Binary, intel hex, uf2 or elf, relative to the manifest.
+    pub file: PathBuf,
+    /// Where a binary is flashed, hex, uf2 and elf files carry their own address.
+    pub address: Option<u32>,
+    #[serde(default)]
+    pub kind: RegionKind,
+    /// Flash reserved for a filesystem or the size of an external flash window, the image may be
+    /// smaller.
+    pub size: Option<u32>,
+    /// Page size of external flash.
+    pub page_size: Option<u32>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct ManifestSupply {
+    pub address: u32,
+    /// Bytes to read, defaults to a u32.
+    #[serde(default = "default_width")]
+    pub width: u8,
+    pub min: u32,
+    /// Only warn instead of refusing to flash when the supply is low.
+    #[serde(default)]
+    pub warn_only: bool,
+}
+
+fn default_width() -> u8 {
+    4
+}
+
+impl ManifestSupply {
+    pub fn check(&self) -> SupplyCheck {
+        SupplyCheck {
+            address: self.address,
+            width: self.width,
+            min: self.min,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Default, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum RegionKind {
+    /// Firmware or data.
+    #[default]
+    Image,
+    /// A filesystem image like LittleFS or FAT, needs an address and the size reserved for it.
+    Filesystem,
+    /// Written to external flash, ie QSPI, the bootloader maps at address, in pages of page_size.
+    External,
+}
+
+impl Manifest {
+    pub fn load(path: &Path) -> Result<Manifest, String> {
+        let contents = std::fs::read_to_string(path)
+            .map_err(|e| format!("Couldn't read manifest {}: {}", path.display(), e))?;
+        toml::from_str(&contents)
+            .map_err(|e| format!("Couldn't parse manifest {}: {}", path.display(), e))
+    }
+
+    /// The filesystem region, if the manifest has one.
+    pub fn filesystem(&self) -> Option<&ManifestRegion> {
+        self.regions
+            .iter()
+            .find(|region| region.kind == RegionKind::Filesystem)
+    }
+
+    /// Loads every regions file, paths are relative to the manifest at path. Filesystem regions are
+    /// checked against the space reserved for them.
+    pub fn regions(&self, path: &Path, bininfo: &BinInfoResponse) -> Result<Vec<Region>, String> {
+        let base = path.parent().unwrap_or_else(|| Path::new("."));
+
+        self.regions
+            .iter()
+            .map(|region| {
+                let file = base.join(&region.file);
+                let hex = file.extension().is_some_and(|ext| ext == "hex");
+                let uf2 = file.extension().is_some_and(|ext| ext == "uf2");
+                let read = |file: &Path| {
+                    std::fs::read(file)
+                        .map_err(|e| format!("Couldn't read region file {}: {}", file.display(), e))
+                };
+                let unreadable =
+                    |e: UtilError| format!("Couldn't read region {}: {:?}", file.display(), e);
+                Ok(match region.address {
+                    Some(address) if region.kind == RegionKind::Filesystem => {
+                        let size = region.size.ok_or("filesystem region needs a size")?;
+                        let binary = read(&file)?;
+                        check_filesystem(&binary, address, size, bininfo).map_err(unreadable)?;
+                        Region {
+                            address,
+                            binary,
+                            page_size: None,
+                        }
+                    }
+                    Some(address) if region.kind == RegionKind::External => {
+                        let page_size = region
+                            .page_size
+                            .ok_or("external region needs a page_size")?;
+                        let binary = read(&file)?;
+                        if region
+                            .size
+                            .is_some_and(|size| binary.len() as u64 > size as u64)
+                        {
+                            return Err(format!(
+                                "{} is bigger than its external flash window",
+                                file.display()
+                            ));
+                        }
+                        Region {
+                            address,
+                            binary,
+                            page_size: Some(page_size),
+                        }
+                    }
+                    None if region.kind != RegionKind::Image => {
+                        return Err("filesystem and external regions need an address".into())
+                    }
+                    None if uf2 => {
+                        let (binary, address) = parse_uf2(&read(&file)?).map_err(unreadable)?;
+                        Region {
+                            address,
+                            binary,
+                            page_size: None,
+                        }
+                    }
+                    None if hex => {
+                        let (binary, address) = hex_to_bin(file.clone()).map_err(unreadable)?;
+                        Region {
+                            address,
+                            binary,
+                            page_size: None,
+                        }
+                    }
+                    Some(address) => Region {
+                        address,
+                        binary: read(&file)?,
+                        page_size: None,
+                    },
+                    None => {
+                        let (binary, address) = elf_to_bin(file.clone()).map_err(unreadable)?;
+                        Region {
+                            address,
+                            binary,
+                            page_size: None,
+                        }
+                    }
+                })
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_manifest() {
+        let manifest: Manifest = toml::from_str(
+            r#"
+            marker = 0x3F000
+
+            [supply]
+            address = 0x20000100
+            min = 3300
+
+            [[region]]
+            file = "settings.bin"
+            address = 0x3E000
+
+            [[region]]
+            file = "app"
+
+            [[region]]
+            kind = "filesystem"
+            file = "fs.bin"
+            address = 0x70000
+            size = 0x10000
+
+            [[region]]
+            kind = "external"
+            file = "circuitpy.bin"
+            address = 0x04000000
+            page_size = 256
+            "#,
+        )
+        .unwrap();
+
+        assert_eq!(manifest.marker, Some(0x3F000));
+        let supply = manifest.supply.as_ref().unwrap();
+        assert_eq!(supply.check().width, 4);
+        assert!(!supply.warn_only);
+        assert_eq!(manifest.regions.len(), 4);
+        assert_eq!(manifest.regions[0].address, Some(0x3E000));
+        assert_eq!(manifest.regions[1].address, None);
+        assert_eq!(manifest.regions[1].kind, RegionKind::Image);
+
+        let fs = manifest.filesystem().unwrap();
+        assert_eq!(fs.address, Some(0x70000));
+        assert_eq!(fs.size, Some(0x10000));
+        assert_eq!(manifest.regions[3].kind, RegionKind::External);
+        assert_eq!(manifest.regions[3].page_size, Some(256));
+    }
+}
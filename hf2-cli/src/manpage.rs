@@ -0,0 +1,81 @@
+use structopt::clap::{App, ErrorKind};
+
+/// Renders a roff man page from the clap definitions, the top level help followed by a section for
+/// each subcommand, so it never drifts from what --help says.
+pub fn render(app: App) -> String {
+    let name = app.get_name().to_string();
+    let mut help = vec![];
+    app.clone()
+        .write_long_help(&mut help)
+        .expect("Couldn't render help");
+    let help = String::from_utf8_lossy(&help).into_owned();
+    // name and version, then the about line
+    let about = help.lines().nth(1).unwrap_or_default();
+
+    let mut page = format!(".TH {} 1\n", name.to_uppercase());
+    page += &format!(".SH NAME\n{} \\- {}\n", name, escape(about));
+    page += &format!(
+        ".SH SYNOPSIS\n.B {}\n[OPTIONS] <SUBCOMMAND>\n",
+        escape(&name)
+    );
+    page += &format!(".SH DESCRIPTION\n{}\n", preformatted(&help));
+
+    page += ".SH COMMANDS\n";
+    for command in subcommands(&help) {
+        let text =
+            match app
+                .clone()
+                .get_matches_from_safe(vec![name.as_str(), command.as_str(), "--help"])
+            {
+                Err(e) if e.kind == ErrorKind::HelpDisplayed => e.message,
+                _ => continue,
+            };
+        page += &format!(".SS {}\n{}\n", escape(&command), preformatted(&text));
+    }
+    page
+}
+
+// subcommand names from the SUBCOMMANDS section of the help, indented under the heading
+fn subcommands(help: &str) -> Vec<String> {
+    help.lines()
+        .skip_while(|line| !line.starts_with("SUBCOMMANDS:"))
+        .skip(1)
+        .take_while(|line| line.is_empty() || line.starts_with(' '))
+        .filter(|line| line.starts_with("    ") && !line.starts_with("     "))
+        .filter_map(|line| line.split_whitespace().next())
+        .filter(|command| *command != "help")
+        .map(String::from)
+        .collect()
+}
+
+fn preformatted(text: &str) -> String {
+    let lines: Vec<String> = text.trim_end().lines().map(escape).collect();
+    format!(".nf\n{}\n.fi", lines.join("\n"))
+}
+
+// roff treats backslashes and leading dots and quotes as markup
+fn escape(text: &str) -> String {
+    let text = text.replace('\\', "\\e").replace('-', "\\-");
+    if text.starts_with('.') || text.starts_with('\'') {
+        format!("\\&{}", text)
+    } else {
+        text
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Opt;
+    use structopt::StructOpt;
+
+    #[test]
+    fn man_page() {
+        let page = render(Opt::clap());
+        assert!(page.starts_with(".TH HF2 1\n"));
+        assert!(page.contains(".SS flash\n"));
+        assert!(page.contains(".SS image\\-diff\n"));
+        assert!(page.contains("\\-\\-verify"));
+        assert!(!page.contains(".SS help\n"));
+    }
+}
@@ -0,0 +1,74 @@
+use crate::exit::{Exit, OrExit};
+use crate::sinks::{Outputs, Sink};
+use crate::timeline::Timeline;
+use hf2::ReadWrite;
+use hf2::SerialOutput;
+use std::io::BufRead;
+use std::sync::mpsc;
+
+/// Send serial output from the device to each sink until interrupted, copying complete lines to
+/// the timeline. With a stdin command id, lines typed are sent to the device using that vendor
+/// command.
+pub fn monitor(
+    d: &impl ReadWrite,
+    stdin_command: Option<u32>,
+    sinks: &[Sink],
+    timeline: &mut Timeline,
+) -> Result<(), Exit> {
+    let mut outputs = Outputs::open(sinks)?;
+    let mut stdout_line = vec![];
+    let mut stderr_line = vec![];
+
+    // reading the terminal blocks, so it gets its own thread and the device is only touched here
+    let (tx, rx) = mpsc::channel::<String>();
+    if stdin_command.is_some() {
+        std::thread::spawn(move || {
+            for line in std::io::stdin().lock().lines().map_while(Result::ok) {
+                if tx.send(line).is_err() {
+                    break;
+                }
+            }
+        });
+    }
+
+    loop {
+        if let Some(command_id) = stdin_command {
+            while let Ok(line) = rx.try_recv() {
+                timeline.line("stdin", &line);
+                hf2::write_stdin(d, command_id, format!("{}\n", line).as_bytes())
+                    .or_exit("stdin write failed")?;
+            }
+        }
+
+        match hf2::read_serial(d).or_exit("read failed")? {
+            Some(SerialOutput::StdOut(data)) => {
+                outputs.data(false, &data);
+                split_lines(&mut stdout_line, &data, |l| {
+                    timeline.line("stdout", l);
+                    outputs.line(false, l);
+                });
+            }
+            Some(SerialOutput::StdErr(data)) => {
+                outputs.data(true, &data);
+                split_lines(&mut stderr_line, &data, |l| {
+                    timeline.line("stderr", l);
+                    outputs.line(true, l);
+                });
+            }
+            None => outputs.flush(),
+        }
+    }
+}
+
+/// Buffers data until there are whole lines to hand on.
+pub fn split_lines(pending: &mut Vec<u8>, data: &[u8], mut f: impl FnMut(&str)) {
+    for byte in data {
+        if *byte == b'\n' {
+            let line = String::from_utf8_lossy(pending);
+            f(line.trim_end_matches('\r'));
+            pending.clear();
+        } else {
+            pending.push(*byte);
+        }
+    }
+}
@@ -0,0 +1,158 @@
+use std::collections::BTreeMap;
+use std::path::PathBuf;
+
+/// Short names for boards by usb serial, kept between runs in the user's config directory, so a
+/// bench of boards reads as board-a3f2 and board-07c1 rather than serials.
+#[derive(Debug, Default, PartialEq)]
+pub struct Nicknames(BTreeMap<String, String>);
+
+// $XDG_CONFIG_HOME/hf2, ~/.config/hf2 or %APPDATA%\hf2
+fn path() -> Option<PathBuf> {
+    let config = std::env::var_os("XDG_CONFIG_HOME")
+        .map(PathBuf::from)
+        .or_else(|| std::env::var_os("HOME").map(|home| PathBuf::from(home).join(".config")))
+        .or_else(|| std::env::var_os("APPDATA").map(PathBuf::from))?;
+    Some(config.join("hf2").join("nicknames.toml"))
+}
+
+impl Nicknames {
+    /// Empty when nothing has been named yet or the file can't be read.
+    pub fn load() -> Self {
+        let names = path()
+            .and_then(|path| std::fs::read_to_string(path).ok())
+            .and_then(|contents| toml::from_str(&contents).ok())
+            .unwrap_or_default();
+        Nicknames(names)
+    }
+
+    /// Written aside and renamed into place, so concurrent invocations never see a half written file.
+    pub fn save(&self) -> std::io::Result<()> {
+        let path = path().ok_or_else(|| {
+            std::io::Error::new(
+                std::io::ErrorKind::NotFound,
+                "no config directory to keep nicknames in",
+            )
+        })?;
+        if let Some(dir) = path.parent() {
+            std::fs::create_dir_all(dir)?;
+        }
+        let staged = path.with_extension(std::process::id().to_string());
+        let contents = toml::to_string(&self.0).map_err(std::io::Error::other)?;
+        std::fs::write(&staged, contents)?;
+        std::fs::rename(&staged, &path)
+    }
+
+    /// Nicknames with the serial they name, in serial order.
+    pub fn iter(&self) -> impl Iterator<Item = (&str, &str)> {
+        self.0
+            .iter()
+            .map(|(serial, name)| (serial.as_str(), name.as_str()))
+    }
+
+    pub fn get(&self, serial: &str) -> Option<&str> {
+        self.0.get(serial).map(String::as_str)
+    }
+
+    /// Serial of the board with this nickname.
+    pub fn serial(&self, nickname: &str) -> Option<&str> {
+        self.0
+            .iter()
+            .find(|(_, name)| *name == nickname)
+            .map(|(serial, _)| serial.as_str())
+    }
+
+    /// Name a board, refusing a nickname another board already has.
+    pub fn set(&mut self, serial: &str, nickname: &str) -> Result<(), String> {
+        match self.serial(nickname) {
+            Some(other) if other != serial => Err(format!("{} already names {}", nickname, other)),
+            _ => {
+                self.0.insert(serial.to_string(), nickname.to_string());
+                Ok(())
+            }
+        }
+    }
+
+    // board- and four hex digits of a hash of the serial, counting up from -2 on the rare clash
+    fn assign(&mut self, serial: &str) -> bool {
+        if self.0.contains_key(serial) {
+            return false;
+        }
+        // fnv-1a, like DeviceInfo::identity, std's hasher isn't guaranteed stable between releases
+        let hash = serial.bytes().fold(0x811c_9dc5_u32, |hash, byte| {
+            (hash ^ byte as u32).wrapping_mul(0x0100_0193)
+        });
+        let base = format!("board-{:04x}", hash as u16);
+        let nickname = (1..)
+            .map(|n| match n {
+                1 => base.clone(),
+                n => format!("{}-{}", base, n),
+            })
+            .find(|nickname| self.serial(nickname).is_none())
+            .unwrap();
+        self.0.insert(serial.to_string(), nickname);
+        true
+    }
+}
+
+/// Gives boards seen for the first time a nickname derived from their serial and saves them.
+/// Boards without a serial can't be recognized next time so aren't named.
+pub fn assign_new(found: &[hf2::DeviceInfo]) {
+    let mut nicknames = Nicknames::load();
+    let mut assigned = false;
+    for serial in found
+        .iter()
+        .filter_map(|info| info.serial_number.as_deref())
+        .filter(|serial| !serial.is_empty())
+    {
+        assigned |= nicknames.assign(serial);
+    }
+    if assigned {
+        // naming is a convenience, the command goes on without it
+        if let Err(e) = nicknames.save() {
+            log::warn!("couldn't save nicknames, {}", e);
+        }
+    }
+}
+
+/// How output names a board, its nickname followed by its identity when it has one.
+pub fn label(info: &hf2::DeviceInfo) -> String {
+    let nickname = info
+        .serial_number
+        .as_deref()
+        .and_then(|serial| Nicknames::load().get(serial).map(str::to_string));
+    match nickname {
+        Some(nickname) => format!("{} ({})", nickname, info.identity()),
+        None => info.identity(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn nicknames() {
+        let mut nicknames = Nicknames::default();
+        assert!(nicknames.assign("8A2E1F3C"));
+        assert!(!nicknames.assign("8A2E1F3C"));
+        let name = nicknames.get("8A2E1F3C").unwrap().to_string();
+        assert!(name.starts_with("board-") && name.len() == 10);
+        assert_eq!(nicknames.serial(&name), Some("8A2E1F3C"));
+
+        // a clash gets the next free name
+        let mut clashing = Nicknames::default();
+        clashing.set("other", &name).unwrap();
+        clashing.assign("8A2E1F3C");
+        assert_eq!(
+            clashing.get("8A2E1F3C"),
+            Some(format!("{}-2", name).as_str())
+        );
+
+        assert!(nicknames.set("other", &name).is_err());
+        assert!(nicknames.set("other", "bench-left").is_ok());
+        assert_eq!(nicknames.serial("bench-left"), Some("other"));
+
+        let saved = toml::to_string(&nicknames.0).unwrap();
+        assert_eq!(Nicknames(toml::from_str(&saved).unwrap()), nicknames);
+    }
+}
@@ -0,0 +1,94 @@
+use std::io::Write;
+use std::process::Command;
+
+/// How to tell someone a long flash finished, for when they've switched to something else.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Notify {
+    /// Ring the terminal bell.
+    Bell,
+    /// Pop up a desktop notification, notify-send on linux and osascript on mac.
+    Desktop,
+    /// Run a shell command with HF2_RESULT set to pass or fail and HF2_MESSAGE describing it.
+    Command(String),
+}
+
+impl std::str::FromStr for Notify {
+    type Err = String;
+
+    fn from_str(input: &str) -> Result<Self, Self::Err> {
+        match input {
+            "" => Err("notify needs bell, desktop or a command".into()),
+            "bell" => Ok(Notify::Bell),
+            "desktop" => Ok(Notify::Desktop),
+            command => Ok(Notify::Command(command.into())),
+        }
+    }
+}
+
+impl Notify {
+    /// Send the notification. Failing to notify is only logged, the flash itself already happened.
+    pub fn send(&self, pass: bool, what: &str) {
+        let message = format!("hf2 {} {}", what, if pass { "succeeded" } else { "failed" });
+
+        match self {
+            Notify::Bell => {
+                let mut err = std::io::stderr();
+                let _ = err.write_all(b"\x07");
+                let _ = err.flush();
+            }
+            Notify::Desktop => {
+                let status = desktop(&message);
+                if status.is_err() {
+                    log::warn!("couldn't show a desktop notification, ringing the bell instead");
+                    Notify::Bell.send(pass, what);
+                }
+            }
+            Notify::Command(command) => {
+                let status = shell(command)
+                    .env("HF2_RESULT", if pass { "pass" } else { "fail" })
+                    .env("HF2_MESSAGE", &message)
+                    .status();
+                if !status.is_ok_and(|status| status.success()) {
+                    log::warn!("notify command {:?} failed", command);
+                }
+            }
+        }
+    }
+}
+
+#[cfg(target_os = "macos")]
+fn desktop(message: &str) -> std::io::Result<()> {
+    let script = format!("display notification {:?} with title \"hf2\"", message);
+    check(Command::new("osascript").args(["-e", &script]).status()?)
+}
+
+#[cfg(not(target_os = "macos"))]
+fn desktop(message: &str) -> std::io::Result<()> {
+    check(
+        Command::new("notify-send")
+            .args(["hf2", message])
+            .status()?,
+    )
+}
+
+fn check(status: std::process::ExitStatus) -> std::io::Result<()> {
+    if status.success() {
+        Ok(())
+    } else {
+        Err(std::io::Error::other("notifier failed"))
+    }
+}
+
+#[cfg(windows)]
+fn shell(command: &str) -> Command {
+    let mut shell = Command::new("cmd");
+    shell.args(["/C", command]);
+    shell
+}
+
+#[cfg(not(windows))]
+fn shell(command: &str) -> Command {
+    let mut shell = Command::new("sh");
+    shell.args(["-c", command]);
+    shell
+}
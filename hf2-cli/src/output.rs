@@ -0,0 +1,36 @@
+use std::io::{IsTerminal, Write};
+
+/// println! that exits quietly once stdout's reader has gone, ie `hf2 list | head -1`, rather than
+/// panicking. Rust's formatting doesn't follow the locale, so numbers print the same everywhere.
+macro_rules! outln {
+    () => {
+        $crate::output::write(b"\n")
+    };
+    ($($arg:tt)*) => {
+        $crate::output::write(format!("{}\n", format_args!($($arg)*)).as_bytes())
+    };
+}
+
+/// print! with the same handling as outln!.
+macro_rules! out {
+    ($($arg:tt)*) => {
+        $crate::output::write(format!($($arg)*).as_bytes())
+    };
+}
+
+/// Writes to stdout and flushes. Any other failure is as fatal as println! makes it.
+pub fn write(bytes: &[u8]) {
+    let mut stdout = std::io::stdout().lock();
+    match stdout.write_all(bytes).and_then(|_| stdout.flush()) {
+        Ok(()) => (),
+        // the reader took what it wanted, like head, nothing left to do
+        Err(e) if e.kind() == std::io::ErrorKind::BrokenPipe => std::process::exit(0),
+        Err(e) => panic!("failed printing to stdout: {}", e),
+    }
+}
+
+/// Whether to color output, only on a terminal and unless NO_COLOR is set, see no-color.org.
+pub fn color() -> bool {
+    std::env::var_os("NO_COLOR").is_none_or(|value| value.is_empty())
+        && std::io::stdout().is_terminal()
+}
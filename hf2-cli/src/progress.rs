@@ -0,0 +1,150 @@
+use hf2::utils::{Phase, Progress, ProgressHandler, Retry, Unreadable};
+use indicatif::{HumanBytes, ProgressBar, ProgressStyle};
+use std::sync::Mutex;
+
+// retries the bars reported, summarized before exiting
+static RETRIED: Mutex<Vec<(Phase, Retry)>> = Mutex::new(vec![]);
+
+/// A bar on stderr for each phase of a flash, pages done of the total and how fast they're going,
+/// with retries printed above it. indicatif hides it when stderr isn't a terminal, ranges a full
+/// verify couldn't read are printed either way.
+#[derive(Default)]
+pub struct ProgressBars {
+    bar: Option<(Phase, ProgressBar)>,
+}
+
+impl ProgressHandler for ProgressBars {
+    fn progress(&mut self, progress: Progress) {
+        if progress.phase == Phase::Resetting {
+            if let Some((_, bar)) = self.bar.take() {
+                bar.finish();
+            }
+            return;
+        }
+
+        let bar = match &self.bar {
+            Some((phase, bar)) if *phase == progress.phase => bar,
+            _ => {
+                if let Some((_, bar)) = self.bar.take() {
+                    bar.finish();
+                }
+                let bar = ProgressBar::new(progress.total as u64)
+                    .with_style(
+                        ProgressStyle::with_template(
+                            "{prefix:>9} [{bar:30}] {pos}/{len} pages {msg}",
+                        )
+                        .expect("progress template")
+                        .progress_chars("=> "),
+                    )
+                    .with_prefix(format!("{:?}", progress.phase));
+                &self.bar.insert((progress.phase, bar)).1
+            }
+        };
+
+        if let Some(retry) = progress.retry {
+            bar.println(describe_retry(progress.phase, &retry));
+            RETRIED
+                .lock()
+                .unwrap_or_else(|e| e.into_inner())
+                .push((progress.phase, retry));
+        }
+        if let Some(unreadable) = progress.unreadable {
+            bar.suspend(|| eprintln!("{}", describe_unreadable(&unreadable)));
+        }
+        bar.set_length(progress.total as u64);
+        bar.set_position(progress.pages as u64);
+        bar.set_message(format!("{}/s", HumanBytes(progress.bytes_per_sec())));
+    }
+}
+
+impl Drop for ProgressBars {
+    // a flash that failed part way leaves its bar where it stopped
+    fn drop(&mut self) {
+        if let Some((_, bar)) = self.bar.take() {
+            bar.abandon();
+        }
+    }
+}
+
+fn describe_retry(phase: Phase, retry: &Retry) -> String {
+    format!(
+        "{:?} page {} at {:#010x} took {} attempts, {}",
+        phase,
+        retry.page,
+        retry.address,
+        retry.attempts,
+        if retry.succeeded { "ok" } else { "failed" }
+    )
+}
+
+fn describe_unreadable(unreadable: &Unreadable) -> String {
+    format!(
+        "{:#010x}..{:#010x} appears read-protected ({}), verified with checksums instead",
+        unreadable.address,
+        unreadable.address as u64 + unreadable.len as u64,
+        if unreadable.refused {
+            "reads refused"
+        } else {
+            "reads as zeros"
+        }
+    )
+}
+
+/// Lists the retries again at the end so they aren't lost among the progress output.
+pub fn report_retries() {
+    let retried = RETRIED.lock().unwrap_or_else(|e| e.into_inner());
+    if retried.is_empty() {
+        return;
+    }
+    eprintln!(
+        "{} retried, the board or its connection may be marginal:",
+        match retried.len() {
+            1 => "1 page was".to_string(),
+            n => format!("{} pages were", n),
+        }
+    );
+    for (phase, retry) in retried.iter() {
+        eprintln!("  {}", describe_retry(*phase, retry));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    #[test]
+    fn bars() {
+        let at = |phase, pages, retry| Progress {
+            phase,
+            pages,
+            total: 4,
+            bytes: pages as u64 * 256,
+            elapsed: Duration::from_millis(100),
+            retry,
+            unreadable: None,
+        };
+        let retry = Retry {
+            page: 1,
+            address: 0x2100,
+            attempts: 2,
+            succeeded: true,
+        };
+
+        let mut bars = ProgressBars::default();
+        bars.progress(at(Phase::Flashing, 1, None));
+        bars.progress(at(Phase::Flashing, 2, Some(retry)));
+        let (phase, bar) = bars.bar.as_ref().unwrap();
+        assert_eq!(
+            (*phase, bar.position(), bar.length()),
+            (Phase::Flashing, 2, Some(4))
+        );
+        assert_eq!(bar.message(), "5.00 KiB/s");
+
+        bars.progress(at(Phase::Verifying, 4, None));
+        assert_eq!(bars.bar.as_ref().unwrap().0, Phase::Verifying);
+        bars.progress(at(Phase::Resetting, 0, None));
+        assert!(bars.bar.is_none());
+        assert!(RETRIED.lock().unwrap().contains(&(Phase::Flashing, retry)));
+    }
+}
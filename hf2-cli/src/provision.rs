@@ -0,0 +1,326 @@
+use crate::exit::{self, Exit, OrExit};
+use hf2::utils::{
+    elf_to_bin, flash_bin, flash_bin_with_progress, hex_to_bin, open_unlocked, parse_uf2,
+    patch_image, PadPolicy, UtilError, VerifyMode,
+};
+use hf2::ErrorCategory;
+use hidapi::{HidApi, HidDevice};
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+
+/// Where each board's image comes from, looked up by usb serial.
+pub enum Images {
+    /// One image per board, named after its serial.
+    Dir(PathBuf),
+    /// One image for every board, patched with that board's row of a csv.
+    Template { image: PathBuf, patches: Patches },
+}
+
+impl Images {
+    /// The image for a board and a description of where it came from, None if there's nothing
+    /// for this serial.
+    pub fn for_serial(
+        &self,
+        serial: &str,
+        address: Option<u32>,
+    ) -> Result<Option<(Vec<u8>, u32, String)>, UtilError> {
+        match self {
+            Images::Dir(dir) => {
+                let path = match image_for(dir, serial) {
+                    Some(path) => path,
+                    None => return Ok(None),
+                };
+                let (binary, address) = load_image(&path, address)?;
+                Ok(Some((binary, address, path.display().to_string())))
+            }
+            Images::Template { image, patches } => {
+                let row = match patches.rows.get(serial) {
+                    Some(row) => row,
+                    None => return Ok(None),
+                };
+                let (mut binary, address) = load_image(image, address)?;
+                for (patch_address, data) in patches.columns.iter().zip(row) {
+                    if let Some(data) = data {
+                        patch_image(&mut binary, address, *patch_address, data)?;
+                    }
+                }
+                Ok(Some((
+                    binary,
+                    address,
+                    format!("{} patched", image.display()),
+                )))
+            }
+        }
+    }
+}
+
+/// Per board values from a csv like
+///
+/// ```csv
+/// serial,0x3F000,0x3F004
+/// ABC123,01000000,DEADBEEF
+/// DEF456,02000000,
+/// ```
+///
+/// The header names the address each column is written at, cells are hex bytes in memory order
+/// and an empty cell leaves the image alone.
+#[derive(Debug, PartialEq)]
+pub struct Patches {
+    pub columns: Vec<u32>,
+    pub rows: HashMap<String, Vec<Option<Vec<u8>>>>,
+}
+
+impl Patches {
+    pub fn load(path: &Path) -> Result<Patches, Exit> {
+        let contents = std::fs::read_to_string(path).or_exit("Couldn't read patches")?;
+        Patches::parse(&contents).map_err(|e| exit::usage(&e))
+    }
+
+    pub fn parse(contents: &str) -> Result<Patches, String> {
+        let mut lines = contents
+            .lines()
+            .map(str::trim)
+            .filter(|line| !line.is_empty() && !line.starts_with('#'));
+
+        let header = lines.next().ok_or("patches csv is empty")?;
+        let columns = header
+            .split(',')
+            .skip(1)
+            .map(|address| {
+                crate::parse_hex_32(address.trim())
+                    .map_err(|_| format!("column {} isn't an address", address))
+            })
+            .collect::<Result<Vec<u32>, String>>()?;
+
+        let mut rows = HashMap::new();
+        for line in lines {
+            let mut cells = line.split(',').map(str::trim);
+            let serial = cells.next().unwrap_or_default().to_string();
+            let values = cells
+                .map(|cell| match cell {
+                    "" => Ok(None),
+                    cell => crate::parse_hex_bytes(cell)
+                        .map(Some)
+                        .map_err(|e| format!("{} for {}", e, serial)),
+                })
+                .collect::<Result<Vec<_>, String>>()?;
+            if values.len() > columns.len() {
+                return Err(format!("{} has more values than columns", serial));
+            }
+            rows.insert(serial, values);
+        }
+
+        Ok(Patches { columns, rows })
+    }
+}
+
+/// Image for a board in dir, named after its serial with a bin, hex, uf2 or elf extension.
+pub fn image_for(dir: &Path, serial: &str) -> Option<PathBuf> {
+    ["bin", "hex", "uf2", "elf"]
+        .iter()
+        .map(|ext| dir.join(format!("{}.{}", serial, ext)))
+        .find(|path| path.is_file())
+}
+
+/// Reads an image, binaries need the address, the other formats carry their own. Elfs are
+/// recognized by their header, as cargo builds them without an extension.
+pub fn load_image(path: &Path, address: Option<u32>) -> Result<(Vec<u8>, u32), UtilError> {
+    let mut magic = [0; 4];
+    let is_elf = std::fs::File::open(path)
+        .and_then(|mut file| std::io::Read::read_exact(&mut file, &mut magic))
+        .is_ok()
+        && magic == *b"\x7fELF";
+    if is_elf {
+        return elf_to_bin(path.to_owned());
+    }
+
+    match path.extension().and_then(|ext| ext.to_str()) {
+        Some("hex") => hex_to_bin(path.to_owned()),
+        Some("uf2") => parse_uf2(&std::fs::read(path).map_err(|_| UtilError::File)?),
+        _ => {
+            let address = address
+                .ok_or_else(|| UtilError::InvalidRegion("binary images need --address".into()))?;
+            Ok((std::fs::read(path).map_err(|_| UtilError::File)?, address))
+        }
+    }
+}
+
+/// Flashes an opened board with its image.
+pub fn flash_one(
+    d: &hf2::Session<HidDevice>,
+    images: &Images,
+    address: Option<u32>,
+    pad: PadPolicy,
+) -> Result<(), Exit> {
+    let serial = d
+        .device()
+        .get_serial_number_string()
+        .ok()
+        .flatten()
+        .ok_or_else(|| exit::usage("device has no serial number to pick its image by"))?;
+    let (binary, address, source) = images
+        .for_serial(&serial, address)
+        .or_exit("couldn't read the image")?
+        .ok_or_else(|| exit::usage(&format!("no image for {}", serial)))?;
+
+    let bininfo = hf2::bin_info(d).or_exit("bin_info failed")?;
+    flash_bin_with_progress(
+        &binary,
+        address,
+        pad,
+        VerifyMode::Checksum,
+        &bininfo,
+        d,
+        &mut crate::ProgressBars::default(),
+    )
+    .or_exit("flash failed")?;
+    outln!("{}: flashed {}", serial, source);
+    Ok(())
+}
+
+/// Flashes every attached board that has an image, all at once, see hf2::set_max_in_flight for
+/// keeping a hub from being overrun. Boards are told apart by serial, interfaces of the same board
+/// share one. Fails once they've all been tried if any board with an image didn't flash.
+pub fn flash_all(
+    api: &HidApi,
+    candidates: &[hf2::DeviceInfo],
+    images: &Images,
+    address: Option<u32>,
+    pad: PadPolicy,
+    steal: bool,
+) -> Result<(), Exit> {
+    let mut serials: Vec<&str> = candidates
+        .iter()
+        .filter_map(|info| info.serial_number.as_deref())
+        .collect();
+    serials.sort_unstable();
+    serials.dedup();
+
+    let unnamed = candidates
+        .iter()
+        .filter(|info| info.serial_number.is_none())
+        .count();
+    if unnamed > 0 {
+        eprintln!("skipping {} interfaces without a serial number", unnamed);
+    }
+
+    // opened here, each board is then flashed on its own thread
+    let mut ok = true;
+    let mut jobs = vec![];
+    for serial in serials {
+        let (binary, address, source) = match images.for_serial(serial, address) {
+            Ok(Some(image)) => image,
+            Ok(None) => {
+                outln!("{}: no image, skipped", serial);
+                continue;
+            }
+            Err(e) => {
+                outln!("{}: couldn't read the image, {:?}", serial, e);
+                ok = false;
+                continue;
+            }
+        };
+
+        let board: Vec<hf2::DeviceInfo> = candidates
+            .iter()
+            .filter(|info| info.serial_number.as_deref() == Some(serial))
+            .cloned()
+            .collect();
+        let identity = crate::nicknames::label(&board[0]);
+        let opened = open_unlocked(api, &board, steal).and_then(|(d, lock)| {
+            let d = crate::connect(d);
+            let bininfo = hf2::bin_info(&d)?;
+            Ok((d, lock, bininfo))
+        });
+        match opened {
+            Ok(opened) => jobs.push((identity, opened, binary, address, source)),
+            Err(e) => {
+                eprintln!("{}: failed {:?}", identity, e);
+                ok = false;
+            }
+        }
+    }
+
+    // --compat is the baseline, boards are flashed one after another rather than all at once
+    let one_at_a_time = jobs.iter().any(|(_, (d, ..), ..)| d.options().compat);
+    // each thread hands back whether its board flashed, only this one decides to fail
+    std::thread::scope(|scope| {
+        let mut flashes = vec![];
+        for (identity, (d, _lock, bininfo), binary, address, source) in jobs {
+            let flash = scope.spawn(move || {
+                let result = flash_bin(&binary, address, pad, &bininfo, &d);
+                match &result {
+                    Ok(()) => outln!("{}: flashed {}", identity, source),
+                    Err(e) => eprintln!("{}: failed {:?}", identity, e),
+                }
+                result.is_ok()
+            });
+            if one_at_a_time {
+                ok &= flash.join().unwrap_or(false);
+            } else {
+                flashes.push(flash);
+            }
+        }
+        for flash in flashes {
+            ok &= flash.join().unwrap_or(false);
+        }
+    });
+    if !ok {
+        return Err(exit::fail(
+            ErrorCategory::Device,
+            exit::Failure::code(&UtilError::Communication),
+            "not every board flashed",
+        ));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn images_by_serial() {
+        let dir = std::env::temp_dir().join(format!("hf2-provision-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(dir.join("ABC123.bin"), [0]).unwrap();
+        std::fs::write(dir.join("DEF456.hex"), ":00000001FF\n").unwrap();
+
+        assert_eq!(image_for(&dir, "ABC123"), Some(dir.join("ABC123.bin")));
+        assert_eq!(image_for(&dir, "DEF456"), Some(dir.join("DEF456.hex")));
+        assert_eq!(image_for(&dir, "XYZ789"), None);
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn elf_without_extension() {
+        let elf: PathBuf = [
+            env!("CARGO_MANIFEST_DIR"),
+            "../hf2/src/utils/testdata/blinky_1.47.0",
+        ]
+        .iter()
+        .collect();
+        let (binary, address) = load_image(&elf, None).unwrap();
+        assert_eq!((binary, address), elf_to_bin(elf).unwrap());
+        assert_eq!(address, 0x4000);
+    }
+
+    #[test]
+    fn parse_patches() {
+        let patches = Patches::parse(
+            "serial,0x3F000,0x3F004\n# spares\nABC123,01000000,DEADBEEF\nDEF456,02000000,\n",
+        )
+        .unwrap();
+
+        assert_eq!(patches.columns, vec![0x3F000, 0x3F004]);
+        assert_eq!(
+            patches.rows["ABC123"],
+            vec![Some(vec![1, 0, 0, 0]), Some(vec![0xDE, 0xAD, 0xBE, 0xEF])]
+        );
+        assert_eq!(patches.rows["DEF456"], vec![Some(vec![2, 0, 0, 0]), None]);
+
+        assert!(Patches::parse("serial,settings\n").is_err());
+        assert!(Patches::parse("serial,0x3F000\nABC123,01,02\n").is_err());
+    }
+}
@@ -0,0 +1,66 @@
+use hf2::utils::Access;
+
+/// Reads a batch script, one access per line
+///
+/// ```text
+/// # comments and blank lines are skipped
+/// read 0x40000400 8
+/// write 0x40000400 01000000
+/// ```
+///
+/// Reads take an address and a byte count, writes an address and hex bytes in memory order.
+pub fn parse(contents: &str) -> Result<Vec<Access>, String> {
+    contents
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .map(|line| {
+            let fields: Vec<&str> = line.split_whitespace().collect();
+            let address = |field: &str| {
+                crate::parse_hex_32(field).map_err(|_| format!("{} isn't an address", field))
+            };
+            match fields.as_slice() {
+                ["read", at, len] => Ok(Access::Read {
+                    address: address(at)?,
+                    len: len
+                        .parse()
+                        .map_err(|_| format!("{} isn't a byte count", len))?,
+                }),
+                ["write", at, data] => Ok(Access::Write {
+                    address: address(at)?,
+                    data: crate::parse_hex_bytes(data)?,
+                }),
+                _ => Err(format!(
+                    "{} isn't read <address> <len> or write <address> <hex>",
+                    line
+                )),
+            }
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_script() {
+        let accesses = parse("# uart\nread 0x40000400 8\n\nwrite 0x40000400 01000000\n").unwrap();
+        assert_eq!(
+            accesses,
+            vec![
+                Access::Read {
+                    address: 0x4000_0400,
+                    len: 8
+                },
+                Access::Write {
+                    address: 0x4000_0400,
+                    data: vec![1, 0, 0, 0]
+                },
+            ]
+        );
+
+        assert!(parse("poke 0x40000400 1").is_err());
+        assert!(parse("read 0x40000400").is_err());
+    }
+}
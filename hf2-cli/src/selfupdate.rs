@@ -0,0 +1,153 @@
+use crate::exit;
+use hf2::ErrorCategory;
+use minisign_verify::{PublicKey, Signature};
+use std::path::Path;
+use std::process::Command;
+
+/// Where releases are published.
+const REPO: &str = "jacobrosenthal/hf2-rs";
+
+// the minisign public key releases are signed with, the base64 line of its .pub file. The release
+// workflow builds with it from the repository's HF2_RELEASE_KEY variable, a build without it can't
+// check a download so won't install one.
+const RELEASE_KEY: Option<&str> = option_env!("HF2_RELEASE_KEY");
+
+/// Replaces this executable with the binary for this platform from the latest GitHub release, once
+/// its minisign signature checks out against the key this build was made with. Releases carry
+/// `hf2-<arch>-<os>` (`.exe` on windows) and `hf2-<arch>-<os>.minisig`, see release.yml. curl does
+/// the downloading, as it's already on every machine that has a browser.
+pub fn self_update(check: bool) -> Result<(), exit::Exit> {
+    let failed = |message: String| exit::fail(ErrorCategory::Host, exit::UPDATE_FAILED, &message);
+
+    let release = fetch(&format!(
+        "https://api.github.com/repos/{}/releases/latest",
+        REPO
+    ))
+    .map_err(failed)?;
+    let release: serde_json::Value = serde_json::from_slice(&release)
+        .map_err(|e| failed(format!("couldn't read the release: {}", e)))?;
+
+    let tag = release["tag_name"].as_str().unwrap_or_default();
+    let current = env!("CARGO_PKG_VERSION");
+    if !newer(tag, current) {
+        outln!("hf2 {} is up to date", current);
+        return Ok(());
+    }
+    outln!("hf2 {} is available, this is {}", tag, current);
+    if check {
+        return Ok(());
+    }
+    let key = RELEASE_KEY.ok_or_else(|| {
+        failed("this build has no release key to check downloads with, update it with cargo install hf2-cli".into())
+    })?;
+
+    let name = asset_name();
+    let url = |name: &str| {
+        release["assets"]
+            .as_array()
+            .into_iter()
+            .flatten()
+            .find(|asset| asset["name"] == name)
+            .and_then(|asset| asset["browser_download_url"].as_str())
+            .map(String::from)
+            .ok_or_else(|| failed(format!("release {} has no {}", tag, name)))
+    };
+
+    let binary = fetch(&url(&name)?).map_err(failed)?;
+    let signature = fetch(&url(&format!("{}.minisig", name))?).map_err(failed)?;
+    verify(key, &binary, &String::from_utf8_lossy(&signature))
+        .map_err(|e| failed(format!("{} isn't signed by the release key, {}", name, e)))?;
+
+    let exe = std::env::current_exe()
+        .map_err(|e| failed(format!("couldn't find this executable: {}", e)))?;
+    replace(&exe, &binary)
+        .map_err(|e| failed(format!("couldn't replace {}: {}", exe.display(), e)))?;
+    outln!("updated {} to {}", exe.display(), tag);
+    Ok(())
+}
+
+fn fetch(url: &str) -> Result<Vec<u8>, String> {
+    log::debug!("fetching {}", url);
+    let output = Command::new("curl")
+        .args(["-fsSL", "-H", "User-Agent: hf2", url])
+        .output()
+        .map_err(|e| format!("couldn't run curl: {}", e))?;
+    if !output.status.success() {
+        return Err(format!(
+            "couldn't fetch {}: {}",
+            url,
+            String::from_utf8_lossy(&output.stderr).trim()
+        ));
+    }
+    Ok(output.stdout)
+}
+
+fn asset_name() -> String {
+    let name = format!("hf2-{}-{}", std::env::consts::ARCH, std::env::consts::OS);
+    if cfg!(windows) {
+        name + ".exe"
+    } else {
+        name
+    }
+}
+
+// whether a release tag, ie v0.3.4, is a later version than current
+fn newer(tag: &str, current: &str) -> bool {
+    let version = |text: &str| -> Vec<u64> {
+        text.trim_start_matches('v')
+            .split('.')
+            .map(|part| part.parse().unwrap_or(0))
+            .collect()
+    };
+    !tag.is_empty() && version(tag) > version(current)
+}
+
+// checks a minisign signature, prehashed as minisign signs by default, against key
+fn verify(key: &str, binary: &[u8], signature: &str) -> Result<(), minisign_verify::Error> {
+    let key = PublicKey::from_base64(key)?;
+    key.verify(binary, &Signature::decode(signature)?, false)
+}
+
+// written beside the executable then renamed over it, so an interrupted update leaves the old one
+// working. Windows won't replace a running executable but will rename it out of the way.
+fn replace(exe: &Path, binary: &[u8]) -> std::io::Result<()> {
+    let staged = exe.with_extension("new");
+    std::fs::write(&staged, binary)?;
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        std::fs::set_permissions(&staged, std::fs::Permissions::from_mode(0o755))?;
+    }
+    if cfg!(windows) {
+        let old = exe.with_extension("old");
+        let _ = std::fs::remove_file(&old);
+        std::fs::rename(exe, &old)?;
+    }
+    std::fs::rename(&staged, exe)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn release_checks() {
+        assert!(newer("v0.3.10", "0.3.3"));
+        assert!(newer("0.4.0", "0.3.3"));
+        assert!(!newer("v0.3.3", "0.3.3"));
+        assert!(!newer("", "0.3.3"));
+
+        // made with a throwaway key, minisign -S -m over b"hf2 release"
+        let key = "RWR1uANnWXSFtOW/3YKcDFDIIJj8HkUCCLATpGrLZEwUi0TAjkCU9hMp";
+        let signature = "untrusted comment: signature from minisign secret key
+RUR1uANnWXSFtOHcR/SBKRLHcZvcEfVYuyU1baXjeMIx2aKktA8ZAf9oX6NjPWUCuu1uaA3Z9MOtBMHVsiTn17SmI6B/O/w2Pgg=
+trusted comment: timestamp:0\tfile:hf2-x86_64-linux
+XEnKM5Pib98vr83DtMs4OQ/7KIJD0kIhIAbUP9uQNkWN6v5hDxvJlEx1fYvnaWhvppr47ggYLtwR4CaZZpbYCQ==
+";
+        assert!(verify(key, b"hf2 release", signature).is_ok());
+        assert!(verify(key, b"hf2 releasE", signature).is_err());
+        assert!(verify(key, b"hf2 release", "not a signature").is_err());
+        let other = "RWTUyy34EOoLnw5gV52MNkefkjbqrNCBTsmYT+AgjmYiATaZxMgTP+9y";
+        assert!(verify(other, b"hf2 release", signature).is_err());
+    }
+}
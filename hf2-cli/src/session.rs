@@ -0,0 +1,24 @@
+use std::path::PathBuf;
+
+/// While a session file exists, invocations leave the board in the bootloader after flashing
+/// instead of resetting into the app, until `hf2 release`.
+fn path() -> PathBuf {
+    std::env::temp_dir().join("hf2-session")
+}
+
+/// Whether an earlier `hf2 hold` is still in effect.
+pub fn active() -> bool {
+    path().exists()
+}
+
+/// Written aside and renamed into place, so concurrent invocations never see a half written file.
+pub fn begin() -> std::io::Result<()> {
+    let staged = path().with_extension(std::process::id().to_string());
+    std::fs::write(&staged, std::process::id().to_string())?;
+    std::fs::rename(&staged, path())
+}
+
+pub fn end() {
+    // nothing to clean up if there was no session
+    let _ = std::fs::remove_file(path());
+}
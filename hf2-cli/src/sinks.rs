@@ -0,0 +1,236 @@
+use crate::exit::{self, Exit, OrExit};
+use std::fs::{File, OpenOptions};
+use std::io::Write;
+use std::net::TcpStream;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+use std::sync::mpsc;
+use std::time::{Duration, Instant};
+
+// lines a webhook batch holds at most, and how long the first of them waits for company
+const BATCH_LINES: usize = 100;
+const BATCH_WAIT: Duration = Duration::from_secs(2);
+
+/// Where monitor sends device output, given as `terminal`, `file:<path>`, `tcp:<host:port>` or an
+/// http(s) url to POST batches of lines to.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Sink {
+    Terminal,
+    /// Appends the raw output of both streams.
+    File(PathBuf),
+    /// Streams the raw output of both streams to a listener, ie `nc -l 9000`.
+    Tcp(String),
+    /// POSTs `{"lines":[{"stream":"stdout","line":"..."}]}` with curl.
+    Webhook(String),
+}
+
+impl std::str::FromStr for Sink {
+    type Err = String;
+
+    fn from_str(input: &str) -> Result<Self, Self::Err> {
+        match input.split_once(':') {
+            _ if input == "terminal" => Ok(Sink::Terminal),
+            Some(("file", path)) if !path.is_empty() => Ok(Sink::File(path.into())),
+            Some(("tcp", address)) if !address.is_empty() => Ok(Sink::Tcp(address.into())),
+            Some(("http" | "https", _)) => Ok(Sink::Webhook(input.into())),
+            _ => Err(format!(
+                "{} isn't a sink, use terminal, file:<path>, tcp:<host:port> or an http url",
+                input
+            )),
+        }
+    }
+}
+
+#[derive(serde::Deserialize)]
+struct SinksFile {
+    sinks: Vec<String>,
+}
+
+/// Sinks listed in a toml file, `sinks = ["terminal", "file:console.log"]`.
+pub fn load(path: &Path) -> Result<Vec<Sink>, Exit> {
+    let contents = std::fs::read_to_string(path).or_exit("Couldn't read sinks file")?;
+    let file: SinksFile = toml::from_str(&contents)
+        .map_err(|e| exit::usage(&format!("Couldn't parse sinks file: {}", e)))?;
+    file.sinks
+        .iter()
+        .map(|sink| sink.parse().map_err(|e: String| exit::usage(&e)))
+        .collect()
+}
+
+/// Every sink monitor writes to. A sink that fails is dropped with a warning and the rest carry
+/// on, losing the console over a closed socket would be worse.
+pub struct Outputs {
+    outputs: Vec<(Sink, Output)>,
+}
+
+enum Output {
+    Terminal,
+    File(File),
+    Tcp(TcpStream),
+    Webhook(Batch),
+}
+
+struct Batch {
+    lines: Vec<serde_json::Value>,
+    since: Option<Instant>,
+    tx: mpsc::Sender<Vec<serde_json::Value>>,
+}
+
+impl Outputs {
+    pub fn open(sinks: &[Sink]) -> Result<Self, Exit> {
+        let outputs = sinks
+            .iter()
+            .map(|sink| {
+                let output = match sink {
+                    Sink::Terminal => Output::Terminal,
+                    Sink::File(path) => Output::File(
+                        OpenOptions::new()
+                            .create(true)
+                            .append(true)
+                            .open(path)
+                            .or_exit("Couldn't open sink file")?,
+                    ),
+                    Sink::Tcp(address) => Output::Tcp(
+                        TcpStream::connect(address).or_exit("Couldn't connect to sink")?,
+                    ),
+                    Sink::Webhook(url) => Output::Webhook(Batch {
+                        lines: vec![],
+                        since: None,
+                        tx: poster(url.clone()),
+                    }),
+                };
+                Ok((sink.clone(), output))
+            })
+            .collect::<Result<_, Exit>>()?;
+        Ok(Outputs { outputs })
+    }
+
+    /// Output as it arrives, for the sinks that stream it.
+    pub fn data(&mut self, stderr: bool, data: &[u8]) {
+        self.outputs.retain_mut(|(sink, output)| {
+            let written = match output {
+                Output::Terminal if stderr => {
+                    let _ = std::io::stderr().write_all(data);
+                    Ok(())
+                }
+                Output::Terminal => {
+                    crate::output::write(data);
+                    Ok(())
+                }
+                Output::File(file) => file.write_all(data),
+                Output::Tcp(stream) => stream.write_all(data),
+                Output::Webhook(_) => Ok(()),
+            };
+            kept(sink, written)
+        });
+    }
+
+    /// A complete line, for the sinks that batch them.
+    pub fn line(&mut self, stderr: bool, line: &str) {
+        for (_, output) in &mut self.outputs {
+            if let Output::Webhook(batch) = output {
+                batch.lines.push(serde_json::json!({
+                    "stream": if stderr { "stderr" } else { "stdout" },
+                    "line": line,
+                }));
+                batch.since.get_or_insert_with(Instant::now);
+            }
+        }
+        self.flush();
+    }
+
+    /// Send batches that are full or have waited long enough.
+    pub fn flush(&mut self) {
+        self.outputs.retain_mut(|(sink, output)| match output {
+            Output::Webhook(batch) => {
+                let due = batch.lines.len() >= BATCH_LINES
+                    || batch
+                        .since
+                        .is_some_and(|since| since.elapsed() >= BATCH_WAIT);
+                if due {
+                    batch.since = None;
+                    let sent = batch.tx.send(std::mem::take(&mut batch.lines));
+                    return kept(sink, sent.map_err(std::io::Error::other));
+                }
+                true
+            }
+            _ => true,
+        });
+    }
+}
+
+fn kept(sink: &Sink, written: std::io::Result<()>) -> bool {
+    match written {
+        Ok(()) => true,
+        Err(e) => {
+            log::warn!("dropping sink {:?}, {}", sink, e);
+            false
+        }
+    }
+}
+
+// posts batches in order off the monitor loop, so a slow endpoint doesn't hold up the console
+fn poster(url: String) -> mpsc::Sender<Vec<serde_json::Value>> {
+    let (tx, rx) = mpsc::channel::<Vec<serde_json::Value>>();
+    std::thread::spawn(move || {
+        for lines in rx {
+            let body = serde_json::json!({ "lines": lines }).to_string();
+            if let Err(e) = post(&url, &body) {
+                log::warn!("couldn't post {} lines to {}, {}", lines.len(), url, e);
+            }
+        }
+    });
+    tx
+}
+
+fn post(url: &str, body: &str) -> std::io::Result<()> {
+    let mut curl = Command::new("curl")
+        .args(["-sSf", "-m", "10", "-H", "Content-Type: application/json"])
+        .args(["--data-binary", "@-", url])
+        .stdin(Stdio::piped())
+        .stdout(Stdio::null())
+        .spawn()?;
+    curl.stdin
+        .take()
+        .ok_or_else(|| std::io::Error::other("curl has no stdin"))?
+        .write_all(body.as_bytes())?;
+    if curl.wait()?.success() {
+        Ok(())
+    } else {
+        Err(std::io::Error::other("curl failed"))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Read;
+    use std::net::TcpListener;
+
+    #[test]
+    fn sinks() {
+        assert_eq!("terminal".parse(), Ok(Sink::Terminal));
+        assert_eq!("file:out.log".parse(), Ok(Sink::File("out.log".into())));
+        assert_eq!(
+            "tcp:127.0.0.1:9000".parse(),
+            Ok(Sink::Tcp("127.0.0.1:9000".into()))
+        );
+        assert_eq!(
+            "https://example.com/hook".parse(),
+            Ok(Sink::Webhook("https://example.com/hook".into()))
+        );
+        assert!("file:".parse::<Sink>().is_err());
+        assert!("console".parse::<Sink>().is_err());
+
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap().to_string();
+        let mut outputs = Outputs::open(&[Sink::Tcp(address)]).unwrap();
+        let (mut accepted, _) = listener.accept().unwrap();
+        outputs.data(false, b"boot\n");
+        outputs.data(true, b"panic\n");
+        drop(outputs);
+        let mut received = String::new();
+        accepted.read_to_string(&mut received).unwrap();
+        assert_eq!(received, "boot\npanic\n");
+    }
+}
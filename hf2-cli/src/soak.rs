@@ -0,0 +1,135 @@
+use crate::exit;
+use crate::timeline::Timeline;
+use hf2::ReadWrite;
+use std::time::{Duration, Instant};
+
+/// Checks on a board under test every interval until it stops answering, printing how long each
+/// BININFO took and any new dmesg output. Misses in a row, a check that failed or didn't answer,
+/// end the soak with the error's exit code, so a notifier or CI job hears about it. A single miss
+/// is only reported, a busy board can drop one.
+pub fn soak(
+    d: &impl ReadWrite,
+    interval: Duration,
+    misses: u32,
+    timeline: &mut Timeline,
+) -> Result<(), exit::Exit> {
+    let started = Instant::now();
+    let mut logs: Option<String> = None;
+    let mut missed = 0;
+    let mut checks = 0_u64;
+
+    loop {
+        checks += 1;
+        let sent = Instant::now();
+        match hf2::bin_info(d) {
+            Ok(bininfo) => {
+                let took = sent.elapsed();
+                outln!(
+                    "{}s check {} ok {:?} in {}ms",
+                    started.elapsed().as_secs(),
+                    checks,
+                    bininfo.mode,
+                    took.as_millis()
+                );
+                timeline.mark(&format!(
+                    "soak check {} ok in {}ms",
+                    checks,
+                    took.as_millis()
+                ));
+                missed = 0;
+            }
+            Err(e) => {
+                missed += 1;
+                outln!(
+                    "{}s check {} failed, {}, {} of {} misses",
+                    started.elapsed().as_secs(),
+                    checks,
+                    e,
+                    missed,
+                    misses
+                );
+                timeline.mark(&format!("soak check {} failed, {}", checks, e));
+                if missed >= misses {
+                    return Err(exit::fail(
+                        e.category(),
+                        exit::Failure::code(&e),
+                        &format!(
+                            "device stopped answering after {}s and {} checks",
+                            started.elapsed().as_secs(),
+                            checks
+                        ),
+                    ));
+                }
+            }
+        }
+
+        // not every bootloader keeps a log
+        if let Ok(dmesg) = hf2::dmesg(d) {
+            let new = match &logs {
+                Some(old) => new_logs(old, &dmesg.logs),
+                None => "",
+            };
+            for line in new.lines().filter(|line| !line.trim().is_empty()) {
+                outln!("  dmesg: {}", line);
+                timeline.line("dmesg", line);
+            }
+            logs = Some(dmesg.logs);
+        }
+
+        std::thread::sleep(interval);
+    }
+}
+
+// what's been logged since old. The buffer may have wrapped and dropped its start, so continue
+// after the last line old ended with when new doesn't simply extend it.
+fn new_logs<'a>(old: &str, new: &'a str) -> &'a str {
+    if let Some(added) = new.strip_prefix(old) {
+        return added;
+    }
+    match old.lines().rev().find(|line| !line.trim().is_empty()) {
+        Some(last) => match new.rfind(last) {
+            Some(at) => &new[at + last.len()..],
+            None => new,
+        },
+        None => new,
+    }
+}
+
+/// Parses 500ms, 60s, 5m or 1h, a bare number being seconds.
+pub fn parse_duration(input: &str) -> Result<Duration, String> {
+    let invalid = || format!("{} isn't a duration, ie 500ms, 60s, 5m or 1h", input);
+    let split = input
+        .find(|c: char| !c.is_ascii_digit())
+        .unwrap_or(input.len());
+    let (number, unit) = input.split_at(split);
+    let number: u64 = number.parse().map_err(|_| invalid())?;
+    match unit {
+        "ms" => Ok(Duration::from_millis(number)),
+        "" | "s" => Ok(Duration::from_secs(number)),
+        "m" => Ok(Duration::from_secs(number * 60)),
+        "h" => Ok(Duration::from_secs(number * 3600)),
+        _ => Err(invalid()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn soak_logs() {
+        assert_eq!(new_logs("boot\n", "boot\nusb up\n"), "usb up\n");
+        // wrapped, the start dropped
+        assert_eq!(
+            new_logs("boot\nusb up\n", "t\nusb up\nreset\n"),
+            "\nreset\n"
+        );
+        assert_eq!(new_logs("boot\n", "boot\n"), "");
+
+        assert_eq!(parse_duration("60s"), Ok(Duration::from_secs(60)));
+        assert_eq!(parse_duration("500ms"), Ok(Duration::from_millis(500)));
+        assert_eq!(parse_duration("2m"), Ok(Duration::from_secs(120)));
+        assert_eq!(parse_duration("15"), Ok(Duration::from_secs(15)));
+        assert!(parse_duration("soon").is_err());
+    }
+}
@@ -0,0 +1,187 @@
+use crate::manifest::Manifest;
+use crate::provision::Patches;
+use crate::timeline::Timeline;
+use hf2::utils::{check_supply, flash_regions, open_unlocked, patch_image, PadPolicy, VerifyMode};
+use hf2::ReadWrite;
+use hidapi::HidApi;
+use std::path::Path;
+use std::time::Duration;
+
+const PASS: [&str; 5] = [
+    "#####    ###    #####   #####",
+    "#    #  #   #  #       #     ",
+    "#####   #####   ####    #### ",
+    "#       #   #       #       #",
+    "#       #   #  #####   ##### ",
+];
+
+const FAIL: [&str; 5] = [
+    "######   ###   #####  #     ",
+    "#       #   #    #    #     ",
+    "####    #####    #    #     ",
+    "#       #   #    #    #     ",
+    "#       #   #  #####  ######",
+];
+
+/// Programming station for people who shouldn't need to know hf2. Waits for a board, flashes the
+/// manifest, patched with the boards row of patches if given, shows a big PASS or FAIL and logs
+/// the result, then waits for that board to be unplugged and the next to arrive. Runs until killed.
+pub fn station(
+    api: &mut HidApi,
+    candidates: impl Fn(&HidApi) -> Vec<hf2::DeviceInfo>,
+    manifest: &Path,
+    patches: Option<&Patches>,
+    verify: VerifyMode,
+    timeline: &mut Timeline,
+) {
+    // serial of the board just programmed, which may still be attached running its new app
+    let mut done: Option<Option<String>> = None;
+    let (mut passed, mut failed) = (0, 0);
+
+    loop {
+        let stability = hf2::stability();
+        outln!(
+            "\nwaiting for a board ({} passed, {} failed, link {}%)",
+            passed,
+            failed,
+            stability.score()
+        );
+        if let Some(suspect) = stability.suspect() {
+            outln!("{}", suspect);
+        }
+        let (serial, board) = next_board(api, &candidates, &mut done);
+
+        let label = crate::nicknames::label(&board[0]);
+        outln!("programming {}", label);
+
+        let result = match open_unlocked(api, &board, false) {
+            Ok((d, _lock)) => program(
+                &crate::connect(d),
+                serial.as_deref(),
+                manifest,
+                patches,
+                verify,
+            ),
+            Err(e) => Err(format!("couldn't open the board {:?}", e)),
+        };
+
+        match result {
+            Ok(()) => {
+                passed += 1;
+                banner(&PASS, "32");
+                timeline.mark(&format!("station {} PASS", label));
+            }
+            Err(reason) => {
+                failed += 1;
+                banner(&FAIL, "31");
+                outln!("{}", reason);
+                timeline.mark(&format!("station {} FAIL {}", label, reason));
+            }
+        }
+        done = Some(serial);
+    }
+}
+
+// blocks until a board other than the one just done is attached
+fn next_board(
+    api: &mut HidApi,
+    candidates: &impl Fn(&HidApi) -> Vec<hf2::DeviceInfo>,
+    done: &mut Option<Option<String>>,
+) -> (Option<String>, Vec<hf2::DeviceInfo>) {
+    loop {
+        let _ = api.refresh_devices();
+        let found = candidates(api);
+
+        if let Some(serial) = done {
+            if !found.iter().any(|info| info.serial_number == *serial) {
+                *done = None;
+            }
+        }
+
+        if let Some(first) = found
+            .iter()
+            .find(|info| done.as_ref() != Some(&info.serial_number))
+        {
+            let serial = first.serial_number.clone();
+            let board = found
+                .iter()
+                .filter(|info| info.serial_number == serial)
+                .cloned()
+                .collect();
+            // let a freshly plugged board finish enumerating
+            std::thread::sleep(Duration::from_millis(500));
+            return (serial, board);
+        }
+
+        std::thread::sleep(Duration::from_millis(250));
+    }
+}
+
+fn program(
+    d: &impl ReadWrite,
+    serial: Option<&str>,
+    manifest_path: &Path,
+    patches: Option<&Patches>,
+    verify: VerifyMode,
+) -> Result<(), String> {
+    let manifest = Manifest::load(manifest_path)?;
+    let bininfo = hf2::bin_info(d).map_err(|e| format!("bininfo failed, {}", e))?;
+
+    // only a board that arrives running its app has a supply reading
+    if let Some(supply) = &manifest.supply {
+        match check_supply(&supply.check(), &bininfo, d) {
+            Ok(Some(low)) if !supply.warn_only => return Err(low.to_string()),
+            Ok(Some(low)) => outln!("warning: {}", low),
+            Ok(None) => (),
+            Err(e) if supply.warn_only => outln!("warning: couldn't check the supply, {:?}", e),
+            Err(e) => return Err(format!("couldn't read supply {:?}", e)),
+        }
+    }
+
+    let mut regions = manifest.regions(manifest_path, &bininfo)?;
+
+    if let Some(patches) = patches {
+        let serial = serial.ok_or("board has no serial to look up patches with")?;
+        let row = patches
+            .rows
+            .get(serial)
+            .ok_or_else(|| format!("no patches for {}", serial))?;
+        for (address, data) in patches.columns.iter().zip(row) {
+            let data = match data {
+                Some(data) => data,
+                None => continue,
+            };
+            let region = regions
+                .iter_mut()
+                .find(|region| {
+                    let end = region.address as u64 + region.binary.len() as u64;
+                    region.address <= *address && *address as u64 + data.len() as u64 <= end
+                })
+                .ok_or_else(|| format!("patch at {:#x} isn't inside any region", address))?;
+            patch_image(&mut region.binary, region.address, *address, data)
+                .map_err(|e| format!("{:?}", e))?;
+        }
+    }
+
+    flash_regions(
+        &regions,
+        manifest.marker,
+        PadPolicy::Zero,
+        verify,
+        &bininfo,
+        d,
+    )
+    .map_err(|e| format!("flash failed {:?}", e))
+}
+
+fn banner(lines: &[&str], color: &str) {
+    outln!();
+    for line in lines {
+        if crate::output::color() {
+            outln!("\x1b[1;{}m    {}\x1b[0m", color, line);
+        } else {
+            outln!("    {}", line);
+        }
+    }
+    outln!();
+}
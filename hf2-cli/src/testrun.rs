@@ -0,0 +1,155 @@
+use crate::exit;
+use crate::monitor::split_lines;
+use crate::timeline::Timeline;
+use hf2::utils::open_unlocked;
+use hf2::{ErrorCategory, RunOutput, SerialOutput};
+use hidapi::HidApi;
+use std::io::Write;
+use std::str::FromStr;
+use std::time::{Duration, Instant};
+
+// a freshly flashed app enumerates in well under this
+const APP_TIMEOUT: Duration = Duration::from_secs(5);
+const APP_POLL: Duration = Duration::from_millis(250);
+
+/// Output lines that end hf2 test, alternatives split on |, ie PASS|FAIL. A line containing the
+/// first passes, one containing any other fails.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Until(Vec<String>);
+
+impl FromStr for Until {
+    type Err = String;
+
+    fn from_str(input: &str) -> Result<Self, Self::Err> {
+        let alternatives: Vec<String> = input.split('|').map(str::to_string).collect();
+        if alternatives.iter().any(String::is_empty) {
+            return Err(format!("{} has an empty alternative, ie PASS|FAIL", input));
+        }
+        Ok(Until(alternatives))
+    }
+}
+
+impl Until {
+    /// Whether line ends the run, and if so whether it passed.
+    fn outcome(&self, line: &str) -> Option<bool> {
+        self.0
+            .iter()
+            .position(|alternative| line.contains(alternative.as_str()))
+            .map(|matched| matched == 0)
+    }
+}
+
+/// Waits for the freshly flashed app to come back as an hf2 device, it may not keep the
+/// bootloader's vid/pid, then prints its serial output. With expect_exit the firmware's exit
+/// status event ends the run and its code becomes hf2's, otherwise the run lasts until the device
+/// goes away. With until a matching line ends it first, failing with exit::TEST_FAILED unless it
+/// matched the pass.
+pub fn attach(
+    api: &mut HidApi,
+    find: impl Fn(&HidApi) -> Vec<hf2::DeviceInfo>,
+    steal: bool,
+    exit_event: u32,
+    expect_exit: bool,
+    until: Option<&Until>,
+    timeline: &mut Timeline,
+) -> Result<(), exit::Exit> {
+    let deadline = Instant::now() + APP_TIMEOUT;
+    let (d, _lock) = loop {
+        std::thread::sleep(APP_POLL);
+        api.refresh_devices().map_err(|e| {
+            exit::fail(
+                ErrorCategory::Host,
+                exit::NO_BACKEND,
+                &format!("Couldn't find system usb: {}", e),
+            )
+        })?;
+        match open_unlocked(api, &find(api), steal) {
+            Ok(opened) => break opened,
+            Err(_) if Instant::now() < deadline => continue,
+            Err(e) => {
+                return Err(exit::fail(
+                    e.category(),
+                    exit::Failure::code(&e),
+                    "app didn't come back as an hf2 device",
+                ))
+            }
+        }
+    };
+    timeline.mark("app attached");
+    let d = crate::connect(d);
+
+    let mut stdout_line = vec![];
+    let mut stderr_line = vec![];
+    loop {
+        let mut ended = None;
+        let mut check = |line: &str| {
+            if ended.is_none() {
+                ended = until
+                    .and_then(|until| until.outcome(line).map(|pass| (pass, line.to_string())));
+            }
+        };
+        match hf2::read_run_output(&d, exit_event) {
+            Ok(Some(RunOutput::Serial(SerialOutput::StdOut(data)))) => {
+                crate::output::write(&data);
+                split_lines(&mut stdout_line, &data, &mut check);
+            }
+            Ok(Some(RunOutput::Serial(SerialOutput::StdErr(data)))) => {
+                let _ = std::io::stderr().write_all(&data);
+                split_lines(&mut stderr_line, &data, &mut check);
+            }
+            Ok(Some(RunOutput::Exit(code))) if expect_exit => {
+                timeline.mark(&format!("firmware exited with {}", code));
+                if code != 0 {
+                    eprintln!("firmware exited with {}", code);
+                    return Err(exit::Exit { code });
+                }
+                return Ok(());
+            }
+            Ok(Some(RunOutput::Exit(code))) => {
+                log::info!("firmware exited with {}, not expecting an exit", code)
+            }
+            Ok(None) => (),
+            Err(e) if expect_exit => {
+                return Err(exit::fail(
+                    e.category(),
+                    exit::Failure::code(&e),
+                    &format!("device went away before reporting an exit status: {}", e),
+                ))
+            }
+            Err(e) => {
+                log::debug!("read failed {:?}", e);
+                eprintln!("device disconnected");
+                return Ok(());
+            }
+        }
+
+        match ended {
+            Some((true, line)) => {
+                timeline.mark(&format!("passed on {}", line));
+                return Ok(());
+            }
+            Some((false, line)) => {
+                timeline.mark(&format!("failed on {}", line));
+                eprintln!("failed on {}", line);
+                return Err(exit::Exit {
+                    code: exit::TEST_FAILED,
+                });
+            }
+            None => (),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn until() {
+        let until: Until = "PASS|FAIL".parse().unwrap();
+        assert_eq!(until.outcome("test blink ... PASS"), Some(true));
+        assert_eq!(until.outcome("FAIL: led stuck"), Some(false));
+        assert_eq!(until.outcome("running 3 tests"), None);
+        assert!("PASS|".parse::<Until>().is_err());
+    }
+}
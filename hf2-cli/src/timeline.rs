@@ -0,0 +1,44 @@
+use std::fs::{File, OpenOptions};
+use std::io::Write;
+use std::path::PathBuf;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Session log shared by every hf2 invocation pointed at the same file. Lines are appended with a
+/// wall clock timestamp so device output from `monitor` and host side markers like flashes,
+/// resets and writes from other invocations merge into a single timeline. Each line goes out in
+/// one append so lines from concurrent invocations don't interleave.
+pub struct Timeline {
+    file: Option<File>,
+}
+
+impl Timeline {
+    pub fn open(path: Option<PathBuf>) -> std::io::Result<Self> {
+        let file = path
+            .map(|path| OpenOptions::new().create(true).append(true).open(path))
+            .transpose()?;
+        Ok(Self { file })
+    }
+
+    /// Record something the host did.
+    pub fn mark(&mut self, event: &str) {
+        self.line("host", event);
+    }
+
+    /// Record a line of output from the device, source being stdout or stderr.
+    pub fn line(&mut self, source: &str, line: &str) {
+        if let Some(file) = &mut self.file {
+            let now = SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .unwrap_or_default();
+            let line = format!(
+                "{}.{:03} {}: {}\n",
+                now.as_secs(),
+                now.subsec_millis(),
+                source,
+                line
+            );
+            // a log that can't be written shouldn't abort a flash
+            let _ = file.write_all(line.as_bytes());
+        }
+    }
+}
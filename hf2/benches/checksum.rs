@@ -0,0 +1,37 @@
+//! Page checksum throughput, `cargo bench -p hf2`. Verify computes one per flash page, so on a
+//! small host this should run far faster than usb can deliver checksums from the device.
+
+use hf2::utils::{Crc16, PageChecksum};
+use std::hint::black_box;
+use std::time::Instant;
+
+const IMAGE_SIZE: usize = 4 * 1024 * 1024;
+const ROUNDS: usize = 10;
+
+fn bench(name: &str, page_size: usize, checksum: impl Fn(&[u8]) -> u16) {
+    let image: Vec<u8> = (0..IMAGE_SIZE).map(|i| (i * 31 % 251) as u8).collect();
+
+    let start = Instant::now();
+    for _ in 0..ROUNDS {
+        for page in image.chunks(page_size) {
+            black_box(checksum(black_box(page)));
+        }
+    }
+    let elapsed = start.elapsed();
+
+    let mb = (IMAGE_SIZE * ROUNDS) as f64 / (1024.0 * 1024.0);
+    println!(
+        "{:<24} {:>5} byte pages {:>8.1} MB/s",
+        name,
+        page_size,
+        mb / elapsed.as_secs_f64()
+    );
+}
+
+fn main() {
+    for page_size in [256, 4096] {
+        for crc in [Crc16::Ccitt, Crc16::CcittFalse, Crc16::Kermit, Crc16::Arc] {
+            bench(&format!("{:?}", crc), page_size, |page| crc.checksum(page));
+        }
+    }
+}
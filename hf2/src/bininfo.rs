@@ -1,9 +1,12 @@
-use crate::command::{rx, xmit, Command, CommandResponse, CommandResponseStatus};
-use crate::Error;
+use crate::command::{xfer, Command};
+use crate::limits::derive_response_limits;
+use crate::{Error, ErrorKind, ReadWrite};
 use core::convert::TryFrom;
+use core::ops::Range;
 use scroll::{ctx, Pread, LE};
+use std::time::Duration;
 
-#[derive(Debug, PartialEq)]
+#[derive(Debug, Clone, Copy, PartialEq)]
 pub enum BinInfoMode {
     //bootloader, and thus flashing of user-space programs is allowed
     Bootloader = 0x0001,
@@ -18,36 +21,96 @@ impl TryFrom<u32> for BinInfoMode {
         match value {
             1 => Ok(BinInfoMode::Bootloader),
             2 => Ok(BinInfoMode::User),
-            _ => Err(Error::Parse),
+            _ => Err(ErrorKind::Parse.into()),
         }
     }
 }
 
 /// This command states the current mode of the device:
-pub fn bin_info(d: &hidapi::HidDevice) -> Result<BinInfoResponse, Error> {
-    xmit(Command::new(0x0001, 0, vec![]), d)?;
-
-    match rx(d) {
-        Ok(CommandResponse {
-            status: CommandResponseStatus::Success,
-            data,
-            ..
-        }) => (data.as_slice()).pread_with(0, LE),
-        Ok(_) => Err(Error::CommandNotRecognized),
-        Err(e) => Err(e),
-    }
+pub fn bin_info(d: &impl ReadWrite) -> Result<BinInfoResponse, Error> {
+    let rsp = xfer(Command::new(0x0001, vec![]), d)?;
+    let bininfo: BinInfoResponse = (rsp.data.as_slice())
+        .pread_with(0, LE)
+        .map_err(|e: Error| e.during(0x0001))?;
+    derive_response_limits(d, bininfo.max_message_size);
+    Ok(bininfo)
 }
 
 ///Response to the bin_info command
-#[derive(Debug, PartialEq)]
+#[derive(Debug, Clone, PartialEq)]
 pub struct BinInfoResponse {
     pub mode: BinInfoMode, //    uint32_t mode;
-    pub flash_page_size: u32,
-    pub flash_num_pages: u32,
+    pub geometry: FlashGeometry,
     pub max_message_size: u32,
     pub family_id: Option<FamilyId>,
 }
 
+impl BinInfoResponse {
+    ///Most pages one checksum_pages command may cover, max_message_size / 2 - 2.
+    pub fn max_checksum_pages(&self) -> u32 {
+        max_checksum_pages(self.max_message_size)
+    }
+}
+
+pub(crate) fn max_checksum_pages(max_message_size: u32) -> u32 {
+    (max_message_size / 2).saturating_sub(2).max(1)
+}
+
+///Flash page size and count, flashing and checksums work on whole pages.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct FlashGeometry {
+    pub page_size: u32,
+    pub num_pages: u32,
+}
+
+impl FlashGeometry {
+    ///Bytes of flash, as u64 so a bogus page count can't overflow.
+    pub fn total_size(&self) -> u64 {
+        self.page_size as u64 * self.num_pages as u64
+    }
+
+    ///Index of the page holding address.
+    pub fn page_of(&self, address: u32) -> u32 {
+        address / self.page_size
+    }
+
+    ///Indexes of the pages touched by len bytes at address.
+    pub fn page_range(&self, address: u32, len: usize) -> Range<u32> {
+        let end = address as u64 + len as u64;
+        let last = end.div_ceil(self.page_size as u64) as u32;
+        self.page_of(address)..last
+    }
+
+    ///Address of the start of page.
+    pub fn page_address(&self, page: u32) -> u32 {
+        page * self.page_size
+    }
+
+    ///Pages needed to hold len bytes.
+    pub fn pages_for(&self, len: usize) -> u32 {
+        len.div_ceil(self.page_size as usize) as u32
+    }
+
+    pub fn is_aligned(&self, address: u32) -> bool {
+        address.is_multiple_of(self.page_size)
+    }
+
+    ///Start of the page holding address.
+    pub fn align_down(&self, address: u32) -> u32 {
+        address - address % self.page_size
+    }
+
+    ///Start of the first page at or after address.
+    pub fn align_up(&self, address: u32) -> u32 {
+        address.div_ceil(self.page_size) * self.page_size
+    }
+
+    ///Whether len bytes at address fit in flash.
+    pub fn contains(&self, address: u32, len: u64) -> bool {
+        address as u64 + len <= self.total_size()
+    }
+}
+
 #[allow(non_camel_case_types)]
 #[derive(Debug, Copy, Clone, PartialEq)]
 pub enum FamilyId {
@@ -76,11 +139,175 @@ impl From<u32> for FamilyId {
     }
 }
 
+impl From<FamilyId> for u32 {
+    fn from(family: FamilyId) -> Self {
+        match family {
+            FamilyId::ATSAMD21 => 0x68ed_2b88,
+            FamilyId::ATSAMD51 => 0x5511_4460,
+            FamilyId::NRF52840 => 0x1b57_745f,
+            FamilyId::STM32F103 => 0x5ee2_1072,
+            FamilyId::STM32F401 => 0x5775_5a57,
+            FamilyId::ATMEGA32 => 0x1657_3617,
+            FamilyId::CYPRESS_FX2 => 0x5a18_069b,
+            FamilyId::UNKNOWN(val) => val,
+        }
+    }
+}
+
+impl FamilyId {
+    ///RAM of the smallest part in the family that ships with a UF2 bootloader, ie the SAMD21G18 and
+    ///STM32F103C8. None for families we know nothing about.
+    pub fn ram_size(&self) -> Option<u32> {
+        match self {
+            FamilyId::ATSAMD21 => Some(32 * 1024),
+            FamilyId::ATSAMD51 => Some(192 * 1024),
+            FamilyId::NRF52840 => Some(256 * 1024),
+            FamilyId::STM32F103 => Some(20 * 1024),
+            FamilyId::STM32F401 => Some(64 * 1024),
+            FamilyId::ATMEGA32 => Some(2 * 1024),
+            FamilyId::CYPRESS_FX2 | FamilyId::UNKNOWN(_) => None,
+        }
+    }
+
+    ///How long to wait for the bootloader to enumerate after handing over from the app. nRF52
+    ///bootloaders bring up the SoftDevice and check settings before usb comes up, so take longest.
+    pub fn handover_timeout(&self) -> Duration {
+        match self {
+            FamilyId::NRF52840 => Duration::from_secs(10),
+            FamilyId::ATSAMD21 | FamilyId::ATSAMD51 => Duration::from_secs(3),
+            _ => DEFAULT_HANDOVER_TIMEOUT,
+        }
+    }
+
+    ///Where the family keeps flash, RAM, peripherals and configuration rows, sized for its largest
+    ///part, with bootloaders and SoftDevices split out of flash where their place is fixed. Empty
+    ///for families we know nothing about.
+    pub fn memory_map(&self) -> &'static [MemoryRegion] {
+        match self {
+            FamilyId::ATSAMD21 => ATSAMD21_MAP,
+            FamilyId::ATSAMD51 => ATSAMD51_MAP,
+            FamilyId::NRF52840 => NRF52840_MAP,
+            FamilyId::STM32F103 => STM32F103_MAP,
+            FamilyId::STM32F401 => STM32F401_MAP,
+            FamilyId::ATMEGA32 | FamilyId::CYPRESS_FX2 | FamilyId::UNKNOWN(_) => &[],
+        }
+    }
+
+    ///The region of memory_map holding address.
+    pub fn region(&self, address: u32) -> Option<&'static MemoryRegion> {
+        self.memory_map()
+            .iter()
+            .find(|region| region.contains(address))
+    }
+}
+
+///A span of a family's address space, see FamilyId::memory_map.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct MemoryRegion {
+    pub name: &'static str,
+    pub start: u32,
+    pub size: u32,
+}
+
+impl MemoryRegion {
+    const fn new(name: &'static str, start: u32, size: u32) -> Self {
+        MemoryRegion { name, start, size }
+    }
+
+    pub fn contains(&self, address: u32) -> bool {
+        address.wrapping_sub(self.start) < self.size
+    }
+}
+
+const ATSAMD21_MAP: &[MemoryRegion] = &[
+    MemoryRegion::new("bootloader", 0x0000_0000, 0x2000),
+    MemoryRegion::new("flash", 0x0000_2000, 0x0003_E000),
+    MemoryRegion::new("user row", 0x0080_4000, 0x100),
+    MemoryRegion::new("calibration", 0x0080_6000, 0x4100),
+    MemoryRegion::new("ram", 0x2000_0000, 0x8000),
+    MemoryRegion::new("peripheral", 0x4000_0000, 0x0300_0000),
+    MemoryRegion::new("iobus", 0x6000_0000, 0x200),
+    CORTEX_M_SYSTEM,
+];
+
+const ATSAMD51_MAP: &[MemoryRegion] = &[
+    MemoryRegion::new("bootloader", 0x0000_0000, 0x4000),
+    MemoryRegion::new("flash", 0x0000_4000, 0x000F_C000),
+    MemoryRegion::new("user row", 0x0080_4000, 0x200),
+    MemoryRegion::new("calibration", 0x0080_6000, 0x4100),
+    MemoryRegion::new("ram", 0x2000_0000, 0x0004_0000),
+    MemoryRegion::new("peripheral", 0x4000_0000, 0x0400_0000),
+    CORTEX_M_SYSTEM,
+];
+
+const NRF52840_MAP: &[MemoryRegion] = &[
+    MemoryRegion::new("softdevice", 0x0000_0000, 0x0002_6000),
+    MemoryRegion::new("flash", 0x0002_6000, 0x000C_E000),
+    MemoryRegion::new("bootloader", 0x000F_4000, 0xC000),
+    MemoryRegion::new("ficr", 0x1000_0000, 0x1000),
+    MemoryRegion::new("uicr", 0x1000_1000, 0x1000),
+    MemoryRegion::new("ram", 0x2000_0000, 0x0004_0000),
+    MemoryRegion::new("peripheral", 0x4000_0000, 0x1000_1000),
+    CORTEX_M_SYSTEM,
+];
+
+const STM32F103_MAP: &[MemoryRegion] = &[
+    MemoryRegion::new("flash", 0x0800_0000, 0x0008_0000),
+    MemoryRegion::new("system memory", 0x1FFF_F000, 0x800),
+    MemoryRegion::new("option bytes", 0x1FFF_F800, 0x10),
+    MemoryRegion::new("ram", 0x2000_0000, 0x0001_0000),
+    MemoryRegion::new("peripheral", 0x4000_0000, 0x2000_0000),
+    CORTEX_M_SYSTEM,
+];
+
+const STM32F401_MAP: &[MemoryRegion] = &[
+    MemoryRegion::new("flash", 0x0800_0000, 0x0008_0000),
+    MemoryRegion::new("system memory", 0x1FFF_0000, 0x7800),
+    MemoryRegion::new("otp", 0x1FFF_7800, 0x210),
+    MemoryRegion::new("option bytes", 0x1FFF_C000, 0x10),
+    MemoryRegion::new("ram", 0x2000_0000, 0x0001_8000),
+    MemoryRegion::new("peripheral", 0x4000_0000, 0x2000_0000),
+    CORTEX_M_SYSTEM,
+];
+
+// the NVIC, SysTick and debug registers every Cortex-M has
+const CORTEX_M_SYSTEM: MemoryRegion = MemoryRegion::new("system", 0xE000_0000, 0x0010_0000);
+
+///Handover timeout for devices that don't say their family.
+pub const DEFAULT_HANDOVER_TIMEOUT: Duration = Duration::from_secs(5);
+
+///Parses a family by name, ie samd21 or nrf52840, or by its UF2 family id in hex.
+impl std::str::FromStr for FamilyId {
+    type Err = String;
+
+    fn from_str(input: &str) -> Result<Self, Self::Err> {
+        match input.to_lowercase().as_str() {
+            "samd21" | "atsamd21" => Ok(FamilyId::ATSAMD21),
+            "samd51" | "atsamd51" => Ok(FamilyId::ATSAMD51),
+            "nrf52840" => Ok(FamilyId::NRF52840),
+            "stm32f103" => Ok(FamilyId::STM32F103),
+            "stm32f401" => Ok(FamilyId::STM32F401),
+            "atmega32" => Ok(FamilyId::ATMEGA32),
+            "fx2" | "cypress_fx2" => Ok(FamilyId::CYPRESS_FX2),
+            other => other
+                .strip_prefix("0x")
+                .and_then(|hex| u32::from_str_radix(hex, 16).ok())
+                .map(FamilyId::from)
+                .ok_or_else(|| {
+                    format!(
+                        "unknown family {}, use a name like samd21 or a 0x family id",
+                        input
+                    )
+                }),
+        }
+    }
+}
+
 impl<'a> ctx::TryFromCtx<'a, scroll::Endian> for BinInfoResponse {
     type Error = Error;
     fn try_from_ctx(this: &'a [u8], le: scroll::Endian) -> Result<(Self, usize), Self::Error> {
         if this.len() < 16 {
-            return Err(Error::Parse);
+            return Err(ErrorKind::Parse.into());
         }
 
         //does it give me offset somehow??? or just slice appropriately for me?s
@@ -88,8 +315,12 @@ impl<'a> ctx::TryFromCtx<'a, scroll::Endian> for BinInfoResponse {
         let mode: u32 = this.gread_with::<u32>(&mut offset, le)?;
         let mode: Result<BinInfoMode, Error> = BinInfoMode::try_from(mode);
         let mode: BinInfoMode = mode?;
-        let flash_page_size = this.gread_with::<u32>(&mut offset, le)?;
-        let flash_num_pages = this.gread_with::<u32>(&mut offset, le)?;
+        let page_size = this.gread_with::<u32>(&mut offset, le)?;
+        let num_pages = this.gread_with::<u32>(&mut offset, le)?;
+        // everything divides by the page size
+        if page_size == 0 {
+            return Err(ErrorKind::Parse.into());
+        }
         let max_message_size = this.gread_with::<u32>(&mut offset, le)?;
 
         let family_id = if this.len() >= 20 {
@@ -102,8 +333,10 @@ impl<'a> ctx::TryFromCtx<'a, scroll::Endian> for BinInfoResponse {
         Ok((
             BinInfoResponse {
                 mode,
-                flash_page_size,
-                flash_num_pages,
+                geometry: FlashGeometry {
+                    page_size,
+                    num_pages,
+                },
                 max_message_size,
                 family_id,
             },
@@ -111,3 +344,57 @@ impl<'a> ctx::TryFromCtx<'a, scroll::Endian> for BinInfoResponse {
         ))
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn geometry() {
+        let geometry = FlashGeometry {
+            page_size: 512,
+            num_pages: 1024,
+        };
+        assert_eq!(geometry.total_size(), 512 * 1024);
+        assert_eq!(geometry.page_of(0x4000), 32);
+        assert_eq!(geometry.page_of(0x41FF), 32);
+        assert_eq!(geometry.page_range(0x4000, 513), 32..34);
+        assert_eq!(geometry.page_range(0x4100, 256), 32..33);
+        assert_eq!(geometry.page_range(0x4000, 0), 32..32);
+        assert_eq!(geometry.pages_for(1000), 2);
+        assert!(geometry.is_aligned(0x4000) && !geometry.is_aligned(0x4100));
+        assert_eq!(geometry.align_down(0x41FF), 0x4000);
+        assert_eq!(geometry.align_up(0x4001), 0x4200);
+        assert!(geometry.contains(0x7_FE00, 0x200));
+        assert!(!geometry.contains(0x7_FE00, 0x201));
+
+        let bininfo = BinInfoResponse {
+            mode: BinInfoMode::Bootloader,
+            geometry,
+            max_message_size: 576,
+            family_id: None,
+        };
+        assert_eq!(bininfo.max_checksum_pages(), 286);
+    }
+
+    #[test]
+    fn family_names() {
+        assert_eq!("SAMD51".parse(), Ok(FamilyId::ATSAMD51));
+        assert_eq!("0x1b57745f".parse(), Ok(FamilyId::NRF52840));
+        assert_eq!("0x1234".parse(), Ok(FamilyId::UNKNOWN(0x1234)));
+        assert!("esp32".parse::<FamilyId>().is_err());
+        assert_eq!(FamilyId::STM32F103.ram_size(), Some(20 * 1024));
+        assert_eq!(FamilyId::ATSAMD21.region(0x2000).unwrap().name, "flash");
+        assert_eq!(
+            FamilyId::ATSAMD21.region(0x0080_40FF).unwrap().name,
+            "user row"
+        );
+        assert_eq!(
+            FamilyId::NRF52840.region(0xE000_E100).unwrap().name,
+            "system"
+        );
+        assert_eq!(FamilyId::NRF52840.region(0x3000_0000), None);
+        assert_eq!(FamilyId::CYPRESS_FX2.region(0), None);
+        assert_eq!(u32::from(FamilyId::from(0x5511_4460)), 0x5511_4460);
+    }
+}
@@ -0,0 +1,133 @@
+use crate::command::{rx, xmit, Command};
+use crate::{Error, ErrorKind, ReadWrite};
+use serialport::{SerialPort, SerialPortType};
+use std::cell::RefCell;
+use std::io::{self, Read, Write};
+use std::time::Duration;
+
+// once a packet's header arrived the rest is already on its way
+const PACKET_TIMEOUT: Duration = Duration::from_millis(1000);
+
+///A board speaking HF2 over a serial port, usually USB CDC, rather than raw HID. A serial stream has
+///no reports to delimit packets, so each is sent as its header byte then the payload the header's
+///length gives, without the report id and padding of a HID report.
+pub struct SerialDevice {
+    port: RefCell<Box<dyn SerialPort>>,
+}
+
+impl SerialDevice {
+    ///Open a serial port, ie /dev/ttyACM0 or COM3. CDC ignores the baud rate.
+    pub fn open(path: &str) -> Result<Self, Error> {
+        let port = serialport::new(path, 115_200)
+            .timeout(PACKET_TIMEOUT)
+            .open()
+            .map_err(transmission)?;
+        Ok(SerialDevice {
+            port: RefCell::new(port),
+        })
+    }
+
+    ///Open the first serial port that answers a BININFO within timeout, trying USB ports only.
+    pub fn find(timeout: Duration) -> Option<(String, Self)> {
+        serial_ports().into_iter().find_map(|path| {
+            let d = SerialDevice::open(&path).ok()?;
+            let mut command = Command::new(0x0001, vec![]);
+            command.tag = crate::tags::next_tag(&d);
+            let tag = command.tag;
+            let answered = xmit(&command, &d).is_ok()
+                && rx(&d, tag, timeout, crate::response_limits(&d)).is_ok();
+            answered.then_some((path, d))
+        })
+    }
+}
+
+///Serial ports that look like USB devices. Without udev on linux the port type isn't known, so the
+///usual names of USB serial ports are taken instead.
+pub fn serial_ports() -> Vec<String> {
+    serialport::available_ports()
+        .unwrap_or_default()
+        .into_iter()
+        .filter(|port| match &port.port_type {
+            SerialPortType::UsbPort(_) => true,
+            SerialPortType::Unknown => ["ttyACM", "ttyUSB", "cu.usbmodem"]
+                .iter()
+                .any(|name| port.port_name.contains(name)),
+            _ => false,
+        })
+        .map(|port| port.port_name)
+        .collect()
+}
+
+impl ReadWrite for SerialDevice {
+    fn hf2_write(&self, data: &[u8]) -> Result<usize, Error> {
+        write_packet(&mut *self.port.borrow_mut(), data).map_err(transmission)?;
+        Ok(data.len())
+    }
+    fn hf2_read(&self, buf: &mut [u8]) -> Result<usize, Error> {
+        self.hf2_read_timeout(buf, PACKET_TIMEOUT)
+    }
+    fn hf2_read_timeout(&self, buf: &mut [u8], timeout: Duration) -> Result<usize, Error> {
+        let mut port = self.port.borrow_mut();
+        port.set_timeout(timeout).map_err(transmission)?;
+        let mut header = [0_u8];
+        match port.read(&mut header) {
+            Ok(0) => return Err(ErrorKind::Transmission.into()),
+            Ok(_) => {}
+            Err(e) if e.kind() == io::ErrorKind::TimedOut => return Ok(0),
+            Err(e) => return Err(transmission(e)),
+        }
+        port.set_timeout(PACKET_TIMEOUT).map_err(transmission)?;
+        read_payload(&mut *port, header[0], buf).map_err(transmission)
+    }
+}
+
+// a HID report, report id then the packet, sent as just the packet
+fn write_packet(port: &mut impl Write, report: &[u8]) -> io::Result<()> {
+    let header = *report
+        .get(1)
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "no packet header"))?;
+    let len = 1 + (header & 0x3F) as usize;
+    let packet = report.get(1..1 + len).ok_or_else(|| {
+        io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "packet shorter than its header says",
+        )
+    })?;
+    port.write_all(packet)?;
+    port.flush()
+}
+
+// the rest of a packet after its header, into buf as a HID report reads, header first
+fn read_payload(port: &mut impl Read, header: u8, buf: &mut [u8]) -> io::Result<usize> {
+    let len = (header & 0x3F) as usize;
+    let mut packet = [0_u8; 64];
+    packet[0] = header;
+    port.read_exact(&mut packet[1..1 + len])?;
+    let count = (1 + len).min(buf.len());
+    buf[..count].copy_from_slice(&packet[..count]);
+    Ok(count)
+}
+
+fn transmission(e: impl std::error::Error + Send + Sync + 'static) -> Error {
+    Error::caused_by(ErrorKind::Transmission, e)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn framing() {
+        // report id, final packet of 3 bytes, padding
+        let report = [0x00, 0x43, 1, 2, 3, 0, 0, 0];
+        let mut stream = vec![];
+        write_packet(&mut stream, &report).unwrap();
+        assert_eq!(stream, [0x43, 1, 2, 3]);
+
+        let mut buf = [0_u8; 64];
+        let count = read_payload(&mut &stream[1..], stream[0], &mut buf).unwrap();
+        assert_eq!(&buf[..count], &[0x43, 1, 2, 3]);
+
+        assert!(write_packet(&mut vec![], &[0x00, 0x45, 1]).is_err());
+    }
+}
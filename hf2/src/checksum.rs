@@ -0,0 +1,136 @@
+// a byte at a time through a 256 entry table, fast enough that verifying megabyte images is bound
+// by usb rather than small hosts cpus
+static CCITT_TABLE: [u16; 256] = table(0x1021);
+static KERMIT_TABLE: [u16; 256] = reflected_table(0x8408);
+static ARC_TABLE: [u16; 256] = reflected_table(0xA001);
+
+const fn table(poly: u16) -> [u16; 256] {
+    let mut table = [0; 256];
+    let mut i = 0;
+    while i < 256 {
+        let mut crc = (i as u16) << 8;
+        let mut bit = 0;
+        while bit < 8 {
+            crc = if crc & 0x8000 != 0 {
+                crc << 1 ^ poly
+            } else {
+                crc << 1
+            };
+            bit += 1;
+        }
+        table[i] = crc;
+        i += 1;
+    }
+    table
+}
+
+const fn reflected_table(poly: u16) -> [u16; 256] {
+    let mut table = [0; 256];
+    let mut i = 0;
+    while i < 256 {
+        let mut crc = i as u16;
+        let mut bit = 0;
+        while bit < 8 {
+            crc = if crc & 1 != 0 {
+                crc >> 1 ^ poly
+            } else {
+                crc >> 1
+            };
+            bit += 1;
+        }
+        table[i] = crc;
+        i += 1;
+    }
+    table
+}
+
+fn digest(table: &[u16; 256], init: u16, data: &[u8]) -> u16 {
+    data.iter().fold(init, |crc, byte| {
+        crc << 8 ^ table[((crc >> 8) as u8 ^ byte) as usize]
+    })
+}
+
+fn reflected_digest(table: &[u16; 256], init: u16, data: &[u8]) -> u16 {
+    data.iter().fold(init, |crc, byte| {
+        crc >> 8 ^ table[(crc as u8 ^ byte) as usize]
+    })
+}
+
+///Checksums a flash page the same way the device answers the checksum command, so verifying
+///doesn't need to read pages back.
+pub trait PageChecksum: Send {
+    fn checksum(&self, page: &[u8]) -> u16;
+}
+
+impl<F: Fn(&[u8]) -> u16 + Send> PageChecksum for F {
+    fn checksum(&self, page: &[u8]) -> u16 {
+        self(page)
+    }
+}
+
+///CRC-16 variants, the spec's CRC-16-CCITT and ones vendor forks of the bootloaders use instead.
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum Crc16 {
+    ///CRC-16-CCITT with a zero initial value, also called XMODEM. What the HF2 spec asks for.
+    #[default]
+    Ccitt,
+    ///CRC-16-CCITT starting from 0xFFFF.
+    CcittFalse,
+    ///Reflected CRC-16-CCITT.
+    Kermit,
+    ///Also called CRC-16/IBM.
+    Arc,
+}
+
+impl Crc16 {
+    ///CRC of data, table driven.
+    pub fn compute(&self, data: &[u8]) -> u16 {
+        match self {
+            Crc16::Ccitt => digest(&CCITT_TABLE, 0, data),
+            Crc16::CcittFalse => digest(&CCITT_TABLE, 0xFFFF, data),
+            Crc16::Kermit => reflected_digest(&KERMIT_TABLE, 0, data),
+            Crc16::Arc => reflected_digest(&ARC_TABLE, 0, data),
+        }
+    }
+}
+
+impl PageChecksum for Crc16 {
+    fn checksum(&self, page: &[u8]) -> u16 {
+        self.compute(page)
+    }
+}
+
+impl std::str::FromStr for Crc16 {
+    type Err = String;
+
+    fn from_str(input: &str) -> Result<Self, Self::Err> {
+        match input {
+            "ccitt" => Ok(Crc16::Ccitt),
+            "ccitt-false" => Ok(Crc16::CcittFalse),
+            "kermit" => Ok(Crc16::Kermit),
+            "arc" => Ok(Crc16::Arc),
+            _ => Err(format!(
+                "unknown checksum {}, use ccitt, ccitt-false, kermit or arc",
+                input
+            )),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn crc16_variants() {
+        // the standard check input
+        let check = b"123456789";
+        assert_eq!(Crc16::Ccitt.checksum(check), 0x31C3);
+        assert_eq!(Crc16::CcittFalse.checksum(check), 0x29B1);
+        assert_eq!(Crc16::Kermit.checksum(check), 0x2189);
+        assert_eq!(Crc16::Arc.checksum(check), 0xBB3D);
+
+        let sum = |page: &[u8]| page.iter().map(|b| *b as u16).sum::<u16>();
+        assert_eq!(sum.checksum(&[1, 2, 3]), 6);
+    }
+}
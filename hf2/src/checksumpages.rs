@@ -1,30 +1,38 @@
-use crate::command::{rx, xmit, Command, CommandResponse, CommandResponseStatus};
-use crate::Error;
+use crate::bininfo::max_checksum_pages;
+use crate::command::{xfer, Command};
+use crate::session::state;
+use crate::{Error, ErrorKind, ReadWrite};
 use scroll::{ctx, Pread, Pwrite, LE};
 
-///Compute checksum of a number of pages. Maximum value for num_pages is max_message_size / 2 - 2. The checksum algorithm used is CRC-16-CCITT.
+///Compute checksum of a number of pages. Maximum value for num_pages is max_message_size / 2 - 2, see BinInfoResponse::max_checksum_pages, more is refused with ErrorKind::Arguments once the session has read a bininfo. The checksum algorithm used is CRC-16-CCITT.
 pub fn checksum_pages(
-    d: &hidapi::HidDevice,
+    d: &impl ReadWrite,
     target_address: u32,
     num_pages: u32,
 ) -> Result<ChecksumPagesResponse, Error> {
+    let too_many = state(d)
+        .max_message_size()
+        .is_some_and(|size| num_pages > max_checksum_pages(size));
+    if num_pages == 0 || too_many {
+        return Err(Error::new(ErrorKind::Arguments)
+            .during(0x0007)
+            .at(target_address));
+    }
+
     let mut buffer = vec![0_u8; 8];
     let mut offset = 0;
 
     buffer.gwrite_with(target_address, &mut offset, scroll::LE)?;
     buffer.gwrite_with(num_pages, &mut offset, scroll::LE)?;
 
-    xmit(Command::new(0x0007, 0, buffer), d)?;
-
-    match rx(d) {
-        Ok(CommandResponse {
-            status: CommandResponseStatus::Success,
-            data,
-            ..
-        }) => (data.as_slice()).pread_with(0, LE),
-        Ok(_) => Err(Error::CommandNotRecognized),
-        Err(e) => Err(e),
+    let failed = |e: Error| e.during(0x0007).at(target_address);
+    let rsp = xfer(Command::new(0x0007, buffer), d).map_err(failed)?;
+    let rsp: ChecksumPagesResponse = (rsp.data.as_slice()).pread_with(0, LE).map_err(failed)?;
+    // one per page asked for, anything else can't be matched up with pages
+    if rsp.checksums.len() != num_pages as usize {
+        return Err(failed(ErrorKind::Parse.into()));
     }
+    Ok(rsp)
 }
 
 ///Response to the checksum_pages command
@@ -37,7 +45,7 @@ impl<'a> ctx::TryFromCtx<'a, scroll::Endian> for ChecksumPagesResponse {
     type Error = Error;
     fn try_from_ctx(this: &'a [u8], le: scroll::Endian) -> Result<(Self, usize), Self::Error> {
         if this.len() < 2 {
-            return Err(Error::Parse);
+            return Err(ErrorKind::Parse.into());
         }
 
         let mut checksums: Vec<u16> = vec![0; this.len() / 2];
@@ -48,3 +56,28 @@ impl<'a> ctx::TryFromCtx<'a, scroll::Endian> for ChecksumPagesResponse {
         Ok((ChecksumPagesResponse { checksums }, offset))
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{FixtureDevice, SAMD21};
+    use crate::Session;
+
+    #[test]
+    fn page_limit() {
+        let d = Session::new(FixtureDevice::new(SAMD21));
+        // nothing to go by before the bininfo
+        assert!(checksum_pages(&d, 0, 200).is_ok());
+
+        let bininfo = crate::bin_info(&d).unwrap();
+        assert_eq!(bininfo.max_checksum_pages(), 158);
+        assert!(checksum_pages(&d, 0, 158).is_ok());
+        let e = checksum_pages(&d, 0, 159).unwrap_err();
+        assert_eq!(e.kind(), ErrorKind::Arguments);
+        assert_eq!(e.address(), Some(0));
+        assert_eq!(
+            checksum_pages(&d, 0, 0).unwrap_err().kind(),
+            ErrorKind::Arguments
+        );
+    }
+}
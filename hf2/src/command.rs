@@ -1,23 +1,49 @@
-use crate::{Error, ReadWrite};
+use crate::events::{record, record_retry, CommandEvent};
+use crate::inflight::in_flight;
+use crate::session::state;
+use crate::tags::{drain, is_stale, next_tag, resync};
+use crate::{
+    deadline, mutates, read_only, response_limits, retry_policy, timeout, write_pacing, Error,
+    ErrorKind, ReadWrite, ResponseLimits,
+};
 use core::convert::TryFrom;
+use std::time::{Duration, Instant, SystemTime};
 
 use scroll::{ctx, Pread, Pwrite, LE};
 
 impl From<scroll::Error> for Error {
-    fn from(_err: scroll::Error) -> Self {
-        Error::Parse
+    fn from(err: scroll::Error) -> Self {
+        Error::caused_by(ErrorKind::Parse, err)
     }
 }
 
 impl From<core::str::Utf8Error> for Error {
-    fn from(_err: core::str::Utf8Error) -> Self {
-        Error::Parse
+    fn from(err: core::str::Utf8Error) -> Self {
+        Error::caused_by(ErrorKind::Parse, err)
     }
 }
 
 impl From<std::io::Error> for Error {
-    fn from(_err: std::io::Error) -> Self {
-        Error::Arguments
+    fn from(err: std::io::Error) -> Self {
+        Error::caused_by(ErrorKind::Arguments, err)
+    }
+}
+
+///Name of a command id as the spec has it, vendor for the ids it leaves to bootloaders.
+pub(crate) fn command_name(id: u32) -> &'static str {
+    match id {
+        0x0001 => "bininfo",
+        0x0002 => "info",
+        0x0003 => "reset_into_app",
+        0x0004 => "reset_into_bootloader",
+        0x0005 => "start_flash",
+        0x0006 => "write_flash_page",
+        0x0007 => "checksum_pages",
+        0x0008 => "read_words",
+        0x0009 => "write_words",
+        0x0010 => "dmesg",
+        crate::PROTOCOL_VERSION => "protocol_version",
+        _ => "vendor",
     }
 }
 
@@ -50,13 +76,13 @@ impl TryFrom<u8> for CommandResponseStatus {
             0 => Ok(CommandResponseStatus::Success),
             1 => Ok(CommandResponseStatus::ParseError),
             2 => Ok(CommandResponseStatus::ExecutionError),
-            _ => Err(Error::Parse),
+            _ => Err(ErrorKind::Parse.into()),
         }
     }
 }
 
-#[derive(Debug, PartialEq)]
-enum PacketType {
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(crate) enum PacketType {
     //Inner packet of a command message
     Inner = 0,
     //Final packet of a command message
@@ -76,7 +102,7 @@ impl TryFrom<u8> for PacketType {
             1 => Ok(PacketType::Final),
             2 => Ok(PacketType::StdOut),
             3 => Ok(PacketType::Stderr),
-            _ => Err(Error::Parse),
+            _ => Err(ErrorKind::Parse.into()),
         }
     }
 }
@@ -87,7 +113,7 @@ impl<'a> ctx::TryFromCtx<'a, scroll::Endian> for CommandResponse {
     type Error = Error;
     fn try_from_ctx(this: &'a [u8], le: scroll::Endian) -> Result<(Self, usize), Self::Error> {
         if this.len() < 4 {
-            return Err(Error::Parse);
+            return Err(ErrorKind::Parse.into());
         }
 
         let mut offset = 0;
@@ -113,7 +139,7 @@ pub(crate) struct Command {
     ///Command ID
     id: u32,
     ///arbitrary number set by the host, for example as sequence number. The response should repeat the tag.
-    tag: u16,
+    pub(crate) tag: u16,
     ///reserved bytes in the command should be sent as zero and ignored by the device
     _reserved0: u8,
     ///reserved bytes in the command should be sent as zero and ignored by the device
@@ -122,10 +148,11 @@ pub(crate) struct Command {
     data: Vec<u8>,
 }
 impl Command {
-    pub(crate) fn new(id: u32, tag: u16, data: Vec<u8>) -> Self {
+    ///An untagged command, xfer and send tag it from the device's session.
+    pub(crate) fn new(id: u32, data: Vec<u8>) -> Self {
         Self {
             id,
-            tag,
+            tag: 0,
             _reserved0: 0,
             _reserved1: 0,
             data,
@@ -133,9 +160,11 @@ impl Command {
     }
 }
 
-///Transmit a Command, command.data should already have been LE converted
-pub(crate) fn xmit(cmd: Command, d: &impl ReadWrite) -> Result<(), Error> {
+///Transmit a Command, command.data should already have been LE converted. Every report is written
+///before anything is read, paced only if its session's write_pacing asks for it.
+pub(crate) fn xmit(cmd: &Command, d: &impl ReadWrite) -> Result<(), Error> {
     log::debug!("{:?}", cmd);
+    let pacing = write_pacing(d);
 
     //Packets are up to 64 bytes long + first byte is Report ID,
     let buffer = &mut [0_u8; 65];
@@ -174,6 +203,10 @@ pub(crate) fn xmit(cmd: Command, d: &impl ReadWrite) -> Result<(), Error> {
     for chunk in cmd.data[count..].chunks(63) {
         count += chunk.len();
 
+        if !pacing.is_zero() {
+            std::thread::sleep(pacing);
+        }
+
         if count == cmd.data.len() {
             buffer[1] = (PacketType::Final as u8) << 6 | chunk.len() as u8;
         } else {
@@ -188,24 +221,153 @@ pub(crate) fn xmit(cmd: Command, d: &impl ReadWrite) -> Result<(), Error> {
     Ok(())
 }
 
-///Receive a CommandResponse, CommandResponse.data is not interpreted in any way.
-pub(crate) fn rx(d: &impl ReadWrite) -> Result<CommandResponse, Error> {
+///Transmit a Command and receive its successful CommandResponse, waiting as long as that command id
+///is expected to take and retrying as its retry_policy says. A response with an error status is
+///returned as that error, along with its status info, and every error notes the command id.
+pub(crate) fn xfer(cmd: Command, d: &impl ReadWrite) -> Result<CommandResponse, Error> {
+    refuse_read_only(d, cmd.id)?;
+    let cmd = Command {
+        tag: next_tag(d),
+        ..cmd
+    };
+    let policy = retry_policy(d, cmd.id);
+    let mut attempt = 1;
+    loop {
+        match exchange(&cmd, d) {
+            Err(e)
+                if attempt <= policy.retries
+                    && matches!(e.kind(), ErrorKind::Timeout | ErrorKind::Parse) =>
+            {
+                log::debug!("attempt {} failed, {}", attempt, e);
+                std::thread::sleep(policy.delay(attempt));
+                record_retry();
+                attempt += 1;
+            }
+            response => {
+                state(d).set_attempts(attempt);
+                return response;
+            }
+        }
+    }
+}
+
+// one attempt at xfer, recorded as its own event
+fn exchange(cmd: &Command, d: &impl ReadWrite) -> Result<CommandResponse, Error> {
+    let timeout = timeout(d, cmd.id);
+    let (id, tag, sent) = (cmd.id, cmd.tag, cmd.data.len());
+    let _slot = in_flight();
+    // the safe point to stop at, between commands
+    if deadline(d).is_some_and(|deadline| Instant::now() >= deadline) {
+        return Err(Error::new(ErrorKind::Deadline).during(id));
+    }
+    if resync(d) {
+        drain(d);
+    }
+    let (time, start) = (SystemTime::now(), Instant::now());
+
+    let response = xmit(cmd, d).and_then(|_| rx(d, tag, timeout, response_limits(d)));
+    let received = response.as_ref().map_or(0, |rsp| rsp.data.len());
+    let response = response
+        .and_then(|rsp| match rsp.status {
+            CommandResponseStatus::Success => Ok(rsp),
+            CommandResponseStatus::ParseError => Err(Error::refused(
+                ErrorKind::CommandNotRecognized,
+                rsp.status_info,
+            )),
+            CommandResponseStatus::ExecutionError => {
+                Err(Error::refused(ErrorKind::Execution, rsp.status_info))
+            }
+        })
+        .map_err(|e| e.during(id));
+
+    record(CommandEvent {
+        time,
+        id,
+        tag,
+        sent,
+        received,
+        duration: start.elapsed(),
+        result: response.as_ref().map(|_| ()).map_err(Error::clone),
+    });
+    response
+}
+
+///Transmit a Command that gets no response, ie a reset.
+pub(crate) fn send(cmd: Command, d: &impl ReadWrite) -> Result<(), Error> {
+    refuse_read_only(d, cmd.id)?;
+    let cmd = Command {
+        tag: next_tag(d),
+        ..cmd
+    };
+    let (id, tag, sent) = (cmd.id, cmd.tag, cmd.data.len());
+    let (time, start) = (SystemTime::now(), Instant::now());
+
+    let result = xmit(&cmd, d).map_err(|e| e.during(id));
+
+    record(CommandEvent {
+        time,
+        id,
+        tag,
+        sent,
+        received: 0,
+        duration: start.elapsed(),
+        result: result.clone(),
+    });
+    result
+}
+
+// before anything is sent, see SessionOptions::read_only
+fn refuse_read_only(d: &impl ReadWrite, id: u32) -> Result<(), Error> {
+    if read_only(d) && mutates(id) {
+        return Err(Error::new(ErrorKind::ReadOnly).during(id));
+    }
+    Ok(())
+}
+
+///Receive the CommandResponse repeating tag, dropping late responses to commands sent before it.
+///CommandResponse.data is not interpreted in any way.
+pub(crate) fn rx(
+    d: &impl ReadWrite,
+    tag: u16,
+    timeout: Duration,
+    limits: ResponseLimits,
+) -> Result<CommandResponse, Error> {
+    let deadline = Instant::now() + timeout;
+    loop {
+        let resp = rx_response(d, deadline, limits)?;
+        if resp.tag == tag {
+            return Ok(resp);
+        }
+        if !is_stale(tag, resp.tag) {
+            log::debug!("response tagged {} while waiting for {}", resp.tag, tag);
+            return Err(ErrorKind::Sequence.into());
+        }
+        log::debug!("dropping late response tagged {}", resp.tag);
+    }
+}
+
+// the next response, whichever command it answers
+fn rx_response(
+    d: &impl ReadWrite,
+    deadline: Instant,
+    limits: ResponseLimits,
+) -> Result<CommandResponse, Error> {
     let mut bitsnbytes: Vec<u8> = vec![];
+    let mut packets = 0;
 
     let buffer = &mut [0_u8; 64];
-    let mut retries = 5;
 
     // keep reading until Final packet
     'outer: loop {
-        let count = d.hf2_read(buffer)?;
+        let remaining = deadline.saturating_duration_since(Instant::now());
+        let count = d.hf2_read_timeout(buffer, remaining)?;
 
         log::debug!("rx count: {:?}", count);
 
         if count < 1 {
-            if retries <= 0 {
-                return Err(Error::Parse);
+            if Instant::now() >= deadline {
+                return Err(ErrorKind::Timeout.into());
             } else {
-                retries -= 1;
                 continue 'outer;
             }
         }
@@ -219,7 +381,7 @@ pub(crate) fn rx(d: &impl ReadWrite) -> Result<CommandResponse, Error> {
         log::debug!("rx len: {:?}", len);
 
         if len >= count {
-            return Err(Error::Parse);
+            return Err(ErrorKind::Parse.into());
         }
 
         log::debug!(
@@ -228,6 +390,18 @@ pub(crate) fn rx(d: &impl ReadWrite) -> Result<CommandResponse, Error> {
             &buffer[1..(len + 1)]
         );
 
+        // serial output interleaved with the response, kept for read_serial
+        if let Some(output) = crate::serial::serial(ptype, buffer[1..(len + 1)].to_vec()) {
+            crate::serial::stash(d, output);
+            continue 'outer;
+        }
+
+        packets += 1;
+        if packets > limits.max_packets || bitsnbytes.len() + len > limits.max_size {
+            log::debug!("rx over limits after {} packets", packets);
+            return Err(ErrorKind::Parse.into());
+        }
+
         //skip the header byte and strip excess bytes remote is allowed to send
         bitsnbytes.extend_from_slice(&buffer[1..(len + 1)]);
 
@@ -263,7 +437,7 @@ mod tests {
         W: Fn(&[u8]) -> usize,
     {
         fn hf2_write(&self, data: &[u8]) -> Result<usize, Error> {
-            let len = (&self.writer)(data);
+            let len = (self.writer)(data);
 
             Ok(len)
         }
@@ -356,9 +530,12 @@ mod tests {
             writer,
         };
 
-        let command = Command::new(0x0006, 4, le_page);
+        let command = Command {
+            tag: 4,
+            ..Command::new(0x0006, le_page)
+        };
 
-        xmit(command, &mock).unwrap();
+        xmit(&command, &mock).unwrap();
     }
 
     #[test]
@@ -410,7 +587,97 @@ mod tests {
             data: result.to_vec(),
         };
 
-        let rsp = rx(&mock).unwrap();
+        let limits = ResponseLimits::for_message_size(1024);
+        let rsp = rx(&mock, 4, Duration::from_millis(1000), limits).unwrap();
         assert_eq!(rsp, response);
     }
+
+    #[test]
+    fn receive_limits() {
+        // a device that never sends its final packet
+        let mock = MyMock {
+            reader: || {
+                let mut packet = vec![0x3F];
+                packet.extend_from_slice(&[0; 63]);
+                packet
+            },
+            writer: |_v| 0,
+        };
+        let limits = ResponseLimits::for_message_size(1024);
+        assert!(rx(&mock, 1, Duration::from_millis(1000), limits).is_err());
+
+        // or sends empty inner packets forever
+        let mock = MyMock {
+            reader: || vec![0x00, 0x00],
+            writer: |_v| 0,
+        };
+        assert!(rx(&mock, 1, Duration::from_millis(1000), limits).is_err());
+    }
+
+    #[test]
+    fn receive_tagged() {
+        // a late response to tag 6, then the one to 7, then one from the future
+        let responses = std::cell::RefCell::new(vec![
+            vec![0x44, 0x06, 0x00, 0x00, 0x00],
+            vec![0x44, 0x07, 0x00, 0x00, 0x00],
+            vec![0x44, 0x09, 0x00, 0x00, 0x00],
+        ]);
+        let mock = MyMock {
+            reader: || responses.borrow_mut().remove(0),
+            writer: |_v| 0,
+        };
+        let limits = ResponseLimits::for_message_size(1024);
+
+        let rsp = rx(&mock, 7, Duration::from_millis(1000), limits).unwrap();
+        assert_eq!(rsp.tag, 7);
+        assert_eq!(
+            rx(&mock, 8, Duration::from_millis(1000), limits)
+                .unwrap_err()
+                .kind(),
+            ErrorKind::Sequence
+        );
+    }
+
+    #[test]
+    fn retried_with_backoff() {
+        let id = 0x7701;
+
+        // silent for the first attempt, then answers
+        let writes = std::cell::Cell::new(0);
+        let tag = std::cell::Cell::new([0, 0]);
+        let mock = MyMock {
+            reader: || match writes.get() {
+                1 => vec![],
+                _ => {
+                    let [low, high] = tag.get();
+                    vec![0x44, low, high, 0x00, 0x00]
+                }
+            },
+            writer: |v: &[u8]| {
+                writes.set(writes.get() + 1);
+                tag.set([v[6], v[7]]);
+                v.len()
+            },
+        };
+        let options = crate::SessionOptions {
+            timeouts: vec![(id, Duration::from_millis(20))],
+            retry_policies: vec![(
+                id,
+                crate::RetryPolicy {
+                    retries: 2,
+                    backoff: Duration::from_millis(1),
+                },
+            )],
+            ..Default::default()
+        };
+        let mock = crate::Session::with_options(mock, options);
+        assert!(xfer(Command::new(id, vec![]), &mock).is_ok());
+        assert_eq!(writes.get(), 2);
+        assert_eq!(state(&mock).attempts(), 2);
+        assert!(crate::stability().retried >= 1);
+
+        let policy = crate::RetryPolicy::default();
+        assert_eq!(policy.delay(1), Duration::from_millis(50));
+        assert_eq!(policy.delay(3), Duration::from_millis(200));
+    }
 }
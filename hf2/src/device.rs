@@ -0,0 +1,300 @@
+use crate::command::{rx, xmit, Command};
+use crate::Error;
+use hidapi::{HidApi, HidDevice};
+use std::ffi::CString;
+use std::sync::Mutex;
+use std::time::Duration;
+
+static BLOCKED: Mutex<Vec<Blocked>> = Mutex::new(Vec::new());
+
+///HF2 interfaces are meant to use this usage page, though not every backend reports usage pages.
+pub const HF2_USAGE_PAGE: u16 = 0xFF97;
+
+///Vendor and product ids of boards known to ship an HF2 bootloader.
+pub const KNOWN_DEVICES: &[(u16, &[u16])] = &[
+    (0x1D50, &[0x6110, 0x6112]),
+    (
+        0x239A,
+        &[
+            0x007F, 0x00B3, 0x003F, 0x0051, 0x0093, 0x0087, 0x003B, 0x0071, 0x0045, 0x0063, 0x0029,
+            0x0079, 0x0061, 0xE005, 0x0095, 0x004D, 0x006B, 0x0057, 0x00B5, 0x007D, 0x00B9, 0x0065,
+            0x0047, 0x0049, 0x00AF, 0x00CD, 0x00BF, 0x00C3, 0x00CB, 0x00AB, 0x00C5, 0x00A5, 0x00A7,
+            0x00C7, 0x002D, 0x0015, 0x001B, 0xB000, 0x0024, 0x000F, 0x0013, 0x0021, 0x0031, 0x0037,
+            0x0035, 0x002F, 0x002B, 0x0033, 0x0034, 0x003D, 0x0018, 0x001C, 0x001E, 0x0027, 0x0022,
+            0x00EF,
+        ],
+    ),
+    (0x04D8, &[0xEC44, 0xEC64, 0xEC63, 0xEDB3, 0xEDBE, 0xEF66]),
+    (0x2341, &[0x0057, 0x024E, 0x8053, 0x024D]),
+    (0x16D0, &[0x0CDA]),
+    (0x03EB, &[0x2402]),
+    (0x2886, &[0x002D, 0x000D, 0x002F]),
+    (0x1B4F, &[0x0D23, 0x0D22, 0x0016]),
+    (0x1209, &[0x805A, 0x7102, 0x4D44, 0x2017]),
+    (0x3171, &[0x0100]),
+    (0x1915, &[0x521F]),
+];
+
+///HID devices that must never be opened or probed, as raw reports written to them can
+///misbehave, ie security keys and KVMs.
+pub const BLOCKED_DEVICES: &[Blocked] = &[
+    // any FIDO authenticator
+    Blocked::new(None, None, Some(0xF1D0)),
+    // Yubico
+    Blocked::new(Some(0x1050), None, None),
+    // Nitrokey 3
+    Blocked::new(Some(0x20A0), Some(0x42B2), None),
+    // SoloKeys
+    Blocked::new(Some(0x0483), Some(0xA2CA), None),
+    // Ledger
+    Blocked::new(Some(0x2C97), None, None),
+    // Trezor
+    Blocked::new(Some(0x1209), Some(0x53C1), None),
+    Blocked::new(Some(0x534C), Some(0x0001), None),
+    // ATEN KVMs
+    Blocked::new(Some(0x0557), None, None),
+];
+
+///A blocklist entry, fields left None match anything.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct Blocked {
+    pub vendor_id: Option<u16>,
+    pub product_id: Option<u16>,
+    pub usage_page: Option<u16>,
+}
+
+impl Blocked {
+    pub const fn new(
+        vendor_id: Option<u16>,
+        product_id: Option<u16>,
+        usage_page: Option<u16>,
+    ) -> Self {
+        Blocked {
+            vendor_id,
+            product_id,
+            usage_page,
+        }
+    }
+
+    pub fn matches(&self, info: &DeviceInfo) -> bool {
+        self.vendor_id.is_none_or(|vid| vid == info.vendor_id)
+            && self.product_id.is_none_or(|pid| pid == info.product_id)
+            && self.usage_page.is_none_or(|page| page == info.usage_page)
+    }
+}
+
+///Parses vid, vid:pid or vid:pid:usage_page in hex, * matches anything, ie 1050, 20a0:42b2 or *:*:f1d0
+impl std::str::FromStr for Blocked {
+    type Err = String;
+
+    fn from_str(input: &str) -> Result<Self, Self::Err> {
+        let invalid = || format!("{} isn't vid, vid:pid or vid:pid:usage_page", input);
+        let mut fields = input.split(':').map(|field| match field.trim() {
+            "*" => Ok(None),
+            field => u16::from_str_radix(field.trim_start_matches("0x"), 16)
+                .map(Some)
+                .map_err(|_| invalid()),
+        });
+        let blocked = Blocked::new(
+            fields.next().unwrap_or(Ok(None))?,
+            fields.next().unwrap_or(Ok(None))?,
+            fields.next().unwrap_or(Ok(None))?,
+        );
+        if fields.next().is_some() {
+            return Err(invalid());
+        }
+        Ok(blocked)
+    }
+}
+
+///Add to the blocklist for this process, on top of BLOCKED_DEVICES.
+pub fn block_device(blocked: Blocked) {
+    let mut extra = BLOCKED.lock().unwrap_or_else(|e| e.into_inner());
+    extra.push(blocked);
+}
+
+///Whether an interface is on the built in or user blocklist.
+pub fn is_blocked(info: &DeviceInfo) -> bool {
+    let extra = BLOCKED.lock().unwrap_or_else(|e| e.into_inner());
+    BLOCKED_DEVICES
+        .iter()
+        .chain(extra.iter())
+        .any(|blocked| blocked.matches(info))
+}
+
+///Metadata of an HF2 capable HID interface, gathered without opening it.
+#[derive(Debug, Clone, PartialEq)]
+pub struct DeviceInfo {
+    pub path: CString,
+    pub vendor_id: u16,
+    pub product_id: u16,
+    pub serial_number: Option<String>,
+    pub manufacturer: Option<String>,
+    pub product: Option<String>,
+    pub interface_number: i32,
+    pub usage_page: u16,
+}
+
+impl DeviceInfo {
+    ///Claim the interface.
+    pub fn open(&self, api: &HidApi) -> Result<HidDevice, Error> {
+        api.open_path(&self.path).map_err(|e| e.into())
+    }
+
+    ///Stable name for the board, `vid:pid:serial` with the ids in hex, ie `239a:003d:8A2E1F3C`, so
+    ///logs, reports and anything kept per board key it the same way. Boards without a serial get a
+    ///hash of the path, `239a:003d:@5f1c02aa`, which only holds while they stay in the same port.
+    pub fn identity(&self) -> String {
+        match &self.serial_number {
+            Some(serial) if !serial.is_empty() => {
+                format!("{:04x}:{:04x}:{}", self.vendor_id, self.product_id, serial)
+            }
+            _ => {
+                // fnv-1a, std's hasher isn't guaranteed stable between releases
+                let hash = self
+                    .path
+                    .as_bytes()
+                    .iter()
+                    .fold(0x811c_9dc5_u32, |hash, byte| {
+                        (hash ^ *byte as u32).wrapping_mul(0x0100_0193)
+                    });
+                format!(
+                    "{:04x}:{:04x}:@{:08x}",
+                    self.vendor_id, self.product_id, hash
+                )
+            }
+        }
+    }
+}
+
+impl From<&hidapi::DeviceInfo> for DeviceInfo {
+    fn from(info: &hidapi::DeviceInfo) -> Self {
+        DeviceInfo {
+            path: info.path().to_owned(),
+            vendor_id: info.vendor_id(),
+            product_id: info.product_id(),
+            serial_number: info.serial_number().map(|s| s.to_owned()),
+            manufacturer: info.manufacturer_string().map(|s| s.to_owned()),
+            product: info.product_string().map(|s| s.to_owned()),
+            interface_number: info.interface_number(),
+            usage_page: info.usage_page(),
+        }
+    }
+}
+
+///Whether an opened interface answers BININFO within timeout. Composite boards show up as one
+///candidate per interface, on Windows one per top level collection, and only one of them speaks HF2.
+pub fn probe(d: &HidDevice, timeout: Duration) -> bool {
+    let mut command = Command::new(0x0001, vec![]);
+    command.tag = crate::tags::next_tag(d);
+    let tag = command.tag;
+    xmit(&command, d).is_ok() && rx(d, tag, timeout, crate::response_limits(d)).is_ok()
+}
+
+///Whether a vendor and product id belong to a board known to ship an HF2 bootloader.
+pub fn is_known_device(vendor_id: u16, product_id: u16) -> bool {
+    KNOWN_DEVICES
+        .iter()
+        .any(|(vid, pids)| *vid == vendor_id && pids.contains(&product_id))
+}
+
+///List connected HF2 interfaces, known boards or any interface on the HF2 usage page, without opening any of them.
+///Takes the HidApi as hidapi only allows one at a time and opened devices need it to stay alive.
+pub fn enumerate(api: &HidApi) -> Vec<DeviceInfo> {
+    api.device_list()
+        .filter(|info| {
+            info.usage_page() == HF2_USAGE_PAGE
+                || is_known_device(info.vendor_id(), info.product_id())
+        })
+        .map(DeviceInfo::from)
+        .filter(|info| !is_blocked(info))
+        .collect()
+}
+
+///Candidates grouped into boards by identity, in the order each board first appears, so a
+///composite board's interfaces count once when picking a board by position.
+pub fn group_boards(candidates: &[DeviceInfo]) -> Vec<Vec<DeviceInfo>> {
+    let mut boards: Vec<Vec<DeviceInfo>> = vec![];
+    for info in candidates {
+        match boards
+            .iter_mut()
+            .find(|board| board[0].identity() == info.identity())
+        {
+            Some(board) => board.push(info.clone()),
+            None => boards.push(vec![info.clone()]),
+        }
+    }
+    boards
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn blocklist() {
+        let info = |vendor_id, product_id, usage_page| DeviceInfo {
+            path: CString::new("test").unwrap(),
+            vendor_id,
+            product_id,
+            serial_number: None,
+            manufacturer: None,
+            product: None,
+            interface_number: 0,
+            usage_page,
+        };
+
+        assert!(is_blocked(&info(0x1050, 0x0407, 0x0001)));
+        assert!(is_blocked(&info(0x9999, 0x0001, 0xF1D0)));
+        assert!(!is_blocked(&info(0x239A, 0x003D, HF2_USAGE_PAGE)));
+
+        let parsed: Blocked = "20a0:*:0xff00".parse().unwrap();
+        assert_eq!(parsed, Blocked::new(Some(0x20A0), None, Some(0xFF00)));
+        assert!(parsed.matches(&info(0x20A0, 0x1234, 0xFF00)));
+        assert!("1050:0407:f1d0:1".parse::<Blocked>().is_err());
+        assert!("keyboard".parse::<Blocked>().is_err());
+    }
+
+    #[test]
+    fn identity() {
+        let mut info = DeviceInfo {
+            path: CString::new("/dev/hidraw3").unwrap(),
+            vendor_id: 0x239A,
+            product_id: 0x003D,
+            serial_number: Some("8A2E1F3C".into()),
+            manufacturer: None,
+            product: None,
+            interface_number: 0,
+            usage_page: HF2_USAGE_PAGE,
+        };
+        assert_eq!(info.identity(), "239a:003d:8A2E1F3C");
+
+        info.serial_number = None;
+        let by_path = info.identity();
+        assert!(by_path.starts_with("239a:003d:@") && by_path.len() == 19);
+        assert_eq!(info.identity(), by_path);
+
+        info.path = CString::new("/dev/hidraw4").unwrap();
+        assert_ne!(info.identity(), by_path);
+    }
+
+    #[test]
+    fn boards() {
+        let info = |path: &str, serial: &str| DeviceInfo {
+            path: CString::new(path).unwrap(),
+            vendor_id: 0x239A,
+            product_id: 0x003D,
+            serial_number: Some(serial.into()),
+            manufacturer: None,
+            product: None,
+            interface_number: 0,
+            usage_page: HF2_USAGE_PAGE,
+        };
+        let candidates = [info("a0", "B"), info("b0", "A"), info("a1", "B")];
+
+        let boards = group_boards(&candidates);
+        assert_eq!(boards.len(), 2);
+        assert_eq!(boards[0], [info("a0", "B"), info("a1", "B")]);
+        assert_eq!(boards[1], [info("b0", "A")]);
+    }
+}
@@ -0,0 +1,102 @@
+use crate::command::{xfer, Command};
+use crate::{info, Error, ErrorKind, ReadWrite};
+
+///Vendor command reserved for asking a bootloader which revision of HF2 extensions it speaks,
+///answered with a 32 bit little endian version. Bootloaders that don't know it speak version 0,
+///the commands the spec defines.
+pub const PROTOCOL_VERSION: u32 = 0x0100;
+
+///Optional behaviors a bootloader opts into by the protocol version it answers with.
+#[non_exhaustive]
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Feature {
+    ///Sends EXIT_STATUS_EVENT when firmware under test finishes.
+    ExitStatus,
+}
+
+impl Feature {
+    ///Protocol version a bootloader answers with from when it has this feature.
+    pub fn since(&self) -> u32 {
+        match self {
+            Feature::ExitStatus => 1,
+        }
+    }
+}
+
+///Which bootloader a device runs and which revision of HF2 it speaks, see negotiate.
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct Dialect {
+    ///First line of INFO_UF2.TXT, ie UF2 Bootloader v3.6.0 SFHWRO.
+    pub bootloader: Option<String>,
+    pub board_id: Option<String>,
+    ///0 for bootloaders that only speak the commands the spec defines.
+    pub protocol: u32,
+}
+
+impl Dialect {
+    ///Dialect from an INFO_UF2.TXT and the protocol version the device answered with.
+    pub fn new(info: Option<&str>, protocol: u32) -> Self {
+        let lines = || info.into_iter().flat_map(str::lines).map(str::trim);
+        Dialect {
+            bootloader: lines().next().filter(|l| !l.is_empty()).map(str::to_string),
+            board_id: lines()
+                .find_map(|l| l.strip_prefix("Board-ID:"))
+                .map(|id| id.trim().to_string()),
+            protocol,
+        }
+    }
+
+    pub fn supports(&self, feature: Feature) -> bool {
+        self.protocol >= feature.since()
+    }
+}
+
+///Asks the device for its INFO_UF2.TXT and protocol version. Run it once per device before relying
+///on any Feature, older bootloaders refuse or ignore PROTOCOL_VERSION and get version 0, so they
+///keep being spoken to the way they always were.
+pub fn negotiate(d: &impl ReadWrite) -> Dialect {
+    let info = info(d).ok();
+    Dialect::new(info.as_ref().map(|i| i.info.as_str()), protocol_version(d))
+}
+
+fn protocol_version(d: &impl ReadWrite) -> u32 {
+    let version = xfer(Command::new(PROTOCOL_VERSION, vec![]), d).and_then(|rsp| {
+        let bytes = rsp
+            .data
+            .get(..4)
+            .ok_or_else(|| Error::from(ErrorKind::Parse))?;
+        Ok(u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))
+    });
+    match version {
+        Ok(version) => version,
+        Err(e) => {
+            log::debug!("no protocol version, speaking plain HF2, {}", e);
+            0
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{FixtureDevice, SAMD51};
+
+    #[test]
+    fn negotiated() {
+        let d = FixtureDevice::new(SAMD51);
+        let dialect = negotiate(&d);
+        assert_eq!(
+            dialect,
+            Dialect {
+                bootloader: Some("UF2 Bootloader v3.6.0 SFHWRO".into()),
+                board_id: Some("SAMD51J19A-PyGamer-M4".into()),
+                protocol: 0,
+            }
+        );
+        assert!(!dialect.supports(Feature::ExitStatus));
+
+        d.set_protocol(1);
+        assert!(negotiate(&d).supports(Feature::ExitStatus));
+        assert_eq!(Dialect::new(None, 0), Dialect::default());
+    }
+}
@@ -1,21 +1,13 @@
-use crate::command::{rx, xmit, Command, CommandResponse, CommandResponseStatus};
-use crate::Error;
+use crate::command::{xfer, Command};
+use crate::{Error, ReadWrite};
 use scroll::{ctx, Pread, LE};
 
 ///Return internal log buffer if any. The result is a character array.
-
-pub fn dmesg(d: &hidapi::HidDevice) -> Result<DmesgResponse, Error> {
-    xmit(Command::new(0x0010, 0, vec![]), d)?;
-
-    match rx(d) {
-        Ok(CommandResponse {
-            status: CommandResponseStatus::Success,
-            data,
-            ..
-        }) => (data.as_slice()).pread_with(0, LE),
-        Ok(_) => Err(Error::CommandNotRecognized),
-        Err(e) => Err(e),
-    }
+pub fn dmesg(d: &impl ReadWrite) -> Result<DmesgResponse, Error> {
+    let rsp = xfer(Command::new(0x0010, vec![]), d)?;
+    (rsp.data.as_slice())
+        .pread_with(0, LE)
+        .map_err(|e: Error| e.during(0x0010))
 }
 
 ///Response to the dmesg command
@@ -24,6 +16,45 @@ pub struct DmesgResponse {
     pub logs: String,
 }
 
+impl DmesgResponse {
+    /// Lines that look like bootloader build info, some bootloaders log their version, build date
+    /// or commit at boot. Repeats from several boots are only returned once.
+    pub fn build_info(&self) -> Vec<&str> {
+        const KEYWORDS: &[&str] = &[
+            "bootloader",
+            "version",
+            "build",
+            "built",
+            "compiled",
+            "commit",
+            "git",
+            "date:",
+        ];
+
+        let mut lines: Vec<&str> = vec![];
+        for line in self.logs.lines().map(str::trim) {
+            let lower = line.to_lowercase();
+            let versioned = lower
+                .split(|c: char| c.is_whitespace() || c == ':')
+                .any(is_version);
+            if (versioned || KEYWORDS.iter().any(|k| lower.contains(k))) && !lines.contains(&line) {
+                lines.push(line);
+            }
+        }
+        lines
+    }
+}
+
+// v3.6.0, 1.2 and the like
+fn is_version(word: &str) -> bool {
+    let word = word.strip_prefix('v').unwrap_or(word);
+    let parts: Vec<&str> = word.split('.').collect();
+    parts.len() >= 2
+        && parts
+            .iter()
+            .all(|p| !p.is_empty() && p.chars().all(|c| c.is_ascii_digit()))
+}
+
 impl<'a> ctx::TryFromCtx<'a, scroll::Endian> for DmesgResponse {
     type Error = Error;
     fn try_from_ctx(this: &'a [u8], le: scroll::Endian) -> Result<(Self, usize), Self::Error> {
@@ -32,8 +63,40 @@ impl<'a> ctx::TryFromCtx<'a, scroll::Endian> for DmesgResponse {
         let mut offset = 0;
         this.gread_inout_with(&mut offset, &mut bytes, le)?;
 
-        let logs = core::str::from_utf8(&bytes)?;
+        // ring buffers can hold unwritten or half overwritten bytes, keep what's readable
+        let logs = String::from_utf8_lossy(&bytes)
+            .trim_end_matches('\0')
+            .to_string();
+
+        Ok((DmesgResponse { logs }, offset))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn build_info() {
+        let dmesg = DmesgResponse {
+            logs: "boot\r\nUF2 Bootloader v3.6.0\r\nflash 0x4000\r\nBuilt: Jan 1 2020\r\nboot\r\nUF2 Bootloader v3.6.0\r\nsd 6.1\r\n".into(),
+        };
+
+        assert_eq!(
+            dmesg.build_info(),
+            vec!["UF2 Bootloader v3.6.0", "Built: Jan 1 2020", "sd 6.1"]
+        );
+    }
+
+    #[test]
+    fn from_device() {
+        use crate::testing::{FixtureDevice, SAMD21};
+
+        let d = FixtureDevice::new(SAMD21);
+        d.set_dmesg("flash erased\r\nboot\r\n\0\0");
+        assert_eq!(dmesg(&d).unwrap().logs, "flash erased\r\nboot\r\n");
 
-        Ok((DmesgResponse { logs: logs.into() }, offset))
+        let garbled: DmesgResponse = b"ok\xFF".pread_with(0, LE).unwrap();
+        assert_eq!(garbled.logs, "ok\u{FFFD}");
     }
 }
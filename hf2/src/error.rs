@@ -0,0 +1,199 @@
+use crate::command::command_name;
+use std::fmt;
+use std::sync::Arc;
+
+///What went wrong, see Error::kind.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ErrorKind {
+    ///Refused before anything was sent, ie an unaligned address.
+    Arguments,
+    ///A response that couldn't be decoded.
+    Parse,
+    ///No response before the command's timeout, the device is there but slow or wedged.
+    Timeout,
+    ///The device answered status 0x01, it didn't understand the command.
+    CommandNotRecognized,
+    ///The device answered status 0x02, it understood but couldn't carry out the command, ie a
+    ///protected page.
+    Execution,
+    Sequence,
+    ///The transport failed, the device went away, ie unplugged or re-enumerating.
+    Transmission,
+    ///The deadline in the device's SessionOptions passed, the command wasn't sent.
+    Deadline,
+    ///The command would change the device and its session is read only, it wasn't sent.
+    ReadOnly,
+    ///The job running an async command panicked, the panic message is its source.
+    Panicked,
+}
+
+///An error talking HF2. Keeps the error that caused it, the command it happened during, the
+///address that command targeted and, when the device refused, the status info it answered with.
+#[derive(Clone, Debug)]
+pub struct Error {
+    kind: ErrorKind,
+    command: Option<u32>,
+    address: Option<u32>,
+    status_info: Option<u8>,
+    // Arc so events can keep a copy of the error
+    source: Option<Arc<dyn std::error::Error + Send + Sync>>,
+}
+
+///Who has to act on an error, so automation can tell what's worth retrying.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ErrorCategory {
+    ///Bad input, ie an address or image for another board. Retrying won't help.
+    User,
+    ///The device failed or refused, ie an execution error or failed verify. Worth a retry or power cycle.
+    Device,
+    ///Something on this machine, ie permissions, a missing usb backend or another process holding the device.
+    Host,
+}
+
+impl Error {
+    pub fn new(kind: ErrorKind) -> Self {
+        Error {
+            kind,
+            command: None,
+            address: None,
+            status_info: None,
+            source: None,
+        }
+    }
+
+    pub(crate) fn caused_by(
+        kind: ErrorKind,
+        source: impl std::error::Error + Send + Sync + 'static,
+    ) -> Self {
+        Error {
+            source: Some(Arc::new(source)),
+            ..Error::new(kind)
+        }
+    }
+
+    ///The error a device's non zero status stands for, with the status info it sent.
+    pub(crate) fn refused(kind: ErrorKind, status_info: u8) -> Self {
+        Error {
+            status_info: Some(status_info),
+            ..Error::new(kind)
+        }
+    }
+
+    ///Note the command this happened during, unless an inner call already did.
+    pub(crate) fn during(mut self, command: u32) -> Self {
+        self.command.get_or_insert(command);
+        self
+    }
+
+    ///Note the address the failing command targeted, unless an inner call already did.
+    pub(crate) fn at(mut self, address: u32) -> Self {
+        self.address.get_or_insert(address);
+        self
+    }
+
+    pub fn kind(&self) -> ErrorKind {
+        self.kind
+    }
+
+    ///Id of the command this happened during, if it got as far as a command.
+    pub fn command(&self) -> Option<u32> {
+        self.command
+    }
+
+    ///Address the failing command targeted, for the memory commands.
+    pub fn address(&self) -> Option<u32> {
+        self.address
+    }
+
+    ///The status info byte of the device's response when it refused the command. HF2 leaves its
+    ///meaning to the bootloader, zero being no further information.
+    pub fn status_info(&self) -> Option<u8> {
+        self.status_info
+    }
+
+    pub fn category(&self) -> ErrorCategory {
+        match self.kind {
+            ErrorKind::Arguments | ErrorKind::ReadOnly => ErrorCategory::User,
+            ErrorKind::Parse
+            | ErrorKind::Timeout
+            | ErrorKind::CommandNotRecognized
+            | ErrorKind::Execution
+            | ErrorKind::Sequence
+            | ErrorKind::Transmission => ErrorCategory::Device,
+            ErrorKind::Deadline | ErrorKind::Panicked => ErrorCategory::Host,
+        }
+    }
+}
+
+impl From<ErrorKind> for Error {
+    fn from(kind: ErrorKind) -> Self {
+        Error::new(kind)
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let what = match self.kind {
+            ErrorKind::Arguments => "invalid arguments",
+            ErrorKind::Parse => "couldn't parse the response",
+            ErrorKind::Timeout => "timed out waiting for the response",
+            ErrorKind::CommandNotRecognized => "command not recognized",
+            ErrorKind::Execution => "command failed",
+            ErrorKind::Sequence => "out of sequence",
+            ErrorKind::Transmission => "device went away",
+            ErrorKind::Deadline => "deadline passed",
+            ErrorKind::ReadOnly => "refused, read only",
+            ErrorKind::Panicked => "panicked",
+        };
+        f.write_str(what)?;
+        if let Some(command) = self.command {
+            write!(f, " in {} {:#06x}", command_name(command), command)?;
+        }
+        if let Some(address) = self.address {
+            write!(f, " at {:#010x}", address)?;
+        }
+        match self.status_info {
+            Some(0) | None => Ok(()),
+            Some(info) => write!(f, ", status info {:#04x}", info),
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        self.source
+            .as_ref()
+            .map(|source| source.as_ref() as &(dyn std::error::Error + 'static))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::error::Error as _;
+
+    #[test]
+    fn context() {
+        let e = Error::refused(ErrorKind::Execution, 3)
+            .at(0x2_7000)
+            .during(0x0006)
+            .at(0x2_8000);
+        assert_eq!(e.kind(), ErrorKind::Execution);
+        assert_eq!(e.command(), Some(0x0006));
+        assert_eq!(e.address(), Some(0x2_7000));
+        assert_eq!(e.status_info(), Some(3));
+        assert_eq!(
+            e.to_string(),
+            "command failed in write_flash_page 0x0006 at 0x00027000, status info 0x03"
+        );
+        assert!(e.source().is_none());
+
+        let e = Error::from(scroll::Error::TooBig { size: 8, len: 4 }).during(0x0001);
+        assert_eq!(e.kind(), ErrorKind::Parse);
+        assert_eq!(
+            e.to_string(),
+            "couldn't parse the response in bininfo 0x0001"
+        );
+        assert!(e.source().unwrap().to_string().contains("too big"));
+    }
+}
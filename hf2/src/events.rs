@@ -0,0 +1,187 @@
+use crate::{Error, ErrorKind};
+use std::fmt;
+use std::sync::Mutex;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+type Sink = Box<dyn FnMut(&CommandEvent) + Send>;
+
+static SINK: Mutex<Option<Sink>> = Mutex::new(None);
+
+static STABILITY: Mutex<Stability> = Mutex::new(Stability {
+    commands: 0,
+    dropped: 0,
+    unanswered: 0,
+    refused: 0,
+    retried: 0,
+});
+
+///One command sent to the device, lighter than a packet trace but enough to see where time goes.
+#[derive(Clone, Debug)]
+pub struct CommandEvent {
+    ///When the command was sent.
+    pub time: SystemTime,
+    pub id: u32,
+    pub tag: u16,
+    ///Command argument bytes sent.
+    pub sent: usize,
+    ///Response data bytes received, 0 for commands that don't answer.
+    pub received: usize,
+    pub duration: Duration,
+    pub result: Result<(), Error>,
+}
+
+impl CommandEvent {
+    ///A single line of json.
+    pub fn to_json(&self) -> String {
+        let time = self
+            .time
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs_f64();
+        let result = match &self.result {
+            Ok(()) => "ok".to_string(),
+            Err(e) => format!("{:?}", e.kind()),
+        };
+        format!(
+            "{{\"time\":{:.6},\"id\":{},\"tag\":{},\"sent\":{},\"received\":{},\"duration_us\":{},\"result\":\"{}\"}}",
+            time,
+            self.id,
+            self.tag,
+            self.sent,
+            self.received,
+            self.duration.as_micros(),
+            result
+        )
+    }
+}
+
+///Call sink with every command sent from now on in this process, ie to write an event log.
+pub fn set_event_sink(sink: impl FnMut(&CommandEvent) + Send + 'static) {
+    let mut current = SINK.lock().unwrap_or_else(|e| e.into_inner());
+    *current = Some(Box::new(sink));
+}
+
+///How the link to the device held up over this process, from every command sent so far. Telling
+///a board that drops off the bus from one that's connected but not answering separates a bad
+///cable, hub or supply from a firmware problem. Re-enumerations aren't seen directly, a board that
+///resets or browns out mid command shows up as dropped commands.
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub struct Stability {
+    pub commands: u32,
+    ///Failed to send or read, the device went away, usually re-enumerating after a brown out or a
+    ///flaky connection.
+    pub dropped: u32,
+    ///Sent but no complete response in time.
+    pub unanswered: u32,
+    ///Answered with an error status.
+    pub refused: u32,
+    ///Sent again by a retry policy, each attempt is also counted in commands and the failed ones as
+    ///unanswered.
+    pub retried: u32,
+}
+
+impl Stability {
+    ///Percent of commands that succeeded, 100 before any are sent.
+    pub fn score(&self) -> u32 {
+        let failed = self.dropped + self.unanswered + self.refused;
+        match self.commands {
+            0 => 100,
+            commands => (commands - failed.min(commands)) * 100 / commands,
+        }
+    }
+
+    ///The likeliest culprit, None when nothing failed.
+    pub fn suspect(&self) -> Option<&'static str> {
+        if self.dropped > 0 {
+            Some("the device dropped off usb, suspect the cable, hub or supply")
+        } else if self.unanswered + self.refused > 0 {
+            Some("the device is connected but not answering properly, suspect the firmware or bootloader")
+        } else {
+            None
+        }
+    }
+}
+
+impl fmt::Display for Stability {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "stability {}%, {} commands, {} dropped, {} unanswered, {} refused, {} retried",
+            self.score(),
+            self.commands,
+            self.dropped,
+            self.unanswered,
+            self.refused,
+            self.retried
+        )?;
+        match self.suspect() {
+            Some(suspect) => write!(f, ", {}", suspect),
+            None => Ok(()),
+        }
+    }
+}
+
+///Link stability of every command sent so far in this process.
+pub fn stability() -> Stability {
+    *STABILITY.lock().unwrap_or_else(|e| e.into_inner())
+}
+
+pub(crate) fn record_retry() {
+    STABILITY.lock().unwrap_or_else(|e| e.into_inner()).retried += 1;
+}
+
+pub(crate) fn record(event: CommandEvent) {
+    {
+        let mut stability = STABILITY.lock().unwrap_or_else(|e| e.into_inner());
+        stability.commands += 1;
+        match &event.result {
+            Ok(()) => {}
+            Err(e) => match e.kind() {
+                ErrorKind::Transmission => stability.dropped += 1,
+                ErrorKind::Parse | ErrorKind::Timeout => stability.unanswered += 1,
+                _ => stability.refused += 1,
+            },
+        }
+    }
+
+    let mut sink = SINK.lock().unwrap_or_else(|e| e.into_inner());
+    if let Some(sink) = sink.as_mut() {
+        sink(&event);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn json() {
+        let event = CommandEvent {
+            time: UNIX_EPOCH + Duration::from_millis(1500),
+            id: 7,
+            tag: 3,
+            sent: 8,
+            received: 20,
+            duration: Duration::from_micros(2500),
+            result: Err(ErrorKind::Execution.into()),
+        };
+        assert_eq!(
+            event.to_json(),
+            r#"{"time":1.500000,"id":7,"tag":3,"sent":8,"received":20,"duration_us":2500,"result":"Execution"}"#
+        );
+    }
+
+    #[test]
+    fn stability_score() {
+        let mut stability = Stability::default();
+        assert_eq!((stability.score(), stability.suspect()), (100, None));
+
+        stability.commands = 200;
+        stability.unanswered = 2;
+        assert_eq!(stability.score(), 99);
+        assert!(stability.suspect().unwrap().contains("firmware"));
+
+        stability.dropped = 1;
+        assert!(stability.suspect().unwrap().contains("cable"));
+    }
+}
@@ -1,5 +1,21 @@
 use crate::{Error, ReadWrite};
+use core::convert::TryFrom;
 use hidapi::HidDevice;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::{Duration, Instant};
+
+static LOW_LATENCY: AtomicBool = AtomicBool::new(false);
+
+// how long low latency reads poll before blocking, most responses arrive well within it
+const SPIN: Duration = Duration::from_millis(2);
+
+///Poll for responses before blocking on them for this process, trading a busy core for less round
+///trip time when a workload is many small commands. hidraw on linux and the IOKit queue on mac
+///answer a zero timeout read without a syscall wait, on windows it checks the pending overlapped
+///read. hidapi doesn't expose the windows input buffer count, so that's left at its default.
+pub fn set_low_latency(low_latency: bool) {
+    LOW_LATENCY.store(low_latency, Ordering::Relaxed);
+}
 
 impl ReadWrite for HidDevice {
     fn hf2_write(&self, data: &[u8]) -> Result<usize, Error> {
@@ -8,10 +24,31 @@ impl ReadWrite for HidDevice {
     fn hf2_read(&self, buf: &mut [u8]) -> Result<usize, Error> {
         self.read_timeout(buf, 1000).map_err(|e| e.into())
     }
+    fn hf2_read_timeout(&self, buf: &mut [u8], timeout: Duration) -> Result<usize, Error> {
+        let mut timeout = timeout;
+        if LOW_LATENCY.load(Ordering::Relaxed) {
+            let start = Instant::now();
+            loop {
+                let count = self.read_timeout(buf, 0)?;
+                if count > 0 {
+                    return Ok(count);
+                }
+                if start.elapsed() >= SPIN.min(timeout) {
+                    break;
+                }
+                std::hint::spin_loop();
+            }
+            timeout = timeout.saturating_sub(start.elapsed());
+        }
+
+        // hidapi takes milliseconds as an i32, where a negative timeout blocks forever
+        let millis = i32::try_from(timeout.as_millis()).unwrap_or(i32::MAX);
+        self.read_timeout(buf, millis).map_err(|e| e.into())
+    }
 }
 
 impl From<hidapi::HidError> for Error {
-    fn from(_err: hidapi::HidError) -> Self {
-        Error::Transmission
+    fn from(err: hidapi::HidError) -> Self {
+        Error::caused_by(crate::ErrorKind::Transmission, err)
     }
 }
@@ -0,0 +1,104 @@
+use std::sync::{Condvar, Mutex, MutexGuard};
+
+static IN_FLIGHT: InFlight = InFlight::new();
+
+///Cap how many commands this process has in flight at once across every device, so flashing many
+///boards at once through one hub or root port doesn't overrun it. Commands waiting for a slot get
+///one in the order they asked, so every device takes its turn rather than a fast one starving the
+///rest. Zero, the default, doesn't limit.
+pub fn set_max_in_flight(max: usize) {
+    IN_FLIGHT.lock().max = max;
+    IN_FLIGHT.turn.notify_all();
+}
+
+///Wait for a slot to send a command and wait for its response in, held until dropped.
+pub(crate) fn in_flight() -> Slot<'static> {
+    IN_FLIGHT.acquire()
+}
+
+pub(crate) struct InFlight {
+    state: Mutex<State>,
+    turn: Condvar,
+}
+
+struct State {
+    max: usize,
+    running: usize,
+    // tickets are handed out and served in order, like a deli counter
+    next_ticket: u64,
+    serving: u64,
+}
+
+pub(crate) struct Slot<'a> {
+    owner: &'a InFlight,
+}
+
+impl InFlight {
+    pub(crate) const fn new() -> Self {
+        InFlight {
+            state: Mutex::new(State {
+                max: 0,
+                running: 0,
+                next_ticket: 0,
+                serving: 0,
+            }),
+            turn: Condvar::new(),
+        }
+    }
+
+    fn lock(&self) -> MutexGuard<'_, State> {
+        self.state.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    pub(crate) fn acquire(&self) -> Slot<'_> {
+        let mut state = self.lock();
+        let ticket = state.next_ticket;
+        state.next_ticket += 1;
+        while state.serving != ticket || (state.max != 0 && state.running >= state.max) {
+            state = self.turn.wait(state).unwrap_or_else(|e| e.into_inner());
+        }
+        state.serving += 1;
+        state.running += 1;
+        // the next ticket may be able to go too
+        self.turn.notify_all();
+        Slot { owner: self }
+    }
+}
+
+impl Drop for Slot<'_> {
+    fn drop(&mut self) {
+        self.owner.lock().running -= 1;
+        self.owner.turn.notify_all();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::time::Duration;
+
+    #[test]
+    fn capped() {
+        let limit = InFlight::new();
+        limit.lock().max = 2;
+        let running = AtomicUsize::new(0);
+        let most = AtomicUsize::new(0);
+
+        std::thread::scope(|scope| {
+            for _ in 0..6 {
+                scope.spawn(|| {
+                    for _ in 0..5 {
+                        let _slot = limit.acquire();
+                        let now = running.fetch_add(1, Ordering::SeqCst) + 1;
+                        most.fetch_max(now, Ordering::SeqCst);
+                        std::thread::sleep(Duration::from_millis(1));
+                        running.fetch_sub(1, Ordering::SeqCst);
+                    }
+                });
+            }
+        });
+        assert!(most.load(Ordering::SeqCst) <= 2);
+        assert_eq!(limit.lock().running, 0);
+    }
+}
@@ -1,20 +1,13 @@
-use crate::command::{rx, xmit, Command, CommandResponse, CommandResponseStatus};
-use crate::Error;
+use crate::command::{xfer, Command};
+use crate::{Error, ReadWrite};
 use scroll::{ctx, Pread, LE};
 
 /// Various device information. The result is a character array. See INFO_UF2.TXT in UF2 format for details.
-pub fn info(d: &hidapi::HidDevice) -> Result<InfoResponse, Error> {
-    xmit(Command::new(0x0002, 0, vec![]), d)?;
-
-    match rx(d) {
-        Ok(CommandResponse {
-            status: CommandResponseStatus::Success,
-            data,
-            ..
-        }) => (data.as_slice()).pread_with(0, LE),
-        Ok(_) => Err(Error::CommandNotRecognized),
-        Err(e) => Err(e),
-    }
+pub fn info(d: &impl ReadWrite) -> Result<InfoResponse, Error> {
+    let rsp = xfer(Command::new(0x0002, vec![]), d)?;
+    (rsp.data.as_slice())
+        .pread_with(0, LE)
+        .map_err(|e: Error| e.during(0x0002))
 }
 
 ///Response to the info command
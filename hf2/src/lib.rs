@@ -6,6 +6,10 @@ pub use bininfo::*;
 mod checksumpages;
 pub use checksumpages::*;
 
+///The CRC-16 variants pages are checksummed with, the spec's and those of vendor forks.
+mod checksum;
+pub use checksum::*;
+
 ///Return internal log buffer if any. The result is a character array.
 mod dmesg;
 pub use dmesg::*;
@@ -34,6 +38,43 @@ pub use startflash::*;
 mod writeflashpage;
 pub use writeflashpage::*;
 
+///Serial stdout and stderr packets the device sends outside of command responses.
+mod serial;
+pub use serial::*;
+
+///Per command response timeouts, with defaults based on how long each command usually takes, retry
+///policies, and pacing between the reports of one command.
+mod timeouts;
+pub use timeouts::*;
+
+///Tagging commands so responses can be matched to them.
+mod tags;
+pub use tags::drain;
+
+///A device and the tags and options of the conversation with it.
+mod session;
+pub use session::*;
+
+///Refusing commands that change the device, for inspecting production boards.
+mod readonly;
+pub use readonly::*;
+
+///A process wide cap on commands in flight, shared fairly between devices.
+mod inflight;
+pub use inflight::set_max_in_flight;
+
+///Negotiating the HF2 extensions a bootloader speaks beyond the spec.
+mod dialect;
+pub use dialect::*;
+
+///Bounds on response reassembly, derived per device from the max_message_size it advertises.
+mod limits;
+pub use limits::*;
+
+///Per command records for event logs and performance analysis.
+mod events;
+pub use events::*;
+
 ///Dual of READ WORDS, with the same constraints. No Result.
 mod writewords;
 pub use writewords::*;
@@ -41,24 +82,80 @@ pub use writewords::*;
 /// Errors and traits to build a command
 mod command;
 
-#[derive(Clone, Debug)]
-pub enum Error {
-    Arguments,
-    Parse,
-    CommandNotRecognized,
-    Execution,
-    Sequence,
-    Transmission,
-}
+///Errors keeping their cause and the command and address they happened at.
+mod error;
+pub use error::*;
+
+use std::time::Duration;
 
-///trait to implement HID devices
+///The transport commands are sent over, one 64 byte HID report per write and read. Implemented for
+///hidapi devices with the hidapi feature and for RemoteDevice, implement it for any other backend,
+///ie serial, WebHID or a mock, and every command and the utils work over it unchanged.
 pub trait ReadWrite {
     fn hf2_write(&self, data: &[u8]) -> Result<usize, Error>;
     fn hf2_read(&self, buf: &mut [u8]) -> Result<usize, Error>;
+    ///read giving up after timeout, returning 0 bytes if nothing arrived. Transports without timeouts fall back to hf2_read.
+    fn hf2_read_timeout(&self, buf: &mut [u8], _timeout: Duration) -> Result<usize, Error> {
+        self.hf2_read(buf)
+    }
+    ///The Session keeping tags and options for this device, None for a bare transport.
+    fn session(&self) -> Option<&SessionState> {
+        None
+    }
 }
 
+impl<T: ReadWrite + ?Sized> ReadWrite for &T {
+    fn hf2_write(&self, data: &[u8]) -> Result<usize, Error> {
+        (**self).hf2_write(data)
+    }
+    fn hf2_read(&self, buf: &mut [u8]) -> Result<usize, Error> {
+        (**self).hf2_read(buf)
+    }
+    fn hf2_read_timeout(&self, buf: &mut [u8], timeout: Duration) -> Result<usize, Error> {
+        (**self).hf2_read_timeout(buf, timeout)
+    }
+    fn session(&self) -> Option<&SessionState> {
+        (**self).session()
+    }
+}
+
+///Recording the reports exchanged with a device, to replay in tests.
+mod record;
+pub use record::*;
+
+///ReadWrite for hidapi devices, with an optional low latency read mode.
 #[cfg(feature = "hidapi")]
 mod hidapi_trait;
+#[cfg(feature = "hidapi")]
+pub use hidapi_trait::*;
+
+///HF2 over a serial port rather than HID.
+#[cfg(feature = "serial")]
+mod cdc;
+#[cfg(feature = "serial")]
+pub use cdc::*;
+
+///Driving devices from async code without blocking its runtime.
+#[cfg(feature = "async")]
+mod nonblocking;
+#[cfg(feature = "async")]
+pub use nonblocking::*;
+
+///Reaching a device attached to another machine.
+mod remote;
+pub use remote::*;
+
+///Finding HF2 devices without opening them.
+#[cfg(feature = "hidapi")]
+mod device;
+#[cfg(feature = "hidapi")]
+pub use device::*;
 
 #[cfg(feature = "utils")]
 pub mod utils;
+
+#[cfg(any(test, feature = "testing"))]
+pub mod testing;
+
+#[cfg(any(test, feature = "testing", feature = "utils"))]
+mod simulator;
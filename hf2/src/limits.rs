@@ -0,0 +1,77 @@
+use crate::session::state;
+use crate::ReadWrite;
+
+// Largest response reassembled for a device whose max_message_size isn't known yet, or
+// that claims something absurd.
+const DEFAULT_MAX_SIZE: usize = 64 * 1024;
+const CEILING: usize = 1024 * 1024;
+
+///How much of a response is reassembled before the device is considered broken, so a device
+///that never sends its final packet can't make the host allocate or loop forever.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct ResponseLimits {
+    ///Bytes of response, including the 4 byte header.
+    pub max_size: usize,
+    ///Packets of response, each carries up to 63 bytes.
+    pub max_packets: usize,
+}
+
+impl ResponseLimits {
+    ///Limits for a device that advertised max_message_size in its bininfo.
+    pub fn for_message_size(max_message_size: u32) -> Self {
+        Self::for_size((max_message_size as usize).saturating_add(4).min(CEILING))
+    }
+
+    // twice the packets full ones would need, devices may send partial packets
+    const fn for_size(max_size: usize) -> Self {
+        Self {
+            max_size,
+            max_packets: max_size.div_ceil(63) * 2,
+        }
+    }
+}
+
+///Response limits in effect for d, set in its SessionOptions or derived from its last bininfo. A
+///bare transport has no session to keep a bininfo in, so it gets the default.
+pub fn response_limits(d: &(impl ReadWrite + ?Sized)) -> ResponseLimits {
+    let state = state(d);
+    state
+        .options()
+        .response_limits
+        .or_else(|| state.derived_limits())
+        .unwrap_or(ResponseLimits::for_size(DEFAULT_MAX_SIZE))
+}
+
+///Follow the max_message_size d advertised, if it has a session to keep it in.
+pub(crate) fn derive_response_limits(d: &(impl ReadWrite + ?Sized), max_message_size: u32) {
+    if let Some(state) = d.session() {
+        state.set_max_message_size(max_message_size);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{FixtureDevice, NRF52840, SAMD21};
+    use crate::Session;
+
+    #[test]
+    fn per_device() {
+        let small = Session::new(FixtureDevice::new(SAMD21));
+        let large = Session::new(FixtureDevice::new(NRF52840));
+        crate::bin_info(&small).unwrap();
+        crate::bin_info(&large).unwrap();
+        assert_eq!(
+            response_limits(&small),
+            ResponseLimits::for_message_size(320)
+        );
+        assert_eq!(
+            response_limits(&large),
+            ResponseLimits::for_message_size(4160)
+        );
+
+        let bare = FixtureDevice::new(SAMD21);
+        crate::bin_info(&bare).unwrap();
+        assert_eq!(response_limits(&bare).max_size, DEFAULT_MAX_SIZE);
+    }
+}
@@ -0,0 +1,340 @@
+use crate::{
+    bin_info, dmesg, info, read_serial, BinInfoResponse, DmesgResponse, Error, ErrorKind,
+    InfoResponse, ReadWrite, SerialOutput,
+};
+use std::collections::VecDeque;
+use std::future::Future;
+use std::panic::AssertUnwindSafe;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::task::{Context, Poll, Waker};
+
+///Runs the blocking jobs behind async commands. HF2 over hidapi or a serial port only has blocking
+///reads, so each command runs as a job off the async runtime, ie on tokio's spawn_blocking or a
+///thread pool a GUI already has.
+pub trait Executor: Send + Sync {
+    fn spawn_blocking(&self, job: Box<dyn FnOnce() + Send>);
+}
+
+///The default executor, a thread per job.
+pub struct ThreadExecutor;
+
+impl Executor for ThreadExecutor {
+    fn spawn_blocking(&self, job: Box<dyn FnOnce() + Send>) {
+        std::thread::spawn(job);
+    }
+}
+
+static EXECUTOR: Mutex<Option<Arc<dyn Executor>>> = Mutex::new(None);
+
+///Run async commands on executor for this process rather than a thread each.
+pub fn set_executor(executor: impl Executor + 'static) {
+    *EXECUTOR.lock().unwrap_or_else(|e| e.into_inner()) = Some(Arc::new(executor));
+}
+
+fn executor() -> Arc<dyn Executor> {
+    EXECUTOR
+        .lock()
+        .unwrap_or_else(|e| e.into_inner())
+        .clone()
+        .unwrap_or_else(|| Arc::new(ThreadExecutor))
+}
+
+struct Slot<T> {
+    result: Option<T>,
+    waker: Option<Waker>,
+}
+
+///Resolves to what a job run on the executor returned. Works with any async runtime, it only
+///needs the waker it's polled with.
+pub struct Blocking<T> {
+    slot: Arc<Mutex<Slot<T>>>,
+}
+
+impl<T> Future for Blocking<T> {
+    type Output = T;
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<T> {
+        let mut slot = lock(&self.slot);
+        match slot.result.take() {
+            Some(result) => Poll::Ready(result),
+            None => {
+                slot.waker = Some(cx.waker().clone());
+                Poll::Pending
+            }
+        }
+    }
+}
+
+// the job's end of a Blocking
+struct Done<T> {
+    slot: Arc<Mutex<Slot<T>>>,
+}
+
+impl<T> Done<T> {
+    fn complete(self, result: T) {
+        let mut slot = lock(&self.slot);
+        slot.result = Some(result);
+        if let Some(waker) = slot.waker.take() {
+            waker.wake();
+        }
+    }
+}
+
+fn pending<T>() -> (Blocking<T>, Done<T>) {
+    let slot = Arc::new(Mutex::new(Slot {
+        result: None,
+        waker: None,
+    }));
+    (Blocking { slot: slot.clone() }, Done { slot })
+}
+
+// runs job, turning a panic into ErrorKind::Panicked so its future still resolves
+fn guarded<T>(job: impl FnOnce() -> T) -> Result<T, Error> {
+    std::panic::catch_unwind(AssertUnwindSafe(job)).map_err(|panic| {
+        let message = panic
+            .downcast_ref::<&str>()
+            .map(|message| message.to_string())
+            .or_else(|| panic.downcast_ref::<String>().cloned())
+            .unwrap_or_else(|| "job panicked".into());
+        Error::caused_by(ErrorKind::Panicked, std::io::Error::other(message))
+    })
+}
+
+///Run job on the executor, resolving to its result, or ErrorKind::Panicked if it panicked.
+pub fn spawn_blocking<T: Send + 'static>(
+    job: impl FnOnce() -> T + Send + 'static,
+) -> Blocking<Result<T, Error>> {
+    let (blocking, done) = pending();
+    executor().spawn_blocking(Box::new(move || done.complete(guarded(job))));
+    blocking
+}
+
+type Job<D> = Box<dyn FnOnce(&D) + Send>;
+
+struct Shared<D> {
+    device: Mutex<D>,
+    queue: Mutex<Queue<D>>,
+}
+
+struct Queue<D> {
+    jobs: VecDeque<Job<D>>,
+    // a job on the executor is running the queue
+    draining: bool,
+}
+
+///A device driven from async code. Commands are queued as they're started and run one at a time
+///in that order, whichever executor thread picks them up, cloning shares the device and its queue.
+pub struct AsyncDevice<D> {
+    shared: Arc<Shared<D>>,
+}
+
+impl<D> Clone for AsyncDevice<D> {
+    fn clone(&self) -> Self {
+        AsyncDevice {
+            shared: self.shared.clone(),
+        }
+    }
+}
+
+impl<D: ReadWrite + Send + 'static> AsyncDevice<D> {
+    pub fn new(device: D) -> Self {
+        AsyncDevice {
+            shared: Arc::new(Shared {
+                device: Mutex::new(device),
+                queue: Mutex::new(Queue {
+                    jobs: VecDeque::new(),
+                    draining: false,
+                }),
+            }),
+        }
+    }
+
+    ///Run any blocking command or util against the device, ie
+    ///`d.with(|d| hf2::read_words(d, 0, 4)).await??`. Resolves to ErrorKind::Panicked if job
+    ///panicked, the device stays usable.
+    pub fn with<T: Send + 'static>(
+        &self,
+        job: impl FnOnce(&D) -> T + Send + 'static,
+    ) -> Blocking<Result<T, Error>> {
+        let (blocking, done) = pending();
+        self.queue(Box::new(move |d| done.complete(guarded(|| job(d)))));
+        blocking
+    }
+
+    // with for jobs that can fail, folding a panic into their own error
+    fn try_with<T: Send + 'static, E: From<Error> + Send + 'static>(
+        &self,
+        job: impl FnOnce(&D) -> Result<T, E> + Send + 'static,
+    ) -> Blocking<Result<T, E>> {
+        let (blocking, done) = pending();
+        self.queue(Box::new(move |d| {
+            done.complete(guarded(|| job(d)).unwrap_or_else(|e| Err(e.into())))
+        }));
+        blocking
+    }
+
+    fn queue(&self, job: Job<D>) {
+        let mut queue = lock(&self.shared.queue);
+        queue.jobs.push_back(job);
+        if !queue.draining {
+            queue.draining = true;
+            let shared = self.shared.clone();
+            executor().spawn_blocking(Box::new(move || drain(&shared)));
+        }
+    }
+
+    pub fn bin_info(&self) -> Blocking<Result<BinInfoResponse, Error>> {
+        self.try_with(|d| bin_info(d))
+    }
+
+    pub fn info(&self) -> Blocking<Result<InfoResponse, Error>> {
+        self.try_with(|d| info(d))
+    }
+
+    pub fn dmesg(&self) -> Blocking<Result<DmesgResponse, Error>> {
+        self.try_with(|d| dmesg(d))
+    }
+
+    ///The next packet of serial output, await it in a loop to follow the stream. None if nothing
+    ///arrived before the read timed out.
+    pub fn read_serial(&self) -> Blocking<Result<Option<SerialOutput>, Error>> {
+        self.try_with(|d| read_serial(d))
+    }
+
+    ///utils::flash_bin_with_progress, with progress called from the executor.
+    #[cfg(feature = "utils")]
+    #[allow(clippy::too_many_arguments)]
+    pub fn flash_bin(
+        &self,
+        binary: Vec<u8>,
+        address: u32,
+        pad_policy: crate::utils::PadPolicy,
+        verify_mode: crate::utils::VerifyMode,
+        bininfo: BinInfoResponse,
+        mut progress: impl crate::utils::ProgressHandler + Send + 'static,
+    ) -> Blocking<Result<(), crate::utils::UtilError>> {
+        self.try_with(move |d| {
+            crate::utils::flash_bin_with_progress(
+                &binary,
+                address,
+                pad_policy,
+                verify_mode,
+                &bininfo,
+                d,
+                &mut progress,
+            )
+        })
+    }
+}
+
+// runs the queued jobs until there are none left, a job started meanwhile joins the same run
+fn drain<D>(shared: &Shared<D>) {
+    loop {
+        let job = {
+            let mut queue = lock(&shared.queue);
+            match queue.jobs.pop_front() {
+                Some(job) => job,
+                None => {
+                    queue.draining = false;
+                    return;
+                }
+            }
+        };
+        job(&lock(&shared.device));
+    }
+}
+
+// jobs catch their own panics, nothing is left half done behind a poisoned lock
+fn lock<T>(mutex: &Mutex<T>) -> MutexGuard<'_, T> {
+    mutex.lock().unwrap_or_else(|e| e.into_inner())
+}
+
+///enumerate on the executor, without holding on to the HidApi.
+#[cfg(feature = "hidapi")]
+pub fn discover() -> Blocking<Result<Vec<crate::DeviceInfo>, Error>> {
+    let (blocking, done) = pending();
+    executor().spawn_blocking(Box::new(move || {
+        done.complete(guarded(|| Ok(crate::enumerate(&hidapi::HidApi::new()?))).unwrap_or_else(Err))
+    }));
+    blocking
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{FixtureDevice, SAMD21};
+    use std::sync::mpsc;
+    use std::task::Wake;
+
+    struct Unpark(std::thread::Thread);
+
+    impl Wake for Unpark {
+        fn wake(self: Arc<Self>) {
+            self.0.unpark();
+        }
+    }
+
+    fn block_on<T>(future: impl Future<Output = T>) -> T {
+        let waker = Waker::from(Arc::new(Unpark(std::thread::current())));
+        let mut cx = Context::from_waker(&waker);
+        let mut future = Box::pin(future);
+        loop {
+            match future.as_mut().poll(&mut cx) {
+                Poll::Ready(result) => return result,
+                Poll::Pending => std::thread::park(),
+            }
+        }
+    }
+
+    #[test]
+    #[cfg(feature = "utils")]
+    fn commands() {
+        let d = AsyncDevice::new(FixtureDevice::new(SAMD21));
+        let bininfo = block_on(d.bin_info()).unwrap();
+        assert_eq!(bininfo.geometry, SAMD21.geometry);
+        assert!(block_on(d.info()).unwrap().info.contains("Feather M0"));
+
+        let (tx, rx) = mpsc::channel();
+        let binary = vec![0x5A; 600];
+        let flashed = d.flash_bin(
+            binary.clone(),
+            0x2000,
+            Default::default(),
+            Default::default(),
+            bininfo,
+            move |progress: crate::utils::Progress| tx.send(progress.phase).unwrap(),
+        );
+        block_on(flashed).unwrap();
+        assert!(rx.try_iter().count() > 0);
+        let flash = block_on(d.with(|d| d.flash())).unwrap();
+        assert_eq!(&flash[0x2000..0x2000 + 600], &binary[..]);
+    }
+
+    #[test]
+    fn in_order() {
+        let d = AsyncDevice::new(FixtureDevice::new(SAMD21));
+        let (tx, rx) = mpsc::channel();
+        let started: Vec<_> = (0..20)
+            .map(|i| {
+                let tx = tx.clone();
+                d.with(move |_| tx.send(i).unwrap())
+            })
+            .collect();
+        for job in started {
+            block_on(job).unwrap();
+        }
+        assert_eq!(
+            rx.try_iter().collect::<Vec<_>>(),
+            (0..20).collect::<Vec<_>>()
+        );
+    }
+
+    #[test]
+    fn panicked() {
+        let d = AsyncDevice::new(FixtureDevice::new(SAMD21));
+        let e = block_on(d.with(|_| -> u32 { panic!("wedged") })).unwrap_err();
+        assert_eq!(e.kind(), ErrorKind::Panicked);
+        assert_eq!(std::error::Error::source(&e).unwrap().to_string(), "wedged");
+        assert!(block_on(d.bin_info()).is_ok());
+    }
+}
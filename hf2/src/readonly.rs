@@ -0,0 +1,53 @@
+use crate::session::state;
+use crate::ReadWrite;
+
+///Whether d's session refuses every command that changes the device, see SessionOptions::read_only.
+pub fn read_only(d: &(impl ReadWrite + ?Sized)) -> bool {
+    state(d).options().read_only
+}
+
+///Whether a command may change the device, resets and starting a flash included. Only the reads
+///the spec defines and PROTOCOL_VERSION are known to leave it alone, vendor commands, ie a stdin
+///command, could do anything so they count as changing it.
+pub fn mutates(command_id: u32) -> bool {
+    !matches!(
+        command_id,
+        0x0001 | 0x0002 | 0x0007 | 0x0008 | 0x0010 | crate::PROTOCOL_VERSION
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{FixtureDevice, SAMD21};
+    use crate::{ErrorKind, Session, SessionOptions};
+
+    #[test]
+    fn refused() {
+        let options = SessionOptions {
+            read_only: true,
+            ..Default::default()
+        };
+        let d = Session::with_options(FixtureDevice::new(SAMD21), options);
+        assert!(read_only(&d) && mutates(0x8001) && !mutates(0x0008));
+        let refused = |result: Result<(), crate::Error>| result.unwrap_err().kind();
+
+        assert_eq!(
+            refused(crate::write_words(&d, 0x2000, 1, vec![1])),
+            ErrorKind::ReadOnly
+        );
+        assert_eq!(refused(crate::reset_into_app(&d)), ErrorKind::ReadOnly);
+        assert_eq!(
+            refused(crate::write_stdin(&d, 0x8001, b"x")),
+            ErrorKind::ReadOnly
+        );
+        assert!(crate::bin_info(&d).is_ok());
+        assert!(crate::read_words(&d, 0, 1).is_ok());
+        // nothing that would change it was sent
+        assert_eq!(d.device().commands(), vec![0x0001, 0x0008]);
+
+        // another session on the same process isn't read only
+        let writable = Session::new(FixtureDevice::new(SAMD21));
+        assert!(crate::write_words(&writable, 0x2000, 1, vec![1]).is_ok());
+    }
+}
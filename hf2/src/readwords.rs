@@ -1,30 +1,32 @@
-use crate::command::{rx, xmit, Command, CommandResponse, CommandResponseStatus};
-use crate::Error;
+use crate::command::{xfer, Command};
+use crate::{Error, ErrorKind, ReadWrite};
 use scroll::{ctx, Pread, Pwrite, LE};
 
-///Read a number of words from memory. Memory is read word by word (and not byte by byte), and target_addr must be suitably aligned. This is to support reading of special IO regions.
+///Read a number of words from memory. Memory is read word by word (and not byte by byte), and target_addr must be suitably aligned. This is to support reading of special IO regions. Unaligned addresses are refused with ErrorKind::Arguments before anything is sent.
 pub fn read_words(
-    d: &hidapi::HidDevice,
+    d: &impl ReadWrite,
     target_address: u32,
     num_words: u32,
 ) -> Result<ReadWordsResponse, Error> {
+    if !target_address.is_multiple_of(4) || num_words == 0 {
+        return Err(Error::new(ErrorKind::Arguments)
+            .during(0x0008)
+            .at(target_address));
+    }
+
     let mut buffer = vec![0_u8; 8];
     let mut offset = 0;
 
     buffer.gwrite_with(target_address, &mut offset, scroll::LE)?;
     buffer.gwrite_with(num_words, &mut offset, scroll::LE)?;
 
-    xmit(Command::new(0x0008, 0, buffer), d)?;
-
-    match rx(d) {
-        Ok(CommandResponse {
-            status: CommandResponseStatus::Success,
-            data,
-            ..
-        }) => (data.as_slice()).pread_with(0, LE),
-        Ok(_) => Err(Error::CommandNotRecognized),
-        Err(e) => Err(e),
+    let failed = |e: Error| e.during(0x0008).at(target_address);
+    let rsp = xfer(Command::new(0x0008, buffer), d).map_err(failed)?;
+    let rsp: ReadWordsResponse = (rsp.data.as_slice()).pread_with(0, LE).map_err(failed)?;
+    if rsp.words.len() != num_words as usize {
+        return Err(failed(ErrorKind::Parse.into()));
     }
+    Ok(rsp)
 }
 
 ///Response to the read_words command
@@ -37,7 +39,7 @@ impl<'a> ctx::TryFromCtx<'a, scroll::Endian> for ReadWordsResponse {
     type Error = Error;
     fn try_from_ctx(this: &'a [u8], le: scroll::Endian) -> Result<(Self, usize), Self::Error> {
         if this.len() < 4 {
-            return Err(Error::Parse);
+            return Err(ErrorKind::Parse.into());
         }
 
         let mut words: Vec<u32> = vec![0; this.len() / 4];
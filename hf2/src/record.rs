@@ -0,0 +1,102 @@
+use crate::{Error, ReadWrite};
+use std::cell::RefCell;
+use std::fs::File;
+use std::io::{self, Write};
+use std::path::Path;
+use std::time::Duration;
+
+///One HID report of a recording, as written to the device or as read from it.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Report {
+    ///Written, starting with the report id.
+    Sent(Vec<u8>),
+    Received(Vec<u8>),
+}
+
+///Passes every report through to device and writes a copy to log, a line of hex per report, > for
+///sent and < for received. Replay a recording with testing::MockTransport.
+pub struct Recorder<D, W> {
+    device: D,
+    log: RefCell<W>,
+}
+
+impl<D: ReadWrite> Recorder<D, File> {
+    ///Record to a new file at path.
+    pub fn create(device: D, path: impl AsRef<Path>) -> io::Result<Self> {
+        let mut log = File::create(path)?;
+        writeln!(log, "# hf2 recording, > sent and < received reports in hex")?;
+        Ok(Recorder::new(device, log))
+    }
+}
+
+impl<D: ReadWrite, W: Write> Recorder<D, W> {
+    pub fn new(device: D, log: W) -> Self {
+        Recorder {
+            device,
+            log: RefCell::new(log),
+        }
+    }
+
+    pub fn into_inner(self) -> (D, W) {
+        (self.device, self.log.into_inner())
+    }
+
+    fn record(&self, direction: char, report: &[u8]) {
+        let hex: String = report.iter().map(|byte| format!("{:02x}", byte)).collect();
+        // a recording missing a line is still worth more than a failed command
+        if let Err(e) = writeln!(self.log.borrow_mut(), "{} {}", direction, hex) {
+            log::warn!("couldn't record report, {}", e);
+        }
+    }
+}
+
+impl<D: ReadWrite, W: Write> ReadWrite for Recorder<D, W> {
+    fn hf2_write(&self, data: &[u8]) -> Result<usize, Error> {
+        self.record('>', data);
+        self.device.hf2_write(data)
+    }
+    fn hf2_read(&self, buf: &mut [u8]) -> Result<usize, Error> {
+        let count = self.device.hf2_read(buf)?;
+        if count > 0 {
+            self.record('<', &buf[..count]);
+        }
+        Ok(count)
+    }
+    fn hf2_read_timeout(&self, buf: &mut [u8], timeout: Duration) -> Result<usize, Error> {
+        let count = self.device.hf2_read_timeout(buf, timeout)?;
+        if count > 0 {
+            self.record('<', &buf[..count]);
+        }
+        Ok(count)
+    }
+    fn session(&self) -> Option<&crate::SessionState> {
+        self.device.session()
+    }
+}
+
+///Reads back what a Recorder wrote, skipping blank lines and # comments.
+pub fn parse_recording(recording: &str) -> Result<Vec<Report>, String> {
+    recording
+        .lines()
+        .enumerate()
+        .map(|(index, line)| (index + 1, line.trim()))
+        .filter(|(_, line)| !line.is_empty() && !line.starts_with('#'))
+        .map(|(number, line)| {
+            let invalid = || format!("line {} isn't a recorded report, {}", number, line);
+            let (direction, hex) = line.split_once(' ').ok_or_else(invalid)?;
+            let bytes = (0..hex.len())
+                .step_by(2)
+                .map(|at| {
+                    hex.get(at..at + 2)
+                        .and_then(|byte| u8::from_str_radix(byte, 16).ok())
+                })
+                .collect::<Option<Vec<u8>>>()
+                .ok_or_else(invalid)?;
+            match direction {
+                ">" => Ok(Report::Sent(bytes)),
+                "<" => Ok(Report::Received(bytes)),
+                _ => Err(invalid()),
+            }
+        })
+        .collect()
+}
@@ -0,0 +1,344 @@
+use crate::{mutates, Error, ErrorKind, ReadWrite};
+use std::io::{self, Read, Write};
+use std::net::{TcpStream, ToSocketAddrs};
+use std::time::Duration;
+
+// once a frame's length arrived the rest is already on its way
+const FRAME_TIMEOUT: Duration = Duration::from_millis(1000);
+// how long the agent waits on either side before checking the other
+const AGENT_POLL: Duration = Duration::from_millis(5);
+// how long either side waits for the other's half of the token exchange
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(5);
+// most of one command the agent holds for a read only client, well past any max message size
+const MAX_HELD: usize = 64 * 1024;
+
+///What a client may do through an agent, decided by the token it connected with.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Access {
+    ///Only commands that don't change the device, see mutates.
+    ReadOnly,
+    ///Every command, including flashing.
+    Flash,
+}
+
+///A device attached to another machine running an agent. The client opens with a frame holding its
+///token and the agent answers with the Access it grants, or hangs up. After that raw HID reports
+///are relayed as frames, a length byte then the report, everything else happens on this side.
+pub struct RemoteDevice {
+    stream: TcpStream,
+    access: Access,
+}
+
+impl RemoteDevice {
+    ///Connect to an agent with one of the tokens it was started with.
+    pub fn connect(addr: impl ToSocketAddrs, token: &str) -> Result<Self, Error> {
+        if token.is_empty() {
+            return Err(ErrorKind::Arguments.into());
+        }
+        let stream = TcpStream::connect(addr).map_err(transmission)?;
+        stream.set_nodelay(true).map_err(transmission)?;
+        write_frame(&stream, token.as_bytes())?;
+
+        let buf = &mut [0_u8; 1];
+        let access = match read_frame(&stream, buf, HANDSHAKE_TIMEOUT)? {
+            None => return Err(refused()),
+            Some(0) => return Err(ErrorKind::Timeout.into()),
+            Some(_) if buf[0] == Access::ReadOnly as u8 => Access::ReadOnly,
+            Some(_) if buf[0] == Access::Flash as u8 => Access::Flash,
+            Some(_) => return Err(ErrorKind::Parse.into()),
+        };
+        Ok(RemoteDevice { stream, access })
+    }
+
+    ///What the agent lets this connection do. Commands a read only connection isn't allowed get it
+    ///disconnected, so open the Session with read_only to refuse them before they're sent.
+    pub fn access(&self) -> Access {
+        self.access
+    }
+}
+
+impl ReadWrite for RemoteDevice {
+    fn hf2_write(&self, data: &[u8]) -> Result<usize, Error> {
+        write_frame(&self.stream, data)?;
+        Ok(data.len())
+    }
+    fn hf2_read(&self, buf: &mut [u8]) -> Result<usize, Error> {
+        self.hf2_read_timeout(buf, Duration::from_millis(1000))
+    }
+    fn hf2_read_timeout(&self, buf: &mut [u8], timeout: Duration) -> Result<usize, Error> {
+        read_frame(&self.stream, buf, timeout)?.ok_or_else(|| ErrorKind::Transmission.into())
+    }
+}
+
+fn write_frame(mut stream: &TcpStream, data: &[u8]) -> Result<(), Error> {
+    if data.len() > u8::MAX as usize {
+        return Err(ErrorKind::Arguments.into());
+    }
+    let mut frame = vec![data.len() as u8];
+    frame.extend_from_slice(data);
+    stream.write_all(&frame).map_err(transmission)
+}
+
+fn transmission(err: io::Error) -> Error {
+    Error::caused_by(ErrorKind::Transmission, err)
+}
+
+fn refused() -> Error {
+    transmission(io::Error::new(
+        io::ErrorKind::PermissionDenied,
+        "the agent refused the token",
+    ))
+}
+
+// looks at every byte whatever the first difference, so timing doesn't give the token away
+fn same(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |diff, (a, b)| diff | (a ^ b)) == 0
+}
+
+// Some(0) if nothing arrived before timeout, None once the other side hung up
+fn read_frame(
+    mut stream: &TcpStream,
+    buf: &mut [u8],
+    timeout: Duration,
+) -> Result<Option<usize>, Error> {
+    // a zero timeout means block forever to std
+    let timeout = timeout.max(Duration::from_millis(1));
+    stream
+        .set_read_timeout(Some(timeout))
+        .map_err(transmission)?;
+
+    let mut len = [0_u8];
+    match stream.read(&mut len) {
+        Ok(0) => return Ok(None),
+        Ok(_) => {}
+        Err(e) if e.kind() == io::ErrorKind::WouldBlock || e.kind() == io::ErrorKind::TimedOut => {
+            return Ok(Some(0))
+        }
+        Err(e) => return Err(transmission(e)),
+    }
+
+    let len = len[0] as usize;
+    let mut frame = vec![0; len];
+    stream
+        .set_read_timeout(Some(FRAME_TIMEOUT))
+        .map_err(transmission)?;
+    stream.read_exact(&mut frame).map_err(transmission)?;
+
+    let count = len.min(buf.len());
+    buf[..count].copy_from_slice(&frame[..count]);
+    Ok(Some(count))
+}
+
+///Relay reports between a client connection and a local device until the client disconnects. This
+///is the whole agent, it only looks at the command id of each command a read only client sends, so
+///it never needs updating alongside the client. A client whose token isn't one of tokens is hung up
+///on, as is a read only client sending a command that mutates the device. A read only client's
+///reports are held until the final packet of its command and the id is checked in the message the
+///device will put together from them, so how the client frames a command can't sneak one past.
+pub fn serve_agent(
+    d: &impl ReadWrite,
+    stream: &TcpStream,
+    tokens: &[(String, Access)],
+) -> Result<(), Error> {
+    stream.set_nodelay(true).map_err(transmission)?;
+    let token = &mut [0_u8; u8::MAX as usize];
+    let access = match read_frame(stream, token, HANDSHAKE_TIMEOUT)? {
+        Some(count) if count > 0 => tokens
+            .iter()
+            .find(|(allowed, _)| same(allowed.as_bytes(), &token[..count]))
+            .map(|(_, access)| *access),
+        _ => None,
+    };
+    let access = access.ok_or_else(refused)?;
+    write_frame(stream, &[access as u8])?;
+
+    let buffer = &mut [0_u8; 65];
+    // a read only client's reports of the command in progress, and their payloads joined
+    let mut held: Vec<Vec<u8>> = vec![];
+    let mut message = vec![];
+
+    loop {
+        match read_frame(stream, buffer, AGENT_POLL)? {
+            None => return Ok(()),
+            Some(0) => {}
+            Some(count) if access == Access::Flash => {
+                d.hf2_write(&buffer[..count])?;
+            }
+            Some(count) => {
+                // the report id, then the header with the packet type and payload length
+                let report = &buffer[..count];
+                let header = report.get(1).copied().unwrap_or_default();
+                let payload = report.get(2..).unwrap_or_default();
+                message.extend_from_slice(&payload[..payload.len().min((header & 0x3F) as usize)]);
+                held.push(report.to_vec());
+                if message.len() > MAX_HELD {
+                    return Err(ErrorKind::Arguments.into());
+                }
+
+                // inner packets are followed by more of the same command
+                if report.len() < 2 || header >> 6 != 0 {
+                    let id = message
+                        .get(..4)
+                        .map(|id| u32::from_le_bytes([id[0], id[1], id[2], id[3]]));
+                    match id {
+                        Some(id) if mutates(id) => {
+                            return Err(Error::new(ErrorKind::ReadOnly).during(id))
+                        }
+                        Some(_) => {}
+                        None => return Err(ErrorKind::ReadOnly.into()),
+                    }
+                    for report in held.drain(..) {
+                        d.hf2_write(&report)?;
+                    }
+                    message.clear();
+                }
+            }
+        }
+
+        let count = d.hf2_read_timeout(buffer, AGENT_POLL)?;
+        if count > 0 {
+            write_frame(stream, &buffer[..count])?;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{FixtureDevice, SAMD21};
+    use std::net::TcpListener;
+
+    #[test]
+    fn frames() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let client = RemoteDevice {
+            stream: TcpStream::connect(listener.local_addr().unwrap()).unwrap(),
+            access: Access::Flash,
+        };
+        let (agent, _) = listener.accept().unwrap();
+
+        client.hf2_write(&[1, 2, 3]).unwrap();
+        let buf = &mut [0_u8; 64];
+        assert_eq!(
+            read_frame(&agent, buf, Duration::from_millis(1000)).unwrap(),
+            Some(3)
+        );
+        assert_eq!(&buf[..3], &[1, 2, 3]);
+
+        // nothing sent yet
+        assert_eq!(
+            client
+                .hf2_read_timeout(buf, Duration::from_millis(10))
+                .unwrap(),
+            0
+        );
+
+        write_frame(&agent, &[4, 5]).unwrap();
+        assert_eq!(client.hf2_read(buf).unwrap(), 2);
+        assert_eq!(&buf[..2], &[4, 5]);
+
+        drop(client);
+        assert_eq!(
+            read_frame(&agent, buf, Duration::from_millis(1000)).unwrap(),
+            None
+        );
+    }
+
+    #[test]
+    fn tokens() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        let agent = std::thread::spawn(move || {
+            let d = FixtureDevice::new(SAMD21);
+            let tokens = [
+                ("flash".to_string(), Access::Flash),
+                ("look".to_string(), Access::ReadOnly),
+            ];
+            (0..3)
+                .map(|_| serve_agent(&d, &listener.accept().unwrap().0, &tokens))
+                .collect::<Vec<_>>()
+        });
+
+        assert_eq!(
+            RemoteDevice::connect(address, "guess")
+                .err()
+                .map(|e| e.kind()),
+            Some(ErrorKind::Transmission)
+        );
+
+        let client = RemoteDevice::connect(address, "look").unwrap();
+        assert_eq!(client.access(), Access::ReadOnly);
+        assert_eq!(crate::bin_info(&client).unwrap().geometry, SAMD21.geometry);
+        crate::reset_into_app(&client).unwrap();
+        assert!(crate::bin_info(&client).is_err());
+
+        let client = RemoteDevice::connect(address, "flash").unwrap();
+        assert_eq!(client.access(), Access::Flash);
+        crate::reset_into_app(&client).unwrap();
+        drop(client);
+
+        let served = agent.join().unwrap();
+        assert!(served[0].is_err());
+        assert_eq!(
+            served[1].as_ref().map_err(|e| (e.kind(), e.command())),
+            Err((ErrorKind::ReadOnly, Some(0x0003)))
+        );
+        assert!(served[2].is_ok());
+    }
+
+    // reports as sent by the client, a report id then the header
+    fn report(final_packet: bool, payload: &[u8]) -> Vec<u8> {
+        let ptype = if final_packet { 0x40 } else { 0 };
+        let mut report = vec![0, ptype | payload.len() as u8];
+        report.extend_from_slice(payload);
+        report
+    }
+
+    #[test]
+    fn read_only_framing() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        let agent = std::thread::spawn(move || {
+            let d = FixtureDevice::new(SAMD21);
+            let tokens = [("look".to_string(), Access::ReadOnly)];
+            let served = (0..3)
+                .map(|_| serve_agent(&d, &listener.accept().unwrap().0, &tokens))
+                .collect::<Vec<_>>();
+            (served, d.commands())
+        });
+        let reset = [0x03, 0, 0, 0, 1, 0, 0, 0];
+
+        // an empty inner packet used to let the next report through unchecked
+        let client = RemoteDevice::connect(address, "look").unwrap();
+        client.hf2_write(&report(false, &[])).unwrap();
+        client.hf2_write(&report(true, &reset)).unwrap();
+        assert!(crate::bin_info(&client).is_err());
+
+        // a mutating command split over an inner and a final packet
+        let client = RemoteDevice::connect(address, "look").unwrap();
+        client.hf2_write(&report(false, &reset[..6])).unwrap();
+        client.hf2_write(&report(true, &reset[6..])).unwrap();
+        assert!(crate::bin_info(&client).is_err());
+
+        // one that doesn't mutate goes through whole once its final packet arrives
+        let client = RemoteDevice::connect(address, "look").unwrap();
+        client
+            .hf2_write(&report(false, &[0x01, 0, 0, 0, 2, 0]))
+            .unwrap();
+        client.hf2_write(&report(true, &[0, 0])).unwrap();
+        let buf = &mut [0_u8; 64];
+        assert!(client.hf2_read(buf).unwrap() > 0);
+        assert_eq!(buf[1..3], [2, 0]);
+        drop(client);
+
+        let (served, commands) = agent.join().unwrap();
+        for refused in &served[..2] {
+            assert_eq!(
+                refused.as_ref().map_err(|e| (e.kind(), e.command())),
+                Err((ErrorKind::ReadOnly, Some(0x0003)))
+            );
+        }
+        assert!(served[2].is_ok());
+        assert_eq!(commands, [0x0001]);
+    }
+}
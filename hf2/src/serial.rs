@@ -0,0 +1,190 @@
+use crate::command::{send, Command, PacketType};
+use crate::{Error, ErrorKind, ReadWrite};
+use core::convert::TryFrom;
+
+// bytes of serial output kept for read_serial, the oldest is dropped past it
+const MAX_PENDING: usize = 64 * 1024;
+
+///Serial output from the device, sent outside of any command response.
+#[derive(Debug, PartialEq)]
+pub enum SerialOutput {
+    StdOut(Vec<u8>),
+    StdErr(Vec<u8>),
+}
+
+///Default id of the exit status event, in the HF2 event range with 0x800000 set.
+pub const EXIT_STATUS_EVENT: u32 = 0x0080_0001;
+
+///What firmware under test sent, serial output or the exit status it finished with.
+#[derive(Debug, PartialEq)]
+pub enum RunOutput {
+    Serial(SerialOutput),
+    Exit(i32),
+}
+
+///Wait for one packet of serial output. None if nothing arrived before the read timed out or the packet wasn't serial.
+pub fn read_serial(d: &impl ReadWrite) -> Result<Option<SerialOutput>, Error> {
+    if let Some(output) = take_pending(d) {
+        return Ok(Some(output));
+    }
+    match rx_packet(d)? {
+        Some((ptype, data)) => Ok(serial(ptype, data)),
+        None => Ok(None),
+    }
+}
+
+///Like read_serial, also recognizing the event on-device test runners send when done. Firmware
+///sends it unprompted as a single final packet, the event id then the exit code, both 32 bit
+///little endian, ie `[0x48, 0x01, 0x00, 0x80, 0x00, 0x00, 0x00, 0x00, 0x00]` for exit code 0.
+pub fn read_run_output(d: &impl ReadWrite, event_id: u32) -> Result<Option<RunOutput>, Error> {
+    if let Some(output) = take_pending(d) {
+        return Ok(Some(RunOutput::Serial(output)));
+    }
+    let (ptype, data) = match rx_packet(d)? {
+        Some(packet) => packet,
+        None => return Ok(None),
+    };
+
+    if ptype == PacketType::Final && data.len() == 8 && data[..4] == event_id.to_le_bytes() {
+        let code = i32::from_le_bytes([data[4], data[5], data[6], data[7]]);
+        return Ok(Some(RunOutput::Exit(code)));
+    }
+    Ok(serial(ptype, data).map(RunOutput::Serial))
+}
+
+///Send console input to firmware implementing a vendor "stdin" command with the given id. The response isn't waited for, it arrives interleaved with serial output and read_serial skips it.
+pub fn write_stdin(d: &impl ReadWrite, command_id: u32, data: &[u8]) -> Result<(), Error> {
+    send(Command::new(command_id, data.to_vec()), d)
+}
+
+fn rx_packet(d: &impl ReadWrite) -> Result<Option<(PacketType, Vec<u8>)>, Error> {
+    let buffer = &mut [0_u8; 64];
+
+    let count = d.hf2_read(buffer)?;
+    if count < 1 {
+        return Ok(None);
+    }
+
+    let ptype = PacketType::try_from(buffer[0] >> 6)?;
+    let len: usize = (buffer[0] & 0x3F) as usize;
+    if len >= count {
+        return Err(ErrorKind::Parse.into());
+    }
+
+    let data = buffer[1..(len + 1)].to_vec();
+    log::debug!("rx serial {:?}: {:02X?}", ptype, data);
+    Ok(Some((ptype, data)))
+}
+
+///Serial output from the device as it arrives, blocking until there's more. Ends after the first
+///error, ie the device going away.
+pub fn serial_stream<D: ReadWrite>(d: &D) -> SerialStream<'_, D> {
+    SerialStream { d, done: false }
+}
+
+///Iterator returned by serial_stream.
+pub struct SerialStream<'a, D> {
+    d: &'a D,
+    done: bool,
+}
+
+impl<D: ReadWrite> Iterator for SerialStream<'_, D> {
+    type Item = Result<SerialOutput, Error>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        while !self.done {
+            match read_serial(self.d) {
+                Ok(Some(output)) => return Some(Ok(output)),
+                Ok(None) => continue,
+                Err(e) => {
+                    self.done = true;
+                    return Some(Err(e));
+                }
+            }
+        }
+        None
+    }
+}
+
+// keeps serial output a command's response was interleaved with in d's session, a bare transport
+// has nowhere to keep it for
+pub(crate) fn stash(d: &impl ReadWrite, output: SerialOutput) {
+    let mut pending = match d.session() {
+        Some(state) => state.pending(),
+        None => {
+            log::debug!(
+                "dropping {} bytes of serial output, the device has no session",
+                output.data().len()
+            );
+            return;
+        }
+    };
+    pending.push_back(output);
+    let mut size: usize = pending.iter().map(|output| output.data().len()).sum();
+    while size > MAX_PENDING {
+        if let Some(dropped) = pending.pop_front() {
+            log::warn!(
+                "dropping {} bytes of unread serial output",
+                dropped.data().len()
+            );
+            size -= dropped.data().len();
+        }
+    }
+}
+
+fn take_pending(d: &impl ReadWrite) -> Option<SerialOutput> {
+    d.session()?.pending().pop_front()
+}
+
+impl SerialOutput {
+    pub fn data(&self) -> &[u8] {
+        match self {
+            SerialOutput::StdOut(data) | SerialOutput::StdErr(data) => data,
+        }
+    }
+}
+
+pub(crate) fn serial(ptype: PacketType, data: Vec<u8>) -> Option<SerialOutput> {
+    match ptype {
+        PacketType::StdOut => Some(SerialOutput::StdOut(data)),
+        PacketType::Stderr => Some(SerialOutput::StdErr(data)),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::MockTransport;
+    use crate::{Report, Session};
+
+    #[test]
+    fn interleaved() {
+        let info = vec![0x00, 0x48, 0x02, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00];
+        let d = Session::new(MockTransport::new([
+            Report::Sent(info),
+            Report::Received(vec![0x08, 0x00, 0x00, 0x00, 0x00, b'U', b'F', b'2', b' ']),
+            Report::Received(vec![0x83, b'h', b'i', b'\n']),
+            Report::Received(vec![0xC2, b'!', b'\n']),
+            Report::Received(vec![0x44, b'B', b'o', b'o', b't']),
+        ]));
+        let other = Session::new(MockTransport::new([Report::Received(vec![
+            0x82, b'a', b'\n',
+        ])]));
+
+        assert_eq!(crate::info(&d).unwrap().info, "UF2 Boot");
+        assert_eq!(
+            read_serial(&other).unwrap(),
+            Some(SerialOutput::StdOut(b"a\n".to_vec()))
+        );
+        assert_eq!(
+            read_serial(&d).unwrap(),
+            Some(SerialOutput::StdOut(b"hi\n".to_vec()))
+        );
+        assert_eq!(
+            read_serial(&d).unwrap(),
+            Some(SerialOutput::StdErr(b"!\n".to_vec()))
+        );
+        assert_eq!(d.device().remaining(), 0);
+    }
+}
@@ -0,0 +1,213 @@
+use crate::{Crc16, Error, ReadWrite, ResponseLimits, RetryPolicy, SerialOutput};
+use std::collections::VecDeque;
+use std::sync::atomic::{AtomicU16, AtomicU32, Ordering};
+use std::sync::{Mutex, MutexGuard, OnceLock};
+use std::time::{Duration, Instant};
+
+///How commands to one device are sent, set when it's wrapped in a Session, so devices driven from
+///the same process, ie by a provisioning station, each keep their own.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct SessionOptions {
+    ///Drain whatever reports are waiting before sending each command, so a response left over from
+    ///a command that timed out can't be read in place of the next one's. Stale responses are
+    ///dropped by their tag either way, this also clears out partial ones.
+    pub resync: bool,
+    ///Refuse every command that changes the device, failing it with ErrorKind::ReadOnly before
+    ///it's sent, so an inspection session on a production board can't write, erase or reset it by
+    ///mistake. See mutates for which commands count.
+    pub read_only: bool,
+    ///Response timeouts of command ids that wait longer or shorter than default_timeout.
+    pub timeouts: Vec<(u32, Duration)>,
+    ///How often commands are sent again when they time out or their answer arrives garbled, by
+    ///command id. By default nothing is retried.
+    pub retry_policies: Vec<(u32, RetryPolicy)>,
+    ///Wait this long between the reports of a command spanning several, for bootloaders that drop
+    ///reports sent back to back. Zero, the default, writes them without pausing.
+    pub write_pacing: Duration,
+    ///Stop sending commands once this passes, failing the next with ErrorKind::Deadline. It's
+    ///checked before each command is sent, so a flash stops between pages with the board left in
+    ///its bootloader, and a command already sent still gets its own timeout.
+    pub deadline: Option<Instant>,
+    ///Spec minimal lock step, a baseline for telling whether an optimization upsets a bootloader.
+    ///Checksums are asked for a page at a time, reads and writes are sized to fit one report
+    ///instead of max_message_size, batches run every access on its own and delta is ignored. Slow.
+    ///Retry policies are still followed, leave them empty for a true baseline.
+    pub compat: bool,
+    ///Flashing only writes the pages whose checksum differs from the image, so reflashing a build
+    ///that mostly didn't change takes a checksum pass instead of a full write. A page that changed
+    ///but kept its CRC-16 is skipped too, VerifyMode::Full still catches it.
+    pub delta: bool,
+    ///Keep writing the remaining pages after the bootloader refuses one, reporting every refused
+    ///page at the end instead of stopping at the first.
+    pub continue_on_error: bool,
+    ///Bounds on response reassembly, otherwise they follow the device's last bininfo.
+    pub response_limits: Option<ResponseLimits>,
+    ///The CRC-16 the bootloader checksums pages with, for verify and delta. CRC-16-CCITT, the
+    ///spec's, unless it's a vendor fork using another.
+    pub checksum: Crc16,
+}
+
+impl SessionOptions {
+    ///Response timeout of a command id, overridden or default.
+    pub fn timeout(&self, command_id: u32) -> Duration {
+        self.timeouts
+            .iter()
+            .rev()
+            .find(|(id, _)| *id == command_id)
+            .map(|(_, timeout)| *timeout)
+            .unwrap_or_else(|| crate::default_timeout(command_id))
+    }
+
+    ///Retry policy of a command id, set or default.
+    pub fn retry_policy(&self, command_id: u32) -> RetryPolicy {
+        self.retry_policies
+            .iter()
+            .rev()
+            .find(|(id, _)| *id == command_id)
+            .map(|(_, policy)| *policy)
+            .unwrap_or_default()
+    }
+}
+
+///What a Session keeps about the conversation with its device.
+#[derive(Debug)]
+pub struct SessionState {
+    options: SessionOptions,
+    next_tag: AtomicU16,
+    // from the device's last bininfo
+    max_message_size: Mutex<Option<u32>>,
+    // serial output that arrived while a command waited for its response, for read_serial
+    pending: Mutex<VecDeque<SerialOutput>>,
+    // how many times the last command was sent, for reporting retries
+    attempts: AtomicU32,
+}
+
+impl SessionState {
+    fn new(options: SessionOptions) -> Self {
+        SessionState {
+            options,
+            next_tag: AtomicU16::new(1),
+            max_message_size: Mutex::new(None),
+            pending: Mutex::new(VecDeque::new()),
+            attempts: AtomicU32::new(0),
+        }
+    }
+
+    pub fn options(&self) -> &SessionOptions {
+        &self.options
+    }
+
+    ///Tag for the next command, counting up and wrapping so every command in flight has its own
+    ///and a late response to one that gave up can't be taken for the answer to the next.
+    pub(crate) fn next_tag(&self) -> u16 {
+        self.next_tag.fetch_add(1, Ordering::Relaxed)
+    }
+
+    pub(crate) fn derived_limits(&self) -> Option<ResponseLimits> {
+        self.max_message_size()
+            .map(ResponseLimits::for_message_size)
+    }
+
+    ///max_message_size of the device's last bininfo, None until one was read.
+    pub fn max_message_size(&self) -> Option<u32> {
+        *self
+            .max_message_size
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+    }
+
+    ///Times the last command was sent, more than 1 when its retry policy sent it again. Bare
+    ///transports share one count, so it's only meaningful for them from a single thread.
+    pub fn attempts(&self) -> u32 {
+        self.attempts.load(Ordering::Relaxed)
+    }
+
+    pub(crate) fn set_attempts(&self, attempts: u32) {
+        self.attempts.store(attempts, Ordering::Relaxed);
+    }
+
+    pub(crate) fn pending(&self) -> MutexGuard<'_, VecDeque<SerialOutput>> {
+        self.pending.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    pub(crate) fn set_max_message_size(&self, max_message_size: u32) {
+        *self
+            .max_message_size
+            .lock()
+            .unwrap_or_else(|e| e.into_inner()) = Some(max_message_size);
+    }
+}
+
+///A device and the state of the conversation with it, the tags of its commands, the options
+///they're sent with, the response limits its bininfo called for and serial output that arrived
+///while a command waited for its response. Every command works on a bare
+///transport too, with default options and tags shared with the other bare transports of the
+///process.
+pub struct Session<D> {
+    device: D,
+    state: SessionState,
+}
+
+impl<D: ReadWrite> Session<D> {
+    pub fn new(device: D) -> Self {
+        Session::with_options(device, SessionOptions::default())
+    }
+
+    pub fn with_options(device: D, options: SessionOptions) -> Self {
+        Session {
+            device,
+            state: SessionState::new(options),
+        }
+    }
+
+    pub fn device(&self) -> &D {
+        &self.device
+    }
+
+    pub fn options(&self) -> &SessionOptions {
+        self.state.options()
+    }
+
+    pub fn into_inner(self) -> D {
+        self.device
+    }
+}
+
+impl<D: ReadWrite> ReadWrite for Session<D> {
+    fn hf2_write(&self, data: &[u8]) -> Result<usize, Error> {
+        self.device.hf2_write(data)
+    }
+    fn hf2_read(&self, buf: &mut [u8]) -> Result<usize, Error> {
+        self.device.hf2_read(buf)
+    }
+    fn hf2_read_timeout(&self, buf: &mut [u8], timeout: Duration) -> Result<usize, Error> {
+        self.device.hf2_read_timeout(buf, timeout)
+    }
+    fn session(&self) -> Option<&SessionState> {
+        Some(&self.state)
+    }
+}
+
+///The session of d, or the one bare transports share.
+pub(crate) fn state<D: ReadWrite + ?Sized>(d: &D) -> &SessionState {
+    static BARE: OnceLock<SessionState> = OnceLock::new();
+    d.session()
+        .unwrap_or_else(|| BARE.get_or_init(|| SessionState::new(SessionOptions::default())))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{FixtureDevice, SAMD21};
+
+    #[test]
+    fn tags_per_session() {
+        let first = Session::new(FixtureDevice::new(SAMD21));
+        let second = Session::new(FixtureDevice::new(SAMD21));
+        assert_eq!(state(&first).next_tag(), 1);
+        assert_eq!(state(&first).next_tag(), 2);
+        assert_eq!(state(&second).next_tag(), 1);
+        crate::bin_info(&second).unwrap();
+        assert_eq!(state(&&second).next_tag(), 3);
+    }
+}
@@ -0,0 +1,290 @@
+//! The bootloader FixtureDevice and DryRun both answer as, so they agree on what a command does.
+
+use crate::{BinInfoMode, BinInfoResponse, Error, ErrorKind, ReadWrite};
+use std::cell::RefCell;
+use std::collections::{HashMap, VecDeque};
+use std::time::Duration;
+
+///Where simulated RAM starts, RAM_SIZE bytes of it.
+pub const RAM_START: u32 = 0x2000_0000;
+pub const RAM_SIZE: u32 = 0x1000;
+
+///An in memory UF2 bootloader answering HF2 reports.
+pub(crate) struct Simulator {
+    info: String,
+    bininfo: BinInfoResponse,
+    // refuse accesses outside flash and RAM like a bootloader would, a dry run takes any address,
+    // ie external flash
+    bounded: bool,
+    checksum: fn(&[u8]) -> u16,
+    pub(crate) state: RefCell<State>,
+}
+
+pub(crate) struct State {
+    page_size: u32,
+    // written bytes by page address, everything else reads as erased
+    pages: HashMap<u32, Vec<u8>>,
+    pub(crate) ram: Vec<u8>,
+    pub(crate) bootloader: bool,
+    pub(crate) dmesg: String,
+    incoming: Vec<u8>,
+    pub(crate) outgoing: VecDeque<Vec<u8>>,
+    // id and argument bytes of every command, in order
+    pub(crate) received: Vec<(u32, Vec<u8>)>,
+    // faults tests set up, see FixtureDevice
+    pub(crate) bad_page: Option<u32>,
+    pub(crate) read_protected: bool,
+    pub(crate) refused_reads: Vec<u32>,
+    pub(crate) protected_pages: Vec<u32>,
+    pub(crate) unsupported: Vec<u32>,
+    pub(crate) protocol: Option<u32>,
+    pub(crate) lost: u32,
+}
+
+impl Simulator {
+    ///Erased flash, in the bootloader unless bininfo's mode says otherwise.
+    pub(crate) fn new(
+        info: &str,
+        bininfo: BinInfoResponse,
+        bounded: bool,
+        checksum: fn(&[u8]) -> u16,
+    ) -> Self {
+        Simulator {
+            info: info.into(),
+            state: RefCell::new(State {
+                page_size: bininfo.geometry.page_size,
+                pages: HashMap::new(),
+                ram: vec![0; RAM_SIZE as usize],
+                bootloader: bininfo.mode == BinInfoMode::Bootloader,
+                dmesg: String::new(),
+                incoming: vec![],
+                outgoing: VecDeque::new(),
+                received: vec![],
+                bad_page: None,
+                read_protected: false,
+                refused_reads: vec![],
+                protected_pages: vec![],
+                unsupported: vec![],
+                protocol: None,
+                lost: 0,
+            }),
+            bininfo,
+            bounded,
+            checksum,
+        }
+    }
+
+    fn command(&self, state: &mut State, message: &[u8]) {
+        if message.len() < 8 {
+            return;
+        }
+        let word = |bytes: &[u8], i: usize| {
+            bytes
+                .get(i..i + 4)
+                .map_or(0, |w| u32::from_le_bytes([w[0], w[1], w[2], w[3]]))
+        };
+        let id = word(message, 0);
+        let tag = [message[4], message[5]];
+        let args = &message[8..];
+        state.received.push((id, args.to_vec()));
+
+        let geometry = self.bininfo.geometry;
+        let page_size = geometry.page_size;
+        let in_flash =
+            |address: u32, len: u32| !self.bounded || geometry.contains(address, len as u64);
+        let in_ram = |address: u32, len: u32| {
+            address >= RAM_START && address as u64 + len as u64 <= (RAM_START + RAM_SIZE) as u64
+        };
+        let enter_bootloader = |state: &mut State| {
+            if !state.bootloader {
+                state.ram.fill(0);
+            }
+            state.bootloader = true;
+        };
+
+        let response: Result<Vec<u8>, u8> = match id {
+            _ if state.unsupported.contains(&id) => Err(1),
+            0x0001 => {
+                let mode: u32 = if state.bootloader { 1 } else { 2 };
+                let mut data = vec![];
+                for word in [
+                    mode,
+                    page_size,
+                    geometry.num_pages,
+                    self.bininfo.max_message_size,
+                ] {
+                    data.extend_from_slice(&word.to_le_bytes());
+                }
+                if let Some(family_id) = self.bininfo.family_id {
+                    data.extend_from_slice(&u32::from(family_id).to_le_bytes());
+                }
+                Ok(data)
+            }
+            0x0002 => Ok(self.info.as_bytes().to_vec()),
+            // resets don't answer
+            0x0003 => {
+                state.bootloader = false;
+                return;
+            }
+            0x0004 => {
+                enter_bootloader(state);
+                return;
+            }
+            0x0005 => {
+                enter_bootloader(state);
+                Ok(vec![])
+            }
+            0x0006 => {
+                let address = word(args, 0);
+                let data = &args[4.min(args.len())..];
+                if (self.bounded && !geometry.is_aligned(address))
+                    || !in_flash(address, data.len() as u32)
+                    || state.protected_pages.contains(&geometry.page_of(address))
+                {
+                    Err(2)
+                } else {
+                    state.write(address, data);
+                    if state.bad_page == Some(geometry.page_of(address)) && !data.is_empty() {
+                        let first = state.read(address, 1)[0];
+                        state.write(address, &[first ^ 0xFF]);
+                    }
+                    Ok(vec![])
+                }
+            }
+            0x0007 => {
+                let (address, pages) = (word(args, 0), word(args, 4));
+                if !in_flash(address, pages * page_size) {
+                    Err(2)
+                } else {
+                    Ok(state
+                        .read(address, pages * page_size)
+                        .chunks(page_size as usize)
+                        .flat_map(|page| (self.checksum)(page).to_le_bytes())
+                        .collect())
+                }
+            }
+            0x0008 => {
+                let (address, words) = (word(args, 0), word(args, 4));
+                let last = address + (words * 4).saturating_sub(1);
+                let refused = (geometry.page_of(address)..=geometry.page_of(last))
+                    .any(|page| state.refused_reads.contains(&page));
+                if in_ram(address, words * 4) {
+                    let start = (address - RAM_START) as usize;
+                    Ok(state.ram[start..][..words as usize * 4].to_vec())
+                } else if !in_flash(address, words * 4) || refused {
+                    Err(2)
+                } else if state.read_protected {
+                    Ok(vec![0; words as usize * 4])
+                } else {
+                    Ok(state.read(address, words * 4))
+                }
+            }
+            0x0009 => {
+                let (address, words) = (word(args, 0), word(args, 4));
+                let data = &args[8.min(args.len())..];
+                if !in_flash(address, words * 4) || data.len() < words as usize * 4 {
+                    Err(2)
+                } else {
+                    state.write(address, &data[..words as usize * 4]);
+                    Ok(vec![])
+                }
+            }
+            0x0010 => Ok(state.dmesg.as_bytes().to_vec()),
+            crate::PROTOCOL_VERSION => state.protocol.map(u32::to_le_bytes).map(Vec::from).ok_or(1),
+            _ => Err(1),
+        };
+
+        if state.lost > 0 {
+            state.lost -= 1;
+            return;
+        }
+        let mut message = tag.to_vec();
+        match response {
+            Ok(data) => {
+                message.extend_from_slice(&[0, 0]);
+                message.extend_from_slice(&data);
+            }
+            Err(status) => message.extend_from_slice(&[status, 0]),
+        }
+
+        let chunks: Vec<&[u8]> = message.chunks(63).collect();
+        for (i, chunk) in chunks.iter().enumerate() {
+            let ptype = if i + 1 == chunks.len() { 1 } else { 0 };
+            let mut packet = vec![ptype << 6 | chunk.len() as u8];
+            packet.extend_from_slice(chunk);
+            // real devices always send whole reports
+            packet.resize(64, 0);
+            state.outgoing.push_back(packet);
+        }
+    }
+}
+
+impl State {
+    ///Flash contents, erased where nothing was written.
+    pub(crate) fn read(&self, address: u32, len: u32) -> Vec<u8> {
+        let page_size = self.page_size as u64;
+        let mut bytes = Vec::with_capacity(len as usize);
+        let (mut address, end) = (address as u64, address as u64 + len as u64);
+        while address < end {
+            let offset = address % page_size;
+            let count = (page_size - offset).min(end - address);
+            match self.pages.get(&((address - offset) as u32)) {
+                Some(page) => bytes.extend_from_slice(&page[offset as usize..][..count as usize]),
+                None => bytes.resize(bytes.len() + count as usize, 0xFF),
+            }
+            address += count;
+        }
+        bytes
+    }
+
+    pub(crate) fn write(&mut self, address: u32, data: &[u8]) {
+        let page_size = self.page_size as u64;
+        let (mut address, mut data) = (address as u64, data);
+        while !data.is_empty() {
+            let offset = address % page_size;
+            let count = ((page_size - offset) as usize).min(data.len());
+            let page = self
+                .pages
+                .entry((address - offset) as u32)
+                .or_insert_with(|| vec![0xFF; page_size as usize]);
+            page[offset as usize..][..count].copy_from_slice(&data[..count]);
+            address += count as u64;
+            data = &data[count..];
+        }
+    }
+}
+
+impl ReadWrite for Simulator {
+    fn hf2_write(&self, data: &[u8]) -> Result<usize, Error> {
+        // report id then the packet header
+        if data.len() < 2 {
+            return Err(ErrorKind::Transmission.into());
+        }
+        let header = data[1];
+        let len = (header & 0x3F) as usize;
+        let payload = data
+            .get(2..2 + len)
+            .ok_or(Error::new(ErrorKind::Transmission))?;
+
+        let mut state = self.state.borrow_mut();
+        state.incoming.extend_from_slice(payload);
+        if header >> 6 == 1 {
+            let message = std::mem::take(&mut state.incoming);
+            self.command(&mut state, &message);
+        }
+        Ok(data.len())
+    }
+    fn hf2_read(&self, buf: &mut [u8]) -> Result<usize, Error> {
+        match self.state.borrow_mut().outgoing.pop_front() {
+            Some(packet) => {
+                let count = packet.len().min(buf.len());
+                buf[..count].copy_from_slice(&packet[..count]);
+                Ok(count)
+            }
+            None => Ok(0),
+        }
+    }
+    fn hf2_read_timeout(&self, buf: &mut [u8], _timeout: Duration) -> Result<usize, Error> {
+        self.hf2_read(buf)
+    }
+}
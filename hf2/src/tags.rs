@@ -0,0 +1,51 @@
+use crate::session::state;
+use crate::ReadWrite;
+use std::time::Duration;
+
+// most reports a drain reads, so a device streaming serial output can't hold it forever
+const DRAIN_LIMIT: usize = 1024;
+
+///Tag for the next command to d, from its session.
+pub(crate) fn next_tag(d: &(impl ReadWrite + ?Sized)) -> u16 {
+    state(d).next_tag()
+}
+
+///Whether a response tagged got is a late answer to a command sent before the one tagged expected,
+///rather than one from the future, which means the device isn't echoing tags properly.
+pub(crate) fn is_stale(expected: u16, got: u16) -> bool {
+    (1..0x8000).contains(&expected.wrapping_sub(got))
+}
+
+///Whether d's session drains pending reports before each command, see SessionOptions::resync.
+pub(crate) fn resync(d: &(impl ReadWrite + ?Sized)) -> bool {
+    state(d).options().resync
+}
+
+///Read and throw away the reports the device has already sent, returning how many there were.
+pub fn drain(d: &impl ReadWrite) -> usize {
+    let buffer = &mut [0_u8; 64];
+    let mut drained = 0;
+    while drained < DRAIN_LIMIT {
+        match d.hf2_read_timeout(buffer, Duration::ZERO) {
+            Ok(count) if count > 0 => drained += 1,
+            _ => break,
+        }
+    }
+    if drained > 0 {
+        log::debug!("drained {} pending reports", drained);
+    }
+    drained
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn stale() {
+        assert!(is_stale(5, 4));
+        assert!(is_stale(2, 0xFFFE));
+        assert!(!is_stale(5, 5));
+        assert!(!is_stale(5, 6));
+    }
+}
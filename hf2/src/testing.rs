@@ -0,0 +1,686 @@
+//! Simulated boards for testing code built on this crate without hardware. A FixtureDevice is a
+//! hand written simulator of the HF2 commands, keeping its flash in memory, and can be passed to
+//! any command or utils function in place of a HidDevice. Its boards' geometry and limits follow
+//! the stock UF2 bootloaders' sources, only the SAMD51 INFO_UF2.TXT is from a real board, so it
+//! shows how a flow drives the protocol but not every quirk of real hardware, ie timing or partial
+//! reports. A MockTransport instead plays back exact reports, canned or recorded from real
+//! hardware with a Recorder, for pinning down what a real bootloader answers.
+
+use crate::simulator::{Simulator, State};
+use crate::{parse_recording, BinInfoMode, BinInfoResponse, ReadWrite, Report};
+use crate::{Error, ErrorKind, FamilyId, FlashGeometry};
+use std::cell::{RefCell, RefMut};
+use std::collections::VecDeque;
+use std::time::Duration;
+
+/// What a simulated board reports about itself.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Board {
+    /// INFO_UF2.TXT contents, returned by the info command.
+    pub info: &'static str,
+    pub family_id: u32,
+    pub geometry: FlashGeometry,
+    pub max_message_size: u32,
+}
+
+impl Board {
+    pub fn family(&self) -> FamilyId {
+        self.family_id.into()
+    }
+}
+
+/// Where the simulated boards' RAM starts, every board has RAM_SIZE bytes of it.
+pub use crate::simulator::{RAM_SIZE, RAM_START};
+
+/// Adafruit Feather M0 style SAMD21 with the uf2-samdx1 bootloader.
+pub const SAMD21: Board = Board {
+    info:
+        "UF2 Bootloader v3.7.0 SFHWRO\r\nModel: Feather M0\r\nBoard-ID: SAMD21G18A-Feather-v0\r\n",
+    family_id: 0x68ed_2b88,
+    geometry: FlashGeometry {
+        page_size: 256,
+        num_pages: 1024,
+    },
+    max_message_size: 320,
+};
+
+/// Adafruit PyGamer SAMD51 with the uf2-samdx1 bootloader.
+pub const SAMD51: Board = Board {
+    info: "UF2 Bootloader v3.6.0 SFHWRO\r\nModel: PyGamer\r\nBoard-ID: SAMD51J19A-PyGamer-M4\r\n",
+    family_id: 0x5511_4460,
+    geometry: FlashGeometry {
+        page_size: 512,
+        num_pages: 1024,
+    },
+    max_message_size: 576,
+};
+
+/// nRF52840 with the Adafruit nRF52 bootloader.
+pub const NRF52840: Board = Board {
+    info: "UF2 Bootloader 0.3.2 s140 6.1.1\r\nModel: Feather nRF52840 Express\r\nBoard-ID: nRF52840-Feather-revD\r\n",
+    family_id: 0x1b57_745f,
+    geometry: FlashGeometry {
+        page_size: 4096,
+        num_pages: 256,
+    },
+    max_message_size: 4160,
+};
+
+/// A simulated board, see the module docs for how far it can be trusted.
+pub struct FixtureDevice {
+    board: Board,
+    simulator: Simulator,
+}
+
+impl FixtureDevice {
+    /// A board sitting in its bootloader with erased flash.
+    pub fn new(board: Board) -> Self {
+        let bininfo = BinInfoResponse {
+            mode: BinInfoMode::Bootloader,
+            geometry: board.geometry,
+            max_message_size: board.max_message_size,
+            family_id: Some(board.family()),
+        };
+        FixtureDevice {
+            board,
+            simulator: Simulator::new(board.info, bininfo, true, crc16),
+        }
+    }
+
+    fn state(&self) -> RefMut<'_, State> {
+        self.simulator.state.borrow_mut()
+    }
+
+    /// Contents of flash.
+    pub fn flash(&self) -> Vec<u8> {
+        self.state()
+            .read(0, self.board.geometry.total_size() as u32)
+    }
+
+    /// Overwrite flash, ie to set up a SoftDevice or settings page before a test.
+    pub fn set_flash(&self, address: u32, data: &[u8]) {
+        self.state().write(address, data);
+    }
+
+    /// Overwrite RAM, ie a static the app keeps up to date. Entering the bootloader clears it, like
+    /// a bootloader reusing the RAM of the app.
+    pub fn set_ram(&self, address: u32, data: &[u8]) {
+        let ram = &mut self.state().ram;
+        ram[(address - RAM_START) as usize..][..data.len()].copy_from_slice(data);
+    }
+
+    /// Whether the board is in its bootloader, as opposed to reset into the app.
+    pub fn in_bootloader(&self) -> bool {
+        self.state().bootloader
+    }
+
+    /// Ids of every command received so far, in order.
+    pub fn commands(&self) -> Vec<u32> {
+        self.state().received.iter().map(|(id, _)| *id).collect()
+    }
+
+    /// Writes to the page holding address store a flipped first byte, like a worn out page.
+    pub fn fail_writes(&self, address: u32) {
+        self.state().bad_page = Some(self.board.geometry.page_of(address));
+    }
+
+    /// Writes to the page holding address are refused with an execution error, like a write
+    /// protected page.
+    pub fn protect_writes(&self, address: u32) {
+        let page = self.board.geometry.page_of(address);
+        self.state().protected_pages.push(page);
+    }
+
+    /// Reads of flash return zeros, like a part with readout protection enabled.
+    pub fn protect_reads(&self) {
+        self.state().read_protected = true;
+    }
+
+    /// Reads touching the page holding address are refused with an execution error, like a part
+    /// with readout protection on part of its flash.
+    pub fn refuse_reads(&self, address: u32) {
+        let page = self.board.geometry.page_of(address);
+        self.state().refused_reads.push(page);
+    }
+
+    /// The command is answered as not understood, like a bootloader that doesn't implement it.
+    pub fn unsupported(&self, command_id: u32) {
+        self.state().unsupported.push(command_id);
+    }
+
+    /// The next count responses never arrive, like a marginal cable or hub.
+    pub fn lose_responses(&self, count: u32) {
+        self.state().lost = count;
+    }
+
+    /// Answer PROTOCOL_VERSION with version, like a bootloader with HF2 extensions. Stock
+    /// bootloaders don't know the command.
+    pub fn set_protocol(&self, version: u32) {
+        self.state().protocol = Some(version);
+    }
+
+    /// Contents of the dmesg buffer.
+    pub fn set_dmesg(&self, logs: &str) {
+        self.state().dmesg = logs.into();
+    }
+
+    /// Queue serial output as the app would send it, stderr when stderr is set.
+    pub fn serial(&self, data: &[u8], stderr: bool) {
+        let ptype = if stderr { 3 } else { 2 };
+        let mut state = self.state();
+        for chunk in data.chunks(63) {
+            let mut packet = vec![ptype << 6 | chunk.len() as u8];
+            packet.extend_from_slice(chunk);
+            state.outgoing.push_back(packet);
+        }
+    }
+
+    /// Queue the exit status event an on-device test runner sends when it's done.
+    pub fn exit_status(&self, event_id: u32, code: i32) {
+        let mut packet = vec![1 << 6 | 8];
+        packet.extend_from_slice(&event_id.to_le_bytes());
+        packet.extend_from_slice(&code.to_le_bytes());
+        self.state().outgoing.push_back(packet);
+    }
+}
+
+impl ReadWrite for FixtureDevice {
+    fn hf2_write(&self, data: &[u8]) -> Result<usize, Error> {
+        self.simulator.hf2_write(data)
+    }
+    fn hf2_read(&self, buf: &mut [u8]) -> Result<usize, Error> {
+        self.simulator.hf2_read(buf)
+    }
+    fn hf2_read_timeout(&self, buf: &mut [u8], timeout: Duration) -> Result<usize, Error> {
+        self.simulator.hf2_read_timeout(buf, timeout)
+    }
+}
+
+/// Plays back reports in order, answering reads with the received ones and checking each write
+/// against the next sent one, panicking where they differ. Tags are taken from the commands
+/// actually sent and put in the responses, so a recording replays whatever tags it was made with.
+/// Once the reports run out the device is gone.
+pub struct MockTransport {
+    reports: RefCell<VecDeque<Report>>,
+    exchange: RefCell<Exchange>,
+}
+
+// where in their messages the last report each way was, for finding the tags
+#[derive(Default)]
+struct Exchange {
+    sending: bool,
+    receiving: bool,
+    tag: [u8; 2],
+    played: usize,
+}
+
+impl MockTransport {
+    pub fn new(reports: impl IntoIterator<Item = Report>) -> Self {
+        MockTransport {
+            reports: RefCell::new(reports.into_iter().collect()),
+            exchange: RefCell::new(Exchange::default()),
+        }
+    }
+
+    /// Plays back a file a Recorder wrote.
+    pub fn replay(path: impl AsRef<std::path::Path>) -> std::io::Result<Self> {
+        let recording = std::fs::read_to_string(path)?;
+        parse_recording(&recording)
+            .map(MockTransport::new)
+            .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidData, e))
+    }
+
+    /// Reports not played back yet.
+    pub fn remaining(&self) -> usize {
+        self.reports.borrow().len()
+    }
+}
+
+impl ReadWrite for MockTransport {
+    fn hf2_write(&self, data: &[u8]) -> Result<usize, Error> {
+        let mut exchange = self.exchange.borrow_mut();
+        let expected = match self.reports.borrow_mut().pop_front() {
+            Some(Report::Sent(expected)) => expected,
+            Some(Report::Received(_)) => panic!(
+                "report {} was sent {:02x?} while a response was due",
+                exchange.played, data
+            ),
+            None => return Err(ErrorKind::Transmission.into()),
+        };
+
+        // report id, header, command id then the tag
+        let mut sent = data.to_vec();
+        if !exchange.sending && sent.len() >= 8 {
+            exchange.tag = [sent[6], sent[7]];
+            sent[6..8].copy_from_slice(expected.get(6..8).unwrap_or(&[0, 0]));
+        }
+        assert_eq!(
+            sent, expected,
+            "report {} sent differs from the one expected",
+            exchange.played
+        );
+        exchange.sending = data.get(1).is_some_and(|header| header >> 6 == 0);
+        exchange.played += 1;
+        Ok(data.len())
+    }
+    fn hf2_read(&self, buf: &mut [u8]) -> Result<usize, Error> {
+        let mut reports = self.reports.borrow_mut();
+        let mut packet = match reports.front() {
+            Some(Report::Received(_)) => match reports.pop_front() {
+                Some(Report::Received(packet)) => packet,
+                _ => unreachable!(),
+            },
+            // nothing due yet, as if it timed out
+            Some(Report::Sent(_)) => return Ok(0),
+            None => return Err(ErrorKind::Transmission.into()),
+        };
+
+        let mut exchange = self.exchange.borrow_mut();
+        // header then the tag, serial packets don't carry one
+        let ptype = packet.first().map_or(0, |header| header >> 6);
+        if ptype <= 1 {
+            if !exchange.receiving && packet.len() >= 3 {
+                packet[1..3].copy_from_slice(&exchange.tag);
+            }
+            exchange.receiving = ptype == 0;
+        }
+        exchange.played += 1;
+
+        let count = packet.len().min(buf.len());
+        buf[..count].copy_from_slice(&packet[..count]);
+        Ok(count)
+    }
+    fn hf2_read_timeout(&self, buf: &mut [u8], _timeout: Duration) -> Result<usize, Error> {
+        self.hf2_read(buf)
+    }
+}
+
+// CRC-16-CCITT as used by the checksum command
+fn crc16(data: &[u8]) -> u16 {
+    let mut crc: u16 = 0;
+    for byte in data {
+        crc ^= (*byte as u16) << 8;
+        for _ in 0..8 {
+            crc = if crc & 0x8000 != 0 {
+                crc << 1 ^ 0x1021
+            } else {
+                crc << 1
+            };
+        }
+    }
+    crc
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{BinInfoMode, Recorder};
+
+    #[test]
+    fn fixtures() {
+        for board in [SAMD21, SAMD51, NRF52840] {
+            let d = FixtureDevice::new(board);
+
+            let bininfo = crate::bin_info(&d).unwrap();
+            assert_eq!(bininfo.mode, BinInfoMode::Bootloader);
+            assert_eq!(bininfo.geometry, board.geometry);
+            assert_eq!(bininfo.family_id, Some(board.family()));
+
+            let info = crate::info(&d).unwrap();
+            assert_eq!(info.info, board.info);
+        }
+    }
+
+    #[test]
+    fn replayed() {
+        // recorded from a simulated board, then played back without it
+        let d = FixtureDevice::new(SAMD21);
+        let recorder = Recorder::new(&d, vec![]);
+        let bininfo = crate::bin_info(&recorder).unwrap();
+        crate::write_flash_page(&recorder, 0x2000, vec![0x5A; 256]).unwrap();
+        let checksums = crate::checksum_pages(&recorder, 0x2000, 2).unwrap();
+        let (_, recording) = recorder.into_inner();
+
+        let reports = parse_recording(&String::from_utf8(recording).unwrap()).unwrap();
+        assert!(matches!(reports[0], Report::Sent(_)));
+        let mock = MockTransport::new(reports);
+        assert_eq!(crate::bin_info(&mock).unwrap(), bininfo);
+        crate::write_flash_page(&mock, 0x2000, vec![0x5A; 256]).unwrap();
+        assert_eq!(crate::checksum_pages(&mock, 0x2000, 2).unwrap(), checksums);
+        assert_eq!(mock.remaining(), 0);
+        // and then the device is gone
+        assert_eq!(
+            crate::bin_info(&mock).unwrap_err().kind(),
+            ErrorKind::Transmission
+        );
+    }
+
+    #[test]
+    #[should_panic(expected = "differs")]
+    fn replay_mismatch() {
+        let d = FixtureDevice::new(SAMD21);
+        let recorder = Recorder::new(&d, vec![]);
+        crate::write_flash_page(&recorder, 0x2000, vec![0x5A; 256]).unwrap();
+        let (_, recording) = recorder.into_inner();
+
+        let mock =
+            MockTransport::new(parse_recording(&String::from_utf8(recording).unwrap()).unwrap());
+        let _ = crate::write_flash_page(&mock, 0x2000, vec![0xA5; 256]);
+    }
+
+    #[cfg(feature = "utils")]
+    #[test]
+    fn flash_round_trip() {
+        use crate::utils::{flash_bin, read_bytes, PadPolicy};
+
+        let d = FixtureDevice::new(SAMD51);
+        let bininfo = crate::bin_info(&d).unwrap();
+
+        let binary: Vec<u8> = (0..1000).map(|i| i as u8).collect();
+        flash_bin(&binary, 0x4000, PadPolicy::Erased, &bininfo, &d).unwrap();
+
+        assert!(!d.in_bootloader());
+        assert_eq!(read_bytes(0x4000, 1000, &bininfo, &d).unwrap(), binary);
+        // padded to the page with erased bytes
+        assert_eq!(d.flash()[0x4000 + 1000..0x4000 + 1024], [0xFF; 24]);
+    }
+
+    #[cfg(feature = "utils")]
+    #[test]
+    fn read_bytes_bounds() {
+        use crate::utils::{read_bytes, UtilError};
+
+        let d = FixtureDevice::new(SAMD21);
+        d.set_flash(0x100, &[1, 2, 3, 4, 5, 6, 7, 8]);
+        let mut bininfo = crate::bin_info(&d).unwrap();
+
+        // unaligned on both ends, and a message that only fits one word per read
+        bininfo.max_message_size = 8;
+        assert_eq!(
+            read_bytes(0x101, 6, &bininfo, &d).unwrap(),
+            [2, 3, 4, 5, 6, 7]
+        );
+
+        for size in [0, 3, 7] {
+            bininfo.max_message_size = size;
+            assert!(matches!(
+                read_bytes(0x100, 4, &bininfo, &d),
+                Err(UtilError::InvalidRegion(_))
+            ));
+        }
+
+        bininfo.max_message_size = 64;
+        assert!(matches!(
+            read_bytes(0xFFFF_FFFE, 4, &bininfo, &d),
+            Err(UtilError::InvalidRegion(_))
+        ));
+    }
+
+    #[cfg(feature = "utils")]
+    #[test]
+    fn regions_by_page() {
+        use crate::utils::{bin_to_uf2, flash_regions, uf2_regions, Region, UtilError};
+
+        let d = FixtureDevice::new(NRF52840);
+        let bininfo = crate::bin_info(&d).unwrap();
+        let region = |address, len| Region {
+            address,
+            binary: vec![0x5A; len],
+            page_size: None,
+        };
+        let refused = |regions: &[Region]| {
+            matches!(
+                flash_regions(
+                    regions,
+                    None,
+                    Default::default(),
+                    Default::default(),
+                    &bininfo,
+                    &d
+                ),
+                Err(UtilError::InvalidRegion(_))
+            )
+        };
+        assert!(refused(&[region(0x2_6100, 256)]));
+        assert!(refused(&[region(0x2_7000, 256), region(0x2_6000, 0x1100)]));
+        assert!(!d.commands().contains(&0x0006));
+
+        // blocks a page apart flash as one region, the gap erased
+        let mut uf2 = bin_to_uf2(&[0x11; 256], 0x2_6000, None);
+        uf2.extend(bin_to_uf2(&[0x22; 256], 0x2_6800, None));
+        let regions = uf2_regions(&uf2, None, bininfo.geometry.page_size).unwrap();
+        flash_regions(
+            &regions,
+            None,
+            Default::default(),
+            Default::default(),
+            &bininfo,
+            &d,
+        )
+        .unwrap();
+        let flash = d.flash();
+        assert_eq!(flash[0x2_6000..0x2_6100], [0x11; 0x100]);
+        assert_eq!(flash[0x2_6100..0x2_6800], [0xFF; 0x700]);
+        assert_eq!(flash[0x2_6800..0x2_6900], [0x22; 0x100]);
+    }
+
+    #[cfg(feature = "utils")]
+    #[test]
+    fn no_reset_after_bad_verify() {
+        use crate::utils::{flash_bin, mismatched_pages, PadPolicy, UtilError};
+
+        let d = FixtureDevice::new(NRF52840);
+        let bininfo = crate::bin_info(&d).unwrap();
+        d.fail_writes(0x2_7000);
+
+        let binary = vec![0x55; 0x3000];
+        let result = flash_bin(&binary, 0x2_6000, PadPolicy::Erased, &bininfo, &d);
+        assert!(matches!(result, Err(UtilError::ContentsDifferent)));
+        assert!(d.in_bootloader());
+        assert!(!d.commands().contains(&0x0003));
+        assert_eq!(
+            mismatched_pages(&binary, 0x2_6000, PadPolicy::Erased, &bininfo, &d).unwrap(),
+            [0x2_7000]
+        );
+    }
+
+    #[cfg(feature = "utils")]
+    #[test]
+    fn full_verify() {
+        use crate::utils::{flash_bin, verify_bin, PadPolicy, VerifyMode};
+
+        let d = FixtureDevice::new(SAMD21);
+        let bininfo = crate::bin_info(&d).unwrap();
+
+        let binary: Vec<u8> = (0..2000).map(|i| (i * 7) as u8).collect();
+        flash_bin(&binary, 0x2000, PadPolicy::Zero, &bininfo, &d).unwrap();
+        verify_bin(
+            &binary,
+            0x2000,
+            PadPolicy::Zero,
+            VerifyMode::Full,
+            &bininfo,
+            &d,
+        )
+        .unwrap();
+
+        d.set_flash(0x2000 + 1500, &[0]);
+        let result = verify_bin(
+            &binary,
+            0x2000,
+            PadPolicy::Zero,
+            VerifyMode::Full,
+            &bininfo,
+            &d,
+        );
+        assert!(result.is_err());
+    }
+
+    #[test]
+    fn read_words_alignment() {
+        let d = FixtureDevice::new(SAMD21);
+        d.set_flash(0x100, &[1, 0, 0, 0, 2, 0, 0, 0]);
+
+        let rsp = crate::read_words(&d, 0x100, 2).unwrap();
+        assert_eq!(rsp.words, vec![1, 2]);
+
+        assert!(matches!(
+            crate::read_words(&d, 0x102, 1),
+            Err(e) if e.kind() == ErrorKind::Arguments
+        ));
+        assert_eq!(d.commands(), vec![0x0008]);
+    }
+
+    #[test]
+    fn write_words_round_trip() {
+        let d = FixtureDevice::new(SAMD21);
+
+        // longer than one report
+        let words: Vec<u32> = (0..40).collect();
+        crate::write_words(&d, 0x200, 40, words.clone()).unwrap();
+        assert_eq!(crate::read_words(&d, 0x200, 40).unwrap().words, words);
+
+        assert!(matches!(
+            crate::write_words(&d, 0x202, 1, vec![0]),
+            Err(e) if e.kind() == ErrorKind::Arguments
+        ));
+        assert!(matches!(
+            crate::write_words(&d, 0x200, 2, vec![0]),
+            Err(e) if e.kind() == ErrorKind::Arguments
+        ));
+        // past the end of memory
+        let e = crate::write_words(&d, 0x4_0000, 1, vec![0]).unwrap_err();
+        assert_eq!(e.kind(), ErrorKind::Execution);
+        assert_eq!((e.command(), e.address()), (Some(0x0009), Some(0x4_0000)));
+    }
+
+    #[cfg(feature = "utils")]
+    #[test]
+    fn rejected_pages() {
+        use crate::utils::{flash_bin, PadPolicy, UtilError};
+        use crate::{Session, SessionOptions};
+
+        let d = FixtureDevice::new(SAMD21);
+        let bininfo = crate::bin_info(&d).unwrap();
+        d.protect_writes(0x2100);
+        d.protect_writes(0x2300);
+
+        let binary = vec![0xAA; 0x400];
+        match flash_bin(&binary, 0x2000, PadPolicy::Zero, &bininfo, &d) {
+            Err(UtilError::PagesRejected(pages)) => {
+                assert_eq!(pages, "page 33 at 0x2100 rejected (likely protected)")
+            }
+            other => panic!("{:?}", other),
+        }
+        assert_eq!(d.flash()[0x2200], 0xFF);
+
+        let options = SessionOptions {
+            continue_on_error: true,
+            ..Default::default()
+        };
+        let keep_going = Session::with_options(&d, options);
+        match flash_bin(&binary, 0x2000, PadPolicy::Zero, &bininfo, &keep_going) {
+            Err(UtilError::PagesRejected(pages)) => assert_eq!(
+                pages,
+                "page 33 at 0x2100, page 35 at 0x2300 rejected (likely protected)"
+            ),
+            other => panic!("{:?}", other),
+        }
+        assert_eq!(d.flash()[0x2200], 0xAA);
+        assert!(d.in_bootloader());
+    }
+
+    #[cfg(feature = "utils")]
+    #[test]
+    fn read_protected_verify() {
+        use crate::utils::{
+            flash_bin, verify_bin_with_progress, PadPolicy, Progress, Unreadable, UtilError,
+            VerifyMode,
+        };
+
+        let d = FixtureDevice::new(SAMD21);
+        let bininfo = crate::bin_info(&d).unwrap();
+        let verify = |binary: &[u8]| {
+            let mut unreadable = vec![];
+            let result = verify_bin_with_progress(
+                binary,
+                0x2000,
+                PadPolicy::Zero,
+                VerifyMode::Full,
+                &bininfo,
+                &d,
+                &mut |progress: Progress| unreadable.extend(progress.unreadable),
+            );
+            (result, unreadable)
+        };
+
+        let binary: Vec<u8> = (0..2000).map(|i| (i * 7) as u8).collect();
+        flash_bin(&binary, 0x2000, PadPolicy::Zero, &bininfo, &d).unwrap();
+
+        // refused reads are reported and checked with checksums rather than left out of the hash
+        d.refuse_reads(0x2200);
+        d.refuse_reads(0x2240);
+        let (result, unreadable) = verify(&binary);
+        result.unwrap();
+        assert_eq!(
+            unreadable,
+            [Unreadable {
+                address: 0x2200,
+                len: 256,
+                refused: true,
+            }]
+        );
+        assert!(matches!(
+            crate::utils::read_bytes(0x2210, 4, &bininfo, &d),
+            Err(UtilError::ReadProtected(_))
+        ));
+
+        // reads are all zeros, so only checksums can tell
+        d.protect_reads();
+        d.state().refused_reads.clear();
+        let (result, unreadable) = verify(&binary);
+        result.unwrap();
+        assert_eq!(
+            unreadable,
+            [Unreadable {
+                address: 0x2000,
+                len: 2048,
+                refused: false,
+            }]
+        );
+
+        d.set_flash(0x2000 + 1500, &[0]);
+        assert!(verify(&binary).0.is_err());
+    }
+
+    #[test]
+    fn run_output() {
+        use crate::{read_run_output, RunOutput, SerialOutput, EXIT_STATUS_EVENT};
+
+        let d = crate::Session::new(FixtureDevice::new(SAMD21));
+        // output sent while a command waits for its response is kept for later
+        d.device().serial(b"boot\n", true);
+        assert_eq!(crate::bin_info(&d).unwrap().geometry, SAMD21.geometry);
+        assert_eq!(
+            crate::serial_stream(&d).next().unwrap().unwrap(),
+            SerialOutput::StdErr(b"boot\n".to_vec())
+        );
+
+        d.device().serial(b"test ok\n", false);
+        d.device().exit_status(0x0080_0002, 1);
+        d.device().exit_status(EXIT_STATUS_EVENT, 3);
+
+        assert_eq!(
+            read_run_output(&d, EXIT_STATUS_EVENT).unwrap(),
+            Some(RunOutput::Serial(SerialOutput::StdOut(
+                b"test ok\n".to_vec()
+            )))
+        );
+        // some other event
+        assert_eq!(read_run_output(&d, EXIT_STATUS_EVENT).unwrap(), None);
+        assert_eq!(
+            read_run_output(&d, EXIT_STATUS_EVENT).unwrap(),
+            Some(RunOutput::Exit(3))
+        );
+    }
+}
@@ -0,0 +1,66 @@
+use crate::session::state;
+use crate::ReadWrite;
+use std::time::{Duration, Instant};
+
+///How long a command is expected to take to respond, page erases and checksums over many pages are far slower than a bininfo.
+pub fn default_timeout(command_id: u32) -> Duration {
+    match command_id {
+        // resets usually never respond at all
+        0x0003 | 0x0004 => Duration::from_millis(0),
+        // handover to the bootloader
+        0x0005 => Duration::from_millis(3000),
+        // bound by a page erase
+        0x0006 => Duration::from_millis(2000),
+        // checksums can cover max_message_size / 2 - 2 pages
+        0x0007 => Duration::from_millis(5000),
+        // answered at once when known, and bootloaders that don't know it may stay silent
+        crate::PROTOCOL_VERSION => Duration::from_millis(200),
+        _ => Duration::from_millis(1000),
+    }
+}
+
+///Response timeout of a command id to d, overridden in its SessionOptions or default.
+pub fn timeout(d: &(impl ReadWrite + ?Sized), command_id: u32) -> Duration {
+    state(d).options().timeout(command_id)
+}
+
+///Pause between reports of one command to d, see SessionOptions::write_pacing.
+pub fn write_pacing(d: &(impl ReadWrite + ?Sized)) -> Duration {
+    state(d).options().write_pacing
+}
+
+///How often a command is sent again when the device was slow to answer or its answer arrived
+///garbled. A device that went away isn't retried, there's nothing to answer.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct RetryPolicy {
+    pub retries: u32,
+    ///Wait before the first retry, doubling before each one after.
+    pub backoff: Duration,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        RetryPolicy {
+            retries: 0,
+            backoff: Duration::from_millis(50),
+        }
+    }
+}
+
+impl RetryPolicy {
+    ///Wait before sending again after attempt, counting from 1.
+    pub fn delay(&self, attempt: u32) -> Duration {
+        self.backoff
+            .saturating_mul(2_u32.saturating_pow(attempt.saturating_sub(1)))
+    }
+}
+
+///Retry policy of a command id to d, set in its SessionOptions or default.
+pub fn retry_policy(d: &(impl ReadWrite + ?Sized), command_id: u32) -> RetryPolicy {
+    state(d).options().retry_policy(command_id)
+}
+
+///When commands to d stop being sent, see SessionOptions::deadline.
+pub fn deadline(d: &(impl ReadWrite + ?Sized)) -> Option<Instant> {
+    state(d).options().deadline
+}
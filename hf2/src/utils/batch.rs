@@ -0,0 +1,194 @@
+use super::{compat, read_bytes, UtilError, COMPAT_WRITE_WORDS};
+use crate::{write_words, BinInfoResponse, ReadWrite};
+
+/// One access in a batch. Reads take any range, writes whole words at word aligned addresses as
+/// registers and RAM are written with WRITE WORDS.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Access {
+    Read { address: u32, len: usize },
+    Write { address: u32, data: Vec<u8> },
+}
+
+impl Access {
+    fn start(&self) -> u32 {
+        match self {
+            Access::Read { address, .. } | Access::Write { address, .. } => *address,
+        }
+    }
+
+    fn end(&self) -> u64 {
+        match self {
+            Access::Read { address, len } => *address as u64 + *len as u64,
+            Access::Write { address, data } => *address as u64 + data.len() as u64,
+        }
+    }
+}
+
+/// Runs accesses in order with as few commands as it can. Consecutive reads that overlap or touch
+/// are read as one range, consecutive writes that continue one another are sent as one, gaps are
+/// never read as they may be registers with side effects. Returns the bytes each access read,
+/// empty for writes. With SessionOptions::compat nothing is
+/// merged.
+pub fn run_batch(
+    accesses: &[Access],
+    bininfo: &BinInfoResponse,
+    d: &impl ReadWrite,
+) -> Result<Vec<Vec<u8>>, UtilError> {
+    for access in accesses {
+        if let Access::Write { address, data } = access {
+            if address % 4 != 0 || data.len() % 4 != 0 || data.is_empty() {
+                return Err(UtilError::InvalidRegion(format!(
+                    "write at {:#x} of {} bytes isn't whole aligned words",
+                    address,
+                    data.len()
+                )));
+            }
+        }
+    }
+
+    let mut results = vec![vec![]; accesses.len()];
+    let mut i = 0;
+    while i < accesses.len() {
+        let is_read = matches!(accesses[i], Access::Read { .. });
+        let run = if compat(d) {
+            1
+        } else {
+            accesses[i..]
+                .iter()
+                .take_while(|access| matches!(access, Access::Read { .. }) == is_read)
+                .count()
+        };
+
+        if is_read {
+            read_run(&accesses[i..i + run], &mut results[i..i + run], bininfo, d)?;
+        } else {
+            write_run(&accesses[i..i + run], bininfo, d)?;
+        }
+        i += run;
+    }
+    Ok(results)
+}
+
+// reads commute, so merge them by address
+fn read_run(
+    reads: &[Access],
+    results: &mut [Vec<u8>],
+    bininfo: &BinInfoResponse,
+    d: &impl ReadWrite,
+) -> Result<(), UtilError> {
+    let mut order: Vec<usize> = (0..reads.len()).collect();
+    order.sort_by_key(|&i| reads[i].start());
+
+    let mut spans: Vec<(u32, u64, Vec<usize>)> = vec![];
+    for i in order {
+        match spans.last_mut() {
+            Some((_, end, members)) if reads[i].start() as u64 <= *end => {
+                *end = (*end).max(reads[i].end());
+                members.push(i);
+            }
+            _ => spans.push((reads[i].start(), reads[i].end(), vec![i])),
+        }
+    }
+
+    for (start, end, members) in spans {
+        let bytes = read_bytes(start, (end - start as u64) as usize, bininfo, d)?;
+        for i in members {
+            let offset = (reads[i].start() - start) as usize;
+            let len = (reads[i].end() - reads[i].start() as u64) as usize;
+            results[i] = bytes[offset..][..len].to_vec();
+        }
+    }
+    Ok(())
+}
+
+// writes don't commute, only join one that picks up where the last left off
+fn write_run(
+    writes: &[Access],
+    bininfo: &BinInfoResponse,
+    d: &impl ReadWrite,
+) -> Result<(), UtilError> {
+    let mut merged: Vec<(u32, Vec<u8>)> = vec![];
+    for write in writes {
+        if let Access::Write { address, data } = write {
+            match merged.last_mut() {
+                Some((start, bytes)) if *start as u64 + bytes.len() as u64 == *address as u64 => {
+                    bytes.extend_from_slice(data)
+                }
+                _ => merged.push((*address, data.clone())),
+            }
+        }
+    }
+
+    // command header, address and count leave the rest of a message for words
+    let max_words = if compat(d) {
+        COMPAT_WRITE_WORDS as usize
+    } else {
+        (bininfo.max_message_size.saturating_sub(16) / 4).max(1) as usize
+    };
+    for (address, bytes) in merged {
+        for (i, chunk) in bytes.chunks(max_words * 4).enumerate() {
+            let words: Vec<u32> = chunk
+                .chunks(4)
+                .map(|word| u32::from_le_bytes([word[0], word[1], word[2], word[3]]))
+                .collect();
+            let target_address = address + (i * max_words * 4) as u32;
+            write_words(d, target_address, words.len() as u32, words).map_err(UtilError::from)?;
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{FixtureDevice, SAMD21};
+
+    #[test]
+    fn batch() {
+        let d = FixtureDevice::new(SAMD21);
+        let bininfo = crate::bin_info(&d).unwrap();
+        d.set_flash(0x100, &[1, 2, 3, 4, 5, 6, 7, 8]);
+        let before = d.commands().len();
+
+        let results = run_batch(
+            &[
+                Access::Read {
+                    address: 0x104,
+                    len: 4,
+                },
+                Access::Read {
+                    address: 0x100,
+                    len: 6,
+                },
+                Access::Write {
+                    address: 0x200,
+                    data: vec![0xAA; 4],
+                },
+                Access::Write {
+                    address: 0x204,
+                    data: vec![0xBB; 4],
+                },
+                Access::Read {
+                    address: 0x200,
+                    len: 8,
+                },
+            ],
+            &bininfo,
+            &d,
+        )
+        .unwrap();
+
+        assert_eq!(results[0], [5, 6, 7, 8]);
+        assert_eq!(results[1], [1, 2, 3, 4, 5, 6]);
+        assert!(results[2].is_empty() && results[3].is_empty());
+        assert_eq!(results[4], [0xAA, 0xAA, 0xAA, 0xAA, 0xBB, 0xBB, 0xBB, 0xBB]);
+        // one read, one write, one read
+        assert_eq!(d.commands()[before..], [0x0008, 0x0009, 0x0008]);
+
+        let unaligned = Access::Write {
+            address: 0x202,
+            data: vec![0; 4],
+        };
+        assert!(run_batch(&[unaligned], &bininfo, &d).is_err());
+    }
+}
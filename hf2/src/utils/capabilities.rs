@@ -0,0 +1,153 @@
+use super::compat;
+use crate::command::command_name;
+use crate::{
+    checksum_pages, default_timeout, dmesg, info, negotiate, read_only, read_words, retry_policy,
+    start_flash, timeout, write_pacing, BinInfoMode, BinInfoResponse, Dialect, Error, ErrorKind,
+    ReadWrite, RetryPolicy,
+};
+
+/// How a bootloader answered the probe of a command.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Support {
+    /// Answered, even if it refused the probe's arguments.
+    Supported,
+    /// Answered that it doesn't understand the command.
+    Unsupported,
+    /// No usable answer in time, some bootloaders ignore commands they don't know.
+    Unanswered,
+    /// Not sent, it would reset the board or change its flash.
+    NotProbed,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct CommandSupport {
+    pub id: u32,
+    pub name: &'static str,
+    pub support: Support,
+}
+
+/// What a bootloader supports and the workarounds this process applies to it, for auditing which
+/// bootloader versions a fleet runs.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Capabilities {
+    pub bininfo: BinInfoResponse,
+    /// INFO_UF2.TXT, naming the bootloader and its version, if it answered.
+    pub info: Option<String>,
+    pub commands: Vec<CommandSupport>,
+    /// Which HF2 extensions it speaks.
+    pub dialect: Dialect,
+    /// Settings in effect for this process that change how commands are sent.
+    pub quirks: Vec<String>,
+}
+
+/// Probes each HF2 command the spec defines that's safe to send, reading and checksumming the
+/// first page of flash and only starting a flash when the board is already in its bootloader and
+/// its session isn't read only.
+pub fn capabilities(bininfo: &BinInfoResponse, d: &impl ReadWrite) -> Capabilities {
+    let support = |result: Result<(), Error>| match result {
+        Ok(()) => Support::Supported,
+        Err(e) => match e.kind() {
+            // understood, just not with these arguments, ie read protected
+            ErrorKind::Execution | ErrorKind::Arguments => Support::Supported,
+            ErrorKind::CommandNotRecognized => Support::Unsupported,
+            _ => Support::Unanswered,
+        },
+    };
+
+    let info = info(d);
+    let mut commands = vec![];
+    for id in [
+        0x0001, 0x0002, 0x0003, 0x0004, 0x0005, 0x0006, 0x0007, 0x0008, 0x0009, 0x0010,
+    ] {
+        let support = match id {
+            // the bininfo is this probe
+            0x0001 => Support::Supported,
+            0x0002 => support(info.as_ref().map(|_| ()).map_err(Error::clone)),
+            0x0005 if bininfo.mode == BinInfoMode::Bootloader && !read_only(d) => {
+                support(start_flash(d))
+            }
+            0x0007 => support(checksum_pages(d, 0, 1).map(|_| ())),
+            0x0008 => support(read_words(d, 0, 1).map(|_| ())),
+            0x0010 => support(dmesg(d).map(|_| ())),
+            _ => Support::NotProbed,
+        };
+        commands.push(CommandSupport {
+            id,
+            name: command_name(id),
+            support,
+        });
+    }
+
+    Capabilities {
+        bininfo: bininfo.clone(),
+        info: info.ok().map(|info| info.info),
+        commands,
+        dialect: negotiate(d),
+        quirks: quirks(d),
+    }
+}
+
+fn quirks(d: &impl ReadWrite) -> Vec<String> {
+    let mut quirks = vec![];
+    if compat(d) {
+        quirks.push("compat, one page checksums and single report commands".to_string());
+    }
+    let pacing = write_pacing(d);
+    if !pacing.is_zero() {
+        quirks.push(format!("{}us between reports", pacing.as_micros()));
+    }
+    if crate::tags::resync(d) {
+        quirks.push("drains pending reports before each command".to_string());
+    }
+    for id in [
+        0x0001, 0x0002, 0x0005, 0x0006, 0x0007, 0x0008, 0x0009, 0x0010,
+    ] {
+        if timeout(d, id) != default_timeout(id) {
+            quirks.push(format!(
+                "{} times out after {}ms",
+                command_name(id),
+                timeout(d, id).as_millis()
+            ));
+        }
+        let policy = retry_policy(d, id);
+        if policy != RetryPolicy::default() {
+            quirks.push(format!(
+                "{} retried {} times from {}ms",
+                command_name(id),
+                policy.retries,
+                policy.backoff.as_millis()
+            ));
+        }
+    }
+    quirks
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{FixtureDevice, SAMD21};
+
+    #[test]
+    fn probed() {
+        let d = FixtureDevice::new(SAMD21);
+        d.unsupported(0x0010);
+        let bininfo = crate::bin_info(&d).unwrap();
+
+        let capabilities = capabilities(&bininfo, &d);
+        let support = |id| {
+            capabilities
+                .commands
+                .iter()
+                .find(|command| command.id == id)
+                .unwrap()
+                .support
+        };
+        assert_eq!(support(0x0008), Support::Supported);
+        assert_eq!(support(0x0010), Support::Unsupported);
+        assert_eq!(support(0x0006), Support::NotProbed);
+        assert!(capabilities.info.unwrap().contains("UF2"));
+        assert_eq!(capabilities.dialect.protocol, 0);
+        // nothing written or reset
+        assert!(!d.commands().iter().any(|id| [3, 4, 6, 9].contains(id)));
+    }
+}
@@ -0,0 +1,127 @@
+use crate::simulator::Simulator;
+use crate::{BinInfoResponse, Crc16, Error, ReadWrite};
+use sha2::{Digest, Sha256};
+use std::fmt;
+use std::time::Duration;
+/// One command a dry run was asked to send.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Step {
+    pub id: u32,
+    /// Target of the memory commands, write flash page, checksum pages and read and write words.
+    pub address: Option<u32>,
+    /// Argument bytes.
+    pub len: usize,
+    /// Start of the SHA-256 of the arguments in hex, enough to tell payloads apart.
+    pub digest: String,
+}
+
+impl Step {
+    pub fn name(&self) -> &'static str {
+        crate::command::command_name(self.id)
+    }
+}
+
+impl fmt::Display for Step {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} {:#06x}", self.name(), self.id)?;
+        if let Some(address) = self.address {
+            write!(f, " at {:#010x}", address)?;
+        }
+        write!(f, " {} bytes sha256 {}", self.len, self.digest)
+    }
+}
+
+/// A stand in device that records the commands a flow sends instead of transmitting them, so what
+/// a flash or manifest would do can be audited, or a flow tested, without touching a board. It
+/// answers like an erased bootloader of the given bininfo, the same simulated bootloader as
+/// testing::FixtureDevice, keeping what's written so checksums and reads agree with it, and START
+/// FLASH moves it into the bootloader. Unlike a fixture it takes any address, ie external flash.
+pub struct DryRun {
+    simulator: Simulator,
+}
+
+impl DryRun {
+    pub fn new(bininfo: BinInfoResponse) -> Self {
+        DryRun {
+            // flows run against it bare, so with the default checksum
+            simulator: Simulator::new("UF2 Bootloader dry run\r\n", bininfo, false, |page| {
+                Crc16::Ccitt.compute(page)
+            }),
+        }
+    }
+
+    /// Every command sent so far, in order.
+    pub fn transcript(&self) -> Vec<Step> {
+        let word = |bytes: &[u8]| {
+            bytes
+                .get(..4)
+                .map_or(0, |w| u32::from_le_bytes([w[0], w[1], w[2], w[3]]))
+        };
+        self.simulator
+            .state
+            .borrow()
+            .received
+            .iter()
+            .map(|(id, args)| Step {
+                id: *id,
+                address: Some(word(args)).filter(|_| (0x0006..=0x0009).contains(id)),
+                len: args.len(),
+                digest: Sha256::digest(args)[..8]
+                    .iter()
+                    .map(|byte| format!("{:02x}", byte))
+                    .collect(),
+            })
+            .collect()
+    }
+}
+
+impl ReadWrite for DryRun {
+    fn hf2_write(&self, data: &[u8]) -> Result<usize, Error> {
+        self.simulator.hf2_write(data)
+    }
+    fn hf2_read(&self, buf: &mut [u8]) -> Result<usize, Error> {
+        self.simulator.hf2_read(buf)
+    }
+    fn hf2_read_timeout(&self, buf: &mut [u8], timeout: Duration) -> Result<usize, Error> {
+        self.simulator.hf2_read_timeout(buf, timeout)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::utils::{flash_bin, PadPolicy};
+    use crate::{BinInfoMode, FamilyId, FlashGeometry};
+
+    #[test]
+    fn flash_transcript() {
+        let dry = DryRun::new(BinInfoResponse {
+            mode: BinInfoMode::User,
+            geometry: FlashGeometry {
+                page_size: 256,
+                num_pages: 1024,
+            },
+            max_message_size: 320,
+            family_id: Some(FamilyId::ATSAMD21),
+        });
+        let bininfo = crate::bin_info(&dry).unwrap();
+        flash_bin(&[0x55; 300], 0x4000, PadPolicy::Erased, &bininfo, &dry).unwrap();
+
+        let steps = dry.transcript();
+        let names: Vec<&str> = steps.iter().map(Step::name).collect();
+        assert_eq!(
+            names,
+            [
+                "bininfo",
+                "start_flash",
+                "write_flash_page",
+                "write_flash_page",
+                "checksum_pages",
+                "reset_into_app"
+            ]
+        );
+        assert_eq!(steps[3].address, Some(0x4100));
+        assert_eq!(steps[3].len, 4 + 256);
+        assert_ne!(steps[2].digest, steps[3].digest);
+    }
+}
@@ -0,0 +1,235 @@
+use super::{read_bytes, UtilError};
+use crate::{BinInfoResponse, ReadWrite};
+use goblin::elf::note::NT_GNU_BUILD_ID;
+use goblin::elf::section_header::{SHF_ALLOC, SHF_WRITE, SHT_NOBITS};
+use std::convert::TryFrom;
+use std::fmt;
+use std::io::Read;
+use std::path::PathBuf;
+
+/// Identifies exactly which build an elf is, from the sections linkers and firmware conventionally
+/// carry, so a deployed device can be tied back to the build it was flashed with.
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct FirmwareInfo {
+    /// Contents of the GNU build id note, `-C link-arg=--build-id` with rust-lld.
+    pub build_id: Option<Vec<u8>>,
+    /// Text of a `.fw_version` section, ie
+    /// `#[link_section = ".fw_version"] static VERSION: [u8; 5] = *b"1.2.0";`
+    pub version: Option<String>,
+}
+
+impl fmt::Display for FirmwareInfo {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.version {
+            Some(version) => write!(f, "version {}", version)?,
+            None => write!(f, "no version")?,
+        }
+        match &self.build_id {
+            Some(id) => {
+                write!(f, " build ")?;
+                id.iter().try_for_each(|byte| write!(f, "{:02x}", byte))
+            }
+            None => write!(f, " no build id"),
+        }
+    }
+}
+
+/// First word of an installed metadata page, "HF2M".
+pub const INSTALLED_MAGIC: u32 = 0x4D32_4648;
+
+impl FirmwareInfo {
+    /// A page of page_size recording this build on the device, the magic then the version and the
+    /// build id, each a length byte followed by its bytes, an absent one having length 0. The rest
+    /// of the page is left erased.
+    pub fn to_page(&self, page_size: u32) -> Result<Vec<u8>, UtilError> {
+        let version = self.version.as_deref().unwrap_or_default().as_bytes();
+        let build_id = self.build_id.as_deref().unwrap_or_default();
+        let mut page = INSTALLED_MAGIC.to_le_bytes().to_vec();
+        for field in [version, build_id] {
+            let len = u8::try_from(field.len()).map_err(|_| {
+                UtilError::InvalidRegion(format!(
+                    "{} byte field doesn't fit a length byte",
+                    field.len()
+                ))
+            })?;
+            page.push(len);
+            page.extend_from_slice(field);
+        }
+        if page.len() > page_size as usize {
+            return Err(UtilError::InvalidRegion(format!(
+                "metadata needs {} bytes but pages are {}",
+                page.len(),
+                page_size
+            )));
+        }
+        page.resize(page_size as usize, 0xFF);
+        Ok(page)
+    }
+
+    /// The build a page written by to_page records, None if it doesn't hold one.
+    pub fn from_page(page: &[u8]) -> Option<FirmwareInfo> {
+        let (magic, mut rest) = page.split_at_checked(4)?;
+        if magic != INSTALLED_MAGIC.to_le_bytes() {
+            return None;
+        }
+        let mut field = || {
+            let (&len, tail) = rest.split_first()?;
+            let (field, tail) = tail.split_at_checked(len as usize)?;
+            rest = tail;
+            Some(field)
+        };
+        let version = field()?;
+        let build_id = field()?;
+        Some(FirmwareInfo {
+            build_id: Some(build_id.to_vec()).filter(|id| !id.is_empty()),
+            version: Some(std::str::from_utf8(version).ok()?.to_string())
+                .filter(|version| !version.is_empty()),
+        })
+    }
+}
+
+/// What the installed metadata page at address says was flashed, None when there isn't one, ie
+/// the page is erased or holds something else.
+pub fn installed_firmware(
+    address: u32,
+    bininfo: &BinInfoResponse,
+    d: &impl ReadWrite,
+) -> Result<Option<FirmwareInfo>, UtilError> {
+    let page = read_bytes(address, bininfo.geometry.page_size as usize, bininfo, d)?;
+    Ok(FirmwareInfo::from_page(&page))
+}
+
+/// Build id and version of an elf file, either may be missing.
+pub fn elf_firmware_info(path: PathBuf) -> Result<FirmwareInfo, UtilError> {
+    let mut file = std::fs::File::open(path).map_err(|_| UtilError::File)?;
+    let mut buffer = vec![];
+    file.read_to_end(&mut buffer).map_err(|_| UtilError::File)?;
+
+    parse_firmware_info(&buffer)
+}
+
+/// Build id and version of an elf already in memory.
+pub fn parse_firmware_info(buffer: &[u8]) -> Result<FirmwareInfo, UtilError> {
+    let elf = goblin::elf::Elf::parse(buffer).map_err(|_| UtilError::Elf)?;
+
+    let build_id = elf
+        .iter_note_sections(buffer, Some(".note.gnu.build-id"))
+        .into_iter()
+        .flatten()
+        .filter_map(Result::ok)
+        .find(|note| note.n_type == NT_GNU_BUILD_ID && note.name == "GNU")
+        .map(|note| note.desc.to_vec());
+
+    let version = elf
+        .section_headers
+        .iter()
+        .find(|sh| matches!(elf.shdr_strtab.get(sh.sh_name), Some(Ok(".fw_version"))))
+        .and_then(|sh| {
+            let start = sh.sh_offset as usize;
+            buffer.get(start..start.checked_add(sh.sh_size as usize)?)
+        })
+        .and_then(|bytes| std::str::from_utf8(bytes).ok())
+        .map(|text| text.trim_end_matches('\0').trim().to_string())
+        .filter(|text| !text.is_empty());
+
+    Ok(FirmwareInfo { build_id, version })
+}
+
+/// A section the linker placed in memory.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Section {
+    pub name: String,
+    pub address: u32,
+    pub size: u32,
+    /// Lives in RAM at runtime, ie .data, .bss and .uninit.
+    pub ram: bool,
+    /// Has contents stored in the image, everything but .bss like sections.
+    pub stored: bool,
+}
+
+/// Allocated sections of an elf, in address order, for size breakdowns.
+pub fn elf_sections(path: PathBuf) -> Result<Vec<Section>, UtilError> {
+    let mut file = std::fs::File::open(path).map_err(|_| UtilError::File)?;
+    let mut buffer = vec![];
+    file.read_to_end(&mut buffer).map_err(|_| UtilError::File)?;
+
+    let elf = goblin::elf::Elf::parse(&buffer).map_err(|_| UtilError::Elf)?;
+    let mut sections: Vec<Section> = elf
+        .section_headers
+        .iter()
+        .filter(|sh| sh.sh_flags & SHF_ALLOC as u64 != 0 && sh.sh_size > 0)
+        .map(|sh| Section {
+            name: match elf.shdr_strtab.get(sh.sh_name) {
+                Some(Ok(name)) => name.to_string(),
+                _ => String::new(),
+            },
+            address: sh.sh_addr as u32,
+            size: sh.sh_size as u32,
+            ram: sh.sh_flags & SHF_WRITE as u64 != 0,
+            stored: sh.sh_type != SHT_NOBITS,
+        })
+        .collect();
+    sections.sort_by_key(|section| section.address);
+    Ok(sections)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn firmware() -> Vec<u8> {
+        // built from testdata/firmware.c
+        let path: PathBuf = [env!("CARGO_MANIFEST_DIR"), "src/utils/testdata/firmware"]
+            .iter()
+            .collect();
+        std::fs::read(path).unwrap()
+    }
+
+    #[test]
+    fn firmware_info() {
+        let info = parse_firmware_info(&firmware()).unwrap();
+        assert_eq!(info.version.as_deref(), Some("1.2.0"));
+        let build_id = info.build_id.as_ref().unwrap();
+        assert_eq!(build_id.len(), 20);
+        assert!(info.to_string().starts_with("version 1.2.0 build "));
+
+        let path: PathBuf = [env!("CARGO_MANIFEST_DIR"), "src/utils/testdata/sections"]
+            .iter()
+            .collect();
+        let info = elf_firmware_info(path).unwrap();
+        assert_eq!(info.version, None);
+        assert_eq!(info.to_string(), "no version no build id");
+
+        assert!(matches!(
+            parse_firmware_info(b"not an elf"),
+            Err(UtilError::Elf)
+        ));
+    }
+
+    #[test]
+    fn installed_page() {
+        use crate::testing::{FixtureDevice, SAMD51};
+
+        let info = parse_firmware_info(&firmware()).unwrap();
+        let page = info.to_page(512).unwrap();
+        assert_eq!(page.len(), 512);
+        assert_eq!(FirmwareInfo::from_page(&page), Some(info.clone()));
+        assert_eq!(
+            FirmwareInfo::from_page(&FirmwareInfo::default().to_page(512).unwrap()),
+            Some(FirmwareInfo::default())
+        );
+        assert!(info.to_page(16).is_err());
+        assert_eq!(FirmwareInfo::from_page(&[0xFF; 512]), None);
+        // a length running past the page
+        assert_eq!(FirmwareInfo::from_page(&page[..8]), None);
+
+        let d = FixtureDevice::new(SAMD51);
+        let bininfo = crate::bin_info(&d).unwrap();
+        assert_eq!(installed_firmware(0x3F000, &bininfo, &d).unwrap(), None);
+        crate::write_flash_page(&d, 0x3F000, page).unwrap();
+        assert_eq!(
+            installed_firmware(0x3F000, &bininfo, &d).unwrap(),
+            Some(info)
+        );
+    }
+}
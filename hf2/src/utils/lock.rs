@@ -0,0 +1,335 @@
+use super::UtilError;
+use crate::{
+    bin_info, is_blocked, probe, start_flash, BinInfoMode, BinInfoResponse, DeviceInfo,
+    DEFAULT_HANDOVER_TIMEOUT, HF2_USAGE_PAGE,
+};
+use hidapi::{HidApi, HidDevice};
+use std::ffi::CStr;
+use std::fs::{File, OpenOptions, TryLockError};
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::time::{Duration, Instant};
+
+// bootloaders answer bininfo in a few ms, a keyboard interface never will
+const PROBE_TIMEOUT: Duration = Duration::from_millis(50);
+const QUEUE_POLL: Duration = Duration::from_millis(200);
+const HANDOVER_POLL: Duration = Duration::from_millis(250);
+
+/// Advisory lock on a device, held until dropped. The OS releases it if the process dies.
+#[derive(Debug)]
+pub struct DeviceLock {
+    _file: File,
+}
+
+/// Takes the per device lock so two processes can't interleave packets to the same device. Fails
+/// with UtilError::Busy and the holders pid when another process has it.
+pub fn lock_device(path: &CStr) -> Result<DeviceLock, UtilError> {
+    let name: String = path
+        .to_string_lossy()
+        .chars()
+        .map(|c| if c.is_ascii_alphanumeric() { c } else { '_' })
+        .collect();
+    let lock_path = std::env::temp_dir().join(format!("hf2-{}.lock", name));
+
+    let mut file = OpenOptions::new()
+        .read(true)
+        .write(true)
+        .create(true)
+        .truncate(false)
+        .open(lock_path)
+        .map_err(|_| UtilError::File)?;
+
+    match file.try_lock() {
+        Ok(()) => (),
+        Err(TryLockError::WouldBlock) => {
+            let mut pid = String::new();
+            let _ = file.read_to_string(&mut pid);
+            return Err(UtilError::Busy(pid.trim().parse().ok()));
+        }
+        Err(TryLockError::Error(_)) => return Err(UtilError::File),
+    }
+
+    file.set_len(0).map_err(|_| UtilError::File)?;
+    file.seek(SeekFrom::Start(0)).map_err(|_| UtilError::File)?;
+    write!(file, "{}", std::process::id()).map_err(|_| UtilError::File)?;
+    file.flush().map_err(|_| UtilError::File)?;
+
+    Ok(DeviceLock { _file: file })
+}
+
+/// Opens and locks the first candidate no other process holds. Stealing ignores other processes
+/// locks, for when a previous session is wedged. Busy if every candidate is held elsewhere. When a
+/// board exposes several interfaces, the one answering BININFO is picked, probing them all at once
+/// so hosts with many keyboards and receivers still pick within a probe timeout. Blocklisted
+/// devices are never opened.
+pub fn open_unlocked(
+    api: &HidApi,
+    candidates: &[DeviceInfo],
+    steal: bool,
+) -> Result<(HidDevice, Option<DeviceLock>), UtilError> {
+    let mut busy = None;
+    let mut unprobed = vec![];
+
+    for info in candidates {
+        if is_blocked(info) {
+            log::debug!("{:?} is blocklisted, skipping", info.path);
+            continue;
+        }
+
+        let lock = match lock_device(&info.path) {
+            Ok(lock) => Some(lock),
+            Err(UtilError::Busy(pid)) if steal => {
+                log::warn!("stealing device from pid {:?}", pid);
+                None
+            }
+            Err(UtilError::Busy(pid)) => {
+                busy = Some(UtilError::Busy(pid));
+                continue;
+            }
+            Err(e) => return Err(e),
+        };
+
+        let d = match info.open(api) {
+            Ok(d) => d,
+            Err(_) => continue,
+        };
+
+        // several interfaces of one composite board, skip any that isn't the hf2 one
+        let composite = info.usage_page != HF2_USAGE_PAGE
+            && candidates.iter().any(|other| {
+                other.path != info.path
+                    && other.vendor_id == info.vendor_id
+                    && other.product_id == info.product_id
+            });
+        if !composite && unprobed.is_empty() {
+            return Ok((d, lock));
+        }
+        unprobed.push((info, d, lock, composite));
+    }
+
+    // in candidate order, the first that needs no probe or answers one
+    let probed = std::thread::scope(|scope| {
+        let probes: Vec<_> = unprobed
+            .into_iter()
+            .map(|(info, d, lock, composite)| {
+                scope.spawn(move || {
+                    let answered = !composite || probe(&d, PROBE_TIMEOUT);
+                    if !answered {
+                        log::debug!("{:?} didn't answer bininfo, skipping", info.path);
+                    }
+                    (answered, d, lock)
+                })
+            })
+            .collect();
+        probes
+            .into_iter()
+            .filter_map(|probe| probe.join().ok())
+            .find(|(answered, _, _)| *answered)
+    });
+
+    match probed {
+        Some((_, d, lock)) => Ok((d, lock)),
+        None => Err(busy.unwrap_or(UtilError::NotFound)),
+    }
+}
+
+/// What a connected interface said when listed.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Probed {
+    /// Answered BININFO, saying whether it's in the bootloader or its app.
+    Answered(BinInfoResponse),
+    /// Held by another process, with its pid, so left alone.
+    Busy(Option<u32>),
+    /// Couldn't be opened or didn't answer, ie another interface of a composite board.
+    Silent,
+}
+
+/// A connected interface and what it answered.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ListedDevice {
+    pub info: DeviceInfo,
+    pub probed: Probed,
+}
+
+/// Probes every candidate with BININFO, all at once, for listing what's connected. Devices
+/// another process holds the lock on aren't touched, and each lock is released before returning.
+pub fn list_devices(api: &HidApi, candidates: &[DeviceInfo]) -> Vec<ListedDevice> {
+    std::thread::scope(|scope| {
+        let probes: Vec<_> = candidates
+            .iter()
+            .filter(|info| !is_blocked(info))
+            .map(|info| {
+                let opened = match lock_device(&info.path) {
+                    Err(UtilError::Busy(pid)) => Err(Probed::Busy(pid)),
+                    lock => info
+                        .open(api)
+                        .map(|d| (d, lock.ok()))
+                        .map_err(|_| Probed::Silent),
+                };
+                scope.spawn(move || {
+                    let probed = match opened {
+                        Ok((d, _lock)) if probe(&d, PROBE_TIMEOUT) => {
+                            bin_info(&d).map_or(Probed::Silent, Probed::Answered)
+                        }
+                        Ok(_) => Probed::Silent,
+                        Err(probed) => probed,
+                    };
+                    ListedDevice {
+                        info: info.clone(),
+                        probed,
+                    }
+                })
+            })
+            .collect();
+        probes
+            .into_iter()
+            .filter_map(|probe| probe.join().ok())
+            .collect()
+    })
+}
+
+/// Like open_unlocked without stealing, but while the device is busy keep retrying until timeout.
+/// Concurrent invocations against one device then run one after another, while different devices
+/// don't wait on each other.
+pub fn open_queued(
+    api: &HidApi,
+    candidates: &[DeviceInfo],
+    timeout: Duration,
+) -> Result<(HidDevice, Option<DeviceLock>), UtilError> {
+    let deadline = Instant::now() + timeout;
+    loop {
+        match open_unlocked(api, candidates, false) {
+            Err(UtilError::Busy(pid)) if Instant::now() < deadline => {
+                log::debug!("device busy (pid {:?}), waiting", pid);
+                std::thread::sleep(QUEUE_POLL);
+            }
+            opened => return opened,
+        }
+    }
+}
+
+/// Hands an opened device running its app over to the bootloader so users don't have to double
+/// tap reset, returning the bootloader and its bininfo. Devices already in the bootloader are
+/// returned as they are. Most boards re-enumerate as a new usb device after START FLASH, so unless
+/// the same handle answers from the bootloader the device is found again among candidates by its
+/// serial number, until timeout. Without a timeout the family's handover_timeout is used. A board
+/// without a serial is refused with UtilError::Unidentified before it's reset, as is a bootloader
+/// under the same serial reporting another family, rather than flashing whichever board shows up.
+pub fn ensure_bootloader(
+    api: &mut HidApi,
+    opened: (HidDevice, Option<DeviceLock>),
+    candidates: impl Fn(&HidApi) -> Vec<DeviceInfo>,
+    timeout: Option<Duration>,
+) -> Result<(HidDevice, Option<DeviceLock>, BinInfoResponse), UtilError> {
+    let (d, lock) = opened;
+    let bininfo = bin_info(&d).map_err(UtilError::from)?;
+    if bininfo.mode == BinInfoMode::Bootloader {
+        return Ok((d, lock, bininfo));
+    }
+    let timeout = timeout.unwrap_or_else(|| {
+        bininfo
+            .family_id
+            .map_or(DEFAULT_HANDOVER_TIMEOUT, |family| family.handover_timeout())
+    });
+
+    let serial = d
+        .get_serial_number_string()
+        .ok()
+        .flatten()
+        .filter(|serial| !serial.is_empty())
+        .ok_or_else(|| {
+            UtilError::Unidentified(
+                "the board has no usb serial to find its bootloader by, double tap reset to start it by hand".into(),
+            )
+        })?;
+    // a board resetting straight into the bootloader may not get to answer
+    if let Err(e) = start_flash(&d) {
+        log::debug!("start flash got no answer, {:?}", e);
+    }
+    if let Ok(bootloader) = bin_info(&d) {
+        if bootloader.mode == BinInfoMode::Bootloader {
+            return Ok((d, lock, bootloader));
+        }
+    }
+    drop(d);
+    drop(lock);
+
+    let deadline = Instant::now() + timeout;
+    while Instant::now() < deadline {
+        std::thread::sleep(HANDOVER_POLL);
+        if api.refresh_devices().is_err() {
+            continue;
+        }
+
+        let found = same_serial(candidates(api), &serial);
+        if let Ok((d, lock)) = open_unlocked(api, &found, false) {
+            match bin_info(&d) {
+                Ok(bootloader) if bootloader.mode == BinInfoMode::Bootloader => {
+                    same_family(&bininfo, &bootloader)?;
+                    return Ok((d, lock, bootloader));
+                }
+                _ => log::debug!("still running the app, waiting for the bootloader"),
+            }
+        }
+    }
+    Err(UtilError::NotFound)
+}
+
+// the board that was handed over, not some other board that happens to be in its bootloader
+fn same_serial(found: Vec<DeviceInfo>, serial: &str) -> Vec<DeviceInfo> {
+    found
+        .into_iter()
+        .filter(|info| info.serial_number.as_deref() == Some(serial))
+        .collect()
+}
+
+// a bootloader of another family isn't the one the app handed over to, whatever its serial says
+fn same_family(app: &BinInfoResponse, bootloader: &BinInfoResponse) -> Result<(), UtilError> {
+    match (app.family_id, bootloader.family_id) {
+        (Some(app), Some(bootloader)) if app != bootloader => {
+            Err(UtilError::Unidentified(format!(
+                "the app reported {:?} but the bootloader under its serial is {:?}",
+                app, bootloader
+            )))
+        }
+        _ => Ok(()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{FixtureDevice, NRF52840, SAMD21};
+    use std::ffi::CString;
+
+    #[test]
+    fn handover_identity() {
+        let info = |serial: Option<&str>| DeviceInfo {
+            path: CString::new(serial.unwrap_or("none")).unwrap(),
+            vendor_id: 0x239A,
+            product_id: 0x000B,
+            serial_number: serial.map(String::from),
+            manufacturer: None,
+            product: None,
+            interface_number: 0,
+            usage_page: HF2_USAGE_PAGE,
+        };
+        let found = vec![info(Some("OTHER")), info(None), info(Some("8A2E1F3C"))];
+        assert_eq!(
+            same_serial(found.clone(), "8A2E1F3C"),
+            [info(Some("8A2E1F3C"))]
+        );
+        assert!(same_serial(found, "MISSING").is_empty());
+
+        let samd21 = bin_info(&FixtureDevice::new(SAMD21)).unwrap();
+        let app = BinInfoResponse {
+            mode: BinInfoMode::User,
+            ..samd21.clone()
+        };
+        assert!(same_family(&app, &samd21).is_ok());
+        let nrf = bin_info(&FixtureDevice::new(NRF52840)).unwrap();
+        assert!(matches!(
+            same_family(&app, &nrf),
+            Err(UtilError::Unidentified(_))
+        ));
+    }
+}
@@ -1,13 +1,39 @@
 use super::{
-    checksum_pages, reset_into_app, start_flash, write_flash_page, BinInfoMode, BinInfoResponse,
-    Error,
+    checksum_pages, read_words, reset_into_app, start_flash, write_flash_page, BinInfoMode,
+    BinInfoResponse, Error, ErrorCategory, ErrorKind, FamilyId, ReadWrite,
 };
-use crc_any::CRCu16;
+pub use super::{Crc16, PageChecksum};
 use goblin::elf::program_header::*;
-use hidapi::HidDevice;
+use sha2::{Digest, Sha256};
+use std::convert::TryFrom;
 use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::{Duration, Instant};
 use std::{fs::File, io::Read};
 
+mod batch;
+mod capabilities;
+mod dryrun;
+mod firmware;
+#[cfg(feature = "hidapi")]
+mod lock;
+mod readcache;
+mod softdevice;
+mod supply;
+mod symbols;
+mod uf2;
+pub use batch::*;
+pub use capabilities::*;
+pub use dryrun::*;
+pub use firmware::*;
+#[cfg(feature = "hidapi")]
+pub use lock::*;
+pub use readcache::*;
+pub use softdevice::*;
+pub use supply::*;
+pub use symbols::*;
+pub use uf2::*;
+
 #[derive(Debug)]
 pub enum UtilError {
     File,
@@ -16,30 +42,92 @@ pub enum UtilError {
     Internal,
     Communication,
     ContentsDifferent,
+    SymbolNotFound,
+    /// The image doesn't look like it was linked for this target, with a hint as to why.
+    InvalidVectorTable(String),
+    /// Transaction marker isn't page aligned or overlaps a region.
+    InvalidMarker,
+    /// A region doesn't fit where it's meant to go, with a hint as to why.
+    InvalidRegion(String),
+    /// Another process holds the device, with its pid when known.
+    Busy(Option<u32>),
+    /// No device could be opened.
+    NotFound,
+    /// The bootloader a board handed over to can't be told apart from other boards, or turned out
+    /// to be another board, with a hint as to why.
+    Unidentified(String),
+    /// The bootloader refused to write pages, likely protected, with which.
+    PagesRejected(String),
+    /// The bootloader refused to read a range, likely readout protection, with which.
+    ReadProtected(String),
+    /// The deadline in the device's session options passed, the rest wasn't sent.
+    Deadline,
+    /// It would change the device and its session is read only.
+    ReadOnly,
+    /// Needs the app running but the device is in its bootloader, with what needed it.
+    AppNotRunning(String),
+}
+
+impl UtilError {
+    pub fn category(&self) -> ErrorCategory {
+        match self {
+            UtilError::File
+            | UtilError::InvalidBinary
+            | UtilError::Elf
+            | UtilError::SymbolNotFound
+            | UtilError::InvalidVectorTable(_)
+            | UtilError::InvalidMarker
+            | UtilError::InvalidRegion(_)
+            | UtilError::Unidentified(_)
+            | UtilError::ReadOnly
+            | UtilError::AppNotRunning(_) => ErrorCategory::User,
+            UtilError::Internal
+            | UtilError::Communication
+            | UtilError::ContentsDifferent
+            | UtilError::PagesRejected(_)
+            | UtilError::ReadProtected(_) => ErrorCategory::Device,
+            UtilError::Busy(_) | UtilError::NotFound | UtilError::Deadline => ErrorCategory::Host,
+        }
+    }
 }
 
 impl From<Error> for UtilError {
     fn from(err: Error) -> UtilError {
-        match err {
-            Error::Parse | Error::Transmission => UtilError::Communication,
+        match err.kind() {
+            ErrorKind::Parse | ErrorKind::Timeout | ErrorKind::Transmission => {
+                UtilError::Communication
+            }
+            ErrorKind::Deadline => UtilError::Deadline,
+            ErrorKind::ReadOnly => UtilError::ReadOnly,
             _ => UtilError::Internal,
         }
     }
 }
 
+// Largest image the parsers will build, far past any flash this talks to, so a corrupt address
+// can't ask for gigabytes.
+const MAX_IMAGE_SIZE: usize = 64 * 1024 * 1024;
+
 /// Returns a contiguous bin with 0s between non-contiguous sections and starting address from an elf.
 pub fn elf_to_bin(path: PathBuf) -> Result<(Vec<u8>, u32), UtilError> {
     let mut file = File::open(path).map_err(|_| UtilError::File)?;
     let mut buffer = vec![];
     file.read_to_end(&mut buffer).map_err(|_| UtilError::File)?;
 
-    let binary = goblin::elf::Elf::parse(buffer.as_slice()).map_err(|_| UtilError::Elf)?;
+    parse_elf(&buffer)
+}
+
+/// elf_to_bin for an elf already in memory. Segments are placed at their load (physical)
+/// address, so .data is flashed where the startup code copies it from rather than its RAM address.
+/// Errors rather than panics on malformed input.
+pub fn parse_elf(buffer: &[u8]) -> Result<(Vec<u8>, u32), UtilError> {
+    let binary = goblin::elf::Elf::parse(buffer).map_err(|_| UtilError::Elf)?;
 
     let mut start_address: u64 = 0;
     let mut last_address: u64 = 0;
 
-    let mut data = vec![];
-    for (i, ph) in binary
+    // linkers usually emit segments in address order, but nothing requires it
+    let mut segments: Vec<_> = binary
         .program_headers
         .iter()
         .filter(|ph| {
@@ -48,8 +136,11 @@ pub fn elf_to_bin(path: PathBuf) -> Result<(Vec<u8>, u32), UtilError> {
                 && ph.p_offset >= binary.header.e_ehsize as u64
                 && ph.is_read()
         })
-        .enumerate()
-    {
+        .collect();
+    segments.sort_by_key(|ph| ph.p_paddr);
+
+    let mut data = vec![];
+    for (i, ph) in segments.into_iter().enumerate() {
         // first time through grab the starting physical address
         if i == 0 {
             start_address = ph.p_paddr;
@@ -57,120 +148,889 @@ pub fn elf_to_bin(path: PathBuf) -> Result<(Vec<u8>, u32), UtilError> {
         // on subsequent passes, if there's a gap between this section and the
         // previous one, fill it with zeros
         else {
-            let difference = (ph.p_paddr - last_address) as usize;
+            let difference = ph
+                .p_paddr
+                .checked_sub(last_address)
+                .ok_or(UtilError::InvalidBinary)? as usize;
+            if data.len() + difference > MAX_IMAGE_SIZE {
+                return Err(UtilError::InvalidBinary);
+            }
             data.resize(data.len() + difference, 0x0);
         }
 
-        data.extend_from_slice(&buffer[ph.p_offset as usize..][..ph.p_filesz as usize]);
+        let segment = usize::try_from(ph.p_offset)
+            .ok()
+            .zip(usize::try_from(ph.p_filesz).ok())
+            .and_then(|(offset, len)| buffer.get(offset..)?.get(..len))
+            .ok_or(UtilError::Elf)?;
+        if data.len() + segment.len() > MAX_IMAGE_SIZE {
+            return Err(UtilError::InvalidBinary);
+        }
+        data.extend_from_slice(segment);
+
+        last_address = ph
+            .p_paddr
+            .checked_add(ph.p_filesz)
+            .ok_or(UtilError::InvalidBinary)?;
+    }
 
-        last_address = ph.p_paddr + ph.p_filesz;
+    if start_address > u32::MAX as u64 {
+        return Err(UtilError::InvalidBinary);
     }
 
     Ok((data, start_address as u32))
 }
 
-/// Flash, Verify and restart into app.
-pub fn flash_bin(
+/// Returns a contiguous bin with 0xFF between non-contiguous records and starting address from an
+/// intel hex file, the format SoftDevices and most vendor images ship in.
+pub fn hex_to_bin(path: PathBuf) -> Result<(Vec<u8>, u32), UtilError> {
+    let contents = std::fs::read_to_string(path).map_err(|_| UtilError::File)?;
+    parse_hex(&contents)
+}
+
+/// hex_to_bin for a hex file already in memory. Errors rather than panics on malformed input.
+pub fn parse_hex(contents: &str) -> Result<(Vec<u8>, u32), UtilError> {
+    let mut records: Vec<(u32, Vec<u8>)> = vec![];
+    let mut base: u32 = 0;
+
+    for line in contents.lines().map(str::trim).filter(|l| !l.is_empty()) {
+        let hex = line.strip_prefix(':').ok_or(UtilError::InvalidBinary)?;
+        if !hex.is_ascii() || !hex.len().is_multiple_of(2) || hex.len() < 10 {
+            return Err(UtilError::InvalidBinary);
+        }
+        let bytes = (0..hex.len())
+            .step_by(2)
+            .map(|i| u8::from_str_radix(&hex[i..i + 2], 16))
+            .collect::<Result<Vec<u8>, _>>()
+            .map_err(|_| UtilError::InvalidBinary)?;
+
+        let len = bytes[0] as usize;
+        if bytes.len() != len + 5 || bytes.iter().fold(0u8, |sum, b| sum.wrapping_add(*b)) != 0 {
+            return Err(UtilError::InvalidBinary);
+        }
+        let offset = u16::from_be_bytes([bytes[1], bytes[2]]) as u32;
+        let data = &bytes[4..4 + len];
+
+        match bytes[3] {
+            0x00 => records.push((base + offset, data.to_vec())),
+            0x01 => break,
+            0x02 if len == 2 => base = (u16::from_be_bytes([data[0], data[1]]) as u32) << 4,
+            0x04 if len == 2 => base = (u16::from_be_bytes([data[0], data[1]]) as u32) << 16,
+            // start addresses, the vector table says where to start
+            0x03 | 0x05 => {}
+            _ => return Err(UtilError::InvalidBinary),
+        }
+    }
+
+    assemble(records)
+}
+
+// lays out (address, data) records as one image with erased gaps, refusing overlapping records
+fn assemble(mut records: Vec<(u32, Vec<u8>)>) -> Result<(Vec<u8>, u32), UtilError> {
+    records.sort_by_key(|(address, _)| *address);
+    let start = records.first().ok_or(UtilError::InvalidBinary)?.0;
+
+    let mut binary = vec![];
+    for (address, data) in records {
+        let offset = (address - start) as usize;
+        if offset < binary.len() || offset + data.len() > MAX_IMAGE_SIZE {
+            return Err(UtilError::InvalidBinary);
+        }
+        binary.resize(offset, 0xFF);
+        binary.extend_from_slice(&data);
+    }
+
+    Ok((binary, start))
+}
+
+/// Sanity checks the Cortex-M vector table at the start of an image, catching the usual symptoms
+/// of a memory.x that doesn't account for the bootloader before they brick the board.
+pub fn check_vector_table(
     binary: &[u8],
     address: u32,
     bininfo: &BinInfoResponse,
-    d: &HidDevice,
 ) -> Result<(), UtilError> {
-    if binary.is_empty() {
-        return Err(UtilError::InvalidBinary);
+    // first flash address past the bootloader, for families where it's fixed
+    let app_base = match bininfo.family_id {
+        Some(FamilyId::ATSAMD21) => Some(0x2000),
+        Some(FamilyId::ATSAMD51) => Some(0x4000),
+        Some(FamilyId::NRF52840) => Some(MBR_SIZE),
+        // not a Cortex-M, or we know nothing about it
+        Some(FamilyId::ATMEGA32) | Some(FamilyId::CYPRESS_FX2) | Some(FamilyId::UNKNOWN(_)) => {
+            return Ok(())
+        }
+        _ => None,
+    };
+
+    if let Some(app_base) = app_base {
+        if address < app_base {
+            return Err(UtilError::InvalidVectorTable(format!(
+                "image starts at {:#x} which is inside the bootloader, the app starts at {:#x}. Check FLASH ORIGIN in memory.x",
+                address, app_base
+            )));
+        }
     }
 
-    let mut binary = binary.to_owned();
+    if binary.len() < 8 {
+        return Err(UtilError::InvalidVectorTable(
+            "image is too small to contain a vector table".into(),
+        ));
+    }
+
+    let stack_pointer = u32::from_le_bytes([binary[0], binary[1], binary[2], binary[3]]);
+    let reset_vector = u32::from_le_bytes([binary[4], binary[5], binary[6], binary[7]]);
 
-    // pad zeros to page size
-    // add divisor-1 to dividend to round up
-    let padded_num_pages =
-        (binary.len() as u32 + (bininfo.flash_page_size - 1)) / bininfo.flash_page_size;
+    if !(0x2000_0000..=0x4000_0000).contains(&stack_pointer) || stack_pointer & 0x3 != 0 {
+        return Err(UtilError::InvalidVectorTable(format!(
+            "initial stack pointer {:#010x} isn't a word aligned RAM address. Check RAM ORIGIN in memory.x",
+            stack_pointer
+        )));
+    }
+
+    let top_address = address + binary.len() as u32;
+    if reset_vector & 1 == 0 || !(address..top_address).contains(&(reset_vector & !1)) {
+        return Err(UtilError::InvalidVectorTable(format!(
+            "reset vector {:#010x} doesn't point at thumb code inside the image ({:#x}..{:#x}). Check FLASH ORIGIN in memory.x",
+            reset_vector, address, top_address
+        )));
+    }
+
+    Ok(())
+}
+
+/// How to fill the rest of the final page when an image isn't a multiple of the page size.
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum PadPolicy {
+    /// Pad with 0x00.
+    #[default]
+    Zero,
+    /// Pad with 0xFF, the same as erased flash.
+    Erased,
+    /// Read back the final page first and keep whatever is stored after the image, ie settings.
+    Preserve,
+}
+
+impl std::str::FromStr for PadPolicy {
+    type Err = String;
+
+    fn from_str(input: &str) -> Result<Self, Self::Err> {
+        match input {
+            "zero" => Ok(PadPolicy::Zero),
+            "erased" => Ok(PadPolicy::Erased),
+            "preserve" => Ok(PadPolicy::Preserve),
+            _ => Err(format!(
+                "unknown pad policy {}, use zero, erased or preserve",
+                input
+            )),
+        }
+    }
+}
 
-    let padded_size = padded_num_pages * bininfo.flash_page_size;
+/// Pads binary out to a whole number of pages according to the policy.
+fn pad(
+    binary: &[u8],
+    address: u32,
+    pad: PadPolicy,
+    bininfo: &BinInfoResponse,
+    d: &impl ReadWrite,
+) -> Result<Vec<u8>, UtilError> {
+    let mut binary = binary.to_owned();
+
+    let geometry = bininfo.geometry;
+    let padded_size = geometry.page_address(geometry.pages_for(binary.len()));
     log::debug!(
-        "binary is {} bytes, padding to {} bytes",
+        "binary is {} bytes, padding to {} bytes with {:?}",
         binary.len(),
-        padded_size
+        padded_size,
+        pad
     );
-    for _i in 0..(padded_size as usize - binary.len()) {
-        binary.push(0x0);
+
+    let tail = padded_size as usize - binary.len();
+    match pad {
+        PadPolicy::Zero => binary.resize(padded_size as usize, 0x0),
+        PadPolicy::Erased => binary.resize(padded_size as usize, 0xFF),
+        PadPolicy::Preserve if tail > 0 => {
+            let existing = read_bytes(address + binary.len() as u32, tail, bininfo, d)?;
+            binary.extend_from_slice(&existing);
+        }
+        PadPolicy::Preserve => (),
+    }
+
+    Ok(binary)
+}
+
+/// What a flash is busy with, reported to progress callbacks.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Phase {
+    Flashing,
+    Verifying,
+    Resetting,
+}
+
+/// Where a flash is at, pages count up to total within each phase.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Progress {
+    pub phase: Phase,
+    pub pages: u32,
+    pub total: u32,
+    /// Bytes the pages done so far cover.
+    pub bytes: u64,
+    /// Since the phase started.
+    pub elapsed: Duration,
+    /// Set when the step just reported needed more than one attempt, see
+    /// SessionOptions::retry_policies.
+    pub retry: Option<Retry>,
+    /// Set when a full verify couldn't read a range back and checked it with checksums instead.
+    pub unreadable: Option<Unreadable>,
+}
+
+/// Told where a flash is at after each page written or batch of pages verified, and when it moves
+/// on to the next phase. Any FnMut(Progress) is one, () ignores progress.
+pub trait ProgressHandler {
+    fn progress(&mut self, progress: Progress);
+}
+
+impl<F: FnMut(Progress)> ProgressHandler for F {
+    fn progress(&mut self, progress: Progress) {
+        self(progress)
+    }
+}
+
+impl ProgressHandler for () {
+    fn progress(&mut self, _progress: Progress) {}
+}
+
+// the Progress reports of one phase, timed from when it started
+struct PhaseProgress {
+    phase: Phase,
+    total: u32,
+    page_size: u32,
+    started: Instant,
+}
+
+impl PhaseProgress {
+    fn start(phase: Phase, total: u32, geometry: crate::FlashGeometry) -> Self {
+        PhaseProgress {
+            phase,
+            total,
+            page_size: geometry.page_size,
+            started: Instant::now(),
+        }
+    }
+
+    fn at(&self, pages: u32, retry: Option<Retry>) -> Progress {
+        Progress {
+            phase: self.phase,
+            pages,
+            total: self.total,
+            bytes: pages as u64 * self.page_size as u64,
+            elapsed: self.started.elapsed(),
+            retry,
+            unreadable: None,
+        }
+    }
+}
+
+/// A page write or checksum that was sent more than once.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Retry {
+    /// Index of the page in flash, the first of the batch for checksums.
+    pub page: u32,
+    pub address: u32,
+    pub attempts: u32,
+    /// Whether the last attempt got through, if not the flash fails with its error.
+    pub succeeded: bool,
+}
+
+/// A range VerifyMode::Full couldn't read back, so it wasn't part of the SHA-256 and the image
+/// was verified with page checksums instead.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Unreadable {
+    pub address: u32,
+    pub len: u32,
+    /// Whether the bootloader refused the reads, otherwise they came back as all zeros. Parts with
+    /// readout protection do either.
+    pub refused: bool,
+}
+
+impl Progress {
+    /// Percent of the current phase done.
+    pub fn percent(&self) -> u32 {
+        (self.pages * 100).checked_div(self.total).unwrap_or(100)
+    }
+
+    /// Throughput of the current phase so far.
+    pub fn bytes_per_sec(&self) -> u64 {
+        match self.elapsed.as_micros() as u64 {
+            0 => 0,
+            micros => self.bytes * 1_000_000 / micros,
+        }
+    }
+}
+
+/// How flashed pages are checked before restarting into the app.
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum VerifyMode {
+    /// Compare the device's page checksums, quick but only as strong as a CRC-16.
+    #[default]
+    Checksum,
+    /// Read every byte back and compare a SHA-256 of it with the image, slower but bit exact.
+    Full,
+}
+
+impl std::str::FromStr for VerifyMode {
+    type Err = String;
+
+    fn from_str(input: &str) -> Result<Self, Self::Err> {
+        match input {
+            "checksum" => Ok(VerifyMode::Checksum),
+            "full" => Ok(VerifyMode::Full),
+            _ => Err(format!(
+                "unknown verify mode {}, use checksum or full",
+                input
+            )),
+        }
+    }
+}
+
+static HOLD_BOOTLOADER: AtomicBool = AtomicBool::new(false);
+
+/// Leave the board in the bootloader after flashing for this process, skipping the restart into
+/// the app, so a sequence of flashes doesn't wait for the board to re-enumerate between each.
+pub fn set_hold_bootloader(hold: bool) {
+    HOLD_BOOTLOADER.store(hold, Ordering::Relaxed);
+}
+
+// how many times the command just sent to d went out, its session's retry policy resends it
+fn attempts(d: &impl ReadWrite) -> u32 {
+    crate::session::state(d).attempts()
+}
+
+// words one read or write fits in a single 64 byte report, after the response or command header
+pub(crate) const COMPAT_READ_WORDS: u32 = 14;
+pub(crate) const COMPAT_WRITE_WORDS: u32 = 11;
+
+// see SessionOptions::compat
+pub(crate) fn compat(d: &impl ReadWrite) -> bool {
+    crate::session::state(d).options().compat
+}
+
+// see SessionOptions::delta, compat writes every page as it doesn't trust checksums to skip any
+fn delta(d: &impl ReadWrite) -> bool {
+    let options = crate::session::state(d).options();
+    options.delta && !options.compat
+}
+
+// restart into the app unless holding the bootloader
+fn finish(d: &impl ReadWrite) -> Result<(), UtilError> {
+    if HOLD_BOOTLOADER.load(Ordering::Relaxed) {
+        log::debug!("holding the bootloader, not resetting into app");
+        return Ok(());
+    }
+    reset_into_app(d).map_err(UtilError::from)
+}
+
+/// Flash, Verify and restart into app. The reset is only sent once every page verified, on any
+/// error the board stays in the bootloader rather than booting a corrupt image.
+pub fn flash_bin(
+    binary: &[u8],
+    address: u32,
+    pad_policy: PadPolicy,
+    bininfo: &BinInfoResponse,
+    d: &impl ReadWrite,
+) -> Result<(), UtilError> {
+    flash_bin_with_progress(
+        binary,
+        address,
+        pad_policy,
+        VerifyMode::Checksum,
+        bininfo,
+        d,
+        &mut (),
+    )
+}
+
+/// flash_bin, calling progress after each page written or batch of pages verified.
+pub fn flash_bin_with_progress(
+    binary: &[u8],
+    address: u32,
+    pad_policy: PadPolicy,
+    verify_mode: VerifyMode,
+    bininfo: &BinInfoResponse,
+    d: &impl ReadWrite,
+    progress: &mut dyn ProgressHandler,
+) -> Result<(), UtilError> {
+    if binary.is_empty() {
+        return Err(UtilError::InvalidBinary);
     }
 
     if bininfo.mode != BinInfoMode::Bootloader {
-        let _ = start_flash(d).map_err(UtilError::from)?;
+        start_flash(d).map_err(UtilError::from)?;
     }
-    flash(&binary, address, bininfo, d)?;
 
-    match verify(&binary, address, bininfo, d) {
+    let binary = pad(binary, address, pad_policy, bininfo, d)?;
+    flash(&binary, address, bininfo, d, delta(d), progress)?;
+
+    match verify(&binary, address, verify_mode, bininfo, d, progress) {
         Ok(false) => return Err(UtilError::ContentsDifferent),
         Err(e) => return Err(e),
         Ok(true) => (),
     };
 
-    reset_into_app(d).map_err(UtilError::from)
+    progress.progress(PhaseProgress::start(Phase::Resetting, 0, bininfo.geometry).at(0, None));
+    finish(d)
 }
 
-/// Flashes binary writing a single page at a time.
-fn flash(
+/// A binary and the address it belongs at, for flashing several at once.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Region {
+    pub address: u32,
+    pub binary: Vec<u8>,
+    /// Page size of external flash the bootloader maps into a window, ie QSPI holding a
+    /// CircuitPython filesystem. None for the chip's own flash.
+    pub page_size: Option<u32>,
+}
+
+impl Region {
+    /// The bininfo to flash and verify this region with, the device's own unless the region is
+    /// external flash with pages of its own. Those pages have to fit one write command.
+    pub fn bininfo(&self, bininfo: &BinInfoResponse) -> Result<BinInfoResponse, UtilError> {
+        let page_size = match self.page_size {
+            Some(page_size) => page_size,
+            None => return Ok(bininfo.clone()),
+        };
+
+        // command header and the page address
+        let fits = page_size
+            .checked_add(12)
+            .is_some_and(|size| size <= bininfo.max_message_size);
+        if page_size == 0 || !fits {
+            return Err(UtilError::InvalidRegion(format!(
+                "external page size {} doesn't fit a {} byte message",
+                page_size, bininfo.max_message_size
+            )));
+        }
+        if !self.address.is_multiple_of(page_size) {
+            return Err(UtilError::InvalidRegion(format!(
+                "external region at {:#x} isn't on a {} byte page",
+                self.address, page_size
+            )));
+        }
+
+        let end = self.address as u64 + self.binary.len() as u64;
+        Ok(BinInfoResponse {
+            geometry: crate::FlashGeometry {
+                page_size,
+                num_pages: end.div_ceil(page_size as u64) as u32,
+            },
+            ..bininfo.clone()
+        })
+    }
+}
+
+/// Checks a filesystem image, ie a prebuilt LittleFS or FAT blob, fits the flash reserved for it.
+/// The reserved area has to be whole pages so flashing the image can't touch anything past it.
+pub fn check_filesystem(
     binary: &[u8],
     address: u32,
+    size: u32,
     bininfo: &BinInfoResponse,
-    d: &HidDevice,
 ) -> Result<(), UtilError> {
-    for (page_index, page) in binary.chunks(bininfo.flash_page_size as usize).enumerate() {
-        let target_address = address + bininfo.flash_page_size * page_index as u32;
+    let geometry = bininfo.geometry;
+    if !geometry.is_aligned(address) || !geometry.is_aligned(size) {
+        return Err(UtilError::InvalidRegion(format!(
+            "filesystem at {:#x} size {:#x} isn't aligned to {:#x} byte pages",
+            address, size, geometry.page_size
+        )));
+    }
+
+    if binary.len() as u64 > size as u64 {
+        return Err(UtilError::InvalidRegion(format!(
+            "filesystem image is {:#x} bytes but only {:#x} are reserved at {:#x}",
+            binary.len(),
+            size,
+            address
+        )));
+    }
 
-        let _ = write_flash_page(d, target_address, page.to_vec()).map_err(UtilError::from)?;
+    if !geometry.contains(address, size as u64) {
+        return Err(UtilError::InvalidRegion(format!(
+            "filesystem {:#x}..{:#x} runs past the end of flash at {:#x}",
+            address,
+            address as u64 + size as u64,
+            geometry.total_size()
+        )));
     }
+
     Ok(())
 }
 
-pub fn verify_bin(
+/// First word of the marker page while a multi region flash is in progress, "HF2T".
+pub const TRANSACTION_MAGIC: u32 = 0x5432_4648;
+
+/// Flashes and verifies several regions as one transaction then restarts into app, only once all
+/// of them verified. Each region has to start on a page and no two may share one, as flashing a
+/// region writes its pages whole, see uf2_regions for merging blocks per page. When given a
+/// marker address, a marker page is written there before any region and erased once every region
+/// verified, so an interrupted flash can be spotted later with transaction_interrupted.
+pub fn flash_regions(
+    regions: &[Region],
+    marker: Option<u32>,
+    pad_policy: PadPolicy,
+    verify_mode: VerifyMode,
+    bininfo: &BinInfoResponse,
+    d: &impl ReadWrite,
+) -> Result<(), UtilError> {
+    if regions.is_empty() || regions.iter().any(|r| r.binary.is_empty()) {
+        return Err(UtilError::InvalidBinary);
+    }
+    check_pages(regions, bininfo)?;
+
+    if let Some(marker) = marker {
+        let geometry = bininfo.geometry;
+        let marker_page = geometry.page_of(marker);
+        let overlaps = regions.iter().any(|r| {
+            geometry
+                .page_range(r.address, r.binary.len())
+                .contains(&marker_page)
+        });
+        if !geometry.is_aligned(marker) || overlaps {
+            return Err(UtilError::InvalidMarker);
+        }
+    }
+
+    if bininfo.mode != BinInfoMode::Bootloader {
+        start_flash(d).map_err(UtilError::from)?;
+    }
+
+    if let Some(marker) = marker {
+        let mut page = vec![0xFF; bininfo.geometry.page_size as usize];
+        page[..4].copy_from_slice(&TRANSACTION_MAGIC.to_le_bytes());
+        write_flash_page(d, marker, page).map_err(UtilError::from)?;
+    }
+
+    for region in regions {
+        log::debug!(
+            "region at {:#x}, {} bytes",
+            region.address,
+            region.binary.len()
+        );
+        let bininfo = &region.bininfo(bininfo)?;
+        let binary = pad(&region.binary, region.address, pad_policy, bininfo, d)?;
+        flash(&binary, region.address, bininfo, d, delta(d), &mut ())?;
+
+        match verify(&binary, region.address, verify_mode, bininfo, d, &mut ()) {
+            Ok(false) => return Err(UtilError::ContentsDifferent),
+            Err(e) => return Err(e),
+            Ok(true) => (),
+        };
+    }
+
+    if let Some(marker) = marker {
+        let page = vec![0xFF; bininfo.geometry.page_size as usize];
+        write_flash_page(d, marker, page).map_err(UtilError::from)?;
+    }
+
+    finish(d)
+}
+
+// regions start on a page and don't share one, a region's pages are written whole so the second
+// would wipe what the first wrote
+fn check_pages(regions: &[Region], bininfo: &BinInfoResponse) -> Result<(), UtilError> {
+    let mut spans = vec![];
+    for region in regions {
+        let geometry = region.bininfo(bininfo)?.geometry;
+        if !geometry.is_aligned(region.address) {
+            return Err(UtilError::InvalidRegion(format!(
+                "region at {:#x} doesn't start on a {} byte page",
+                region.address, geometry.page_size
+            )));
+        }
+        let end = region.address as u64 + region.binary.len() as u64;
+        let end = end.div_ceil(geometry.page_size as u64) * geometry.page_size as u64;
+        spans.push((region.address as u64, end));
+    }
+    spans.sort_unstable();
+    match spans.windows(2).find(|pair| pair[1].0 < pair[0].1) {
+        Some(pair) => Err(UtilError::InvalidRegion(format!(
+            "regions at {:#x} and {:#x} share a page",
+            pair[0].0, pair[1].0
+        ))),
+        None => Ok(()),
+    }
+}
+
+/// Checks whether a flash_regions transaction using this marker address never completed.
+pub fn transaction_interrupted(
+    marker: u32,
+    bininfo: &BinInfoResponse,
+    d: &impl ReadWrite,
+) -> Result<bool, UtilError> {
+    let word = read_bytes(marker, 4, bininfo, d)?;
+    Ok(word == TRANSACTION_MAGIC.to_le_bytes())
+}
+
+/// Writes bytes at any address without clobbering the rest of the page. The pages touched are
+/// read back, patched and rewritten, everything else is left alone. Doesn't reset into the app.
+pub fn write_bytes_preserving(
+    address: u32,
+    data: &[u8],
+    bininfo: &BinInfoResponse,
+    d: &impl ReadWrite,
+) -> Result<(), UtilError> {
+    if data.is_empty() {
+        return Err(UtilError::InvalidBinary);
+    }
+
+    let geometry = bininfo.geometry;
+    let pages = geometry.page_range(address, data.len());
+    let first_page = geometry.page_address(pages.start);
+    let top_address = geometry.page_address(pages.end);
+
+    if bininfo.mode != BinInfoMode::Bootloader {
+        start_flash(d).map_err(UtilError::from)?;
+    }
+
+    let mut pages = read_bytes(first_page, (top_address - first_page) as usize, bininfo, d)?;
+    let offset = (address - first_page) as usize;
+    pages[offset..][..data.len()].copy_from_slice(data);
+
+    flash(&pages, first_page, bininfo, d, delta(d), &mut ())?;
+
+    match verify(
+        &pages,
+        first_page,
+        VerifyMode::Checksum,
+        bininfo,
+        d,
+        &mut (),
+    ) {
+        Ok(false) => Err(UtilError::ContentsDifferent),
+        Err(e) => Err(e),
+        Ok(true) => Ok(()),
+    }
+}
+
+/// Flashes binary writing a single page at a time, with delta only the pages whose checksum
+/// differs. A page the bootloader refuses stops the flash, or with continue_on_error is
+/// skipped, and either way is reported as PagesRejected.
+fn flash(
     binary: &[u8],
     address: u32,
     bininfo: &BinInfoResponse,
-    d: &HidDevice,
+    d: &impl ReadWrite,
+    delta: bool,
+    progress: &mut dyn ProgressHandler,
 ) -> Result<(), UtilError> {
-    let mut binary = binary.to_owned();
+    let geometry = bininfo.geometry;
+    let changed = if delta {
+        let changed = checksum_mismatches(binary, address, bininfo, d, &mut ())?;
+        log::info!(
+            "{} of {} pages changed",
+            changed.len(),
+            geometry.pages_for(binary.len())
+        );
+        Some(changed)
+    } else {
+        None
+    };
+    let total = changed
+        .as_ref()
+        .map_or(geometry.pages_for(binary.len()), |changed| {
+            changed.len() as u32
+        });
+    let keep_going = crate::session::state(d).options().continue_on_error;
+    let mut rejected = vec![];
+    let mut written = 0;
+    let tracker = PhaseProgress::start(Phase::Flashing, total, geometry);
 
-    // pad zeros to page size
-    // add divisor-1 to dividend to round up
-    let padded_num_pages =
-        (binary.len() as u32 + (bininfo.flash_page_size - 1)) / bininfo.flash_page_size;
+    for (page_index, page) in binary.chunks(geometry.page_size as usize).enumerate() {
+        let target_address = address + geometry.page_address(page_index as u32);
+        if let Some(changed) = &changed {
+            if !changed.contains(&target_address) {
+                continue;
+            }
+        }
 
-    let padded_size = padded_num_pages * bininfo.flash_page_size;
+        let result = write_flash_page(d, target_address, page.to_vec());
+        let attempts = attempts(d);
+        let retry = (attempts > 1).then(|| Retry {
+            page: geometry.page_of(target_address),
+            address: target_address,
+            attempts,
+            succeeded: result.is_ok(),
+        });
+        match result {
+            Ok(()) => (),
+            Err(e) if e.kind() == ErrorKind::Execution => {
+                let page = geometry.page_of(target_address);
+                log::warn!(
+                    "page {} at {:#x} rejected (likely protected)",
+                    page,
+                    target_address
+                );
+                rejected.push(format!("page {} at {:#x}", page, target_address));
+                if !keep_going {
+                    break;
+                }
+            }
+            Err(e) => {
+                if retry.is_some() {
+                    progress.progress(tracker.at(written, retry));
+                }
+                return Err(e.into());
+            }
+        }
+        written += 1;
+        progress.progress(tracker.at(written, retry));
+    }
 
-    for _i in 0..(padded_size as usize - binary.len()) {
-        binary.push(0x0);
+    if rejected.is_empty() {
+        Ok(())
+    } else {
+        Err(UtilError::PagesRejected(format!(
+            "{} rejected (likely protected)",
+            rejected.join(", ")
+        )))
     }
+}
+
+/// Verify binary, padded the same way it was flashed.
+pub fn verify_bin(
+    binary: &[u8],
+    address: u32,
+    pad_policy: PadPolicy,
+    verify_mode: VerifyMode,
+    bininfo: &BinInfoResponse,
+    d: &impl ReadWrite,
+) -> Result<(), UtilError> {
+    verify_bin_with_progress(
+        binary,
+        address,
+        pad_policy,
+        verify_mode,
+        bininfo,
+        d,
+        &mut (),
+    )
+}
+
+/// verify_bin, calling progress after each batch of pages verified.
+pub fn verify_bin_with_progress(
+    binary: &[u8],
+    address: u32,
+    pad_policy: PadPolicy,
+    verify_mode: VerifyMode,
+    bininfo: &BinInfoResponse,
+    d: &impl ReadWrite,
+    progress: &mut dyn ProgressHandler,
+) -> Result<(), UtilError> {
+    let binary = pad(binary, address, pad_policy, bininfo, d)?;
 
-    match verify(&binary, address, bininfo, d) {
+    match verify(&binary, address, verify_mode, bininfo, d, progress) {
         Ok(false) => Err(UtilError::ContentsDifferent),
         Err(e) => Err(e),
         Ok(true) => Ok(()),
     }
 }
 
-/// Verifys checksum of binary.
 fn verify(
     binary: &[u8],
     address: u32,
+    verify_mode: VerifyMode,
+    bininfo: &BinInfoResponse,
+    d: &impl ReadWrite,
+    progress: &mut dyn ProgressHandler,
+) -> Result<bool, UtilError> {
+    match verify_mode {
+        VerifyMode::Checksum => verify_checksums(binary, address, bininfo, d, progress),
+        VerifyMode::Full => verify_full(binary, address, bininfo, d, progress),
+    }
+}
+
+/// Addresses of the pages whose device checksum differs from binary's, padded the same way it was
+/// flashed, to say where a failed verify went wrong.
+pub fn mismatched_pages(
+    binary: &[u8],
+    address: u32,
+    pad_policy: PadPolicy,
     bininfo: &BinInfoResponse,
-    d: &HidDevice,
+    d: &impl ReadWrite,
+) -> Result<Vec<u32>, UtilError> {
+    let binary = pad(binary, address, pad_policy, bininfo, d)?;
+    checksum_mismatches(&binary, address, bininfo, d, &mut ())
+}
+
+/// Verifys checksum of binary.
+fn verify_checksums(
+    binary: &[u8],
+    address: u32,
+    bininfo: &BinInfoResponse,
+    d: &impl ReadWrite,
+    progress: &mut dyn ProgressHandler,
 ) -> Result<bool, UtilError> {
-    // get checksums of existing pages
+    let mismatched = checksum_mismatches(binary, address, bininfo, d, progress)?;
+    for page in &mismatched {
+        log::warn!("page at {:#x} differs", page);
+    }
+    Ok(mismatched.is_empty())
+}
 
+// addresses of pages that differ. The local checksums are computed on another thread while the
+// device computes its own, so slow hosts aren't adding their cpu time to the usb time.
+fn checksum_mismatches(
+    binary: &[u8],
+    address: u32,
+    bininfo: &BinInfoResponse,
+    d: &impl ReadWrite,
+    progress: &mut dyn ProgressHandler,
+) -> Result<Vec<u32>, UtilError> {
+    let geometry = bininfo.geometry;
+    let checksum = crate::session::state(d).options().checksum;
+
+    std::thread::scope(|scope| {
+        let local = scope.spawn(|| {
+            binary
+                .chunks(geometry.page_size as usize)
+                .map(|page| checksum.checksum(page))
+                .collect::<Vec<u16>>()
+        });
+
+        let device_checksums = device_checksums(binary, address, bininfo, d, progress)?;
+        let binary_checksums = local.join().map_err(|_| UtilError::Internal)?;
+
+        Ok(binary_checksums
+            .iter()
+            .enumerate()
+            .filter(|(i, checksum)| device_checksums.get(*i) != Some(checksum))
+            .map(|(i, _)| address + geometry.page_address(i as u32))
+            .collect())
+    })
+}
+
+// checksums of the pages binary covers as the device computes them
+fn device_checksums(
+    binary: &[u8],
+    address: u32,
+    bininfo: &BinInfoResponse,
+    d: &impl ReadWrite,
+    progress: &mut dyn ProgressHandler,
+) -> Result<Vec<u16>, UtilError> {
+    let geometry = bininfo.geometry;
     let top_address = address + binary.len() as u32;
+    let total = geometry.pages_for(binary.len());
 
-    let max_pages = bininfo.max_message_size / 2 - 2;
-    let steps = max_pages * bininfo.flash_page_size;
+    let max_pages = if compat(d) {
+        1
+    } else {
+        bininfo.max_checksum_pages()
+    };
+    let steps = geometry.page_address(max_pages);
     let mut device_checksums = vec![];
+    let tracker = PhaseProgress::start(Phase::Verifying, total, geometry);
 
     for target_address in (address..top_address).step_by(steps as usize) {
-        // add divisor-1 to dividend to round up
-        let pages_left = (top_address - target_address + (bininfo.flash_page_size - 1))
-            / bininfo.flash_page_size;
+        let pages_left = geometry.pages_for((top_address - target_address) as usize);
 
         let num_pages = if pages_left < max_pages {
             pages_left
@@ -178,41 +1038,294 @@ fn verify(
             max_pages
         };
 
-        let chk = checksum_pages(d, target_address, num_pages).map_err(UtilError::from)?;
+        let result = checksum_pages(d, target_address, num_pages);
+        let attempts = attempts(d);
+        let retry = (attempts > 1).then(|| Retry {
+            page: geometry.page_of(target_address),
+            address: target_address,
+            attempts,
+            succeeded: result.is_ok(),
+        });
+        if result.is_err() && retry.is_some() {
+            progress.progress(tracker.at(device_checksums.len() as u32, retry));
+        }
+        let chk = result.map_err(UtilError::from)?;
         device_checksums.extend_from_slice(&chk.checksums);
+        progress.progress(tracker.at(device_checksums.len() as u32, retry));
     }
 
-    let mut binary_checksums = vec![];
+    Ok(device_checksums)
+}
+
+/// Reads binary back a batch of pages at a time and compares SHA-256 digests. Parts with readout
+/// protection refuse reads or return zeros. When a batch is refused, or every batch reads as zeros,
+/// it falls back to checksums rather than claiming the flash is corrupt, reporting the ranges it
+/// couldn't read to progress.
+fn verify_full(
+    binary: &[u8],
+    address: u32,
+    bininfo: &BinInfoResponse,
+    d: &impl ReadWrite,
+    progress: &mut dyn ProgressHandler,
+) -> Result<bool, UtilError> {
+    let geometry = bininfo.geometry;
+    let total = geometry.pages_for(binary.len());
+    // as many whole pages as fit in one read_words response
+    let batch = (bininfo.max_message_size.saturating_sub(8) / geometry.page_size).max(1);
+
+    // hashing the image locally overlaps reading it back
+    std::thread::scope(|scope| {
+        let image = scope.spawn(|| Sha256::digest(binary));
+
+        let mut device = Sha256::new();
+        let mut pages = 0;
+        let tracker = PhaseProgress::start(Phase::Verifying, total, geometry);
+        let mut zeros = true;
+        let mut refused: Vec<Unreadable> = vec![];
+        for chunk in binary.chunks(geometry.page_address(batch) as usize) {
+            let target_address = address + geometry.page_address(pages);
+            match read_bytes(target_address, chunk.len(), bininfo, d) {
+                Ok(bytes) => {
+                    zeros &= bytes.iter().all(|byte| *byte == 0);
+                    device.update(bytes);
+                }
+                // keep going to find every range that's refused, adjacent ones as one
+                Err(UtilError::ReadProtected(_)) => match refused.last_mut() {
+                    Some(last) if last.address + last.len == target_address => {
+                        last.len += chunk.len() as u32
+                    }
+                    _ => refused.push(Unreadable {
+                        address: target_address,
+                        len: chunk.len() as u32,
+                        refused: true,
+                    }),
+                },
+                Err(e) => return Err(e),
+            }
+            pages += geometry.pages_for(chunk.len());
+            progress.progress(tracker.at(pages, None));
+        }
+
+        let unreadable = if !refused.is_empty() {
+            refused
+        } else if zeros && binary.iter().any(|byte| *byte != 0) {
+            vec![Unreadable {
+                address,
+                len: binary.len() as u32,
+                refused: false,
+            }]
+        } else {
+            vec![]
+        };
+        if !unreadable.is_empty() {
+            for range in unreadable {
+                log::warn!(
+                    "{:#x}..{:#x} appears read-protected, verifying with checksums instead",
+                    range.address,
+                    range.address as u64 + range.len as u64
+                );
+                progress.progress(Progress {
+                    unreadable: Some(range),
+                    ..tracker.at(pages, None)
+                });
+            }
+            return verify_checksums(binary, address, bininfo, d, progress);
+        }
+
+        let device = device.finalize();
+        let image = image.join().map_err(|_| UtilError::Internal)?;
+        log::debug!("sha256 image {:x} device {:x}", image, device);
+        Ok(image == device)
+    })
+}
 
-    //collect and sums so we can view all mismatches, not just first
-    for page in binary.chunks(bininfo.flash_page_size as usize) {
-        let mut xmodem = CRCu16::crc16xmodem();
-        xmodem.digest(&page);
+/// Reads an arbitrary byte range, widening it to the word aligned region read_words requires.
+pub fn read_bytes(
+    address: u32,
+    len: usize,
+    bininfo: &BinInfoResponse,
+    d: &impl ReadWrite,
+) -> Result<Vec<u8>, UtilError> {
+    let start = address & !0x3;
+    // in u64 so a range ending at the top of the address space can't wrap
+    let end = (address as u64 + len as u64 + 3) & !0x3;
+    if end > 1 << 32 {
+        return Err(UtilError::InvalidRegion(format!(
+            "{} bytes at {:#x} run past the end of the address space",
+            len, address
+        )));
+    }
+    let max_words = if compat(d) {
+        COMPAT_READ_WORDS
+    } else {
+        match bininfo.max_message_size.checked_sub(4).map(|size| size / 4) {
+            Some(words) if words > 0 => words,
+            _ => {
+                return Err(UtilError::InvalidRegion(format!(
+                    "max message size {} can't hold a word",
+                    bininfo.max_message_size
+                )))
+            }
+        }
+    };
 
-        binary_checksums.push(xmodem.get_crc());
+    let mut bytes = vec![];
+    let mut target_address = start as u64;
+    while target_address < end {
+        let num_words = ((end - target_address) / 4).min(max_words as u64) as u32;
+        let rsp = read_words(d, target_address as u32, num_words).map_err(|e| match e.kind() {
+            // refused rather than lost, parts with readout protection answer reads this way
+            ErrorKind::Execution => UtilError::ReadProtected(format!(
+                "{:#x}..{:#x}",
+                target_address,
+                target_address + num_words as u64 * 4
+            )),
+            _ => UtilError::from(e),
+        })?;
+        for word in rsp.words {
+            bytes.extend_from_slice(&word.to_le_bytes());
+        }
+        target_address += num_words as u64 * 4;
     }
 
-    Ok(binary_checksums.eq(&device_checksums))
+    // a device answering with fewer words than asked for
+    let skip = (address - start) as usize;
+    bytes
+        .get(skip..skip + len)
+        .map(|bytes| bytes.to_vec())
+        .ok_or(UtilError::Communication)
+}
+
+/// Indexes of the pages that differ between two images flashed at the same address, a page only
+/// present in the longer image counts as changed. Panics if page_size is 0.
+pub fn changed_pages(old: &[u8], new: &[u8], page_size: usize) -> Vec<usize> {
+    let pages = old.len().max(new.len()).div_ceil(page_size);
+    let page = |image: &[u8], i: usize| {
+        let start = (i * page_size).min(image.len());
+        let end = ((i + 1) * page_size).min(image.len());
+        image[start..end].to_vec()
+    };
+
+    (0..pages)
+        .filter(|&i| page(old, i) != page(new, i))
+        .collect()
+}
+
+/// Overwrites bytes of an image flashed at address, ie a per unit id, without rebuilding it.
+/// Patches have to land inside the image.
+pub fn patch_image(
+    binary: &mut [u8],
+    address: u32,
+    patch_address: u32,
+    data: &[u8],
+) -> Result<(), UtilError> {
+    let start = patch_address
+        .checked_sub(address)
+        .map(|offset| offset as usize)
+        .filter(|offset| offset + data.len() <= binary.len())
+        .ok_or_else(|| {
+            UtilError::InvalidRegion(format!(
+                "patch at {:#x} isn't inside the image at {:#x}..{:#x}",
+                patch_address,
+                address,
+                address as u64 + binary.len() as u64
+            ))
+        })?;
+
+    binary[start..][..data.len()].copy_from_slice(data);
+    Ok(())
 }
 
+/// Known boards vendor ids and their product ids.
+#[cfg(feature = "hidapi")]
 pub fn vendor_map() -> std::collections::HashMap<u16, Vec<u16>> {
-    maplit::hashmap! {
-        0x1D50 => vec![0x6110, 0x6112],
-        0x239A => vec![0x007F, 0x00B3, 0x003F, 0x0051, 0x0093, 0x0087, 0x003B, 0x0071, 0x0045, 0x0063, 0x0029, 0x0079, 0x0061, 0xE005, 0x0095, 0x004D, 0x006B, 0x0057, 0x00B5, 0x007D, 0x00B9, 0x0065, 0x0047, 0x0049, 0x00AF, 0x00CD, 0x00BF, 0x00C3, 0x00CB, 0x00AB, 0x00C5, 0x00A5, 0x00A7, 0x00C7, 0x002D, 0x0015, 0x001B, 0xB000, 0x0024, 0x000F, 0x0013, 0x0021, 0x0031, 0x0037, 0x0035, 0x002F, 0x002B, 0x0033, 0x0034, 0x003D, 0x0018, 0x001C, 0x001E, 0x0027, 0x0022, 0x00EF],
-        0x04D8 => vec![0xEC44, 0xEC64, 0xEC63, 0xEDB3, 0xEDBE, 0xEF66],
-        0x2341 => vec![0x0057, 0x024E, 0x8053, 0x024D],
-        0x16D0 => vec![0x0CDA],
-        0x03EB => vec![0x2402],
-        0x2886 => vec![0x002D, 0x000D, 0x002F],
-        0x1B4F => vec![0x0D23, 0x0D22, 0x0016],
-        0x1209 => vec![0x805A, 0x7102, 0x4D44, 0x2017],
-        0x3171 => vec![0x0100],
-        0x1915 => vec![0x521F],
-    }
+    crate::KNOWN_DEVICES
+        .iter()
+        .map(|(vid, pids)| (*vid, pids.to_vec()))
+        .collect()
 }
 
 #[cfg(test)]
 mod tests {
+    use super::*;
+    use crate::FlashGeometry;
+
+    fn samd51() -> BinInfoResponse {
+        BinInfoResponse {
+            mode: BinInfoMode::Bootloader,
+            geometry: FlashGeometry {
+                page_size: 512,
+                num_pages: 1024,
+            },
+            max_message_size: 1024,
+            family_id: Some(FamilyId::ATSAMD51),
+        }
+    }
+
+    #[test]
+    fn vector_table() {
+        let (binary, address) = elf_to_bin(
+            [
+                env!("CARGO_MANIFEST_DIR"),
+                "src/utils/testdata/blinky_1.47.0",
+            ]
+            .iter()
+            .collect(),
+        )
+        .unwrap();
+        check_vector_table(&binary, address, &samd51()).unwrap();
+
+        // linked without the bootloader offset
+        assert!(check_vector_table(&binary, 0, &samd51()).is_err());
+    }
+
+    #[test]
+    fn filesystem() {
+        let image = vec![0xFF; 0x1000];
+        check_filesystem(&image, 0x7_0000, 0x1_0000, &samd51()).unwrap();
+        // too big for the space reserved
+        assert!(check_filesystem(&image, 0x7_0000, 0x800, &samd51()).is_err());
+        // not page aligned
+        assert!(check_filesystem(&image, 0x7_0100, 0x1_0000, &samd51()).is_err());
+        // past the end of the 512KiB flash
+        assert!(check_filesystem(&image, 0x7_8000, 0x1_0000, &samd51()).is_err());
+    }
+
+    #[test]
+    fn patch() {
+        let mut image = vec![0xFF; 0x100];
+        patch_image(&mut image, 0x4000, 0x40FC, &[1, 2, 3, 4]).unwrap();
+        assert_eq!(&image[0xFC..], &[1, 2, 3, 4]);
+        // runs off the end or starts before the image
+        assert!(patch_image(&mut image, 0x4000, 0x40FD, &[1, 2, 3, 4]).is_err());
+        assert!(patch_image(&mut image, 0x4000, 0x3FFF, &[1]).is_err());
+    }
+
+    #[test]
+    fn page_diff() {
+        let old = vec![0; 1024];
+        let mut new = old.clone();
+        new[300] = 1;
+        new.extend_from_slice(&[0; 10]);
+        assert_eq!(changed_pages(&old, &new, 256), vec![1, 4]);
+        assert!(changed_pages(&old, &old, 256).is_empty());
+    }
+
+    #[test]
+    fn hex() {
+        let (binary, address) =
+            parse_hex(":020000040001F9\n:0400000001020304F2\n:02000800AABB91\n:00000001FF\n")
+                .unwrap();
+        assert_eq!(address, 0x10000);
+        assert_eq!(
+            binary,
+            [1, 2, 3, 4, 0xFF, 0xFF, 0xFF, 0xFF, 0xAA, 0xBB].to_vec()
+        );
+
+        // bad checksum
+        assert!(parse_hex(":0400000001020304F3\n").is_err());
+    }
+
     #[test]
     fn elf_rustc_1_44_0() {
         let (_, start_addr) = super::elf_to_bin(
@@ -255,4 +1368,141 @@ mod tests {
         assert_eq!(data[12], 2);
         assert_eq!(data[20], 3);
     }
+
+    #[test]
+    fn external_region() {
+        let mut region = Region {
+            address: 0x0400_0000,
+            binary: vec![0; 1000],
+            page_size: None,
+        };
+        assert_eq!(region.bininfo(&samd51()).unwrap(), samd51());
+
+        region.page_size = Some(256);
+        let bininfo = region.bininfo(&samd51()).unwrap();
+        assert_eq!(bininfo.geometry.page_size, 256);
+        assert!(bininfo.geometry.contains(0x0400_0000, 1000));
+
+        region.page_size = Some(4096);
+        assert!(region.bininfo(&samd51()).is_err());
+        region.page_size = Some(u32::MAX);
+        assert!(matches!(
+            region.bininfo(&samd51()),
+            Err(UtilError::InvalidRegion(_))
+        ));
+        region.page_size = Some(256);
+        region.address += 4;
+        assert!(region.bininfo(&samd51()).is_err());
+    }
+
+    #[test]
+    fn delta_flash() {
+        use crate::testing::{FixtureDevice, SAMD51};
+
+        let d = FixtureDevice::new(SAMD51);
+        let bininfo = crate::bin_info(&d).unwrap();
+        let mut binary = vec![0x55; 2048];
+        flash(&binary, 0x4000, &bininfo, &d, false, &mut ()).unwrap();
+
+        binary[1100] = 0xAA;
+        let before = d.commands().len();
+        flash(&binary, 0x4000, &bininfo, &d, true, &mut ()).unwrap();
+        // only the third page rewritten
+        let writes = d.commands()[before..]
+            .iter()
+            .filter(|&&id| id == 0x0006)
+            .count();
+        assert_eq!(writes, 1);
+        assert_eq!(read_bytes(0x4000, 2048, &bininfo, &d).unwrap(), binary);
+    }
+
+    #[test]
+    fn compat_writes_every_page() {
+        use crate::testing::{FixtureDevice, SAMD51};
+        use crate::{Session, SessionOptions};
+
+        let options = SessionOptions {
+            compat: true,
+            delta: true,
+            ..Default::default()
+        };
+        let d = Session::with_options(FixtureDevice::new(SAMD51), options);
+        let bininfo = crate::bin_info(&d).unwrap();
+        let binary = vec![0x55; 2048];
+        flash_bin(&binary, 0x4000, PadPolicy::Zero, &bininfo, &d).unwrap();
+
+        // unchanged, but compat writes it all again and checksums a page at a time
+        let before = d.device().commands().len();
+        flash_bin(&binary, 0x4000, PadPolicy::Zero, &bininfo, &d).unwrap();
+        let sent = &d.device().commands()[before..];
+        assert_eq!(sent.iter().filter(|&&id| id == 0x0006).count(), 4);
+        assert_eq!(sent.iter().filter(|&&id| id == 0x0007).count(), 4);
+    }
+
+    #[test]
+    fn checksum_per_session() {
+        use crate::testing::{FixtureDevice, SAMD51};
+        use crate::{Session, SessionOptions};
+
+        // fixtures checksum with CRC-16-CCITT, only the session expecting it verifies
+        let ccitt = Session::new(FixtureDevice::new(SAMD51));
+        let kermit = Session::with_options(
+            FixtureDevice::new(SAMD51),
+            SessionOptions {
+                checksum: Crc16::Kermit,
+                ..Default::default()
+            },
+        );
+        let binary = vec![0x55; 1024];
+        for d in [&ccitt, &kermit] {
+            let bininfo = crate::bin_info(d).unwrap();
+            flash(&binary, 0x4000, &bininfo, d, false, &mut ()).unwrap();
+        }
+
+        let bininfo = crate::bin_info(&ccitt).unwrap();
+        let verify = |d| {
+            verify_bin(
+                &binary,
+                0x4000,
+                PadPolicy::Zero,
+                VerifyMode::Checksum,
+                &bininfo,
+                d,
+            )
+        };
+        assert!(verify(&ccitt).is_ok());
+        assert!(verify(&kermit).is_err());
+    }
+
+    #[test]
+    fn retried() {
+        use crate::testing::{FixtureDevice, SAMD51};
+        use crate::{RetryPolicy, Session, SessionOptions};
+
+        let policy = RetryPolicy {
+            retries: 2,
+            backoff: Duration::from_millis(1),
+        };
+        let options = SessionOptions {
+            timeouts: vec![(0x0006, Duration::from_millis(20))],
+            retry_policies: vec![(0x0006, policy)],
+            ..Default::default()
+        };
+        let d = Session::with_options(FixtureDevice::new(SAMD51), options);
+        let bininfo = crate::bin_info(&d).unwrap();
+        let mut retries = vec![];
+        let mut progress = |progress: Progress| retries.extend(progress.retry);
+
+        // the first page's response is lost once, the write goes out again
+        d.device().lose_responses(1);
+        flash(&[0x55; 1024], 0x4000, &bininfo, &d, false, &mut progress).unwrap();
+        assert_eq!(retries.len(), 1);
+        assert_eq!((retries[0].attempts, retries[0].succeeded), (2, true));
+
+        // a refusal isn't sent again
+        d.device().protect_writes(0x4000);
+        let before = d.device().commands().len();
+        assert!(flash(&[0x55; 512], 0x4000, &bininfo, &d, false, &mut ()).is_err());
+        assert_eq!(d.device().commands().len() - before, 1);
+    }
 }
@@ -0,0 +1,89 @@
+use super::{read_bytes, UtilError};
+use crate::{BinInfoResponse, ReadWrite};
+use std::cell::RefCell;
+use std::time::{Duration, Instant};
+
+/// Reads memory through a short lived cache, so polling several overlapping regions, ie a struct
+/// and one of its fields, costs one device round trip per ttl instead of one per region.
+pub struct ReadCache {
+    ttl: Duration,
+    entries: RefCell<Vec<Entry>>,
+}
+
+struct Entry {
+    address: u32,
+    bytes: Vec<u8>,
+    read_at: Instant,
+}
+
+impl Entry {
+    fn covers(&self, address: u32, len: usize) -> bool {
+        address >= self.address
+            && address as u64 + len as u64 <= self.address as u64 + self.bytes.len() as u64
+    }
+}
+
+impl ReadCache {
+    /// Reads are reused for ttl, a zero ttl always reads the device.
+    pub fn new(ttl: Duration) -> Self {
+        ReadCache {
+            ttl,
+            entries: RefCell::new(vec![]),
+        }
+    }
+
+    /// Like read_bytes, answered from a fresh earlier read covering the whole range if there is one.
+    pub fn read(
+        &self,
+        address: u32,
+        len: usize,
+        bininfo: &BinInfoResponse,
+        d: &impl ReadWrite,
+    ) -> Result<Vec<u8>, UtilError> {
+        let mut entries = self.entries.borrow_mut();
+        entries.retain(|entry| entry.read_at.elapsed() < self.ttl);
+
+        if let Some(entry) = entries.iter().find(|entry| entry.covers(address, len)) {
+            let start = (address - entry.address) as usize;
+            return Ok(entry.bytes[start..][..len].to_vec());
+        }
+
+        let bytes = read_bytes(address, len, bininfo, d)?;
+        if !self.ttl.is_zero() {
+            entries.push(Entry {
+                address,
+                bytes: bytes.clone(),
+                read_at: Instant::now(),
+            });
+        }
+        Ok(bytes)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{FixtureDevice, SAMD21};
+
+    #[test]
+    fn overlapping_reads() {
+        let d = FixtureDevice::new(SAMD21);
+        let bininfo = crate::bin_info(&d).unwrap();
+        d.set_flash(0x100, &[1, 2, 3, 4, 5, 6, 7, 8]);
+
+        let cache = ReadCache::new(Duration::from_secs(60));
+        assert_eq!(
+            cache.read(0x100, 8, &bininfo, &d).unwrap(),
+            [1, 2, 3, 4, 5, 6, 7, 8]
+        );
+        let reads = d.commands().len();
+
+        // inside the first read, answered without the device
+        assert_eq!(cache.read(0x102, 2, &bininfo, &d).unwrap(), [3, 4]);
+        assert_eq!(d.commands().len(), reads);
+
+        // past it, read again
+        cache.read(0x106, 4, &bininfo, &d).unwrap();
+        assert!(d.commands().len() > reads);
+    }
+}
@@ -0,0 +1,131 @@
+use super::{read_bytes, UtilError};
+use crate::ReadWrite;
+use crate::{BinInfoResponse, FamilyId};
+
+/// Flash taken by the nRF52 master boot record, a SoftDevice starts right after it.
+pub const MBR_SIZE: u32 = 0x1000;
+/// Where apps linked against the usual S140 SoftDevice start, apps without one start at MBR_SIZE.
+pub const SOFTDEVICE_APP_BASE: u32 = 0x26000;
+
+// the SoftDevice info structure lives at a fixed offset into the SoftDevice, see nrf_sdm.h
+const INFO_ADDRESS: u32 = MBR_SIZE + 0x2000;
+const INFO_MAGIC: u32 = 0x51B1_E5DB;
+
+/// A SoftDevice found in flash.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct SoftDevice {
+    /// Address just past the SoftDevice, which is where the app has to start.
+    pub end: u32,
+    pub firmware_id: u16,
+}
+
+/// Something off about where an image sits relative to the SoftDevice.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum SoftDeviceWarning {
+    /// The image is linked to run on a SoftDevice but there isn't one installed.
+    Missing,
+    /// The image would overwrite the installed SoftDevice.
+    Overwrites(SoftDevice),
+}
+
+impl std::fmt::Display for SoftDeviceWarning {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            SoftDeviceWarning::Missing => write!(
+                f,
+                "image starts past {:#x} so expects a SoftDevice, but none is installed",
+                SOFTDEVICE_APP_BASE
+            ),
+            SoftDeviceWarning::Overwrites(sd) => write!(
+                f,
+                "image overwrites the installed SoftDevice (firmware id {:#06x}, {:#x}..{:#x}), link it at {:#x} or flash without it",
+                sd.firmware_id, MBR_SIZE, sd.end, sd.end
+            ),
+        }
+    }
+}
+
+/// Reads the SoftDevice info structure. None when the board isn't an nRF52 or has no SoftDevice.
+pub fn installed_softdevice(
+    bininfo: &BinInfoResponse,
+    d: &impl ReadWrite,
+) -> Result<Option<SoftDevice>, UtilError> {
+    if bininfo.family_id != Some(FamilyId::NRF52840) {
+        return Ok(None);
+    }
+
+    let info = read_bytes(INFO_ADDRESS + 4, 12, bininfo, d)?;
+    Ok(parse_info(&info))
+}
+
+/// Checks an image about to be flashed against the SoftDevice on an nRF52. Images starting inside
+/// the MBR are taken to be a SoftDevice themselves and not checked.
+pub fn check_softdevice(
+    binary: &[u8],
+    address: u32,
+    bininfo: &BinInfoResponse,
+    d: &impl ReadWrite,
+) -> Result<Option<SoftDeviceWarning>, UtilError> {
+    if bininfo.family_id != Some(FamilyId::NRF52840) || address < MBR_SIZE {
+        return Ok(None);
+    }
+
+    let installed = installed_softdevice(bininfo, d)?;
+    Ok(softdevice_warning(
+        address,
+        address + binary.len() as u32,
+        installed,
+    ))
+}
+
+fn softdevice_warning(
+    start: u32,
+    end: u32,
+    installed: Option<SoftDevice>,
+) -> Option<SoftDeviceWarning> {
+    match installed {
+        Some(sd) if start < sd.end && end > MBR_SIZE => Some(SoftDeviceWarning::Overwrites(sd)),
+        None if start >= SOFTDEVICE_APP_BASE => Some(SoftDeviceWarning::Missing),
+        _ => None,
+    }
+}
+
+// magic, size and firmware id words
+fn parse_info(info: &[u8]) -> Option<SoftDevice> {
+    let word = |i: usize| u32::from_le_bytes([info[i], info[i + 1], info[i + 2], info[i + 3]]);
+    if word(0) != INFO_MAGIC {
+        return None;
+    }
+
+    Some(SoftDevice {
+        end: word(4),
+        firmware_id: word(8) as u16,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn overlap() {
+        let info = [
+            0xDB, 0xE5, 0xB1, 0x51, 0x00, 0x60, 0x02, 0x00, 0xB6, 0x00, 0xFF, 0xFF,
+        ];
+        let sd = parse_info(&info).unwrap();
+        assert_eq!(sd.end, 0x26000);
+        assert_eq!(sd.firmware_id, 0xB6);
+        assert_eq!(parse_info(&[0xFF; 12]), None);
+
+        assert_eq!(softdevice_warning(0x26000, 0x30000, Some(sd)), None);
+        assert_eq!(
+            softdevice_warning(0x1000, 0x8000, Some(sd)),
+            Some(SoftDeviceWarning::Overwrites(sd))
+        );
+        assert_eq!(
+            softdevice_warning(0x26000, 0x30000, None),
+            Some(SoftDeviceWarning::Missing)
+        );
+        assert_eq!(softdevice_warning(0x1000, 0x8000, None), None);
+    }
+}
@@ -0,0 +1,115 @@
+use super::{read_bytes, UtilError};
+use crate::ReadWrite;
+use crate::{BinInfoMode, BinInfoResponse};
+
+/// Where a board reports its supply, ie a static the firmware updates from an ADC or a brownout
+/// flag, and the lowest reading it's safe to flash at. For a flag set when power is good use a
+/// min of 1. The value lives in the app's RAM, which the bootloader reuses, so it's read while the
+/// app runs, before handing over to the bootloader.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct SupplyCheck {
+    pub address: u32,
+    /// Bytes read, 1, 2 or 4, little endian.
+    pub width: u8,
+    pub min: u32,
+}
+
+/// Supply reading too low to flash safely.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct LowSupply {
+    pub reading: u32,
+    pub min: u32,
+}
+
+impl std::fmt::Display for LowSupply {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(
+            f,
+            "supply reads {} but {} is needed to flash safely, a brownout mid flash corrupts the image",
+            self.reading, self.min
+        )
+    }
+}
+
+/// Reads the supply value check points at. Refused with UtilError::AppNotRunning when bininfo is
+/// the bootloader's, whatever is at the address then isn't the app's reading.
+pub fn read_supply(
+    check: &SupplyCheck,
+    bininfo: &BinInfoResponse,
+    d: &impl ReadWrite,
+) -> Result<u32, UtilError> {
+    if bininfo.mode != BinInfoMode::User {
+        return Err(UtilError::AppNotRunning(
+            "the supply is read from the app's RAM, the board is already in its bootloader".into(),
+        ));
+    }
+    if ![1, 2, 4].contains(&check.width) {
+        return Err(UtilError::InvalidRegion(format!(
+            "supply width {} isn't 1, 2 or 4 bytes",
+            check.width
+        )));
+    }
+
+    let bytes = read_bytes(check.address, check.width as usize, bininfo, d)?;
+    Ok(bytes
+        .iter()
+        .rev()
+        .fold(0, |value, byte| value << 8 | *byte as u32))
+}
+
+/// Some when the supply reads under the minimum.
+pub fn check_supply(
+    check: &SupplyCheck,
+    bininfo: &BinInfoResponse,
+    d: &impl ReadWrite,
+) -> Result<Option<LowSupply>, UtilError> {
+    let reading = read_supply(check, bininfo, d)?;
+    if reading < check.min {
+        Ok(Some(LowSupply {
+            reading,
+            min: check.min,
+        }))
+    } else {
+        Ok(None)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{FixtureDevice, RAM_START, SAMD51};
+
+    #[test]
+    fn supply() {
+        let d = FixtureDevice::new(SAMD51);
+        crate::reset_into_app(&d).unwrap();
+        let bininfo = crate::bin_info(&d).unwrap();
+        // millivolts, kept in a static by the app
+        d.set_ram(RAM_START + 0x100, &3100_u16.to_le_bytes());
+
+        let check = SupplyCheck {
+            address: RAM_START + 0x100,
+            width: 2,
+            min: 3300,
+        };
+        assert_eq!(read_supply(&check, &bininfo, &d).unwrap(), 3100);
+        assert_eq!(
+            check_supply(&check, &bininfo, &d).unwrap(),
+            Some(LowSupply {
+                reading: 3100,
+                min: 3300
+            })
+        );
+
+        let check = SupplyCheck { min: 3000, ..check };
+        assert_eq!(check_supply(&check, &bininfo, &d).unwrap(), None);
+
+        // the bootloader reuses the app's RAM
+        crate::start_flash(&d).unwrap();
+        let bininfo = crate::bin_info(&d).unwrap();
+        assert!(matches!(
+            check_supply(&check, &bininfo, &d),
+            Err(UtilError::AppNotRunning(_))
+        ));
+    }
+}
@@ -0,0 +1,492 @@
+use super::UtilError;
+use gimli::{AttributeValue, EndianSlice, LittleEndian, Operation, Unit, UnitOffset};
+use std::path::PathBuf;
+use std::{fs::File, io::Read};
+
+type Reader<'a> = EndianSlice<'a, LittleEndian>;
+
+/// Layout of a variable as described by the elfs debug info, enough to decode its bytes.
+#[derive(Debug, Clone, PartialEq)]
+pub enum VariableType {
+    Unsigned(u64),
+    Signed(u64),
+    Float(u64),
+    Bool,
+    Pointer(u64),
+    Enum {
+        name: String,
+        size: u64,
+        variants: Vec<(i64, String)>,
+    },
+    Struct {
+        name: String,
+        size: u64,
+        members: Vec<(String, u64, VariableType)>,
+    },
+    Array {
+        element: Box<VariableType>,
+        count: u64,
+    },
+    /// No usable type information, just the size in bytes.
+    Opaque(u64),
+}
+
+impl VariableType {
+    /// Size of the type in bytes.
+    pub fn size(&self) -> u64 {
+        match self {
+            VariableType::Unsigned(size)
+            | VariableType::Signed(size)
+            | VariableType::Float(size)
+            | VariableType::Pointer(size)
+            | VariableType::Opaque(size)
+            | VariableType::Enum { size, .. }
+            | VariableType::Struct { size, .. } => *size,
+            VariableType::Bool => 1,
+            VariableType::Array { element, count } => element.size().saturating_mul(*count),
+        }
+    }
+
+    /// Format little endian bytes read from the device as this type.
+    pub fn decode(&self, bytes: &[u8]) -> String {
+        let bytes = match bytes.get(..self.size() as usize) {
+            Some(bytes) => bytes,
+            None => return format!("{:02X?}", bytes),
+        };
+        let size = bytes.len();
+
+        match self {
+            VariableType::Unsigned(_) => match le_u64(bytes) {
+                Some(val) => format!("{}", val),
+                None => format!("{:02X?}", bytes),
+            },
+            VariableType::Signed(_) => match le_u64(bytes) {
+                Some(val) => {
+                    // sign extend from the variables width
+                    let shift = 64 - 8 * size as u32;
+                    format!("{}", ((val << shift) as i64) >> shift)
+                }
+                None => format!("{:02X?}", bytes),
+            },
+            VariableType::Float(4) => {
+                format!("{}", f32::from_bits(le_u64(bytes).unwrap_or(0) as u32))
+            }
+            VariableType::Float(8) => format!("{}", f64::from_bits(le_u64(bytes).unwrap_or(0))),
+            VariableType::Bool => format!("{}", bytes[0] != 0),
+            VariableType::Pointer(_) => format!("{:#010x}", le_u64(bytes).unwrap_or(0)),
+            VariableType::Enum { variants, .. } => {
+                let val = le_u64(bytes).unwrap_or(0) as i64;
+                match variants.iter().find(|(discr, _)| *discr == val) {
+                    Some((_, name)) => name.clone(),
+                    None => format!("{}", val),
+                }
+            }
+            VariableType::Struct { name, members, .. } => {
+                let fields: Vec<String> = members
+                    .iter()
+                    .map(|(member, offset, ty)| {
+                        // a member placed past the end decodes as missing rather than panicking
+                        let member_bytes = bytes.get(*offset as usize..).unwrap_or(&[]);
+                        format!("{}: {}", member, ty.decode(member_bytes))
+                    })
+                    .collect();
+                format!("{} {{ {} }}", name, fields.join(", "))
+            }
+            VariableType::Array { element, .. } => {
+                let elements: Vec<String> = bytes
+                    .chunks(element.size().max(1) as usize)
+                    .map(|chunk| element.decode(chunk))
+                    .collect();
+                format!("[{}]", elements.join(", "))
+            }
+            _ => format!("{:02X?}", bytes),
+        }
+    }
+}
+
+fn le_u64(bytes: &[u8]) -> Option<u64> {
+    if bytes.is_empty() || bytes.len() > 8 {
+        return None;
+    }
+    Some(
+        bytes
+            .iter()
+            .rev()
+            .fold(0_u64, |acc, byte| acc << 8 | *byte as u64),
+    )
+}
+
+/// A variable found in an elf, with its address in device memory.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Variable {
+    pub name: String,
+    pub address: u32,
+    pub ty: VariableType,
+}
+
+/// Looks up a static variable by name, decoding its type from DWARF info when present and
+/// otherwise falling back to the symbol table with an opaque type of the symbols size.
+pub fn elf_variable(path: PathBuf, name: &str) -> Result<Variable, UtilError> {
+    let mut file = File::open(path).map_err(|_| UtilError::File)?;
+    let mut buffer = vec![];
+    file.read_to_end(&mut buffer).map_err(|_| UtilError::File)?;
+
+    let binary = goblin::elf::Elf::parse(buffer.as_slice()).map_err(|_| UtilError::Elf)?;
+
+    let section = |section_name: &str| -> &[u8] {
+        binary
+            .section_headers
+            .iter()
+            .find(
+                |sh| matches!(binary.shdr_strtab.get(sh.sh_name), Some(Ok(n)) if n == section_name),
+            )
+            // a truncated file has nothing to read there
+            .and_then(|sh| {
+                let start = sh.sh_offset as usize;
+                buffer.get(start..start.checked_add(sh.sh_size as usize)?)
+            })
+            .unwrap_or(&[])
+    };
+
+    let dwarf = gimli::Dwarf::load(|id| -> Result<Reader, gimli::Error> {
+        Ok(EndianSlice::new(section(id.name()), LittleEndian))
+    })
+    .map_err(|_| UtilError::Elf)?;
+
+    if let Some(variable) = dwarf_variable(&dwarf, name).map_err(|_| UtilError::Elf)? {
+        return Ok(variable);
+    }
+
+    binary
+        .syms
+        .iter()
+        .find(|sym| matches!(binary.strtab.get(sym.st_name), Some(Ok(n)) if n == name))
+        .map(|sym| Variable {
+            name: name.into(),
+            address: sym.st_value as u32,
+            ty: VariableType::Opaque(sym.st_size),
+        })
+        .ok_or(UtilError::SymbolNotFound)
+}
+
+fn dwarf_variable(
+    dwarf: &gimli::Dwarf<Reader>,
+    name: &str,
+) -> Result<Option<Variable>, gimli::Error> {
+    let mut units = dwarf.units();
+    while let Some(header) = units.next()? {
+        let unit = dwarf.unit(header)?;
+        let mut entries = unit.entries();
+        while let Some((_, entry)) = entries.next_dfs()? {
+            if entry.tag() != gimli::DW_TAG_variable {
+                continue;
+            }
+            match entry.attr_value(gimli::DW_AT_name)? {
+                Some(attr) if dwarf.attr_string(&unit, attr)?.slice() == name.as_bytes() => (),
+                _ => continue,
+            }
+
+            // only statics have a fixed address, locals live on the stack
+            let address = match entry.attr_value(gimli::DW_AT_location)? {
+                Some(AttributeValue::Exprloc(expr)) => {
+                    match expr.operations(unit.encoding()).next()? {
+                        Some(Operation::Address { address }) => address,
+                        _ => continue,
+                    }
+                }
+                _ => continue,
+            };
+
+            let ty = match entry.attr_value(gimli::DW_AT_type)? {
+                Some(AttributeValue::UnitRef(offset)) => resolve_type(dwarf, &unit, offset, 0)?,
+                _ => VariableType::Opaque(0),
+            };
+
+            return Ok(Some(Variable {
+                name: name.into(),
+                address: address as u32,
+                ty,
+            }));
+        }
+    }
+    Ok(None)
+}
+
+fn resolve_type(
+    dwarf: &gimli::Dwarf<Reader>,
+    unit: &Unit<Reader>,
+    offset: UnitOffset,
+    depth: usize,
+) -> Result<VariableType, gimli::Error> {
+    // guard against self referential types
+    if depth > 16 {
+        return Ok(VariableType::Opaque(0));
+    }
+
+    let entry = unit.entry(offset)?;
+    let size = entry
+        .attr_value(gimli::DW_AT_byte_size)?
+        .and_then(|v| v.udata_value())
+        .unwrap_or(0);
+    let name = match entry.attr_value(gimli::DW_AT_name)? {
+        Some(attr) => dwarf
+            .attr_string(unit, attr)?
+            .to_string_lossy()
+            .into_owned(),
+        None => String::new(),
+    };
+    let inner = match entry.attr_value(gimli::DW_AT_type)? {
+        Some(AttributeValue::UnitRef(offset)) => Some(offset),
+        _ => None,
+    };
+
+    let ty = match entry.tag() {
+        gimli::DW_TAG_base_type => match entry.attr_value(gimli::DW_AT_encoding)? {
+            Some(AttributeValue::Encoding(gimli::DW_ATE_boolean)) => VariableType::Bool,
+            Some(AttributeValue::Encoding(gimli::DW_ATE_float)) => VariableType::Float(size),
+            Some(AttributeValue::Encoding(gimli::DW_ATE_signed))
+            | Some(AttributeValue::Encoding(gimli::DW_ATE_signed_char)) => {
+                VariableType::Signed(size)
+            }
+            Some(AttributeValue::Encoding(gimli::DW_ATE_unsigned))
+            | Some(AttributeValue::Encoding(gimli::DW_ATE_unsigned_char)) => {
+                VariableType::Unsigned(size)
+            }
+            _ => VariableType::Opaque(size),
+        },
+        gimli::DW_TAG_pointer_type | gimli::DW_TAG_reference_type => {
+            VariableType::Pointer(if size == 0 { 4 } else { size })
+        }
+        gimli::DW_TAG_typedef
+        | gimli::DW_TAG_const_type
+        | gimli::DW_TAG_volatile_type
+        | gimli::DW_TAG_atomic_type => match inner {
+            Some(inner) => resolve_type(dwarf, unit, inner, depth + 1)?,
+            None => VariableType::Opaque(size),
+        },
+        gimli::DW_TAG_structure_type | gimli::DW_TAG_class_type => {
+            let mut members = vec![];
+            let mut tree = unit.entries_tree(Some(offset))?;
+            let mut children = tree.root()?.children();
+            while let Some(child) = children.next()? {
+                let member = child.entry();
+                if member.tag() != gimli::DW_TAG_member {
+                    continue;
+                }
+                let member_name = match member.attr_value(gimli::DW_AT_name)? {
+                    Some(attr) => dwarf
+                        .attr_string(unit, attr)?
+                        .to_string_lossy()
+                        .into_owned(),
+                    None => String::new(),
+                };
+                let member_offset = member
+                    .attr_value(gimli::DW_AT_data_member_location)?
+                    .and_then(|v| v.udata_value())
+                    .unwrap_or(0);
+                let member_type = match member.attr_value(gimli::DW_AT_type)? {
+                    Some(AttributeValue::UnitRef(offset)) => {
+                        resolve_type(dwarf, unit, offset, depth + 1)?
+                    }
+                    _ => VariableType::Opaque(0),
+                };
+                members.push((member_name, member_offset, member_type));
+            }
+            VariableType::Struct {
+                name,
+                size,
+                members,
+            }
+        }
+        gimli::DW_TAG_enumeration_type => {
+            let mut variants = vec![];
+            let mut tree = unit.entries_tree(Some(offset))?;
+            let mut children = tree.root()?.children();
+            while let Some(child) = children.next()? {
+                let variant = child.entry();
+                if variant.tag() != gimli::DW_TAG_enumerator {
+                    continue;
+                }
+                let variant_name = match variant.attr_value(gimli::DW_AT_name)? {
+                    Some(attr) => dwarf
+                        .attr_string(unit, attr)?
+                        .to_string_lossy()
+                        .into_owned(),
+                    None => String::new(),
+                };
+                let value = variant
+                    .attr_value(gimli::DW_AT_const_value)?
+                    .and_then(|v| v.sdata_value())
+                    .unwrap_or(0);
+                variants.push((value, variant_name));
+            }
+            VariableType::Enum {
+                name,
+                size,
+                variants,
+            }
+        }
+        gimli::DW_TAG_array_type => {
+            let element = match inner {
+                Some(inner) => resolve_type(dwarf, unit, inner, depth + 1)?,
+                None => VariableType::Opaque(0),
+            };
+            let mut count = 0;
+            let mut tree = unit.entries_tree(Some(offset))?;
+            let mut children = tree.root()?.children();
+            while let Some(child) = children.next()? {
+                let subrange = child.entry();
+                if subrange.tag() != gimli::DW_TAG_subrange_type {
+                    continue;
+                }
+                count = match subrange.attr_value(gimli::DW_AT_count)? {
+                    Some(v) => v.udata_value().unwrap_or(0),
+                    None => subrange
+                        .attr_value(gimli::DW_AT_upper_bound)?
+                        .and_then(|v| v.udata_value())
+                        .map(|upper| upper + 1)
+                        .unwrap_or(0),
+                };
+            }
+            VariableType::Array {
+                element: Box::new(element),
+                count,
+            }
+        }
+        _ => VariableType::Opaque(size),
+    };
+
+    Ok(ty)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn symbol_fallback() {
+        let variable = elf_variable(
+            [
+                env!("CARGO_MANIFEST_DIR"),
+                "src/utils/testdata/blinky_1.47.0",
+            ]
+            .iter()
+            .collect(),
+            "DEVICE_PERIPHERALS",
+        )
+        .unwrap();
+        assert_eq!(variable.address, 0x2000_0000);
+        assert_eq!(variable.ty, VariableType::Opaque(1));
+    }
+
+    #[test]
+    fn decode_struct() {
+        let ty = VariableType::Struct {
+            name: "State".into(),
+            size: 8,
+            members: vec![
+                ("count".into(), 0, VariableType::Unsigned(4)),
+                ("delta".into(), 4, VariableType::Signed(2)),
+                ("on".into(), 6, VariableType::Bool),
+            ],
+        };
+        assert_eq!(
+            ty.decode(&[0x2A, 0x00, 0x00, 0x00, 0xFE, 0xFF, 0x01, 0x00]),
+            "State { count: 42, delta: -2, on: true }"
+        );
+
+        let ty = VariableType::Struct {
+            name: "Broken".into(),
+            size: 4,
+            members: vec![("far".into(), 0x1000, VariableType::Unsigned(4))],
+        };
+        assert_eq!(ty.decode(&[0; 4]), "Broken { far: [] }");
+        assert_eq!(VariableType::Unsigned(4).decode(&[1, 2]), "[01, 02]");
+    }
+
+    #[test]
+    fn dwarf_types() {
+        // built from testdata/variables.c
+        let path = || -> PathBuf {
+            [env!("CARGO_MANIFEST_DIR"), "src/utils/testdata/variables"]
+                .iter()
+                .collect()
+        };
+
+        let state = elf_variable(path(), "STATE").unwrap();
+        assert_eq!(state.address, 0x2000_0000);
+        assert_eq!(
+            state.ty,
+            VariableType::Struct {
+                name: "state".into(),
+                size: 16,
+                members: vec![
+                    ("count".into(), 0, VariableType::Unsigned(4)),
+                    ("delta".into(), 4, VariableType::Signed(2)),
+                    ("on".into(), 6, VariableType::Bool),
+                    (
+                        "mode".into(),
+                        8,
+                        VariableType::Enum {
+                            name: "mode".into(),
+                            size: 4,
+                            variants: vec![(0, "IDLE".into()), (5, "RUNNING".into())],
+                        }
+                    ),
+                    (
+                        "history".into(),
+                        12,
+                        VariableType::Array {
+                            element: Box::new(VariableType::Unsigned(1)),
+                            count: 3,
+                        }
+                    ),
+                ],
+            }
+        );
+        assert_eq!(
+            state
+                .ty
+                .decode(&[42, 0, 0, 0, 0xFE, 0xFF, 1, 0, 5, 0, 0, 0, 1, 2, 3, 0]),
+            "state { count: 42, delta: -2, on: true, mode: RUNNING, history: [1, 2, 3] }"
+        );
+
+        let gain = elf_variable(path(), "GAIN").unwrap();
+        assert_eq!(gain.ty, VariableType::Float(4));
+        assert_eq!(gain.ty.decode(&1.5_f32.to_le_bytes()), "1.5");
+
+        assert!(matches!(
+            elf_variable(path(), "MISSING"),
+            Err(UtilError::SymbolNotFound)
+        ));
+    }
+
+    #[test]
+    fn section_past_end() {
+        let path: PathBuf = [env!("CARGO_MANIFEST_DIR"), "src/utils/testdata/variables"]
+            .iter()
+            .collect();
+        let mut buffer = std::fs::read(path).unwrap();
+
+        // .debug_info claiming to run past the end of the file, sh_size is 32 bytes into the
+        // header of a 64 bit elf
+        let elf = goblin::elf::Elf::parse(&buffer).unwrap();
+        let index = elf
+            .section_headers
+            .iter()
+            .position(|sh| matches!(elf.shdr_strtab.get(sh.sh_name), Some(Ok(".debug_info"))))
+            .unwrap();
+        let size = elf.header.e_shoff as usize + index * elf.header.e_shentsize as usize + 32;
+        buffer[size..size + 8].copy_from_slice(&u64::MAX.to_le_bytes());
+
+        let broken = std::env::temp_dir().join(format!("hf2-broken-{}", std::process::id()));
+        std::fs::write(&broken, &buffer).unwrap();
+        let state = elf_variable(broken.clone(), "STATE");
+        std::fs::remove_file(&broken).unwrap();
+
+        // no debug info left to decode, so the symbol table's size
+        let state = state.unwrap();
+        assert_eq!(state.address, 0x2000_0000);
+        assert_eq!(state.ty, VariableType::Opaque(16));
+    }
+}
@@ -0,0 +1,7 @@
+// gcc -nostdlib -static -Os -Wl,--build-id=sha1 -o firmware firmware.c
+__attribute__((section(".fw_version"), used)) static const char VERSION[] = "1.2.0";
+
+void _start(void) {
+    for (;;) {
+    }
+}
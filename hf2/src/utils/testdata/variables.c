@@ -0,0 +1,18 @@
+// built with gcc -g -gdwarf-4 -O0 -nostdlib -static -Wl,-e,0 -Wl,-Ttext=0x1000 -Wl,-Tdata=0x20000000 -o variables variables.c
+#include <stdbool.h>
+#include <stdint.h>
+
+enum mode { IDLE, RUNNING = 5 };
+
+struct state {
+    uint32_t count;
+    int16_t delta;
+    bool on;
+    enum mode mode;
+    uint8_t history[3];
+};
+
+typedef volatile struct state state_t;
+
+state_t STATE = { 42, -2, true, RUNNING, { 1, 2, 3 } };
+const float GAIN = 1.5f;
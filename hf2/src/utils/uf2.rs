@@ -0,0 +1,295 @@
+use super::{assemble, Region, UtilError};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+
+const MAGIC_START0: u32 = 0x0A32_4655;
+const MAGIC_START1: u32 = 0x9E5D_5157;
+const MAGIC_END: u32 = 0x0AB1_6F30;
+const FLAG_NOT_MAIN_FLASH: u32 = 0x0000_0001;
+const FLAG_FAMILY_ID: u32 = 0x0000_2000;
+const PAYLOAD_SIZE: usize = 256;
+
+/// A mounted UF2 bootloader drive, recognized by the INFO_UF2.TXT every UF2 bootloader serves.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Uf2Volume {
+    pub path: PathBuf,
+    /// Board-ID line of INFO_UF2.TXT, if there was one.
+    pub board_id: Option<String>,
+}
+
+/// Wraps a binary into UF2 blocks for copying onto a UF2 drive. The family id is optional, most
+/// bootloaders take blocks without one.
+pub fn bin_to_uf2(binary: &[u8], address: u32, family_id: Option<u32>) -> Vec<u8> {
+    let num_blocks = binary.len().div_ceil(PAYLOAD_SIZE);
+    let mut uf2 = Vec::with_capacity(num_blocks * 512);
+
+    for (block_no, chunk) in binary.chunks(PAYLOAD_SIZE).enumerate() {
+        let mut block = [0u8; 512];
+        let header = [
+            MAGIC_START0,
+            MAGIC_START1,
+            family_id.map_or(0, |_| FLAG_FAMILY_ID),
+            address + (block_no * PAYLOAD_SIZE) as u32,
+            PAYLOAD_SIZE as u32,
+            block_no as u32,
+            num_blocks as u32,
+            family_id.unwrap_or(0),
+        ];
+        for (i, word) in header.iter().enumerate() {
+            block[i * 4..][..4].copy_from_slice(&word.to_le_bytes());
+        }
+        block[32..][..chunk.len()].copy_from_slice(chunk);
+        block[508..].copy_from_slice(&MAGIC_END.to_le_bytes());
+        uf2.extend_from_slice(&block);
+    }
+
+    uf2
+}
+
+/// Returns a contiguous bin with 0xFF between non-contiguous blocks and starting address from a
+/// UF2 file, skipping blocks not meant for main flash. Errors rather than panics on malformed input.
+pub fn parse_uf2(uf2: &[u8]) -> Result<(Vec<u8>, u32), UtilError> {
+    let records = blocks(uf2)?
+        .into_iter()
+        .map(|block| (block.address, block.data))
+        .collect();
+    assemble(records)
+}
+
+/// Regions of a UF2 file to flash over hf2, one per run of flash pages holding blocks so the pages
+/// between them are left alone. Blocks are smaller than most pages, so each region starts on a page
+/// and blocks sharing a page go in the same region, the gaps between them erased as a UF2 drive
+/// leaves them. Like a UF2 drive, blocks tagged for a family other than family_id are skipped,
+/// which is how one file carries images for several chips.
+pub fn uf2_regions(
+    uf2: &[u8],
+    family_id: Option<u32>,
+    page_size: u32,
+) -> Result<Vec<Region>, UtilError> {
+    if page_size == 0 {
+        return Err(UtilError::InvalidRegion("page size of 0".into()));
+    }
+    let mut blocks: Vec<Block> = blocks(uf2)?
+        .into_iter()
+        .filter(|block| match (block.family_id, family_id) {
+            (Some(block), Some(device)) => block == device,
+            _ => true,
+        })
+        .collect();
+    blocks.sort_by_key(|block| block.address);
+
+    let mut regions: Vec<Region> = vec![];
+    for block in blocks {
+        let page = block.address - block.address % page_size;
+        if let Some(region) = regions.last_mut() {
+            let end = region.address as u64 + region.binary.len() as u64;
+            if (block.address as u64) < end {
+                return Err(UtilError::InvalidBinary);
+            }
+            // in the page the region ends in, or right after it
+            if end > page as u64 || end == block.address as u64 {
+                region
+                    .binary
+                    .resize((block.address - region.address) as usize, 0xFF);
+                region.binary.extend_from_slice(&block.data);
+                continue;
+            }
+        }
+        let mut binary = vec![0xFF; (block.address - page) as usize];
+        binary.extend_from_slice(&block.data);
+        regions.push(Region {
+            address: page,
+            binary,
+            page_size: None,
+        });
+    }
+    Ok(regions)
+}
+
+struct Block {
+    address: u32,
+    family_id: Option<u32>,
+    data: Vec<u8>,
+}
+
+// main flash blocks, checking each is whole and numbered within its file
+fn blocks(uf2: &[u8]) -> Result<Vec<Block>, UtilError> {
+    if uf2.is_empty() || !uf2.len().is_multiple_of(512) {
+        return Err(UtilError::InvalidBinary);
+    }
+
+    let mut blocks = vec![];
+    for block in uf2.chunks(512) {
+        let word =
+            |i: usize| u32::from_le_bytes([block[i], block[i + 1], block[i + 2], block[i + 3]]);
+        if word(0) != MAGIC_START0 || word(4) != MAGIC_START1 || word(508) != MAGIC_END {
+            return Err(UtilError::InvalidBinary);
+        }
+        if word(20) >= word(24) {
+            return Err(UtilError::InvalidBinary);
+        }
+        if word(8) & FLAG_NOT_MAIN_FLASH != 0 {
+            continue;
+        }
+
+        let payload_size = word(16) as usize;
+        if payload_size > 476 {
+            return Err(UtilError::InvalidBinary);
+        }
+        blocks.push(Block {
+            address: word(12),
+            family_id: Some(word(28)).filter(|_| word(8) & FLAG_FAMILY_ID != 0),
+            data: block[32..][..payload_size].to_vec(),
+        });
+    }
+    Ok(blocks)
+}
+
+/// Mounted UF2 drives, for boards that are in the bootloader but not reachable over hf2.
+pub fn uf2_volumes() -> Vec<Uf2Volume> {
+    mount_points()
+        .into_iter()
+        .filter_map(|path| {
+            let info = std::fs::read_to_string(path.join("INFO_UF2.TXT")).ok()?;
+            let board_id = info
+                .lines()
+                .find_map(|line| line.strip_prefix("Board-ID:"))
+                .map(|id| id.trim().to_string());
+            Some(Uf2Volume { path, board_id })
+        })
+        .collect()
+}
+
+/// Copies a UF2 file onto the drive, the bootloader flashes it and resets into the app, then waits
+/// up to timeout for the drive to unmount as the board resets. There's no hf2 session to be read
+/// only, callers inspecting a board shouldn't call it.
+pub fn flash_uf2_volume(
+    uf2: &[u8],
+    volume: &Uf2Volume,
+    timeout: Duration,
+) -> Result<(), UtilError> {
+    std::fs::write(volume.path.join("FLASH.UF2"), uf2).map_err(|_| UtilError::File)?;
+
+    let deadline = Instant::now() + timeout;
+    while Instant::now() < deadline {
+        if !is_mounted(&volume.path) {
+            return Ok(());
+        }
+        std::thread::sleep(Duration::from_millis(100));
+    }
+    Err(UtilError::Communication)
+}
+
+fn is_mounted(path: &Path) -> bool {
+    path.join("INFO_UF2.TXT").exists()
+}
+
+#[cfg(target_os = "linux")]
+fn mount_points() -> Vec<PathBuf> {
+    let mounts = std::fs::read_to_string("/proc/mounts").unwrap_or_default();
+    mounts
+        .lines()
+        .filter_map(|line| line.split_whitespace().nth(1))
+        // spaces in mount points are escaped
+        .map(|path| PathBuf::from(path.replace("\\040", " ")))
+        .collect()
+}
+
+#[cfg(target_os = "macos")]
+fn mount_points() -> Vec<PathBuf> {
+    std::fs::read_dir("/Volumes")
+        .map(|dir| dir.filter_map(|e| e.ok()).map(|e| e.path()).collect())
+        .unwrap_or_default()
+}
+
+#[cfg(windows)]
+fn mount_points() -> Vec<PathBuf> {
+    (b'A'..=b'Z')
+        .map(|letter| PathBuf::from(format!("{}:\\", letter as char)))
+        .collect()
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "macos", windows)))]
+fn mount_points() -> Vec<PathBuf> {
+    vec![]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn uf2_blocks() {
+        let binary = vec![0xAB; 300];
+        let uf2 = bin_to_uf2(&binary, 0x4000, Some(0x55114460));
+        assert_eq!(uf2.len(), 1024);
+
+        let word = |block: usize, i: usize| {
+            u32::from_le_bytes([
+                uf2[block * 512 + i],
+                uf2[block * 512 + i + 1],
+                uf2[block * 512 + i + 2],
+                uf2[block * 512 + i + 3],
+            ])
+        };
+        assert_eq!(word(0, 0), MAGIC_START0);
+        assert_eq!(word(0, 8), FLAG_FAMILY_ID);
+        assert_eq!(word(1, 12), 0x4100);
+        assert_eq!(word(1, 20), 1);
+        assert_eq!(word(1, 24), 2);
+        assert_eq!(word(1, 28), 0x55114460);
+        assert_eq!(word(1, 508), MAGIC_END);
+        // the tail of the last block is zero
+        assert_eq!(uf2[512 + 32 + 44], 0);
+
+        // round trips, padded out to the last payload
+        let (parsed, address) = parse_uf2(&uf2).unwrap();
+        assert_eq!(address, 0x4000);
+        assert_eq!(&parsed[..300], &binary[..]);
+        assert_eq!(parsed.len(), 512);
+
+        assert!(parse_uf2(&uf2[..511]).is_err());
+        assert!(parse_uf2(&[0; 512]).is_err());
+    }
+
+    #[test]
+    fn regions() {
+        let mut uf2 = bin_to_uf2(&[0x11; 512], 0x4000, Some(0x55114460));
+        uf2.extend(bin_to_uf2(&[0x22; 256], 0x8000, Some(0x55114460)));
+        uf2.extend(bin_to_uf2(&[0x33; 256], 0x4000, Some(0x1b57745f)));
+
+        let regions = uf2_regions(&uf2, Some(0x55114460), 256).unwrap();
+        assert_eq!(regions.len(), 2);
+        assert_eq!((regions[0].address, regions[0].binary.len()), (0x4000, 512));
+        assert_eq!((regions[1].address, regions[1].binary.len()), (0x8000, 256));
+
+        // without a family every block is taken, and these overlap
+        assert!(uf2_regions(&uf2, None, 256).is_err());
+
+        // a block numbered past the count
+        let mut bad = bin_to_uf2(&[0; 256], 0x4000, None);
+        bad[20..24].copy_from_slice(&1u32.to_le_bytes());
+        assert!(uf2_regions(&bad, None, 256).is_err());
+    }
+
+    #[test]
+    fn regions_by_page() {
+        // two blocks in one 4KiB page with a gap, one starting mid page further on
+        let mut uf2 = bin_to_uf2(&[0x11; 256], 0x1000, None);
+        uf2.extend(bin_to_uf2(&[0x22; 256], 0x1400, None));
+        uf2.extend(bin_to_uf2(&[0x33; 256], 0x3100, None));
+
+        let regions = uf2_regions(&uf2, None, 4096).unwrap();
+        assert_eq!(regions.len(), 2);
+        assert_eq!(regions[0].address, 0x1000);
+        assert_eq!(&regions[0].binary[..0x100], &[0x11; 0x100][..]);
+        assert_eq!(&regions[0].binary[0x100..0x400], &[0xFF; 0x300][..]);
+        assert_eq!(&regions[0].binary[0x400..], &[0x22; 0x100][..]);
+        assert_eq!(regions[1].address, 0x3000);
+        assert_eq!(&regions[1].binary[..0x100], &[0xFF; 0x100][..]);
+        assert_eq!(&regions[1].binary[0x100..], &[0x33; 0x100][..]);
+
+        // with 256 byte pages they stay apart
+        assert_eq!(uf2_regions(&uf2, None, 256).unwrap().len(), 3);
+    }
+}
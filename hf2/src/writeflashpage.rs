@@ -1,10 +1,11 @@
-use crate::command::{rx, xmit, Command};
-use crate::Error;
+use crate::command::{xfer, Command};
+use crate::{Error, ReadWrite};
 use scroll::Pwrite;
 
-///Write a single page of flash memory. Empty tuple response.
+///Write a single page of flash memory. Empty tuple response, Execution when the bootloader refuses
+///the page, ie one that's protected.
 pub fn write_flash_page(
-    d: &hidapi::HidDevice,
+    d: &impl ReadWrite,
     target_address: u32,
     data: Vec<u8>,
 ) -> Result<(), Error> {
@@ -16,7 +17,7 @@ pub fn write_flash_page(
         buffer.gwrite_with(i, &mut offset, scroll::LE)?;
     }
 
-    xmit(Command::new(0x0006, 0, buffer), d)?;
-
-    rx(d).map(|_| ())
+    xfer(Command::new(0x0006, buffer), d)
+        .map(|_| ())
+        .map_err(|e| e.at(target_address))
 }
@@ -1,14 +1,20 @@
-use crate::command::{rx, xmit, Command};
-use crate::Error;
+use crate::command::{xfer, Command};
+use crate::{Error, ErrorKind, ReadWrite};
 use scroll::Pwrite;
 
-///Dual of READ WORDS, with the same constraints. Empty tuple response.
+///Dual of READ WORDS, with the same constraints. Empty tuple response. Unaligned addresses or a num_words that isn't words.len() are refused with ErrorKind::Arguments before anything is sent, Execution when the device refuses the write.
 pub fn write_words(
-    d: &hidapi::HidDevice,
+    d: &impl ReadWrite,
     target_address: u32,
     num_words: u32,
     words: Vec<u32>,
 ) -> Result<(), Error> {
+    if !target_address.is_multiple_of(4) || words.is_empty() || words.len() != num_words as usize {
+        return Err(Error::new(ErrorKind::Arguments)
+            .during(0x0009)
+            .at(target_address));
+    }
+
     let mut buffer = vec![0_u8; words.len() * 4 + 8];
     let mut offset = 0;
 
@@ -18,7 +24,7 @@ pub fn write_words(
         buffer.gwrite_with(i, &mut offset, scroll::LE)?;
     }
 
-    xmit(Command::new(0x0009, 0, buffer), d)?;
-
-    rx(d).map(|_| ())
+    xfer(Command::new(0x0009, buffer), d)
+        .map(|_| ())
+        .map_err(|e| e.at(target_address))
 }